@@ -0,0 +1,477 @@
+// Optional accounts (ACCOUNTS): a poster who registers and logs in has their
+// username shown on their posts instead of "Anonymous". Posting without an
+// account keeps working as before.
+//
+// Keys:
+// user_{name}       JSON User, the name lowercased so names differ by more than case
+// session_{token}   JSON Session, the token being the value of the session cookie
+//
+// Passwords are hashed with PBKDF2-HMAC-SHA256, as sha2 is already a dependency
+// and no Argon2 implementation is. The iteration count is stored with each hash,
+// so it can be raised later without invalidating existing passwords.
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use askama::Template;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::Db;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::admin::constant_time_eq;
+use crate::cooldown::{self, Cooldowns};
+use crate::settings::Settings;
+use crate::client_ip;
+
+const SESSION_COOKIE: &str = "session";
+#[cfg(not(test))]
+const PBKDF2_ITERATIONS: u32 = 600_000;
+// The tests hash many passwords, which would take minutes unoptimized
+#[cfg(test)]
+const PBKDF2_ITERATIONS: u32 = 1_000;
+const MIN_USERNAME_CHARS: usize = 3;
+const MAX_USERNAME_CHARS: usize = 20;
+const MIN_PASSWORD_CHARS: usize = 8;
+const MAX_PASSWORD_CHARS: usize = 128;
+
+#[derive(Serialize, Deserialize)]
+struct User {
+    // As registered, with its case
+    name: String,
+    // pbkdf2-sha256${iterations}${salt}${hash}, salt and hash in base64
+    password_hash: String,
+    created_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Session {
+    username: String,
+    expires_at: i64,
+}
+
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate<'a> {
+    username: Option<String>,
+    error: Option<&'a str>,
+    base_path: &'a str,
+    theme_color: &'a str,
+}
+
+#[derive(Deserialize)]
+pub struct AccountForm {
+    username: String,
+    password: String,
+}
+
+// The username of the poster behind `req`, if they are logged in
+pub fn current_user(req: &HttpRequest, db: &Db, settings: &Settings) -> Option<String> {
+    if !settings.accounts {
+        return None;
+    }
+    let token = req.cookie(SESSION_COOKIE)?.value().to_string();
+    let session: Session = serde_json::from_slice(&db.get(session_key(&token)).ok()??).ok()?;
+    if session.expires_at <= chrono::Utc::now().timestamp() {
+        let _ = db.remove(session_key(&token));
+        return None;
+    }
+    Some(session.username)
+}
+
+// Account page handler: the login and registration forms, or who is logged in
+pub async fn account_page(req: HttpRequest, db: web::Data<Arc<Db>>, settings: web::Data<Settings>) -> impl Responder {
+    if !settings.accounts {
+        return HttpResponse::NotFound().body("Not found");
+    }
+    page(HttpResponse::Ok(), &settings, current_user(&req, &db, &settings), None)
+}
+
+// Registration handler; a new account is logged in straight away
+pub async fn register(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    cooldowns: web::Data<Cooldowns>,
+    form: web::Form<AccountForm>,
+) -> impl Responder {
+    if !settings.accounts {
+        return HttpResponse::NotFound().body("Not found");
+    }
+    if let Some(throttled) = throttle(&req, &settings, &cooldowns) {
+        return throttled;
+    }
+    let name = form.username.trim();
+    if let Some(problem) = username_problem(name).or_else(|| password_problem(&form.password)) {
+        return page(HttpResponse::BadRequest(), &settings, None, Some(problem));
+    }
+
+    // Hashing takes a noticeable moment on purpose, so keep it off the workers
+    let password = form.password.clone();
+    let password_hash = match web::block(move || hash_password(&password)).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return HttpResponse::InternalServerError().body("Registration failed");
+        }
+    };
+    let user = User {
+        name: name.to_string(),
+        password_hash,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    let value = serde_json::to_vec(&user).expect("Failed to serialize user");
+    match db.compare_and_swap(user_key(name), None as Option<&[u8]>, Some(value)) {
+        Ok(Ok(())) => {
+            info!("Registered user {}", name);
+            logged_in(&db, &settings, name)
+        }
+        Ok(Err(_)) => page(HttpResponse::Conflict(), &settings, None, Some("That username is taken")),
+        Err(e) => {
+            error!("Failed to store user {}: {}", name, e);
+            HttpResponse::InternalServerError().body("Registration failed")
+        }
+    }
+}
+
+// Login handler
+pub async fn login(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    cooldowns: web::Data<Cooldowns>,
+    form: web::Form<AccountForm>,
+) -> impl Responder {
+    if !settings.accounts {
+        return HttpResponse::NotFound().body("Not found");
+    }
+    if let Some(throttled) = throttle(&req, &settings, &cooldowns) {
+        return throttled;
+    }
+    let user: Option<User> = db
+        .get(user_key(form.username.trim()))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok());
+    let password = form.password.clone();
+    let verified = web::block(move || match user {
+        Some(user) => verify_password(&password, &user.password_hash).then_some(user.name),
+        None => {
+            verify_password(&password, &dummy_hash());
+            None
+        }
+    })
+    .await
+    .ok()
+    .flatten();
+    match verified {
+        Some(name) => logged_in(&db, &settings, &name),
+        None => {
+            info!("Rejected login attempt for {:?}", form.username.trim());
+            page(HttpResponse::Unauthorized(), &settings, None, Some("Wrong username or password"))
+        }
+    }
+}
+
+// Logout handler: ends the session and clears its cookie
+pub async fn logout(req: HttpRequest, db: web::Data<Arc<Db>>, settings: web::Data<Settings>) -> impl Responder {
+    if !settings.accounts {
+        return HttpResponse::NotFound().body("Not found");
+    }
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let _ = db.remove(session_key(cookie.value()));
+    }
+    let mut cookie = session_cookie(&settings, String::new());
+    cookie.make_removal();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header(("Location", settings.url("/account")))
+        .finish()
+}
+
+// Delete the sessions that ran out without their owner coming back
+pub fn purge_expired_sessions(db: &Db) -> usize {
+    let now = chrono::Utc::now().timestamp();
+    let mut purged = 0;
+    for (key, value) in db.scan_prefix(b"session_").flatten() {
+        let expired = serde_json::from_slice::<Session>(&value).map_or(true, |session| session.expires_at <= now);
+        if expired && db.remove(key).is_ok() {
+            purged += 1;
+        }
+    }
+    purged
+}
+
+// Count a login or registration attempt from the poster behind `req`, or give
+// the 429 turning them away while ACCOUNT_COOLDOWN since their last one runs
+fn throttle(req: &HttpRequest, settings: &Settings, cooldowns: &Cooldowns) -> Option<HttpResponse> {
+    let ip = client_ip(req, settings);
+    if let Some(seconds) = cooldowns.accounts.remaining(&ip) {
+        return Some(cooldown::too_many_requests(seconds, "trying again"));
+    }
+    cooldowns.accounts.record(&ip);
+    None
+}
+
+// Start a session for `username` and send the browser back to the board
+fn logged_in(db: &Db, settings: &Settings, username: &str) -> HttpResponse {
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let session = Session {
+        username: username.to_string(),
+        expires_at: chrono::Utc::now().timestamp() + settings.session_ttl,
+    };
+    let value = serde_json::to_vec(&session).expect("Failed to serialize session");
+    if let Err(e) = db.insert(session_key(&token), value) {
+        error!("Failed to store session for {}: {}", username, e);
+        return HttpResponse::InternalServerError().body("Login failed");
+    }
+    HttpResponse::SeeOther()
+        .cookie(session_cookie(settings, token))
+        .append_header(("Location", settings.url("/")))
+        .finish()
+}
+
+fn session_cookie(settings: &Settings, token: String) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token)
+        .path(if settings.base_path.is_empty() { "/".to_string() } else { settings.base_path.clone() })
+        .http_only(true)
+        .secure(settings.secure_cookies())
+        .same_site(SameSite::Lax)
+        .max_age(actix_web::cookie::time::Duration::seconds(settings.session_ttl))
+        .finish()
+}
+
+fn page(
+    mut status: actix_web::HttpResponseBuilder,
+    settings: &Settings,
+    username: Option<String>,
+    error: Option<&str>,
+) -> HttpResponse {
+    let tmpl = AccountTemplate {
+        username,
+        error,
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+    };
+    match tmpl.render() {
+        Ok(rendered) => status.content_type("text/html").body(rendered),
+        Err(e) => {
+            error!("Template rendering error: {}", e);
+            HttpResponse::InternalServerError().body("Error rendering page")
+        }
+    }
+}
+
+// Letters, digits and underscores, and nothing that reads as an anonymous post
+fn username_problem(name: &str) -> Option<&'static str> {
+    let length = name.chars().count();
+    if !(MIN_USERNAME_CHARS..=MAX_USERNAME_CHARS).contains(&length) {
+        Some("Usernames are 3 to 20 characters long")
+    } else if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Some("Usernames may only use letters, digits and _")
+    } else if name.eq_ignore_ascii_case("anonymous") {
+        Some("That username is taken")
+    } else {
+        None
+    }
+}
+
+fn password_problem(password: &str) -> Option<&'static str> {
+    let length = password.chars().count();
+    (!(MIN_PASSWORD_CHARS..=MAX_PASSWORD_CHARS).contains(&length)).then_some("Passwords are 8 to 128 characters long")
+}
+
+fn user_key(name: &str) -> Vec<u8> {
+    format!("user_{}", name.to_lowercase()).into_bytes()
+}
+
+fn session_key(token: &str) -> Vec<u8> {
+    format!("session_{}", token).into_bytes()
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = Uuid::new_v4();
+    let hash = pbkdf2_sha256(password.as_bytes(), salt.as_bytes(), PBKDF2_ITERATIONS);
+    format!(
+        "pbkdf2-sha256${}${}${}",
+        PBKDF2_ITERATIONS,
+        BASE64.encode(salt.as_bytes()),
+        BASE64.encode(hash)
+    )
+}
+
+// Checked against when the username doesn't exist, so a login for an unknown
+// name takes as long as one with a wrong password
+fn dummy_hash() -> String {
+    let (salt, hash) = (BASE64.encode([0u8; 16]), BASE64.encode([0u8; 32]));
+    format!("pbkdf2-sha256${}${}${}", PBKDF2_ITERATIONS, salt, hash)
+}
+
+fn verify_password(password: &str, stored: &str) -> bool {
+    let mut parts = stored.split('$');
+    let (Some("pbkdf2-sha256"), Some(iterations), Some(salt), Some(hash), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    let (Ok(iterations), Ok(salt), Ok(hash)) = (iterations.parse(), BASE64.decode(salt), BASE64.decode(hash)) else {
+        return false;
+    };
+    constant_time_eq(&pbkdf2_sha256(password.as_bytes(), &salt, iterations), &hash)
+}
+
+// PBKDF2 (RFC 8018) with HMAC-SHA256, for a single 32-byte block
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    // HMAC keys longer than a block are hashed first
+    let mut key = [0u8; 64];
+    if password.len() > 64 {
+        key[..32].copy_from_slice(&Sha256::digest(password));
+    } else {
+        key[..password.len()].copy_from_slice(password);
+    }
+    let inner = Sha256::new().chain_update(key.map(|byte| byte ^ 0x36));
+    let outer = Sha256::new().chain_update(key.map(|byte| byte ^ 0x5c));
+    let hmac = |message: &[u8]| -> [u8; 32] {
+        let inner_hash = inner.clone().chain_update(message).finalize();
+        outer.clone().chain_update(inner_hash).finalize().into()
+    };
+
+    let mut block = hmac(&[salt, &1u32.to_be_bytes()].concat());
+    let mut result = block;
+    for _ in 1..iterations {
+        block = hmac(&block);
+        result.iter_mut().zip(block).for_each(|(byte, next)| *byte ^= next);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use actix_web::cookie::Cookie;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    // The first 32 bytes of the PBKDF2-HMAC-SHA256 vectors in RFC 7914 section 11
+    #[test]
+    fn pbkdf2_matches_the_rfc_vectors() {
+        let short = pbkdf2_sha256(b"passwd", b"salt", 1);
+        assert_eq!(hex(&short), "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        let long = pbkdf2_sha256(b"Password", b"NaCl", 80000);
+        assert_eq!(hex(&long), "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56");
+    }
+
+    #[test]
+    fn the_dummy_hash_costs_as_much_as_a_real_one() {
+        let real = hash_password("correct horse");
+        let shape = |hash: &str| {
+            let parts: Vec<&str> = hash.split('$').collect();
+            let decoded = |part: &str| BASE64.decode(part).unwrap().len();
+            (parts[0].to_string(), parts[1].to_string(), decoded(parts[2]), decoded(parts[3]))
+        };
+        assert_eq!(shape(&dummy_hash()), shape(&real));
+        assert!(verify_password("correct horse", &real));
+        assert!(!verify_password("correct horse", &dummy_hash()));
+        assert!(!verify_password("correct horse", "plain text"));
+    }
+
+    fn account(uri: &str, username: &str, password: &str) -> TestRequest {
+        TestRequest::post()
+            .uri(uri)
+            .peer_addr("192.0.2.1:4000".parse().unwrap())
+            .set_form([("username", username), ("password", password)])
+    }
+
+    fn session<B>(response: &actix_web::dev::ServiceResponse<B>) -> Option<Cookie<'static>> {
+        response.response().cookies().find(|cookie| cookie.name() == SESSION_COOKIE).map(|cookie| cookie.into_owned())
+    }
+
+    #[actix_web::test]
+    async fn a_registered_poster_logs_in_and_posts_under_their_name() {
+        let state = test_support::state(&[("ACCOUNTS", "true"), ("ACCOUNT_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let registered = call_service(&app, account("/register", "Magnus", "checkmate1").to_request()).await;
+        assert_eq!(registered.status(), 303);
+        let cookie = session(&registered).unwrap();
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_ne!(cookie.secure(), Some(true));
+        let taken = call_service(&app, account("/register", "magnus", "stalemate1").to_request()).await;
+        assert_eq!(taken.status(), 409);
+        for (username, password) in [("ab", "checkmate1"), ("bad name", "checkmate1"), ("Anonymous", "checkmate1")]
+            .into_iter()
+            .chain([("Hikaru", "short")])
+        {
+            let refused = call_service(&app, account("/register", username, password).to_request()).await;
+            assert_eq!(refused.status(), 400, "{:?}", username);
+        }
+
+        let logged_in = call_service(&app, account("/login", "MAGNUS", "checkmate1").to_request()).await;
+        assert_eq!(logged_in.status(), 303);
+        let cookie = session(&logged_in).unwrap();
+        let post = form_post("/thread", &[("title", b"Signed"), ("message", b"By me")]).cookie(cookie.clone());
+        assert!(call_service(&app, post.to_request()).await.status().is_redirection());
+        let thread = crate::get_all_threads(&state.db).pop().unwrap();
+        assert_eq!(thread.username.as_deref(), Some("Magnus"));
+        let page = TestRequest::get().uri(&format!("/thread/{}", thread.id)).to_request();
+        let page = String::from_utf8(call_and_read_body(&app, page).await.to_vec()).unwrap();
+        assert!(page.contains("Magnus"), "{}", page);
+
+        let anonymous = form_post("/thread", &[("title", b"Unsigned"), ("message", b"Anyone")])
+            .peer_addr("192.0.2.2:4000".parse().unwrap());
+        assert!(call_service(&app, anonymous.to_request()).await.status().is_redirection());
+        let thread = crate::get_all_threads(&state.db).into_iter().find(|thread| thread.title == "Unsigned").unwrap();
+        assert_eq!(thread.username, None);
+
+        let logout = TestRequest::post().uri("/logout").cookie(cookie.clone()).to_request();
+        assert_eq!(call_service(&app, logout).await.status(), 303);
+        let account_page = TestRequest::get().uri("/account").cookie(cookie).to_request();
+        let account_page = String::from_utf8(call_and_read_body(&app, account_page).await.to_vec()).unwrap();
+        assert!(!account_page.contains("Logged in as"));
+    }
+
+    #[actix_web::test]
+    async fn wrong_passwords_and_unknown_names_get_the_same_answer() {
+        let state = test_support::state(&[("ACCOUNTS", "true"), ("ACCOUNT_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        assert_eq!(call_service(&app, account("/register", "Judit", "polgar123").to_request()).await.status(), 303);
+
+        let wrong = call_service(&app, account("/login", "Judit", "polgar124").to_request()).await;
+        let unknown = call_service(&app, account("/login", "Garry", "polgar123").to_request()).await;
+        assert_eq!((wrong.status().as_u16(), unknown.status().as_u16()), (401, 401));
+        assert!(session(&wrong).is_none() && session(&unknown).is_none());
+        assert_eq!(read_body(wrong).await, read_body(unknown).await);
+    }
+
+    #[actix_web::test]
+    async fn attempts_wait_out_the_account_cooldown() {
+        let state = test_support::state(&[("ACCOUNTS", "true"), ("ACCOUNT_COOLDOWN", "60")]);
+        let app = init_service(crate::app(&state)).await;
+
+        assert_eq!(call_service(&app, account("/login", "Garry", "kasparov1").to_request()).await.status(), 401);
+        for uri in ["/login", "/register"] {
+            let throttled = call_service(&app, account(uri, "Garry", "kasparov1").to_request()).await;
+            assert_eq!(throttled.status(), 429, "{}", uri);
+            assert!(throttled.headers().contains_key("retry-after"));
+        }
+        let elsewhere = account("/login", "Garry", "kasparov1").peer_addr("192.0.2.2:4000".parse().unwrap());
+        assert_eq!(call_service(&app, elsewhere.to_request()).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn the_session_cookie_is_secure_over_https() {
+        for vars in [[("SITE_URL", "https://example.org")], [("TLS_CERT", "cert.pem")]] {
+            let state = test_support::state(&[vars[0], ("ACCOUNTS", "true")]);
+            let app = init_service(crate::app(&state)).await;
+            let registered = call_service(&app, account("/register", "Bobby", "fischer72").to_request()).await;
+            assert_eq!(session(&registered).unwrap().secure(), Some(true), "{:?}", vars);
+        }
+    }
+}
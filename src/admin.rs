@@ -0,0 +1,1439 @@
+use actix_multipart::Multipart;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
+use askama::Template;
+use futures_util::stream::StreamExt;
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sled::transaction::TransactionResult;
+use sled::Db;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::export;
+use crate::image_check;
+use crate::maintenance::Maintenance;
+use crate::modlog::{self, Entry};
+use crate::moderation::{self, MergeError};
+use crate::reports::{self, Report};
+use crate::repository::{Repository, ThreadOrder};
+use crate::repost::{self, Flag};
+use crate::settings::Settings;
+use crate::{filters, upload, CustomThumbnail, Reply, Sticky, Thread, THUMB_DIR, UPLOAD_DIR};
+
+// Holds a random session id; the sessions live under admin_session_{id}, each
+// with its expiry as big-endian unix seconds
+const ADMIN_COOKIE: &str = "admin_session";
+const ADMIN_SESSION_TTL: i64 = 12 * 60 * 60;
+// Counting every reply and stat'ing every upload is slow on a big board, so the
+// dashboard numbers are reused for this long
+const STATS_CACHE_TTL: Duration = Duration::from_secs(30);
+const RECENT_THREADS: usize = 5;
+// Flagged reposts listed on the dashboard, newest first
+const RECENT_REPOSTS: usize = 20;
+// Moderation log entries listed on the dashboard, newest first
+const RECENT_MODLOG: usize = 20;
+// Shown on /admin/settings only as set or not
+const SECRET_VARIABLES: [&str; 3] = ["ADMIN_TOKEN", "API_KEY", "POSTER_HASH_SALT"];
+// Files younger than this may belong to a post that is still being submitted
+const GC_GRACE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct DashboardTemplate<'a> {
+    stats: &'a DashboardStats,
+    recent: &'a [Thread],
+    // Uploads let through by REPOST_CHECK=flag
+    reposts: &'a [Flag],
+    // Reported posts still waiting for a moderator
+    reports: &'a [Report],
+    modlog: &'a [Entry],
+    read_only: bool,
+    announcement: String,
+    // APP_NAME, which the wipe form asks for
+    board_name: &'a str,
+    base_path: &'a str,
+    theme_color: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "admin_login.html")]
+struct LoginTemplate<'a> {
+    failed: bool,
+    base_path: &'a str,
+    theme_color: &'a str,
+}
+
+#[derive(Clone)]
+struct DashboardStats {
+    total_threads: usize,
+    total_replies: usize,
+    upload_files: usize,
+    upload_bytes: u64,
+    quota_used: u64,
+    quota_bytes: u64,
+}
+
+// Briefly cached dashboard counts, shared by all workers
+#[derive(Default)]
+pub struct StatsCache(Mutex<Option<(Instant, DashboardStats)>>);
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct DismissForm {
+    thread: i32,
+    // The opening post without it
+    reply: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct ReadOnlyForm {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AnnouncementForm {
+    // Empty clears the banner
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+pub struct LockForm {
+    locked: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveForm {
+    archived: bool,
+}
+
+#[derive(Deserialize)]
+pub struct StickyForm {
+    sticky: bool,
+    // Seconds the pin lasts; 0 keeps it until the thread is unpinned
+    #[serde(default)]
+    duration: i64,
+}
+
+#[derive(Deserialize)]
+pub struct MergeForm {
+    // The thread that receives the replies
+    target: i32,
+}
+
+#[derive(Deserialize)]
+pub struct WipeParams {
+    // Only report what would go
+    #[serde(default)]
+    dry: bool,
+}
+
+#[derive(Deserialize)]
+pub struct WipeForm {
+    // APP_NAME typed out, to be sure the right board is being wiped
+    confirm: String,
+}
+
+#[derive(Deserialize)]
+pub struct PosterForm {
+    // Poster hash as shown in /admin/export
+    poster: String,
+    // Only delete the poster's posts in this thread
+    thread: Option<i32>,
+}
+
+// Why an admin request was turned away
+pub enum AdminDenied {
+    // ADMIN_TOKEN is unset, so the admin area doesn't exist
+    Disabled,
+    Unauthorized,
+    // The tool works on the sled post records, which DB_BACKEND=sqlite doesn't use
+    SledOnly,
+}
+
+impl AdminDenied {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            AdminDenied::Disabled => HttpResponse::NotFound().body("Not found"),
+            AdminDenied::Unauthorized => HttpResponse::Unauthorized().body("Admin token required"),
+            AdminDenied::SledOnly => HttpResponse::NotImplemented().body("Not available with DB_BACKEND=sqlite"),
+        }
+    }
+}
+
+// There is no admin area unless ADMIN_TOKEN is set. Scripts authenticate with
+// "Authorization: Bearer <token>", browsers with the session cookie set by /admin/login.
+pub fn require_admin(req: &HttpRequest, settings: &Settings) -> Result<(), AdminDenied> {
+    let expected = match &settings.admin_token {
+        Some(token) => token,
+        None => return Err(AdminDenied::Disabled),
+    };
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if bearer.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+        return Ok(());
+    }
+
+    let session = req.cookie(ADMIN_COOKIE);
+    let db = req.app_data::<web::Data<Arc<Db>>>();
+    match (session, db) {
+        (Some(session), Some(db)) if session_is_valid(db, session.value()) => Ok(()),
+        _ => Err(AdminDenied::Unauthorized),
+    }
+}
+
+// For the tools that read or change thread and reply records in sled. With the
+// posts stored elsewhere they would find nothing, or change copies nobody reads.
+pub fn require_sled_posts(settings: &Settings) -> Result<(), AdminDenied> {
+    match settings.posts_in_sled() {
+        true => Ok(()),
+        false => Err(AdminDenied::SledOnly),
+    }
+}
+
+fn session_key(id: &str) -> String {
+    format!("admin_session_{}", id)
+}
+
+// Whether `id` names an admin session that hasn't expired; expired ones are removed
+fn session_is_valid(db: &Db, id: &str) -> bool {
+    let key = session_key(id);
+    let expires_at = match db.get(&key) {
+        Ok(Some(value)) => value.as_ref().try_into().map(i64::from_be_bytes).unwrap_or(0),
+        _ => return false,
+    };
+    if expires_at > chrono::Utc::now().timestamp() {
+        return true;
+    }
+    if let Err(e) = db.remove(&key) {
+        error!("Failed to remove an expired admin session: {}", e);
+    }
+    false
+}
+
+// Compare secrets without an early exit that would leak how much of them
+// matched. Both sides are hashed first, so what's compared is always 32 bytes
+// and the time taken says nothing about the secret's length either.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Admin dashboard handler
+pub async fn dashboard(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    cache: web::Data<StatsCache>,
+    maintenance: web::Data<Maintenance>,
+) -> impl Responder {
+    match require_admin(&req, &settings) {
+        Ok(()) => {}
+        // Offer the login form to browsers instead of a bare 401
+        Err(AdminDenied::Unauthorized) => {
+            let login = LoginTemplate {
+                failed: false,
+                base_path: &settings.base_path,
+                theme_color: &settings.theme_color,
+            };
+            return render(login.render());
+        }
+        Err(denied) => return denied.to_response(),
+    }
+
+    let stats = {
+        let mut cached = cache.0.lock().expect("stats cache poisoned");
+        match cached.as_ref() {
+            Some((computed_at, stats)) if computed_at.elapsed() < STATS_CACHE_TTL => stats.clone(),
+            _ => {
+                let stats = compute_stats(repo.get_ref(), &db, &settings);
+                *cached = Some((Instant::now(), stats.clone()));
+                stats
+            }
+        }
+    };
+    let recent = repo.list_threads(ThreadOrder::Bump, 0, RECENT_THREADS);
+    let reposts = repost::flags(&db, RECENT_REPOSTS);
+    let reports = reports::open(&db, repo.get_ref());
+    let modlog = modlog::recent(&db, RECENT_MODLOG);
+
+    render(
+        DashboardTemplate {
+            stats: &stats,
+            recent: &recent,
+            reposts: &reposts,
+            reports: &reports,
+            modlog: &modlog,
+            read_only: maintenance.is_read_only(),
+            announcement: maintenance.announcement(),
+            board_name: &settings.app_name,
+            base_path: &settings.base_path,
+            theme_color: &settings.theme_color,
+        }
+        .render(),
+    )
+}
+
+// Admin login handler; starts a session and keeps its id in an HttpOnly,
+// same-site-only cookie, which is Secure when SITE_URL is https
+pub async fn login(
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    form: web::Form<LoginForm>,
+) -> impl Responder {
+    let expected = match &settings.admin_token {
+        Some(token) => token,
+        None => return HttpResponse::NotFound().body("Not found"),
+    };
+
+    if !constant_time_eq(form.token.as_bytes(), expected.as_bytes()) {
+        info!("Rejected admin login attempt");
+        let login = LoginTemplate {
+            failed: true,
+            base_path: &settings.base_path,
+            theme_color: &settings.theme_color,
+        };
+        return render(login.render());
+    }
+
+    let id = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let expires_at = chrono::Utc::now().timestamp() + ADMIN_SESSION_TTL;
+    if let Err(e) = db.insert(session_key(&id), &expires_at.to_be_bytes()) {
+        error!("Failed to store an admin session: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to log in");
+    }
+
+    let cookie = Cookie::build(ADMIN_COOKIE, id)
+        .path(if settings.base_path.is_empty() { "/" } else { &settings.base_path })
+        .http_only(true)
+        .secure(settings.secure_cookies())
+        .same_site(SameSite::Strict)
+        .max_age(actix_web::cookie::time::Duration::seconds(ADMIN_SESSION_TTL))
+        .finish();
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header(("Location", settings.url("/admin")))
+        .finish()
+}
+
+// Read-only mode toggle handler
+pub async fn set_read_only(
+    req: HttpRequest,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    form: web::Form<ReadOnlyForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    maintenance.set_read_only(form.enabled);
+    info!("Read-only mode {}", if form.enabled { "enabled" } else { "disabled" });
+    HttpResponse::SeeOther()
+        .append_header(("Location", settings.url("/admin")))
+        .finish()
+}
+
+// Announcement banner handler
+pub async fn set_announcement(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    form: web::Form<AnnouncementForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    if let Err(e) = maintenance.set_announcement(&db, &form.text) {
+        error!("Failed to save announcement: {}", e);
+        return HttpResponse::InternalServerError().body("Failed to save announcement");
+    }
+    info!("Announcement {}", if form.text.trim().is_empty() { "cleared" } else { "updated" });
+    HttpResponse::SeeOther()
+        .append_header(("Location", settings.url("/admin")))
+        .finish()
+}
+
+// Thread merge handler: /admin/thread/{id}/merge folds thread {id} into `target`
+pub async fn merge_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    form: web::Form<MergeForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let (source, target) = (path.into_inner(), form.target);
+    let global_numbers = settings.global_post_numbers;
+    let store = db.get_ref().clone();
+    match web::block(move || moderation::merge_threads(&store, source, target, global_numbers)).await {
+        Ok(Ok(moved)) => {
+            info!("Merged thread {} into thread {} ({} posts moved)", source, target, moved);
+            modlog::record(&db, "merge", format!("Thread {} into thread {} ({} posts)", source, target, moved));
+            HttpResponse::Ok().json(json!({ "merged": moved, "into": target }))
+        }
+        Ok(Err(MergeError::SameThread)) => {
+            HttpResponse::BadRequest().json(json!({ "error": "A thread can't be merged into itself" }))
+        }
+        Ok(Err(MergeError::NotFound(id))) => {
+            HttpResponse::NotFound().json(json!({ "error": format!("Thread {} not found", id) }))
+        }
+        Ok(Err(MergeError::Busy)) => HttpResponse::Conflict()
+            .json(json!({ "error": format!("Thread {} is taking replies too fast to merge; try again", source) })),
+        Ok(Err(MergeError::Storage(e))) => {
+            error!("Failed to merge thread {} into {}: {}", source, target, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Thread merge failed" }))
+        }
+        Err(e) => {
+            error!("Failed to merge thread {} into {}: {}", source, target, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Thread merge failed" }))
+        }
+    }
+}
+
+// Custom thumbnail handler: the "image" field of the multipart form becomes the
+// thread's thumbnail, and an empty file input goes back to the thread's own
+pub async fn set_thumbnail(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, actix_web::Error> {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return Ok(denied.to_response());
+    }
+    let thread_id = path.into_inner();
+
+    let mut meta = None;
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        if field.content_disposition().get_name() != Some("image") {
+            continue;
+        }
+        meta = match upload::save_upload(&mut field, &settings, &db, None).await {
+            Ok(meta) => meta,
+            Err(e) => return Ok(e.to_response()),
+        };
+        break;
+    }
+
+    let custom = meta.as_ref().map(|meta| CustomThumbnail {
+        image_url: meta.url(),
+        thumbnails: meta.thumbnails.clone(),
+    });
+    match moderation::update_thread(&db, thread_id, |thread| thread.custom_thumbnail = custom.clone()) {
+        Ok(Some(previous)) => {
+            if let Some(meta) = &meta {
+                if let Err(e) = meta.commit() {
+                    error!("Failed to move upload {} into place: {}", meta.filename, e);
+                }
+            }
+            if let Some(previous) = previous.custom_thumbnail {
+                upload::delete_image(&db, &previous.image_url);
+            }
+            info!("Set the custom thumbnail of thread {}", thread_id);
+            modlog::record(&db, "thumbnail", format!("Thread {}", thread_id));
+            Ok(HttpResponse::Ok().json(json!({ "thread": thread_id, "thumbnail": meta.map(|meta| meta.url()) })))
+        }
+        Ok(None) => {
+            if let Some(meta) = &meta {
+                upload::discard(&db, meta);
+            }
+            Ok(HttpResponse::NotFound().json(json!({ "error": format!("Thread {} not found", thread_id) })))
+        }
+        Err(e) => {
+            error!("Failed to set the thumbnail of thread {}: {:?}", thread_id, e);
+            if let Some(meta) = &meta {
+                upload::discard(&db, meta);
+            }
+            Ok(HttpResponse::InternalServerError().json(json!({ "error": "Failed to set thumbnail" })))
+        }
+    }
+}
+
+// Lock handler: a locked thread stays up but takes no new replies
+pub async fn lock_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    form: web::Form<LockForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+    let locked = form.locked;
+    set_thread_state(&db, path.into_inner(), "locked", locked, |thread| thread.locked = locked)
+}
+
+// Archive handler: an archived thread is kept for reading but closed to replies
+pub async fn archive_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    form: web::Form<ArchiveForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+    let archived = form.archived;
+    set_thread_state(&db, path.into_inner(), "archived", archived, |thread| thread.archived = archived)
+}
+
+// Sticky handler: a pinned thread tops the first page of the board index,
+// for `duration` seconds if one is given
+pub async fn sticky_thread(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    form: web::Form<StickyForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let thread_id = path.into_inner();
+    let until = (form.sticky && form.duration > 0).then(|| chrono::Utc::now().timestamp() + form.duration);
+    let sticky = form.sticky.then_some(until.map_or(Sticky::Forever, Sticky::Until));
+    match moderation::set_sticky(&db, thread_id, sticky) {
+        Ok(Some(_)) => {
+            let detail = match (sticky, until) {
+                (None, _) => format!("Thread {} unpinned", thread_id),
+                (Some(_), None) => format!("Thread {} pinned", thread_id),
+                (Some(_), Some(_)) => format!("Thread {} pinned for {}s", thread_id, form.duration),
+            };
+            info!("{}", detail);
+            modlog::record(&db, "sticky", detail);
+            HttpResponse::Ok().json(json!({ "thread": thread_id, "sticky": form.sticky, "until": until }))
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({ "error": format!("Thread {} not found", thread_id) })),
+        Err(e) => {
+            error!("Failed to pin thread {}: {:?}", thread_id, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to update thread" }))
+        }
+    }
+}
+
+// Store one of the flags behind thread_state::can_reply, answering with its new value
+fn set_thread_state(db: &Db, thread_id: i32, flag: &str, value: bool, change: impl Fn(&mut Thread)) -> HttpResponse {
+    match moderation::update_thread(db, thread_id, change) {
+        Ok(Some(_)) => {
+            info!("Set thread {} {} to {}", thread_id, flag, value);
+            modlog::record(db, flag, format!("Thread {} set {}={}", thread_id, flag, value));
+            HttpResponse::Ok().json(json!({ "thread": thread_id, flag: value }))
+        }
+        Ok(None) => HttpResponse::NotFound().json(json!({ "error": format!("Thread {} not found", thread_id) })),
+        Err(e) => {
+            error!("Failed to set thread {} {}: {:?}", thread_id, flag, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to update thread" }))
+        }
+    }
+}
+
+// Delete-image handler for an opening post: the image, its thumbnails and any
+// custom thumbnail are deleted and a placeholder shows instead; the text stays
+pub async fn delete_thread_image(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let thread_id = path.into_inner();
+    let updated = moderation::update_thread(&db, thread_id, |thread| {
+        thread.image_removed |= thread.image_url.is_some();
+        thread.image_url = None;
+        thread.thumbnails.clear();
+        thread.image_size = None;
+        thread.custom_thumbnail = None;
+    });
+    let removed = updated.map(|previous| {
+        previous.map(|thread| {
+            let custom = thread.custom_thumbnail.map(|custom| custom.image_url);
+            (thread.image_url, custom)
+        })
+    });
+    image_deleted(&db, format!("Thread {}", thread_id), removed)
+}
+
+// Delete-image handler for a reply, same as delete_thread_image
+pub async fn delete_reply_image(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<(i32, i32)>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let (thread_id, reply_id) = path.into_inner();
+    let updated = moderation::update_reply(&db, thread_id, reply_id, |reply| {
+        reply.image_removed |= reply.image_url.is_some();
+        reply.image_url = None;
+        reply.thumbnails.clear();
+        reply.image_size = None;
+    });
+    let removed = updated.map(|previous| previous.map(|reply| (reply.image_url, None)));
+    image_deleted(&db, format!("Reply {} in thread {}", reply_id, thread_id), removed)
+}
+
+// Delete the files a delete-image handler took off `post`: its own image and,
+// for an opening post, its custom thumbnail
+fn image_deleted(
+    db: &Db,
+    post: String,
+    removed: TransactionResult<Option<(Option<String>, Option<String>)>>,
+) -> HttpResponse {
+    match removed {
+        Ok(Some((Some(image_url), custom))) => {
+            upload::delete_image(db, &image_url);
+            if let Some(custom) = custom {
+                upload::delete_image(db, &custom);
+            }
+            info!("Deleted the image of {}", post);
+            modlog::record(db, "delete-image", format!("{} ({})", post, image_url));
+            HttpResponse::Ok().json(json!({ "deleted": image_url }))
+        }
+        Ok(Some((None, _))) => HttpResponse::BadRequest().json(json!({ "error": format!("{} has no image", post) })),
+        Ok(None) => HttpResponse::NotFound().json(json!({ "error": format!("{} not found", post) })),
+        Err(e) => {
+            error!("Failed to delete the image of {}: {:?}", post, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to delete image" }))
+        }
+    }
+}
+
+// Bulk delete handler: /admin/delete-by-poster removes every post with the
+// given poster hash, for cleaning up after a spam run
+pub async fn delete_by_poster(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    form: web::Form<PosterForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let PosterForm { poster, thread } = form.into_inner();
+    let poster = poster.trim().to_string();
+    if poster.is_empty() {
+        return HttpResponse::BadRequest().json(json!({ "error": "A poster hash is required" }));
+    }
+    let scope = thread.map_or_else(|| "the whole board".to_string(), |id| format!("thread {}", id));
+
+    let store = db.get_ref().clone();
+    let hash = poster.clone();
+    match web::block(move || moderation::delete_by_poster(&store, &hash, thread)).await {
+        Ok(Ok(deleted)) => {
+            let detail = format!(
+                "Poster {} in {} ({} threads, {} replies)",
+                poster, scope, deleted.threads, deleted.replies
+            );
+            info!("Deleted the posts of {}", detail);
+            modlog::record(&db, "delete-by-poster", detail);
+            HttpResponse::Ok().json(json!({ "threads": deleted.threads, "replies": deleted.replies }))
+        }
+        Ok(Err(e)) => {
+            error!("Failed to delete the posts of poster {}: {:?}", poster, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Bulk delete failed" }))
+        }
+        Err(e) => {
+            error!("Failed to delete the posts of poster {}: {}", poster, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Bulk delete failed" }))
+        }
+    }
+}
+
+// Board wipe handler. ?dry=true reports what would be deleted; the real wipe
+// also needs the board's name (APP_NAME) typed into `confirm`.
+pub async fn wipe(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    cache: web::Data<StatsCache>,
+    params: web::Query<WipeParams>,
+    // A dry run may be posted without a body
+    form: Option<web::Form<WipeForm>>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+    let dry_run = params.dry;
+    let confirmed = form.is_some_and(|form| form.confirm.trim() == settings.app_name);
+    if !dry_run && !confirmed {
+        return HttpResponse::BadRequest().json(json!({ "error": "Type the board's name to confirm the wipe" }));
+    }
+
+    let store = db.get_ref().clone();
+    match web::block(move || moderation::wipe(&store, dry_run)).await {
+        Ok(Ok(wiped)) => {
+            if !dry_run {
+                let detail = format!(
+                    "{} threads, {} replies, {} files ({} bytes)",
+                    wiped.threads, wiped.replies, wiped.files, wiped.bytes
+                );
+                info!("Wiped the board: {}", detail);
+                modlog::record(&db, "wipe", detail);
+                *cache.0.lock().expect("stats cache poisoned") = None;
+            }
+            HttpResponse::Ok().json(wiped)
+        }
+        Ok(Err(e)) => {
+            error!("Failed to wipe the board: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Wipe failed" }))
+        }
+        Err(e) => {
+            error!("Failed to wipe the board: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Wipe failed" }))
+        }
+    }
+}
+
+// Missing image report handler: every post with an image, thumbnail or custom
+// thumbnail file that is gone from disk, whether or not CHECK_IMAGE_FILES is on
+pub async fn missing_images(req: HttpRequest, db: web::Data<Arc<Db>>, settings: web::Data<Settings>) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let db = db.get_ref().clone();
+    match web::block(move || image_check::find_missing(&db)).await {
+        Ok(posts) => HttpResponse::Ok().json(json!({ "posts": posts })),
+        Err(e) => {
+            error!("Missing image check failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Missing image check failed" }))
+        }
+    }
+}
+
+// Settings page handler: every variable read at startup with the value it had,
+// secrets masked
+pub async fn settings_page(req: HttpRequest, settings: web::Data<Settings>) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    let variables: serde_json::Map<String, serde_json::Value> = settings
+        .sources
+        .iter()
+        .map(|(name, value)| {
+            let shown = match value {
+                Some(_) if SECRET_VARIABLES.contains(&name.as_str()) => json!("(set)"),
+                Some(value) => json!(value),
+                None => serde_json::Value::Null,
+            };
+            (name.clone(), shown)
+        })
+        .collect();
+    HttpResponse::Ok().json(json!({ "variables": variables }))
+}
+
+// Report dismissal handler
+pub async fn dismiss_report(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    form: web::Form<DismissForm>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    let post = match form.reply {
+        Some(reply) => format!("Reply {} in thread {}", reply, form.thread),
+        None => format!("Thread {}", form.thread),
+    };
+    match reports::dismiss(&db, form.thread, form.reply) {
+        Ok(true) => {
+            modlog::record(&db, "dismiss-report", post);
+            HttpResponse::SeeOther()
+                .append_header(("Location", settings.url("/admin")))
+                .finish()
+        }
+        Ok(false) => HttpResponse::NotFound().json(json!({ "error": format!("{} has no open report", post) })),
+        Err(e) => {
+            error!("Failed to dismiss the report of {}: {}", post, e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to dismiss the report" }))
+        }
+    }
+}
+
+// Refused uploads counted by reason since the board started counting
+pub async fn upload_stats(req: HttpRequest, db: web::Data<Arc<Db>>, settings: web::Data<Settings>) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    let counts = upload::rejection_counts(&db);
+    let total: u64 = counts.iter().map(|(_, count)| count).sum();
+    let rejections: serde_json::Map<String, serde_json::Value> =
+        counts.into_iter().map(|(reason, count)| (reason.to_string(), count.into())).collect();
+    HttpResponse::Ok().json(json!({ "total": total, "rejections": rejections }))
+}
+
+// Full JSON export handler, streamed so it works for any size of board
+pub async fn export(req: HttpRequest, db: web::Data<Arc<Db>>, settings: web::Data<Settings>) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let filename = format!("export-{}.json", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .append_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+        .streaming(export::export_stream(db.get_ref().clone()))
+}
+
+// Orphaned upload cleanup handler
+pub async fn collect_garbage(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings) {
+        return denied.to_response();
+    }
+
+    let db = db.get_ref().clone();
+    match web::block(move || remove_orphaned_files(repo.get_ref(), &db)).await {
+        Ok((removed, bytes)) => {
+            info!("Garbage collection removed {} files ({} bytes)", removed, bytes);
+            HttpResponse::Ok().json(json!({ "removed": removed, "bytes": bytes }))
+        }
+        Err(e) => {
+            error!("Garbage collection failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Garbage collection failed" }))
+        }
+    }
+}
+
+// Delete files in UPLOAD_DIR and THUMB_DIR that no post or pending upload token
+// refers to. Returns how many files went and how many upload bytes were freed.
+fn remove_orphaned_files(repo: &dyn Repository, db: &Db) -> (usize, u64) {
+    let mut referenced = repo.referenced_files();
+    referenced.extend(upload::pending_token_files(db));
+
+    let mut removed = 0;
+    let mut freed = 0;
+    for dir in [UPLOAD_DIR, THUMB_DIR] {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            let recent = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_none_or(|age| age < GC_GRACE_PERIOD);
+            if referenced.contains(&name) || recent {
+                continue;
+            }
+
+            if std::fs::remove_file(entry.path()).is_ok() {
+                info!("Removed orphaned file {}{}", dir, name);
+                removed += 1;
+                if dir == UPLOAD_DIR {
+                    freed += metadata.len();
+                    repost::forget(db, &name);
+                }
+            }
+        }
+    }
+
+    let _ = upload::release_usage(db, freed);
+    (removed, freed)
+}
+
+// Thumbnail rebuild handler, for after THUMB_SIZES change or thumbnails go missing
+pub async fn rebuild_thumbnails(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+) -> impl Responder {
+    if let Err(denied) = require_admin(&req, &settings).and_then(|()| require_sled_posts(&settings)) {
+        return denied.to_response();
+    }
+
+    let db = db.get_ref().clone();
+    let format = settings.thumb_format;
+    match web::block(move || rebuild_all_thumbnails(&db, format)).await {
+        Ok((rebuilt, failed)) => {
+            info!("Rebuilt thumbnails for {} images, {} failed", rebuilt, failed);
+            HttpResponse::Ok().json(json!({ "rebuilt": rebuilt, "failed": failed }))
+        }
+        Err(e) => {
+            error!("Thumbnail rebuild failed: {}", e);
+            HttpResponse::InternalServerError().json(json!({ "error": "Thumbnail rebuild failed" }))
+        }
+    }
+}
+
+// Regenerate the thumbnails of every post image. Returns how many images were
+// rebuilt and how many could not be read.
+fn rebuild_all_thumbnails(db: &Db, format: upload::ThumbFormat) -> (usize, usize) {
+    let mut counts = (0, 0);
+    rebuild_post_thumbnails::<Thread>(db, b"thread_", format, &mut counts, |thread| {
+        (thread.image_url.as_deref(), &mut thread.thumbnails)
+    });
+    rebuild_post_thumbnails::<Reply>(db, b"reply_", format, &mut counts, |reply| {
+        (reply.image_url.as_deref(), &mut reply.thumbnails)
+    });
+    counts
+}
+
+// Rebuild the thumbnails of each post stored under `prefix`, and update the
+// post when the set of thumbnails came out different
+fn rebuild_post_thumbnails<T: Serialize + DeserializeOwned>(
+    db: &Db,
+    prefix: &[u8],
+    format: upload::ThumbFormat,
+    (rebuilt, failed): &mut (usize, usize),
+    image: impl Fn(&mut T) -> (Option<&str>, &mut Vec<String>),
+) {
+    for (key, value) in db.scan_prefix(prefix).flatten() {
+        let mut post: T = match serde_json::from_slice(&value) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        let (image_url, thumbnails) = image(&mut post);
+        let filename = match image_url.and_then(|url| url.rsplit('/').next()) {
+            Some(filename) => filename.to_string(),
+            None => continue,
+        };
+
+        match upload::rebuild_thumbnails(&filename, format) {
+            Ok(new_thumbnails) => {
+                *rebuilt += 1;
+                if *thumbnails != new_thumbnails {
+                    *thumbnails = new_thumbnails;
+                    // A post updated in the meantime (a bump) keeps its record;
+                    // the next rebuild catches it up
+                    let updated = serde_json::to_vec(&post).expect("Failed to serialize post");
+                    let _ = db.compare_and_swap(&key, Some(value), Some(updated));
+                }
+            }
+            Err(e) => {
+                log::warn!("Failed to rebuild thumbnails for {}: {}", filename, e);
+                *failed += 1;
+            }
+        }
+    }
+}
+
+fn compute_stats(repo: &dyn Repository, db: &Db, settings: &Settings) -> DashboardStats {
+    let (upload_files, upload_bytes) = directory_usage(UPLOAD_DIR);
+    DashboardStats {
+        total_threads: repo.count_threads(),
+        total_replies: repo.count_replies(),
+        upload_files,
+        upload_bytes,
+        quota_used: upload::usage(db),
+        quota_bytes: settings.upload_quota_bytes,
+    }
+}
+
+// Number of files in a directory and the sum of their sizes
+fn directory_usage(dir: &str) -> (usize, u64) {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .fold((0, 0), |(files, bytes), metadata| (files + 1, bytes + metadata.len()))
+        })
+        .unwrap_or((0, 0))
+}
+
+fn render(result: askama::Result<String>) -> HttpResponse {
+    match result {
+        Ok(rendered) => HttpResponse::Ok().content_type("text/html").body(rendered),
+        Err(e) => {
+            error!("Template rendering error: {}", e);
+            HttpResponse::InternalServerError().body("Error rendering page")
+        }
+    }
+}
+
+// Human readable byte count for the dashboard
+fn format_bytes(bytes: &u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = *bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::SqliteRepository;
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest};
+
+    #[test]
+    fn secrets_compare_equal_only_when_identical() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(constant_time_eq(b"", b""));
+        for other in [&b"hunter3"[..], b"hunter", b"hunter22", b""] {
+            assert!(!constant_time_eq(b"hunter2", other), "{:?}", other);
+        }
+    }
+
+    fn login_request(token: &str) -> TestRequest {
+        TestRequest::post().uri("/admin/login").set_form([("token", token)])
+    }
+
+    #[actix_web::test]
+    async fn login_keeps_a_session_id_not_the_token() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let response = call_service(&app, login_request("hunter2").to_request()).await;
+        assert!(response.status().is_redirection());
+        let cookie = response.response().cookies().find(|cookie| cookie.name() == ADMIN_COOKIE).unwrap();
+        assert_ne!(cookie.value(), "hunter2");
+        assert!(!cookie.value().contains("hunter2"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_ne!(cookie.secure(), Some(true));
+        let session = cookie.value().to_string();
+
+        let settings = TestRequest::get().uri("/admin/settings").cookie(Cookie::new(ADMIN_COOKIE, session));
+        assert!(call_service(&app, settings.to_request()).await.status().is_success());
+
+        // The token itself is no good as a cookie
+        let forged = TestRequest::get().uri("/admin/settings").cookie(Cookie::new(ADMIN_COOKIE, "hunter2"));
+        assert_eq!(call_service(&app, forged.to_request()).await.status(), 401);
+
+        let wrong = call_service(&app, login_request("hunter3").to_request()).await;
+        assert_eq!(wrong.response().cookies().count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn the_session_cookie_is_secure_on_an_https_site() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("SITE_URL", "https://example.org")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let response = call_service(&app, login_request("hunter2").to_request()).await;
+        let cookie = response.response().cookies().find(|cookie| cookie.name() == ADMIN_COOKIE).unwrap();
+        assert_eq!(cookie.secure(), Some(true));
+    }
+
+    #[actix_web::test]
+    async fn expired_sessions_are_refused_and_removed() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        let expired = chrono::Utc::now().timestamp() - 1;
+        state.db.insert(session_key("old"), &expired.to_be_bytes()).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::get().uri("/admin/settings").cookie(Cookie::new(ADMIN_COOKIE, "old"));
+        assert_eq!(call_service(&app, request.to_request()).await.status(), 401);
+        assert!(state.db.get(session_key("old")).unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn the_dashboard_counts_open_reports_and_links_the_tools() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        let thread = crate::insert_thread(&state.db, test_support::new_thread("Spam", "Buy now"), false).unwrap();
+        reports::file(&state.db, thread.id, None, Some("a")).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let dashboard = || {
+            TestRequest::get()
+                .uri("/admin")
+                .insert_header(("Authorization", "Bearer hunter2"))
+                .to_request()
+        };
+
+        let page = String::from_utf8(call_and_read_body(&app, dashboard()).await.to_vec()).unwrap();
+        assert!(page.contains("<th>Open reports</th><td>1</td>"));
+        assert!(page.contains(&format!("href=\"/thread/{}\">Thread {}</a>", thread.id, thread.id)));
+        for link in ["href=\"#modlog\"", "href=\"/admin/settings\"", "href=\"/admin/missing-images\""] {
+            assert!(page.contains(link), "no {} on the dashboard", link);
+        }
+
+        let dismiss = TestRequest::post()
+            .uri("/admin/reports/dismiss")
+            .insert_header(("Authorization", "Bearer hunter2"))
+            .set_form([("thread", thread.id.to_string())])
+            .to_request();
+        assert!(call_service(&app, dismiss).await.status().is_redirection());
+        let page = String::from_utf8(call_and_read_body(&app, dashboard()).await.to_vec()).unwrap();
+        assert!(page.contains("<th>Open reports</th><td>0</td>"));
+    }
+
+    #[actix_web::test]
+    async fn the_settings_page_masks_secrets() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("THREADS_PER_PAGE", "15")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::get()
+            .uri("/admin/settings")
+            .insert_header(("Authorization", "Bearer hunter2"))
+            .to_request();
+        let body: serde_json::Value = serde_json::from_slice(&call_and_read_body(&app, request).await).unwrap();
+        let variables = &body["variables"];
+        assert_eq!(variables["THREADS_PER_PAGE"], "15");
+        assert_eq!(variables["ADMIN_TOKEN"], "(set)");
+        assert_eq!(variables["POSTER_HASH_SALT"], "(set)");
+        assert!(variables["API_KEY"].is_null());
+    }
+
+    // A file written an hour ago, past the garbage collection's grace period
+    fn old_file(path: &str, contents: &[u8]) {
+        std::fs::write(path, contents).unwrap();
+        let an_hour_ago = std::time::SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(an_hour_ago).unwrap();
+    }
+
+    // The posts are stored through the repository, so this runs against any backend
+    async fn check_garbage_collection(state: crate::AppState) {
+        let mut thread = test_support::new_thread("Kept", "Image");
+        thread.image_url = Some("/uploads/gc-kept.jpg".to_string());
+        thread.thumbnails = vec!["/thumbs/gc-kept-250.jpg".to_string()];
+        let thread = state.repository.create_thread(thread).unwrap();
+        let mut reply = test_support::new_reply("Reply image");
+        reply.image_url = Some("/uploads/gc-reply.jpg".to_string());
+        state.repository.create_reply(thread.id, reply, true).unwrap();
+        old_file(&format!("{}gc-kept.jpg", UPLOAD_DIR), b"kept");
+        old_file(&format!("{}gc-reply.jpg", UPLOAD_DIR), b"reply");
+        old_file(&format!("{}gc-kept-250.jpg", THUMB_DIR), b"thumb");
+        old_file(&format!("{}gc-orphan.jpg", UPLOAD_DIR), b"orphaned");
+        old_file(&format!("{}gc-orphan-250.jpg", THUMB_DIR), b"thumb");
+        // Young enough to belong to a post still being submitted
+        std::fs::write(format!("{}gc-pending.jpg", UPLOAD_DIR), b"pending").unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::post()
+            .uri("/admin/gc")
+            .insert_header(("Authorization", "Bearer hunter2"))
+            .to_request();
+        let body: serde_json::Value = serde_json::from_slice(&call_and_read_body(&app, request).await).unwrap();
+        assert_eq!(body, json!({ "removed": 2, "bytes": 8 }));
+        let uploads = ["gc-kept.jpg", "gc-reply.jpg", "gc-pending.jpg"];
+        for kept in uploads.iter().map(|name| UPLOAD_DIR.to_string() + name) {
+            assert!(std::path::Path::new(&kept).exists());
+        }
+        assert!(std::path::Path::new(&format!("{}gc-kept-250.jpg", THUMB_DIR)).exists());
+        assert!(!std::path::Path::new(&format!("{}gc-orphan.jpg", UPLOAD_DIR)).exists());
+        assert!(!std::path::Path::new(&format!("{}gc-orphan-250.jpg", THUMB_DIR)).exists());
+        for name in uploads {
+            std::fs::remove_file(format!("{}{}", UPLOAD_DIR, name)).unwrap();
+        }
+        std::fs::remove_file(format!("{}gc-kept-250.jpg", THUMB_DIR)).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn garbage_collection_keeps_referenced_files() {
+        let _files = test_support::files().await;
+        check_garbage_collection(test_support::state(&[("ADMIN_TOKEN", "hunter2")])).await;
+    }
+
+    #[actix_web::test]
+    async fn garbage_collection_keeps_files_of_sqlite_posts() {
+        let _files = test_support::files().await;
+        let mut state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("DB_BACKEND", "sqlite")]);
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        let sqlite = SqliteRepository::with_connection(connection, false, 0, 0).unwrap();
+        state.repository = web::Data::from(Arc::new(sqlite) as Arc<dyn Repository>);
+        check_garbage_collection(state).await;
+    }
+
+    #[actix_web::test]
+    async fn the_sled_only_tools_refuse_sqlite_posts() {
+        let mut state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("DB_BACKEND", "sqlite")]);
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        let sqlite = SqliteRepository::with_connection(connection, false, 0, 0).unwrap();
+        sqlite.create_thread(test_support::new_thread("In SQLite", "Not in sled")).unwrap();
+        state.repository = web::Data::from(Arc::new(sqlite) as Arc<dyn Repository>);
+        let app = init_service(crate::app(&state)).await;
+        let admin = |request: TestRequest| request.insert_header(("Authorization", "Bearer hunter2")).to_request();
+
+        let lock = TestRequest::post().uri("/admin/thread/1/lock").set_form([("locked", "true")]);
+        assert_eq!(call_service(&app, admin(lock)).await.status(), 501);
+        let wipe = TestRequest::post().uri("/admin/wipe?dry=true");
+        assert_eq!(call_service(&app, admin(wipe)).await.status(), 501);
+        let dashboard = call_and_read_body(&app, admin(TestRequest::get().uri("/admin"))).await;
+        assert!(String::from_utf8(dashboard.to_vec()).unwrap().contains("In SQLite"));
+    }
+
+    #[actix_web::test]
+    async fn rebuilding_recreates_missing_thumbnails() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        std::fs::write(format!("{}rebuild.png", UPLOAD_DIR), test_support::png(300, 200, 255)).unwrap();
+        let mut thread = test_support::new_thread("Rebuilt", "Thumbnails went missing");
+        thread.image_url = Some("/uploads/rebuild.png".to_string());
+        let thread = crate::insert_thread(&state.db, thread, false).unwrap();
+        let mut reply = test_support::new_reply("Image gone too");
+        reply.image_url = Some("/uploads/rebuild-gone.png".to_string());
+        crate::insert_reply(&state.db, thread.id, reply, true, 0, 0, false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::post()
+            .uri("/admin/rebuild-thumbnails")
+            .insert_header(("Authorization", "Bearer hunter2"))
+            .to_request();
+        let body: serde_json::Value = serde_json::from_slice(&call_and_read_body(&app, request).await).unwrap();
+        assert_eq!(body, json!({ "rebuilt": 1, "failed": 1 }));
+        let thumbnails = crate::get_thread(&state.db, thread.id).unwrap().thumbnails;
+        assert_eq!(thumbnails.len(), 2);
+        for url in &thumbnails {
+            let path = format!("{}{}", THUMB_DIR, url.rsplit('/').next().unwrap());
+            assert!(image::open(&path).is_ok(), "{} was not recreated", path);
+            std::fs::remove_file(path).unwrap();
+        }
+        std::fs::remove_file(format!("{}rebuild.png", UPLOAD_DIR)).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn a_custom_thumbnail_shows_instead_of_the_generated_one() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("ALLOWED_IMAGE_TYPES", "jpeg,png")]);
+        let app = init_service(crate::app(&state)).await;
+        let jpeg = test_support::jpeg(300, 200);
+        let fields: [(&str, &[u8]); 3] = [("title", b"Thread"), ("message", b"Hi"), ("image", &jpeg)];
+        assert_eq!(call_service(&app, test_support::form_post("/thread", &fields).to_request()).await.status(), 303);
+        let thread = crate::get_thread(&state.db, 1).unwrap();
+        let page = |uri: &'static str| {
+            let request = TestRequest::get().uri(uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        let set_thumbnail = |fields: &[(&str, &[u8])]| {
+            let request = test_support::form_post("/admin/thread/1/thumbnail", fields);
+            call_service(&app, request.insert_header(("Authorization", "Bearer hunter2")).to_request())
+        };
+
+        let png = test_support::png(400, 400, 255);
+        let unauthorized = test_support::form_post("/admin/thread/1/thumbnail", &[("image", &png)]);
+        assert_eq!(call_service(&app, unauthorized.to_request()).await.status(), 401);
+        let response = set_thumbnail(&[("image", &png)]).await;
+        assert_eq!(response.status(), 200);
+        let custom = crate::get_thread(&state.db, 1).unwrap().custom_thumbnail.unwrap();
+        assert_eq!(custom.thumbnails.len(), 2);
+
+        let generated = &thread.thumbnails[0];
+        for uri in ["/", "/catalog", "/thread/1"] {
+            let page = page(uri).await;
+            assert!(page.contains(&format!("<img src=\"{}\"", custom.thumbnails[0])), "{}: {}", uri, page);
+            assert!(!page.contains(generated.as_str()), "{} still shows {}", uri, generated);
+        }
+        // The full image stays the one that was posted
+        assert!(page("/thread/1").await.contains(&format!("href=\"{}\"", thread.image_url.as_ref().unwrap())));
+
+        // An empty file input goes back to the generated thumbnail and deletes the custom one
+        assert_eq!(set_thumbnail(&[("image@", b"")]).await.status(), 200);
+        assert!(crate::get_thread(&state.db, 1).unwrap().custom_thumbnail.is_none());
+        assert!(page("/thread/1").await.contains(&format!("<img src=\"{}\"", generated)));
+        let stored = format!("{}{}", UPLOAD_DIR, custom.image_url.rsplit('/').next().unwrap());
+        assert!(!std::path::Path::new(&stored).exists());
+        upload::delete_image(&state.db, thread.image_url.as_ref().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn deleting_an_image_keeps_the_text() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        let app = init_service(crate::app(&state)).await;
+        let jpeg = test_support::jpeg(300, 200);
+        let fields: [(&str, &[u8]); 3] = [("title", b"Thread"), ("message", b"Opening words"), ("image", &jpeg)];
+        assert_eq!(call_service(&app, test_support::form_post("/thread", &fields).to_request()).await.status(), 303);
+        let fields: [(&str, &[u8]); 3] = [("parent_id", b"1"), ("message", b"Reply words"), ("image", &jpeg)];
+        assert_eq!(call_service(&app, test_support::form_post("/reply", &fields).to_request()).await.status(), 303);
+        let thread = crate::get_thread(&state.db, 1).unwrap();
+        let reply = crate::get_reply(&state.db, 1, 1).unwrap();
+        let delete = |uri: &'static str, token: &str| {
+            let request = TestRequest::post().uri(uri).insert_header(("Authorization", format!("Bearer {}", token)));
+            call_service(&app, request.to_request())
+        };
+
+        assert_eq!(delete("/admin/thread/1/delete-image", "wrong").await.status(), 401);
+        assert_eq!(delete("/admin/thread/1/delete-image", "hunter2").await.status(), 200);
+        assert_eq!(delete("/admin/thread/1/reply/1/delete-image", "hunter2").await.status(), 200);
+        // Nothing left to delete, and no such reply
+        assert_eq!(delete("/admin/thread/1/delete-image", "hunter2").await.status(), 400);
+        assert_eq!(delete("/admin/thread/1/reply/2/delete-image", "hunter2").await.status(), 404);
+
+        let after = crate::get_thread(&state.db, 1).unwrap();
+        let reply_after = crate::get_reply(&state.db, 1, 1).unwrap();
+        assert_eq!((after.message.as_str(), reply_after.message.as_str()), ("Opening words", "Reply words"));
+        assert!(after.image_url.is_none() && after.thumbnails.is_empty() && after.image_removed);
+        assert!(reply_after.image_url.is_none() && reply_after.thumbnails.is_empty() && reply_after.image_removed);
+        let files = [&thread.image_url, &reply.image_url].map(|url| url.as_ref().unwrap().clone());
+        for url in files.iter().chain(&thread.thumbnails).chain(&reply.thumbnails) {
+            let dir = if url.starts_with("/uploads/") { UPLOAD_DIR } else { THUMB_DIR };
+            let path = format!("{}{}", dir, url.rsplit('/').next().unwrap());
+            assert!(!std::path::Path::new(&path).exists(), "{} is still there", path);
+        }
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert_eq!(page.matches("<div class=\"post-image image-removed\">[image removed by staff]</div>").count(), 2);
+        assert!(page.contains("Opening words") && page.contains("Reply words"));
+        let logged: Vec<_> = modlog::recent(&state.db, 10).into_iter().map(|entry| entry.detail).collect();
+        assert_eq!(logged, [format!("Reply 1 in thread 1 ({})", files[1]), format!("Thread 1 ({})", files[0])]);
+    }
+
+    #[actix_web::test]
+    async fn a_pin_with_a_duration_runs_out() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        let old = crate::insert_thread(&state.db, test_support::new_thread("Pinned", "Hi"), false).unwrap();
+        test_support::backdate(&state.db, old.id, 1_000);
+        crate::insert_thread(&state.db, test_support::new_thread("Newer", "Hi"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let homepage = || async {
+            let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+            let page = String::from_utf8(page.to_vec()).unwrap();
+            let pinned_first = page.find("Pinned").unwrap() < page.find("Newer").unwrap();
+            (pinned_first, page.contains("<span class=\"sticky-label\">Sticky</span>"))
+        };
+        assert_eq!(homepage().await, (false, false));
+
+        let pin = TestRequest::post()
+            .uri(&format!("/admin/thread/{}/sticky", old.id))
+            .insert_header(("Authorization", "Bearer hunter2"))
+            .set_form([("sticky", "true"), ("duration", "1")]);
+        let body = call_and_read_body(&app, pin.to_request()).await;
+        let until = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["until"].as_i64().unwrap();
+        assert!(until > chrono::Utc::now().timestamp() - 1);
+        assert!(matches!(crate::get_thread(&state.db, old.id).unwrap().sticky, Some(Sticky::Until(at)) if at == until));
+        assert_eq!(homepage().await, (true, true));
+        assert_eq!(modlog::recent(&state.db, 1)[0].detail, format!("Thread {} pinned for 1s", old.id));
+
+        // Nothing has to run for the pin to lapse; reads compare it with the clock
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(2100)).await;
+        assert!(!crate::get_thread(&state.db, old.id).unwrap().is_sticky());
+        assert_eq!(homepage().await, (false, false));
+    }
+
+    #[actix_web::test]
+    async fn a_wipe_needs_the_board_name_unless_it_is_a_dry_run() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("APP_NAME", "Chess Board")]);
+        let app = init_service(crate::app(&state)).await;
+        let jpeg = test_support::jpeg(64, 48);
+        for (address, title) in [(1, "First"), (2, "Second")] {
+            let fields: [(&str, &[u8]); 3] = [("title", title.as_bytes()), ("message", b"Hi"), ("image", &jpeg)];
+            let post = test_support::form_post("/thread", &fields)
+                .peer_addr(format!("192.0.2.{}:4000", address).parse().unwrap());
+            assert!(call_service(&app, post.to_request()).await.status().is_redirection());
+        }
+        crate::insert_reply(&state.db, 1, test_support::new_reply("Gone too"), true, 0, 0, false).unwrap();
+        state.db.insert("user_magnus", b"{}".as_slice()).unwrap();
+        // Files and bytes in the upload directories, which may hold other tests' files as well
+        let on_disk = || {
+            let entries = [UPLOAD_DIR, THUMB_DIR].into_iter().flat_map(|dir| std::fs::read_dir(dir).unwrap().flatten());
+            let sizes: Vec<u64> = entries.map(|entry| entry.metadata().unwrap().len()).collect();
+            (sizes.len(), sizes.iter().sum::<u64>())
+        };
+        let (files, bytes) = on_disk();
+        let wipe = |uri: &str, confirm: Option<&str>| {
+            let request = TestRequest::post().uri(uri).insert_header(("Authorization", "Bearer hunter2"));
+            match confirm {
+                Some(confirm) => request.set_form([("confirm", confirm)]).to_request(),
+                None => request.to_request(),
+            }
+        };
+
+        let dry: serde_json::Value = call_and_read_body_json(&app, wipe("/admin/wipe?dry=true", None)).await;
+        let counted = json!({ "dry_run": true, "threads": 2, "replies": 1, "files": files, "bytes": bytes });
+        assert_eq!(dry, counted);
+        for confirm in [None, Some("chess board"), Some("Rust Lang is god!")] {
+            let refused = call_service(&app, wipe("/admin/wipe", confirm)).await;
+            assert_eq!(refused.status(), 400, "{:?}", confirm);
+        }
+        assert_eq!(crate::get_all_threads(&state.db).len(), 2);
+        assert_eq!(on_disk(), (files, bytes));
+
+        let confirmed = wipe("/admin/wipe", Some(" Chess Board "));
+        let wiped: serde_json::Value = call_and_read_body_json(&app, confirmed).await;
+        assert_eq!(wiped["dry_run"], false);
+        assert_eq!((wiped["threads"].as_u64(), wiped["replies"].as_u64()), (Some(2), Some(1)));
+        assert_eq!(on_disk(), (0, 0));
+        assert!(crate::get_all_threads(&state.db).is_empty());
+        assert!(state.db.scan_prefix(b"reply_").next().is_none());
+        assert!(state.db.contains_key("user_magnus").unwrap());
+        assert_eq!(modlog::recent(&state.db, 1)[0].action, "wipe");
+        let next = crate::insert_thread(&state.db, test_support::new_thread("Fresh", "Start"), false).unwrap();
+        assert_eq!(next.id, 1);
+    }
+
+    #[actix_web::test]
+    async fn upload_stats_count_refusals_by_reason() {
+        let _files = test_support::files().await;
+        let vars = [
+            ("ADMIN_TOKEN", "hunter2"),
+            ("THREAD_COOLDOWN", "0"),
+            ("MAX_UPLOAD_BYTES", "4000"),
+            ("MAX_IMAGE_PIXELS", "2000"),
+        ];
+        let state = test_support::state(&vars);
+        let app = init_service(crate::app(&state)).await;
+        let jpeg = test_support::jpeg(32, 32);
+        // Declared too long to read at all, past the form allowance
+        let oversized = vec![0xff; 300 * 1024];
+        let refused: [(&str, &[u8]); 6] = [
+            ("image@notes.txt", b"hello"),
+            ("image@notes.txt", b"again"),
+            ("image@big.jpg", &[0xff; 8000]),
+            ("image@huge.jpg", &oversized),
+            ("image@cut.jpg", &jpeg[..jpeg.len() / 2]),
+            ("image@wide.jpg", &test_support::jpeg(64, 64)),
+        ];
+        for (field, bytes) in refused {
+            let post = test_support::form_post("/thread", &[("title", b"Bad"), ("message", b"Image"), (field, bytes)]);
+            assert!(call_service(&app, post.to_request()).await.status().is_client_error(), "{}", field);
+        }
+        let fine = test_support::form_post("/thread", &[("title", b"Good"), ("message", b"Image"), ("image", &jpeg)]);
+        assert!(call_service(&app, fine.to_request()).await.status().is_redirection());
+
+        let stats = TestRequest::get().uri("/admin/upload-stats");
+        assert_eq!(call_service(&app, stats.to_request()).await.status(), 401);
+        let stats = TestRequest::get().uri("/admin/upload-stats").insert_header(("Authorization", "Bearer hunter2"));
+        let stats: serde_json::Value = call_and_read_body_json(&app, stats.to_request()).await;
+        assert_eq!(stats["total"], 6);
+        let rejections = &stats["rejections"];
+        assert_eq!((rejections["wrong_type"].as_u64(), rejections["too_large"].as_u64()), (Some(2), Some(2)));
+        assert_eq!((rejections["decode_failure"].as_u64(), rejections["too_many_pixels"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(rejections.as_object().unwrap().len(), upload::REJECTION_REASONS.len());
+    }
+}
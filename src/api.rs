@@ -0,0 +1,737 @@
+use actix_multipart::Multipart;
+use actix_web::{http::header, web, Error, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::stream::StreamExt;
+use log::error;
+use serde::Deserialize;
+use serde_json::json;
+use sled::Db;
+use std::sync::Arc;
+
+use crate::accounts;
+use crate::admin::constant_time_eq;
+use crate::flood::ReplyFlood;
+use crate::format;
+use crate::geoip;
+use crate::repository::{RepoError, Repository, ThreadOrder};
+use crate::settings::Settings;
+use crate::tags;
+use crate::upload;
+use crate::validation::{self, ValidationError};
+use crate::{post_options, poster_hash, prune_pages, should_bump, NewReply, NewThread};
+
+// Replies per page of GET /api/thread/{id}, and the most a client may ask for
+const DEFAULT_REPLIES_PER_PAGE: usize = 50;
+const MAX_REPLIES_PER_PAGE: usize = 200;
+
+// Most threads one page of GET /api/threads can hold
+const MAX_THREADS_PER_PAGE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ApiThreadRequest {
+    // Ignored with TITLES_ENABLED=false
+    #[serde(default)]
+    title: String,
+    message: String,
+    #[serde(default)]
+    email: String,
+    // Comma-separated, like the form field; ignored with MAX_TAGS=0
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    nsfw: bool,
+    // Token returned by /api/upload
+    image_token: Option<String>,
+    // Or the image itself, base64 encoded (a data: URL works too)
+    image_base64: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiReplyRequest {
+    parent_id: i32,
+    message: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    nsfw: bool,
+    // Token returned by /api/upload
+    image_token: Option<String>,
+    // Or the image itself, base64 encoded (a data: URL works too)
+    image_base64: Option<String>,
+}
+
+enum AttachError {
+    Rejected(&'static str),
+    Upload(upload::UploadError),
+}
+
+impl AttachError {
+    fn to_response(&self) -> HttpResponse {
+        match self {
+            AttachError::Rejected(reason) => bad_request(reason),
+            AttachError::Upload(e) => e.to_response(),
+        }
+    }
+}
+
+// The image of a JSON post. A base64 image is only committed once the post is
+// stored; a token's image was committed when the token was issued.
+#[derive(Default)]
+struct Attachment {
+    image_url: Option<String>,
+    thumbnails: Vec<String>,
+    image_size: Option<upload::ImageSize>,
+    pending: Option<upload::UploadMeta>,
+}
+
+impl Attachment {
+    fn commit(&self) {
+        if let Some(meta) = &self.pending {
+            if let Err(e) = meta.commit() {
+                error!("Failed to move upload {} into place: {}", meta.filename, e);
+            }
+        }
+    }
+
+    fn discard(&mut self, db: &Db) {
+        if let Some(meta) = self.pending.take() {
+            upload::discard(db, &meta);
+        }
+    }
+}
+
+// Resolve an optional upload token or store an inline base64 image
+async fn attach_image(
+    db: &Db,
+    settings: &Settings,
+    token: Option<&str>,
+    base64: Option<&str>,
+    poster: Option<&str>,
+) -> Result<Attachment, AttachError> {
+    let token = token.map(str::trim).filter(|token| !token.is_empty());
+    let base64 = base64.filter(|data| !data.trim().is_empty());
+    match (token, base64) {
+        (Some(_), Some(_)) => Err(AttachError::Rejected("Send either image_token or image_base64, not both")),
+        (Some(token), None) => upload::redeem_token(db, token, settings.upload_token_ttl)
+            .map(|image| Attachment {
+                image_url: Some(image.image_url),
+                thumbnails: image.thumbnails,
+                image_size: image.image_size,
+                pending: None,
+            })
+            .ok_or(AttachError::Rejected("Unknown or expired image token")),
+        (None, Some(data)) => upload::save_base64(data, settings, db, poster)
+            .await
+            .map(|meta| Attachment {
+                image_url: Some(meta.url()),
+                thumbnails: meta.thumbnails.clone(),
+                image_size: Some(meta.image_size()),
+                pending: Some(meta),
+            })
+            .map_err(AttachError::Upload),
+        (None, None) => Ok(Attachment::default()),
+    }
+}
+
+// The JSON write endpoints only exist when API_KEY is set, and every call must
+// carry "Authorization: Bearer <key>"
+fn require_api_key(req: &HttpRequest, settings: &Settings) -> Option<HttpResponse> {
+    let expected = match &settings.api_key {
+        Some(key) => key,
+        None => return Some(HttpResponse::NotFound().json(json!({ "error": "Not found" }))),
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(key) if constant_time_eq(key.as_bytes(), expected.as_bytes()) => None,
+        _ => Some(
+            HttpResponse::Unauthorized()
+                .append_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .json(json!({ "error": "Missing or invalid API key" })),
+        ),
+    }
+}
+
+// Whether a JSON post carries an image in either of its image fields
+fn has_image(token: &Option<String>, base64: &Option<String>) -> bool {
+    [token, base64]
+        .iter()
+        .any(|field| field.as_deref().is_some_and(|value| !value.trim().is_empty()))
+}
+
+fn bad_request(message: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(json!({ "error": message }))
+}
+
+// JSON thread creation handler
+pub async fn create_thread(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    body: web::Json<ApiThreadRequest>,
+) -> HttpResponse {
+    if let Some(denied) = require_api_key(&req, &settings) {
+        return denied;
+    }
+
+    let body = body.into_inner();
+    let with_image = has_image(&body.image_token, &body.image_base64);
+    let errors = validation::thread_errors(&settings, &body.title, &body.message, with_image);
+    if !errors.is_empty() {
+        return validation::json_errors(&errors);
+    }
+
+    let filter = &settings.word_filter;
+    let title = if settings.titles_enabled { body.title.trim() } else { "" };
+    let (title, message) = match (filter.apply(title), filter.apply(body.message.trim())) {
+        (Ok(title), Ok(message)) => (title, message),
+        _ => return validation::json_errors(&[ValidationError::BlockedWord]),
+    };
+
+    let poster = poster_hash(&req, &settings);
+    let (token, base64) = (body.image_token.as_deref(), body.image_base64.as_deref());
+    let image = attach_image(&db, &settings, token, base64, poster.as_deref());
+    let mut image = match image.await {
+        Ok(image) => image,
+        Err(e) => return e.to_response(),
+    };
+
+    let new_thread = NewThread {
+        title,
+        message,
+        image_url: image.image_url.clone(),
+        thumbnails: image.thumbnails.clone(),
+        image_size: image.image_size,
+        email: post_options::sanitize_email(&body.email),
+        poster_hash: poster,
+        username: accounts::current_user(&req, &db, &settings),
+        tags: tags::parse(&body.tags, settings.max_tags),
+        country: geoip::poster_country(&req, &settings),
+        nsfw: body.nsfw,
+    };
+
+    match repo.create_thread(new_thread) {
+        Ok(thread) => {
+            image.commit();
+            prune_pages(&db, &settings).await;
+            HttpResponse::Created()
+                .append_header((header::LOCATION, settings.url(&format!("/thread/{}", thread.id))))
+                .json(thread.public(&settings))
+        }
+        Err(e) => {
+            error!("Failed to insert thread into sled db: {}", e);
+            image.discard(&db);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to create thread" }))
+        }
+    }
+}
+
+// JSON reply creation handler
+pub async fn create_reply(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    reply_flood: web::Data<ReplyFlood>,
+    body: web::Json<ApiReplyRequest>,
+) -> HttpResponse {
+    if let Some(denied) = require_api_key(&req, &settings) {
+        return denied;
+    }
+
+    let body = body.into_inner();
+    let with_image = has_image(&body.image_token, &body.image_base64);
+    let mut errors = validation::reply_errors(&settings, &body.message, with_image);
+    if with_image && !settings.allow_image_reply {
+        errors.push(ValidationError::ReplyImagesDisabled);
+    }
+    if !errors.is_empty() {
+        return validation::json_errors(&errors);
+    }
+    let message = match settings.word_filter.apply(body.message.trim()) {
+        Ok(message) => message,
+        Err(_) => return validation::json_errors(&[ValidationError::BlockedWord]),
+    };
+    let poster = poster_hash(&req, &settings);
+    let (token, base64) = (body.image_token.as_deref(), body.image_base64.as_deref());
+    let image = attach_image(&db, &settings, token, base64, poster.as_deref());
+    let mut image = match image.await {
+        Ok(image) => image,
+        Err(e) => return e.to_response(),
+    };
+
+    let email = post_options::sanitize_email(&body.email);
+    let bump = should_bump(&settings, email.as_deref());
+    let new_reply = NewReply {
+        message,
+        image_url: image.image_url.clone(),
+        thumbnails: image.thumbnails.clone(),
+        image_size: image.image_size,
+        email,
+        poster_hash: poster,
+        username: accounts::current_user(&req, &db, &settings),
+        country: geoip::poster_country(&req, &settings),
+        nsfw: body.nsfw,
+    };
+
+    match repo.create_reply(body.parent_id, new_reply, bump) {
+        Ok(reply) => {
+            image.commit();
+            reply_flood.record(&db, body.parent_id);
+            HttpResponse::Created()
+                .append_header((header::LOCATION, settings.url(&format!("/thread/{}", body.parent_id))))
+                .json(reply.public(&settings))
+        }
+        Err(RepoError::Refused(reason)) => {
+            image.discard(&db);
+            reason.to_json()
+        }
+        Err(e) => {
+            error!("Failed to insert reply into sled db: {}", e);
+            image.discard(&db);
+            HttpResponse::InternalServerError().json(json!({ "error": "Failed to post reply" }))
+        }
+    }
+}
+
+// Image upload handler for API clients; the returned token attaches the image to
+// a later /api/thread or /api/reply call
+pub async fn upload_image(
+    req: HttpRequest,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    if let Some(denied) = require_api_key(&req, &settings) {
+        return Ok(denied);
+    }
+    let poster = poster_hash(&req, &settings);
+    if let Err(e) = upload::check_declared_length(&req, &settings, &db, poster.as_deref()) {
+        return Ok(e.to_response());
+    }
+
+    while let Some(item) = payload.next().await {
+        let mut field = item?;
+        if field.content_disposition().get_name() != Some("image") {
+            continue;
+        }
+
+        let meta = match upload::save_upload(&mut field, &settings, &db, poster.as_deref()).await {
+            Ok(Some(meta)) => meta,
+            Ok(None) => break,
+            Err(e) => return Ok(e.to_response()),
+        };
+
+        // Committed straight away: from here on the token is what references the image
+        let issued = meta.commit().map_err(sled::Error::from).and_then(|()| upload::issue_token(&db, &meta));
+        return match issued {
+            Ok(token) => {
+                let mut response = HttpResponse::Created().json(json!({
+                    "image_url": settings.url(&meta.url()),
+                    "token": token,
+                }));
+                meta.add_received_header(response.headers_mut());
+                Ok(response)
+            }
+            Err(e) => {
+                error!("Failed to store upload token: {}", e);
+                upload::discard(&db, &meta);
+                Ok(HttpResponse::InternalServerError().json(json!({ "error": "Failed to store upload" })))
+            }
+        };
+    }
+
+    Ok(bad_request("No image provided"))
+}
+
+#[derive(Deserialize)]
+pub struct ThreadListParams {
+    page: Option<usize>,
+    per_page: Option<usize>,
+    // next_cursor of the previous response
+    cursor: Option<String>,
+}
+
+// Threads in bump order, paged either by ?page= or by ?cursor=. Every response
+// carries a next_cursor while more threads follow; walking the cursors doesn't
+// skip or repeat threads when new ones are posted in between, which pages can.
+pub async fn list_threads(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    query: web::Query<ThreadListParams>,
+) -> HttpResponse {
+    let per_page = query
+        .per_page
+        .unwrap_or(settings.threads_per_page as usize)
+        .clamp(1, MAX_THREADS_PER_PAGE);
+
+    // One extra thread tells whether there is a next page
+    let (mut threads, page) = match (&query.cursor, query.page) {
+        (Some(_), Some(_)) => return bad_request("Use either page or cursor, not both"),
+        (Some(cursor), None) => match decode_cursor(cursor) {
+            Some(after) => (repo.list_threads_after(Some(after), per_page + 1), None),
+            None => return bad_request("Invalid cursor"),
+        },
+        (None, Some(page)) => {
+            let page = page.max(1);
+            let offset = (page - 1).saturating_mul(per_page);
+            (repo.list_threads(ThreadOrder::Bump, offset, per_page + 1), Some(page))
+        }
+        (None, None) => (repo.list_threads_after(None, per_page + 1), None),
+    };
+
+    let more = threads.len() > per_page;
+    threads.truncate(per_page);
+    let next_cursor = threads
+        .last()
+        .filter(|_| more)
+        .map(|thread| encode_cursor(thread.last_updated, thread.id));
+    let threads: Vec<_> = threads.into_iter().map(|thread| thread.public(&settings)).collect();
+
+    let mut body = json!({
+        "threads": threads,
+        "per_page": per_page,
+        "next_cursor": next_cursor,
+    });
+    if let Some(page) = page {
+        body["page"] = json!(page);
+        body["total_pages"] = json!(repo.count_threads().div_ceil(per_page));
+    }
+    HttpResponse::Ok().json(body)
+}
+
+// Cursors are the bump position of the last thread of a page, kept opaque so
+// clients don't start building them
+fn encode_cursor(last_updated: i64, thread_id: i32) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}_{}", last_updated, thread_id))
+}
+
+fn decode_cursor(cursor: &str) -> Option<(i64, i32)> {
+    let decoded = String::from_utf8(URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()?;
+    let (last_updated, thread_id) = decoded.split_once('_')?;
+    Some((last_updated.parse().ok()?, thread_id.parse().ok()?))
+}
+
+#[derive(Deserialize)]
+pub struct ReplyPageParams {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+// A thread with one page of its replies, oldest first. Public like quote_reply.
+pub async fn get_thread(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    query: web::Query<ReplyPageParams>,
+) -> HttpResponse {
+    let thread = match repo.get_thread(path.into_inner()) {
+        Some(thread) => thread,
+        None => return HttpResponse::NotFound().json(json!({ "error": "Thread not found" })),
+    };
+
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_REPLIES_PER_PAGE)
+        .clamp(1, MAX_REPLIES_PER_PAGE);
+    let page = query.page.unwrap_or(1).max(1);
+
+    let replies = repo.list_replies(thread.id);
+    let total_replies = replies.len();
+    let page_replies: Vec<_> = replies
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .map(|reply| reply.public(&settings))
+        .collect();
+
+    HttpResponse::Ok().json(json!({
+        "thread": thread.public(&settings),
+        "replies": page_replies,
+        "page": page,
+        "per_page": per_page,
+        "total_replies": total_replies,
+        "total_pages": total_replies.div_ceil(per_page),
+    }))
+}
+
+// A single reply. Public like quote_reply.
+pub async fn get_reply(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    path: web::Path<(i32, i32)>,
+) -> HttpResponse {
+    let (thread_id, reply_id) = path.into_inner();
+    match repo.get_reply(thread_id, reply_id) {
+        Some(reply) => HttpResponse::Ok().json(reply.public(&settings)),
+        None => HttpResponse::NotFound().json(json!({ "error": "Reply not found" })),
+    }
+}
+
+// Text to prepend to a new reply when quoting reply `rid`. Read-only, so unlike
+// the write endpoints it is public and works without API_KEY.
+pub async fn quote_reply(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    path: web::Path<(i32, i32)>,
+) -> HttpResponse {
+    let (thread_id, reply_id) = path.into_inner();
+    // With global numbers the opening post has a number to quote too
+    let opening_post = settings.global_post_numbers && reply_id == thread_id && repo.get_thread(thread_id).is_some();
+    if !opening_post && !repo.reply_exists(thread_id, reply_id) {
+        return HttpResponse::NotFound().json(json!({ "error": "Reply not found" }));
+    }
+
+    HttpResponse::Ok().json(json!({
+        "thread_id": thread_id,
+        "reply_id": reply_id,
+        "quote": format::quote_text(reply_id),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::UPLOAD_DIR;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+
+    fn api_post(uri: &str, key: Option<&str>, body: serde_json::Value) -> TestRequest {
+        let request = TestRequest::post().uri(uri).peer_addr("192.0.2.1:4000".parse().unwrap()).set_json(body);
+        match key {
+            Some(key) => request.insert_header((header::AUTHORIZATION, format!("Bearer {}", key))),
+            None => request,
+        }
+    }
+
+    #[actix_web::test]
+    async fn writes_need_the_api_key() {
+        let state = test_support::state(&[("API_KEY", "sesame"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        let thread = || json!({ "title": "Keys", "message": "Hello" });
+
+        for key in [None, Some("sesam"), Some("sesame2"), Some("")] {
+            let response = call_service(&app, api_post("/api/thread", key, thread()).to_request()).await;
+            assert_eq!(response.status(), 401, "key {:?}", key);
+            assert_eq!(response.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+        }
+        let reply = json!({ "parent_id": 1, "message": "Hi" });
+        assert_eq!(call_service(&app, api_post("/api/reply", None, reply.clone()).to_request()).await.status(), 401);
+        assert!(state.db.scan_prefix(b"thread_").next().is_none());
+
+        let response = call_service(&app, api_post("/api/thread", Some("sesame"), thread()).to_request()).await;
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/thread/1");
+        let response = call_service(&app, api_post("/api/reply", Some("sesame"), reply).to_request()).await;
+        assert_eq!(response.status(), 201);
+        assert_eq!(crate::get_replies(&state.db, 1).len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn without_an_api_key_the_endpoints_do_not_exist() {
+        let state = test_support::state(&[]);
+        let app = init_service(crate::app(&state)).await;
+        let request = api_post("/api/thread", Some("anything"), json!({ "title": "Keys", "message": "Hello" }));
+        assert_eq!(call_service(&app, request.to_request()).await.status(), 404);
+    }
+
+    fn upload_request(jpeg: &[u8]) -> TestRequest {
+        test_support::form_post("/api/upload", &[("image", jpeg)])
+            .insert_header((header::AUTHORIZATION, "Bearer sesame"))
+    }
+
+    #[actix_web::test]
+    async fn an_uploaded_image_is_attached_by_its_token_once() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("API_KEY", "sesame"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let uploaded: serde_json::Value =
+            call_and_read_body_json(&app, upload_request(&test_support::jpeg(64, 48)).to_request()).await;
+        let token = uploaded["token"].as_str().unwrap().to_string();
+        let image_url = uploaded["image_url"].as_str().unwrap().to_string();
+
+        let thread = json!({ "title": "Token", "message": "Hi", "image_token": token });
+        let created: serde_json::Value =
+            call_and_read_body_json(&app, api_post("/api/thread", Some("sesame"), thread.clone()).to_request()).await;
+        assert_eq!(created["image_url"], image_url.as_str());
+        assert_eq!(crate::get_thread(&state.db, 1).unwrap().image_url.as_deref(), Some(image_url.as_str()));
+
+        // Spent
+        let again = call_service(&app, api_post("/api/thread", Some("sesame"), thread).to_request()).await;
+        assert_eq!(again.status(), 400);
+        assert!(std::path::Path::new(&image_url[1..]).exists());
+    }
+
+    #[actix_web::test]
+    async fn expired_tokens_attach_nothing_and_lose_their_file() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("API_KEY", "sesame"), ("UPLOAD_TOKEN_TTL", "60")]);
+        let app = init_service(crate::app(&state)).await;
+        let age = |token: &str| {
+            let key = format!("upload_token_{}", token);
+            let mut record: serde_json::Value = serde_json::from_slice(&state.db.get(&key).unwrap().unwrap()).unwrap();
+            record["created_at"] = json!(record["created_at"].as_i64().unwrap() - 61);
+            state.db.insert(key, serde_json::to_vec(&record).unwrap()).unwrap();
+        };
+        let file_of = |uploaded: &serde_json::Value| {
+            let url = uploaded["image_url"].as_str().unwrap();
+            std::path::PathBuf::from(format!("{}{}", UPLOAD_DIR, url.rsplit('/').next().unwrap()))
+        };
+
+        // Startup measured whatever other tests left in the upload directory
+        let measured = upload::usage(&state.db);
+        let uploaded: serde_json::Value =
+            call_and_read_body_json(&app, upload_request(&test_support::jpeg(64, 48)).to_request()).await;
+        let token = uploaded["token"].as_str().unwrap();
+        age(token);
+        let thread = json!({ "title": "Late", "message": "Hi", "image_token": token });
+        let response = call_service(&app, api_post("/api/thread", Some("sesame"), thread).to_request()).await;
+        assert_eq!(response.status(), 400);
+        assert!(!file_of(&uploaded).exists());
+        assert_eq!(upload::usage(&state.db), measured);
+
+        // Tokens nobody tries to use go in the periodic cleanup
+        let unclaimed: serde_json::Value =
+            call_and_read_body_json(&app, upload_request(&test_support::jpeg(64, 48)).to_request()).await;
+        let fresh: serde_json::Value =
+            call_and_read_body_json(&app, upload_request(&test_support::jpeg(64, 48)).to_request()).await;
+        age(unclaimed["token"].as_str().unwrap());
+        assert_eq!(upload::purge_expired_tokens(&state.db, 60), 1);
+        assert!(!file_of(&unclaimed).exists());
+        assert!(file_of(&fresh).exists());
+        assert!(upload::redeem_token(&state.db, fresh["token"].as_str().unwrap(), 60).is_some());
+    }
+
+    #[actix_web::test]
+    async fn the_quote_endpoint_returns_the_text_to_prepend() {
+        let state = test_support::state(&[]);
+        let thread = crate::insert_thread(&state.db, test_support::new_thread("Quotes", "Hi"), false).unwrap();
+        let reply =
+            crate::insert_reply(&state.db, thread.id, test_support::new_reply("Quote me"), true, 0, 0, false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let uri = format!("/api/thread/{}/quote/{}", thread.id, reply.id);
+        let quote: serde_json::Value = call_and_read_body_json(&app, TestRequest::get().uri(&uri).to_request()).await;
+        let expected = format!(">>{}\n", reply.id);
+        assert_eq!(quote, json!({ "thread_id": thread.id, "reply_id": reply.id, "quote": expected }));
+        assert_eq!(format::quote_text(reply.id), format!(">>{}\n", reply.id));
+
+        let uri = format!("/api/thread/{}/quote/{}", thread.id, reply.id + 1);
+        assert_eq!(call_service(&app, TestRequest::get().uri(&uri).to_request()).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn base64_images_go_through_the_upload_checks() {
+        use base64::engine::general_purpose::STANDARD;
+
+        let _files = test_support::files().await;
+        let vars = [("API_KEY", "sesame"), ("THREAD_COOLDOWN", "0"), ("MAX_UPLOAD_BYTES", "20000")];
+        let state = test_support::state(&vars);
+        let app = init_service(crate::app(&state)).await;
+        let post = |image: String| {
+            let body = json!({ "title": "Inline", "message": "Hi", "image_base64": image });
+            api_post("/api/thread", Some("sesame"), body).to_request()
+        };
+        let jpeg = STANDARD.encode(test_support::jpeg(64, 48));
+
+        let created: serde_json::Value = call_and_read_body_json(&app, post(jpeg.clone())).await;
+        assert!(created["image_url"].as_str().unwrap().ends_with(".jpg"));
+        let data_url = format!("data:image/jpeg;base64,{}", jpeg);
+        let from_url: serde_json::Value = call_and_read_body_json(&app, post(data_url)).await;
+        assert_eq!(from_url["image_size"], json!({ "width": 64, "height": 48 }));
+
+        for malformed in ["not base64!", "AAA", &jpeg[..jpeg.len() - 3]] {
+            assert_eq!(call_service(&app, post(malformed.to_string())).await.status(), 400, "{:?}", malformed);
+        }
+        // Valid base64, but not an image of an allowed type
+        let text = STANDARD.encode(b"just some text");
+        assert_eq!(call_service(&app, post(text)).await.status(), 400);
+        // Refused on its encoded length; it would not even decode
+        let oversized = "A".repeat(20000 / 3 * 4 + 8);
+        assert_eq!(call_service(&app, post(oversized)).await.status(), 413);
+        assert_eq!(upload::rejection_counts(&state.db).iter().map(|(_, n)| n).sum::<u64>(), 5);
+        assert_eq!(state.db.scan_prefix(b"thread_").count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn thread_replies_come_a_page_at_a_time() {
+        let state = test_support::state(&[]);
+        let thread = crate::insert_thread(&state.db, test_support::new_thread("Long", "Many replies"), false).unwrap();
+        for n in 1..=23 {
+            let reply = test_support::new_reply(&format!("Reply {}", n));
+            crate::insert_reply(&state.db, thread.id, reply, true, 0, 0, false).unwrap();
+        }
+        let app = init_service(crate::app(&state)).await;
+        let page = |query: &str| {
+            let request = TestRequest::get().uri(&format!("/api/thread/{}?{}", thread.id, query)).to_request();
+            call_and_read_body_json::<_, _, serde_json::Value>(&app, request)
+        };
+        let messages = |body: &serde_json::Value| -> Vec<String> {
+            body["replies"].as_array().unwrap().iter().map(|reply| reply["message"].as_str().unwrap().into()).collect()
+        };
+
+        let body = page("page=3&per_page=10").await;
+        assert_eq!(messages(&body), ["Reply 21", "Reply 22", "Reply 23"]);
+        assert_eq!((body["page"].as_u64(), body["per_page"].as_u64()), (Some(3), Some(10)));
+        assert_eq!((body["total_replies"].as_u64(), body["total_pages"].as_u64()), (Some(23), Some(3)));
+        assert_eq!(body["thread"]["title"], "Long");
+
+        let body = page("").await;
+        assert_eq!(messages(&body).len(), 23);
+        assert_eq!((body["page"].as_u64(), body["total_pages"].as_u64()), (Some(1), Some(1)));
+        let body = page("page=0&per_page=100000").await;
+        assert_eq!((body["page"].as_u64(), body["per_page"].as_u64()), (Some(1), Some(MAX_REPLIES_PER_PAGE as u64)));
+        assert!(messages(&page("page=4&per_page=10").await).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn cursors_neither_skip_nor_repeat_threads_posted_meanwhile() {
+        let state = test_support::state(&[]);
+        let now = chrono::Utc::now().timestamp();
+        for n in 1..=5 {
+            let thread = test_support::new_thread(&format!("Thread {}", n), "Hi");
+            let thread = crate::insert_thread(&state.db, thread, false).unwrap();
+            test_support::backdate(&state.db, thread.id, now - 100 + n);
+        }
+        let app = init_service(crate::app(&state)).await;
+        let list = |query: String| {
+            let request = TestRequest::get().uri(&format!("/api/threads?{}", query)).to_request();
+            call_and_read_body_json::<_, _, serde_json::Value>(&app, request)
+        };
+        let titles = |body: &serde_json::Value| -> Vec<String> {
+            body["threads"].as_array().unwrap().iter().map(|thread| thread["title"].as_str().unwrap().into()).collect()
+        };
+
+        let first = list("per_page=2".to_string()).await;
+        assert_eq!(titles(&first), ["Thread 5", "Thread 4"]);
+        crate::insert_thread(&state.db, test_support::new_thread("Posted meanwhile", "Hi"), false).unwrap();
+
+        let mut seen = titles(&first);
+        let mut cursor = first["next_cursor"].as_str().unwrap().to_string();
+        loop {
+            let body = list(format!("per_page=2&cursor={}", cursor)).await;
+            seen.extend(titles(&body));
+            match body["next_cursor"].as_str() {
+                Some(next) => cursor = next.to_string(),
+                None => break,
+            }
+        }
+        assert_eq!(seen, ["Thread 5", "Thread 4", "Thread 3", "Thread 2", "Thread 1"]);
+
+        // Page 2 moved under the new thread and repeats one from page 1
+        assert_eq!(titles(&list("per_page=2&page=2".to_string()).await), ["Thread 4", "Thread 3"]);
+
+        for query in ["cursor=nonsense", "cursor=MTBfMg&page=1"] {
+            let request = TestRequest::get().uri(&format!("/api/threads?{}", query)).to_request();
+            assert_eq!(call_service(&app, request).await.status(), 400, "{}", query);
+        }
+    }
+}
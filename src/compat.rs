@@ -0,0 +1,306 @@
+// Read-only JSON in the shape 4chan's API uses, for existing imageboard clients:
+// /catalog.json and /thread/{id}.json.
+//
+// How our posts map onto 4chan post objects:
+// no              thread or reply id, as shown on the board
+//                 (unique across the board only with POST_NUMBERING=global; otherwise
+//                 replies count from 1 in every thread, as 4chan's never do)
+// resto           0 for an opening post, the thread id for a reply
+// time            created_at
+// name            the poster's username with ACCOUNTS, otherwise "Anonymous"
+// sub             thread title, left out when it is empty
+// com             the message as HTML, like the board renders it, with <br> for newlines
+// tim, ext        stem and extension of the stored file, e.g. "4f1c..." and ".jpg";
+//                 tim is a string, not 4chan's millisecond number
+// filename        the stored name as well, since the original name isn't kept
+// w, h            size of the full image, when known
+// replies         reply count
+// images          replies with an image, in thread JSON only
+// last_modified   the later of the last bump and the newest reply
+// closed          1 for a locked thread
+// archived        1 for an archived thread
+//
+// 4chan builds image links from tim on its own host, so every post with a file
+// also carries image_url and thumb_url, which clients of this board should use.
+
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::format::{format_message, QuoteLinks};
+use crate::repository::{ReplySummary, Repository, ThreadOrder};
+use crate::settings::Settings;
+use crate::upload::ImageSize;
+use crate::{resolve_quotes, Thread};
+
+#[derive(Serialize)]
+struct Page {
+    page: usize,
+    threads: Vec<Post>,
+}
+
+// A thread with its replies, oldest first
+#[derive(Serialize)]
+struct ThreadPosts {
+    posts: Vec<Post>,
+}
+
+#[derive(Serialize)]
+struct Post {
+    no: i32,
+    resto: i32,
+    time: i64,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    com: String,
+    #[serde(flatten)]
+    file: Option<File>,
+    #[serde(flatten)]
+    thread: Option<ThreadInfo>,
+}
+
+#[derive(Serialize)]
+struct File {
+    tim: String,
+    ext: String,
+    filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    w: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    h: Option<u32>,
+    image_url: String,
+    thumb_url: String,
+}
+
+// Fields only opening posts have
+#[derive(Serialize)]
+struct ThreadInfo {
+    replies: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<usize>,
+    last_modified: i64,
+    closed: u8,
+    archived: u8,
+}
+
+// Catalog handler: every thread in bump order, split into pages of
+// THREADS_PER_PAGE like the board index
+pub async fn catalog(repo: web::Data<dyn Repository>, settings: web::Data<Settings>) -> HttpResponse {
+    let threads = repo.list_threads(ThreadOrder::Bump, 0, usize::MAX);
+    let thread_ids: Vec<i32> = threads.iter().map(|thread| thread.id).collect();
+    let summaries = repo.reply_summaries(&thread_ids);
+    let links = resolve_quotes(
+        repo.get_ref(),
+        &settings,
+        threads.iter().map(|thread| (thread.id, thread.message.as_str())),
+    );
+
+    let pages: Vec<Page> = threads
+        .chunks(settings.threads_per_page as usize)
+        .enumerate()
+        .map(|(index, threads)| Page {
+            page: index + 1,
+            threads: threads
+                .iter()
+                .map(|thread| {
+                    let summary = summaries.get(&thread.id).copied().unwrap_or_default();
+                    opening_post(&settings, thread, summary, None, com(&thread.message, thread.id, &links))
+                })
+                .collect(),
+        })
+        .collect();
+    HttpResponse::Ok().json(pages)
+}
+
+// Thread handler: the opening post followed by every reply, oldest first, like
+// 4chan's res/{id}.json
+pub async fn thread(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+) -> HttpResponse {
+    let thread = match repo.get_thread(path.into_inner()) {
+        Some(thread) => thread,
+        None => return HttpResponse::NotFound().json(json!({ "error": "Thread not found" })),
+    };
+    let replies = repo.list_replies(thread.id);
+    let messages = std::iter::once(thread.message.as_str()).chain(replies.iter().map(|reply| reply.message.as_str()));
+    let links = resolve_quotes(repo.get_ref(), &settings, messages.map(|message| (thread.id, message)));
+
+    let summary = ReplySummary {
+        count: replies.len(),
+        last_reply_at: replies.last().map(|reply| reply.created_at),
+    };
+    let images = replies.iter().filter(|reply| reply.image_url.is_some()).count();
+    let opening = opening_post(&settings, &thread, summary, Some(images), com(&thread.message, thread.id, &links));
+    let posts = std::iter::once(opening)
+        .chain(replies.iter().map(|reply| Post {
+            no: reply.id,
+            resto: thread.id,
+            time: reply.created_at,
+            name: reply.display_name().to_string(),
+            sub: None,
+            com: com(&reply.message, thread.id, &links),
+            file: file(&settings, reply.image_url.as_deref(), reply.thumb_src(), reply.image_size),
+            thread: None,
+        }))
+        .collect();
+    HttpResponse::Ok().json(ThreadPosts { posts })
+}
+
+fn opening_post(
+    settings: &Settings,
+    thread: &Thread,
+    summary: ReplySummary,
+    images: Option<usize>,
+    com: String,
+) -> Post {
+    Post {
+        no: thread.id,
+        resto: 0,
+        time: thread.created_at,
+        name: thread.display_name().to_string(),
+        sub: Some(thread.title.clone()).filter(|title| !title.is_empty()),
+        com,
+        file: file(settings, thread.image_url.as_deref(), thread.thumb_src(), thread.image_size),
+        thread: Some(ThreadInfo {
+            replies: summary.count,
+            images,
+            last_modified: summary.last_reply_at.map_or(thread.last_updated, |at| at.max(thread.last_updated)),
+            closed: thread.locked.into(),
+            archived: thread.archived.into(),
+        }),
+    }
+}
+
+fn com(message: &str, thread_id: i32, links: &QuoteLinks) -> String {
+    format_message(message, thread_id, links).replace('\n', "<br>")
+}
+
+fn file(settings: &Settings, image_url: Option<&str>, thumb_src: &str, size: Option<ImageSize>) -> Option<File> {
+    let image_url = image_url?;
+    let name = image_url.rsplit('/').next()?;
+    let (tim, ext) = name.rsplit_once('.')?;
+    Some(File {
+        tim: tim.to_string(),
+        ext: format!(".{}", ext),
+        filename: tim.to_string(),
+        w: size.map(|size| size.width),
+        h: size.map(|size| size.height),
+        image_url: settings.url(image_url),
+        thumb_url: settings.url(thumb_src),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use crate::upload::ImageSize;
+    use crate::{insert_reply, insert_thread, moderation};
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, TestRequest};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn the_catalog_is_pages_of_opening_posts() {
+        let state = test_support::state(&[("THREADS_PER_PAGE", "2"), ("BASE_PATH", "/board")]);
+        let mut pictured = test_support::new_thread("Pictured", "Line one\nLine two");
+        pictured.image_url = Some("/uploads/4f1c.jpg".to_string());
+        pictured.thumbnails = vec!["/thumbs/4f1c_125.jpg".to_string()];
+        pictured.image_size = Some(ImageSize { width: 300, height: 200 });
+        let pictured = insert_thread(&state.db, pictured, false).unwrap();
+        let untitled = insert_thread(&state.db, test_support::new_thread("", "No title"), false).unwrap();
+        let locked = insert_thread(&state.db, test_support::new_thread("Locked", "Closed"), false).unwrap();
+        moderation::update_thread(&state.db, locked.id, |thread| thread.locked = true).unwrap();
+        for _ in 0..2 {
+            insert_reply(&state.db, untitled.id, test_support::new_reply("Bump"), true, 0, 0, false).unwrap();
+        }
+        test_support::backdate(&state.db, pictured.id, 1_000);
+        test_support::backdate(&state.db, locked.id, 2_000);
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::get().uri("/board/catalog.json").to_request();
+        let pages = call_and_read_body_json::<_, _, Value>(&app, request).await;
+        let pages = pages.as_array().unwrap();
+        assert_eq!(pages.len(), 2);
+        let numbers: Vec<Vec<i64>> = pages
+            .iter()
+            .map(|page| page["threads"].as_array().unwrap().iter().map(|post| post["no"].as_i64().unwrap()).collect())
+            .collect();
+        assert_eq!((pages[0]["page"].clone(), pages[1]["page"].clone()), (json!(1), json!(2)));
+        // Bump order: the thread with replies, then the ones backdated, latest first
+        assert_eq!(numbers, [vec![untitled.id as i64, locked.id as i64], vec![pictured.id as i64]]);
+
+        let post = &pages[1]["threads"][0];
+        let expected = json!({
+            "no": pictured.id,
+            "resto": 0,
+            "time": pictured.created_at,
+            "name": "Anonymous",
+            "sub": "Pictured",
+            "com": "Line one<br>Line two",
+            "tim": "4f1c",
+            "ext": ".jpg",
+            "filename": "4f1c",
+            "w": 300,
+            "h": 200,
+            "image_url": "/board/uploads/4f1c.jpg",
+            "thumb_url": "/board/thumbs/4f1c_125.jpg",
+            "replies": 0,
+            "last_modified": 1_000,
+            "closed": 0,
+            "archived": 0,
+        });
+        assert_eq!(post, &expected);
+
+        let post = pages[0]["threads"][0].as_object().unwrap();
+        assert_eq!((post["replies"].clone(), post["closed"].clone()), (json!(2), json!(0)));
+        // No title and no file: those fields are left out rather than empty
+        for absent in ["sub", "tim", "ext", "filename", "w", "h", "image_url", "thumb_url", "images"] {
+            assert!(!post.contains_key(absent), "{} is present", absent);
+        }
+        assert!(post["last_modified"].as_i64().unwrap() >= untitled.created_at);
+        assert_eq!(pages[0]["threads"][1]["closed"], 1);
+    }
+
+    #[actix_web::test]
+    async fn thread_json_puts_the_opening_post_first_and_the_replies_in_order() {
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Thread", "Opening"), false).unwrap();
+        for message in ["First", ">>1 second"] {
+            insert_reply(&state.db, thread.id, test_support::new_reply(message), true, 0, 0, false).unwrap();
+        }
+        let mut pictured = test_support::new_reply("Third");
+        pictured.image_url = Some("/uploads/abc.png".to_string());
+        pictured.image_size = Some(ImageSize { width: 40, height: 30 });
+        insert_reply(&state.db, thread.id, pictured, true, 0, 0, false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::get().uri(&format!("/thread/{}.json", thread.id)).to_request();
+        let body = call_and_read_body_json::<_, _, Value>(&app, request).await;
+        let posts = body["posts"].as_array().unwrap();
+        let order: Vec<(i64, i64)> =
+            posts.iter().map(|post| (post["no"].as_i64().unwrap(), post["resto"].as_i64().unwrap())).collect();
+        assert_eq!(order, [(1, 0), (1, 1), (2, 1), (3, 1)]);
+
+        // Only the opening post has a subject and the thread fields
+        assert_eq!((posts[0]["sub"].clone(), posts[0]["com"].clone()), (json!("Thread"), json!("Opening")));
+        assert_eq!((posts[0]["replies"].clone(), posts[0]["images"].clone()), (json!(3), json!(1)));
+        for reply in &posts[1..] {
+            let reply = reply.as_object().unwrap();
+            assert_eq!(reply["name"], "Anonymous");
+            assert!(reply["time"].as_i64().unwrap() >= thread.created_at);
+            for absent in ["sub", "replies", "images", "last_modified", "closed", "archived"] {
+                assert!(!reply.contains_key(absent), "{} is present", absent);
+            }
+        }
+        assert_eq!(posts[1]["com"], "First");
+        assert!(posts[2]["com"].as_str().unwrap().contains("href=\"/thread/1#p1\" class=\"quotelink\""));
+        let file = |key: &str| posts[3][key].clone();
+        assert_eq!((file("tim"), file("ext"), file("filename")), (json!("abc"), json!(".png"), json!("abc")));
+        assert_eq!((file("w"), file("h"), file("image_url")), (json!(40), json!(30), json!("/uploads/abc.png")));
+
+        let missing = call_service(&app, TestRequest::get().uri("/thread/9.json").to_request()).await;
+        assert_eq!(missing.status(), 404);
+    }
+}
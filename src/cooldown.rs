@@ -0,0 +1,191 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The cooldowns of the HTML post forms, shared through app_data
+pub struct Cooldowns {
+    // Between two new threads (THREAD_COOLDOWN)
+    pub threads: Cooldown,
+    // Threads started in the last DUPLICATE_THREAD_WINDOW seconds, to catch double submits
+    pub recent_threads: RecentThreads,
+    // Between two login or registration attempts (ACCOUNT_COOLDOWN)
+    pub accounts: Cooldown,
+}
+
+// Per-IP waiting period between two posts of one kind. Kept in memory only, so
+// a restart forgets it, which is fine for spam throttling.
+pub struct Cooldown {
+    period: Duration,
+    last_post: Mutex<HashMap<String, Instant>>,
+}
+
+impl Cooldown {
+    // A period of zero turns the cooldown off
+    pub fn new(seconds: u64) -> Self {
+        Cooldown {
+            period: Duration::from_secs(seconds),
+            last_post: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Seconds `ip` still has to wait, rounded up, or None if it may post now
+    pub fn remaining(&self, ip: &str) -> Option<u64> {
+        if self.period.is_zero() || ip.is_empty() {
+            return None;
+        }
+        let last_post = self.last_post.lock().expect("cooldown map poisoned");
+        let elapsed = last_post.get(ip)?.elapsed();
+        let left = self.period.checked_sub(elapsed).filter(|left| !left.is_zero())?;
+        Some(left.as_millis().div_ceil(1000) as u64)
+    }
+
+    // Start the waiting period after a successful post
+    pub fn record(&self, ip: &str) {
+        if self.period.is_zero() || ip.is_empty() {
+            return;
+        }
+        let mut last_post = self.last_post.lock().expect("cooldown map poisoned");
+        // Forget addresses whose period is over, so the map stays small
+        last_post.retain(|_, posted| posted.elapsed() < self.period);
+        last_post.insert(ip.to_string(), Instant::now());
+    }
+
+    // X-RateLimit-* headers for `ip`: one post per period, whether it may post
+    // now, and the seconds until it may. Left out while the cooldown is off.
+    pub fn add_rate_limit_headers(&self, ip: &str, headers: &mut HeaderMap) {
+        if self.period.is_zero() || ip.is_empty() {
+            return;
+        }
+        let reset = self.remaining(ip);
+        let values = [
+            ("x-ratelimit-limit", 1),
+            ("x-ratelimit-remaining", u64::from(reset.is_none())),
+            ("x-ratelimit-reset", reset.unwrap_or(0)),
+        ];
+        for (name, value) in values {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+// Remembers who recently started which thread, keyed on a hash of the IP and
+// the post's content, so submitting the same form twice (a double click, or
+// resending after a slow response) leads back to the first thread instead of
+// making a second one. Kept in memory only, like the cooldowns.
+pub struct RecentThreads {
+    window: Duration,
+    threads: Mutex<HashMap<[u8; 32], (i32, Instant)>>,
+}
+
+impl RecentThreads {
+    // A window of zero turns the check off
+    pub fn new(seconds: u64) -> Self {
+        RecentThreads {
+            window: Duration::from_secs(seconds),
+            threads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The key of a thread from `ip`. Title and message are compared with their
+    // whitespace collapsed, and the image by its hash.
+    pub fn key(ip: &str, title: &str, message: &str, image_sha256: Option<&str>) -> [u8; 32] {
+        let normalize = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+        Sha256::new()
+            .chain_update(ip)
+            .chain_update([0])
+            .chain_update(normalize(title))
+            .chain_update([0])
+            .chain_update(normalize(message))
+            .chain_update([0])
+            .chain_update(image_sha256.unwrap_or_default())
+            .finalize()
+            .into()
+    }
+
+    // The thread created under `key` within the window, if any
+    pub fn find(&self, key: &[u8; 32]) -> Option<i32> {
+        if self.window.is_zero() {
+            return None;
+        }
+        let threads = self.threads.lock().expect("recent thread map poisoned");
+        threads
+            .get(key)
+            .filter(|(_, created)| created.elapsed() < self.window)
+            .map(|(thread_id, _)| *thread_id)
+    }
+
+    pub fn record(&self, key: [u8; 32], thread_id: i32) {
+        if self.window.is_zero() {
+            return;
+        }
+        let mut threads = self.threads.lock().expect("recent thread map poisoned");
+        threads.retain(|_, (_, created)| created.elapsed() < self.window);
+        threads.insert(key, (thread_id, Instant::now()));
+    }
+}
+
+// 429 telling the poster how long to wait
+pub fn too_many_requests(seconds: u64, what: &str) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .append_header(("Retry-After", seconds.to_string()))
+        .body(format!("Please wait {} seconds before {}", seconds, what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use actix_web::test::{call_service, init_service};
+
+    fn rate_limit(headers: &HeaderMap) -> Option<[String; 3]> {
+        let header = |name: &str| Some(headers.get(name)?.to_str().ok()?.to_string());
+        Some([header("x-ratelimit-limit")?, header("x-ratelimit-remaining")?, header("x-ratelimit-reset")?])
+    }
+
+    #[test]
+    fn the_headers_count_down_the_cooldown() {
+        let cooldown = Cooldown::new(30);
+        let mut headers = HeaderMap::new();
+        cooldown.add_rate_limit_headers("192.0.2.1", &mut headers);
+        assert_eq!(rate_limit(&headers), Some(["1", "1", "0"].map(String::from)));
+
+        cooldown.record("192.0.2.1");
+        cooldown.add_rate_limit_headers("192.0.2.1", &mut headers);
+        assert_eq!(rate_limit(&headers), Some(["1", "0", "30"].map(String::from)));
+
+        let mut off = HeaderMap::new();
+        Cooldown::new(0).add_rate_limit_headers("192.0.2.1", &mut off);
+        Cooldown::new(30).add_rate_limit_headers("", &mut off);
+        assert!(off.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn thread_posts_answer_with_rate_limit_headers() {
+        let state = test_support::state(&[("THREAD_COOLDOWN", "30")]);
+        let app = init_service(crate::app(&state)).await;
+        let post = |title: &'static str| form_post("/thread", &[("title", title.as_bytes()), ("message", b"Hi")]);
+
+        let refused = call_service(&app, post("").to_request()).await;
+        assert_eq!(refused.status(), 400);
+        assert_eq!(rate_limit(refused.headers()), Some(["1", "1", "0"].map(String::from)));
+
+        let posted = call_service(&app, post("First").to_request()).await;
+        assert_eq!(posted.status(), 303);
+        assert_eq!(rate_limit(posted.headers()), Some(["1", "0", "30"].map(String::from)));
+
+        let throttled = call_service(&app, post("Second").to_request()).await;
+        assert_eq!(throttled.status(), 429);
+        let [limit, remaining, reset] = rate_limit(throttled.headers()).unwrap();
+        assert_eq!((limit.as_str(), remaining.as_str()), ("1", "0"));
+        assert!((29..=30).contains(&reset.parse::<u64>().unwrap()));
+
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        let unthrottled = call_service(&app, post("Free").to_request()).await;
+        assert_eq!(unthrottled.status(), 303);
+        assert_eq!(rate_limit(unthrottled.headers()), None);
+    }
+}
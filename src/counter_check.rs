@@ -0,0 +1,135 @@
+// Startup check (COUNTER_CHECK) that the id counters are ahead of every record
+// stored. A counter left behind, say by a botched import or a restored backup
+// of only some keys, would hand out ids that overwrite existing posts, so each
+// one found behind is raised to the highest id in use. Counters are only ever
+// raised: one ahead of the records just leaves a gap in the numbering.
+
+use log::warn;
+use sled::Db;
+use std::collections::HashMap;
+
+use crate::post_numbers::POST_COUNTER_KEY;
+use crate::{decode_counter, reply_counter_key, THREAD_COUNTER_KEY};
+
+// Raise every counter that is behind its records; returns how many were repaired
+pub fn repair(db: &Db) -> sled::Result<usize> {
+    let mut highest_thread = 0;
+    for key in db.scan_prefix(b"thread_").keys() {
+        if let Some(thread_id) = key_ids(&key?, "thread_").first() {
+            highest_thread = highest_thread.max(*thread_id);
+        }
+    }
+
+    let mut highest_replies: HashMap<i32, i32> = HashMap::new();
+    for key in db.scan_prefix(b"reply_").keys() {
+        if let [parent_id, reply_id] = key_ids(&key?, "reply_")[..] {
+            let highest = highest_replies.entry(parent_id).or_insert(0);
+            *highest = (*highest).max(reply_id);
+        }
+    }
+
+    let mut repaired = usize::from(raise(db, THREAD_COUNTER_KEY, highest_thread)?);
+    for (&parent_id, &reply_id) in &highest_replies {
+        repaired += usize::from(raise(db, &reply_counter_key(parent_id), reply_id)?);
+    }
+
+    // Global post numbers cover threads and replies alike, and post_{N} entries
+    // outlive neither, but an import may bring them along
+    if db.contains_key(POST_COUNTER_KEY)? {
+        let mut highest_post = highest_replies.values().fold(highest_thread, |a, &b| a.max(b));
+        for key in db.scan_prefix(b"post_").keys() {
+            if let Some(number) = key_ids(&key?, "post_").first() {
+                highest_post = highest_post.max(*number);
+            }
+        }
+        repaired += usize::from(raise(db, POST_COUNTER_KEY, highest_post)?);
+    }
+
+    Ok(repaired)
+}
+
+// The numbers after `prefix` in a key like reply_{tid}_{rid}, or none when a
+// part of it isn't a number
+fn key_ids(key: &[u8], prefix: &str) -> Vec<i32> {
+    let key = String::from_utf8_lossy(key);
+    let parts = key.strip_prefix(prefix).map(|rest| rest.split('_').map(str::parse).collect());
+    parts.and_then(Result::ok).unwrap_or_default()
+}
+
+// Set the counter at `key` to `highest` if it is below it
+fn raise(db: &Db, key: &[u8], highest: i32) -> sled::Result<bool> {
+    let stored = db.get(key)?.map(|value| decode_counter(&value));
+    if highest == 0 || stored.is_some_and(|counter| counter >= highest) {
+        return Ok(false);
+    }
+    warn!(
+        "Counter {} was at {} behind id {} already in use; raised it",
+        String::from_utf8_lossy(key),
+        stored.unwrap_or(0),
+        highest
+    );
+    db.insert(key, &highest.to_be_bytes())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_numbers::{self, post_key};
+    use crate::test_support;
+    use crate::{get_replies, insert_reply, insert_thread};
+
+    fn counter(db: &Db, key: &[u8]) -> Option<i32> {
+        db.get(key).unwrap().map(|value| decode_counter(&value))
+    }
+
+    #[test]
+    fn counters_behind_their_records_are_raised() {
+        let db = test_support::temp_db();
+        for title in ["One", "Two", "Three"] {
+            insert_thread(&db, test_support::new_thread(title, "Hi"), false).unwrap();
+        }
+        for message in ["a", "b"] {
+            insert_reply(&db, 2, test_support::new_reply(message), true, 0, 0, false).unwrap();
+        }
+        assert_eq!(repair(&db).unwrap(), 0);
+
+        // As if the counters had been restored from an older backup
+        db.insert(THREAD_COUNTER_KEY, &1i32.to_be_bytes()).unwrap();
+        db.remove(reply_counter_key(2)).unwrap();
+        db.insert(b"thread_notes", b"not a thread").unwrap();
+        assert_eq!(repair(&db).unwrap(), 2);
+        assert_eq!(counter(&db, THREAD_COUNTER_KEY), Some(3));
+        assert_eq!(counter(&db, &reply_counter_key(2)), Some(2));
+        assert_eq!(repair(&db).unwrap(), 0);
+
+        // New posts get fresh ids instead of overwriting the old ones
+        assert_eq!(insert_thread(&db, test_support::new_thread("Four", "Hi"), false).unwrap().id, 4);
+        insert_reply(&db, 2, test_support::new_reply("c"), true, 0, 0, false).unwrap();
+        let messages: Vec<_> = get_replies(&db, 2).into_iter().map(|reply| reply.message).collect();
+        assert_eq!(messages, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn counters_ahead_are_left_alone() {
+        let db = test_support::temp_db();
+        insert_thread(&db, test_support::new_thread("One", "Hi"), false).unwrap();
+        db.insert(THREAD_COUNTER_KEY, &10i32.to_be_bytes()).unwrap();
+        assert_eq!(repair(&db).unwrap(), 0);
+        assert_eq!(counter(&db, THREAD_COUNTER_KEY), Some(10));
+    }
+
+    #[test]
+    fn the_post_number_counter_covers_threads_replies_and_imported_numbers() {
+        let db = test_support::temp_db();
+        post_numbers::seed_counter(&db).unwrap();
+        let thread = insert_thread(&db, test_support::new_thread("One", "Hi"), true).unwrap();
+        insert_reply(&db, thread.id, test_support::new_reply("a"), true, 0, 0, true).unwrap();
+        assert_eq!(counter(&db, POST_COUNTER_KEY), Some(2));
+
+        db.insert(post_key(7), &thread.id.to_be_bytes()).unwrap();
+        db.insert(POST_COUNTER_KEY, &0i32.to_be_bytes()).unwrap();
+        assert_eq!(repair(&db).unwrap(), 1);
+        assert_eq!(counter(&db, POST_COUNTER_KEY), Some(7));
+    }
+}
@@ -0,0 +1,139 @@
+// Video links that MEDIA_EMBEDS turns into click-to-load players. Only the
+// hosts below are recognised; every other URL stays a plain link.
+
+// A video on a known host
+pub struct Embed {
+    // Shown on the placeholder
+    pub site: &'static str,
+    pub id: String,
+}
+
+impl Embed {
+    // Address of the player, without autoplay. YouTube's no-cookie host sets
+    // no cookies until the video is played.
+    pub fn player_src(&self) -> String {
+        match self.site {
+            "YouTube" => format!("https://www.youtube-nocookie.com/embed/{}", self.id),
+            _ => format!("https://player.vimeo.com/video/{}", self.id),
+        }
+    }
+}
+
+// Hosts the players are served from, for frame-src in the default CSP
+pub const PLAYER_ORIGINS: &str = "https://www.youtube-nocookie.com https://player.vimeo.com";
+
+// The video `url` (not HTML-escaped) points at, if it is on a known host
+pub fn find(url: &str) -> Option<Embed> {
+    let rest = url.split_once("://")?.1;
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let host = host.to_ascii_lowercase();
+    let host = host.strip_prefix("www.").or_else(|| host.strip_prefix("m.")).unwrap_or(&host);
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let path = path.split('#').next().unwrap_or_default();
+
+    match host {
+        "youtube.com" => {
+            let id = match path.split_once('/') {
+                Some(("shorts" | "embed" | "live", id)) => id,
+                _ if path == "watch" => query.split('&').find_map(|pair| pair.strip_prefix("v="))?,
+                _ => return None,
+            };
+            youtube(id)
+        }
+        "youtu.be" => youtube(path),
+        "vimeo.com" => {
+            let id = path.trim_end_matches('/');
+            (!id.is_empty() && id.len() <= 12 && id.bytes().all(|b| b.is_ascii_digit())).then(|| Embed {
+                site: "Vimeo",
+                id: id.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+// YouTube ids are 11 characters from the URL-safe base64 alphabet
+fn youtube(id: &str) -> Option<Embed> {
+    let id = id.split('#').next()?;
+    let valid = id.len() == 11 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    valid.then(|| Embed {
+        site: "YouTube",
+        id: id.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::{format_message, QuoteLinks};
+    use crate::insert_thread;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    fn video(url: &str) -> Option<(&'static str, String)> {
+        find(url).map(|embed| (embed.site, embed.id))
+    }
+
+    #[test]
+    fn known_video_links_are_recognised() {
+        let id = Some(("YouTube", "dQw4w9WgXcQ".to_string()));
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://m.youtube.com/watch?feature=share&v=dQw4w9WgXcQ#t=10",
+            "http://YouTube.com/shorts/dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?t=42",
+        ] {
+            assert_eq!(video(url), id, "{}", url);
+        }
+        assert_eq!(video("https://vimeo.com/76979871"), Some(("Vimeo", "76979871".to_string())));
+        let player = find("https://youtu.be/dQw4w9WgXcQ").unwrap().player_src();
+        assert_eq!(player, "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ");
+    }
+
+    #[test]
+    fn other_links_are_not_embedded() {
+        for url in [
+            "https://example.com/watch?v=dQw4w9WgXcQ",
+            "https://youtube.com.evil.example/watch?v=dQw4w9WgXcQ",
+            "https://www.youtube.com/channel/UC38IQsAvIsxxjztdMZQtwHA",
+            "https://www.youtube.com/watch?v=short",
+            "https://youtu.be/dQw4w9WgXcQ\"><script>",
+            "https://vimeo.com/channels/staffpicks",
+        ] {
+            assert_eq!(video(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn embeds_render_as_links_until_clicked() {
+        let links = |embeds| QuoteLinks::resolve("", [], |_| None, |_, _| true, |_| false).with_embeds(embeds);
+        let message = "https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=5 and https://example.com/a";
+        let rendered = format_message(message, 1, &links(true));
+        assert_eq!(
+            rendered,
+            "<a href=\"https://www.youtube.com/watch?v=dQw4w9WgXcQ&amp;t=5\" class=\"media-embed\" \
+             data-embed-src=\"https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ\" \
+             rel=\"noopener noreferrer nofollow\" target=\"_blank\">&#9654; YouTube video dQw4w9WgXcQ</a> and \
+             <a href=\"https://example.com/a\" rel=\"noopener noreferrer nofollow\" \
+             target=\"_blank\">https://example.com/a</a>"
+        );
+        assert!(!rendered.contains("<iframe") && !rendered.contains("autoplay"));
+        assert!(!format_message(message, 1, &links(false)).contains("media-embed"));
+    }
+
+    #[actix_web::test]
+    async fn the_setting_turns_embeds_and_their_frame_source_on() {
+        for on in [false, true] {
+            let state = test_support::state(&[("MEDIA_EMBEDS", if on { "true" } else { "false" })]);
+            let message = "Watch https://youtu.be/dQw4w9WgXcQ";
+            insert_thread(&state.db, test_support::new_thread("Video", message), false).unwrap();
+            let app = init_service(crate::app(&state)).await;
+            let response = call_service(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+            let policy = response.headers().get("content-security-policy").unwrap().to_str().unwrap().to_string();
+            assert_eq!(policy.contains(&format!("frame-src {}", PLAYER_ORIGINS)), on);
+            let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert_eq!(page.contains("class=\"media-embed\""), on);
+            assert!(page.contains("href=\"https://youtu.be/dQw4w9WgXcQ\""));
+        }
+    }
+}
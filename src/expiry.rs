@@ -0,0 +1,239 @@
+use log::{error, info, warn};
+use sled::transaction::{ConflictableTransactionError, TransactionResult};
+use sled::Db;
+
+use crate::{bump_key, decode_counter, get_thread, reply_count_key, reply_counter_key, sticky_key, upload, Reply, Thread};
+use crate::{adjust_thread_count, moderation, post_numbers, tags};
+
+// What happens to a thread pushed past the last page by MAX_PAGES (PRUNE_MODE)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PruneMode {
+    // Gone for good, with its replies and images
+    Delete,
+    // Closed to replies and left off the board index, but still readable at its link
+    Archive,
+}
+
+impl PruneMode {
+    // Unknown modes delete, with a warning
+    pub fn parse(name: &str) -> Self {
+        match name.trim().to_lowercase().as_str() {
+            "delete" => PruneMode::Delete,
+            "archive" => PruneMode::Archive,
+            _ => {
+                warn!("Ignoring unknown PRUNE_MODE {:?}, deleting pruned threads", name);
+                PruneMode::Delete
+            }
+        }
+    }
+}
+
+// Delete every thread that hasn't been bumped for `ttl` seconds, with its
+// replies and their images. This is permanent removal for boards with a
+// retention period; a TTL of 0 keeps threads forever. Returns how many went.
+pub fn purge_expired_threads(db: &Db, ttl: i64) -> usize {
+    if ttl <= 0 {
+        return 0;
+    }
+
+    let cutoff = chrono::Utc::now().timestamp() - ttl;
+    let expired: Vec<i32> = db
+        .scan_prefix(b"thread_")
+        .values()
+        .flatten()
+        .filter_map(|value| serde_json::from_slice::<Thread>(&value).ok())
+        .filter(|thread| thread.last_updated < cutoff)
+        .map(|thread| thread.id)
+        .collect();
+
+    let mut purged = 0;
+    for thread_id in expired {
+        match delete_thread(db, thread_id, cutoff) {
+            Ok(Some(thread)) => {
+                info!("Deleted expired thread {} (last bumped {})", thread.id, thread.last_updated);
+                purged += 1;
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to delete expired thread {}: {:?}", thread_id, e),
+        }
+    }
+    purged
+}
+
+// Prune the threads after the first `keep` in bump order. Pinned threads don't
+// take up a place and are never pruned. Only a new thread can push one past the
+// end, since a bump just reorders the threads already there.
+//
+// Archived threads stay in the bump index and, never being bumped again, pile
+// up at its end. After a new thread, the walk stops at the first one already
+// archived, since everything past it was pruned before; `full` goes through to
+// the end, for startup, when MAX_PAGES or PRUNE_MODE may have changed.
+pub fn prune_past_limit(db: &Db, keep: usize, mode: PruneMode, full: bool) -> usize {
+    let thread_ids = db.scan_prefix(b"bump_").values().flatten().map(|value| decode_counter(&value));
+    let mut kept = 0;
+    let mut pruned = 0;
+    for thread_id in thread_ids {
+        if db.contains_key(sticky_key(thread_id)).unwrap_or(false) {
+            continue;
+        }
+        if kept < keep {
+            kept += 1;
+            continue;
+        }
+        let outcome = match mode {
+            PruneMode::Delete => delete_thread(db, thread_id, i64::MAX).map(|deleted| deleted.is_some()),
+            PruneMode::Archive => match get_thread(db, thread_id) {
+                Some(thread) if thread.archived && !full => break,
+                Some(thread) if thread.archived => Ok(false),
+                _ => moderation::update_thread(db, thread_id, |thread| thread.archived = true)
+                    .map(|previous| previous.is_some()),
+            },
+        };
+        match outcome {
+            Ok(true) => {
+                info!("Pruned thread {} past the last page ({:?})", thread_id, mode);
+                pruned += 1;
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to prune thread {}: {:?}", thread_id, e),
+        }
+    }
+    pruned
+}
+
+// Remove a thread unless it was bumped past `cutoff` in the meantime; a cutoff
+// of i64::MAX removes it regardless. The thread record goes first, in a
+// transaction, so replies posted while this runs either land before it (and
+// are swept up below) or find no thread.
+pub fn delete_thread(db: &Db, thread_id: i32, cutoff: i64) -> TransactionResult<Option<Thread>> {
+    let thread_key = format!("thread_{}", thread_id).into_bytes();
+    let thread = db.transaction(|tx| {
+        let thread: Thread = match tx.get(&thread_key)?.and_then(|value| serde_json::from_slice(&value).ok()) {
+            Some(thread) => thread,
+            None => return Ok(None),
+        };
+        if thread.last_updated >= cutoff {
+            return Ok(None);
+        }
+
+        tx.remove(thread_key.as_slice())?;
+        adjust_thread_count(tx, -1)?;
+        tx.remove(bump_key(thread.last_updated, thread.id))?;
+        tx.remove(reply_counter_key(thread.id))?;
+        tx.remove(reply_count_key(thread.id))?;
+        tags::unindex(tx, &thread)?;
+        Ok::<_, ConflictableTransactionError<()>>(Some(thread))
+    })?;
+
+    let thread = match thread {
+        Some(thread) => thread,
+        None => return Ok(None),
+    };
+
+    let mut images: Vec<String> = thread.image_url.iter().cloned().collect();
+    images.extend(thread.custom_thumbnail.as_ref().map(|custom| custom.image_url.clone()));
+    let mut numbers = vec![thread.id];
+    for (key, value) in db.scan_prefix(format!("reply_{}_", thread_id)).flatten() {
+        if let Ok(reply) = serde_json::from_slice::<Reply>(&value) {
+            images.extend(reply.image_url);
+            numbers.push(reply.id);
+        }
+        db.remove(key)?;
+    }
+    for number in numbers {
+        post_numbers::forget(db, number, thread_id)?;
+    }
+    for url in images {
+        upload::delete_image(db, &url);
+    }
+
+    Ok(Some(thread))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use crate::{get_replies, insert_reply, insert_thread};
+    use actix_web::test::{call_service, init_service};
+
+    #[test]
+    fn threads_past_the_ttl_are_deleted_with_their_replies() {
+        let db = test_support::temp_db();
+        let old = insert_thread(&db, test_support::new_thread("Old", "Stale"), false).unwrap();
+        insert_reply(&db, old.id, test_support::new_reply("Also stale"), true, 0, 0, false).unwrap();
+        let fresh = insert_thread(&db, test_support::new_thread("Fresh", "New"), false).unwrap();
+        let hour_ago = chrono::Utc::now().timestamp() - 3600;
+        test_support::backdate(&db, old.id, hour_ago);
+
+        assert_eq!(purge_expired_threads(&db, 0), 0);
+        assert!(get_thread(&db, old.id).is_some());
+
+        assert_eq!(purge_expired_threads(&db, 60), 1);
+        assert!(get_thread(&db, old.id).is_none());
+        assert!(get_replies(&db, old.id).is_empty());
+        assert!(!db.contains_key(bump_key(hour_ago, old.id)).unwrap());
+        assert!(get_thread(&db, fresh.id).is_some());
+        assert_eq!(purge_expired_threads(&db, 60), 0);
+    }
+
+    // Threads 1 to `count`, thread 1 bumped longest ago
+    fn board(db: &Db, count: i32) -> Vec<i32> {
+        (1..=count)
+            .map(|n| {
+                let thread = insert_thread(db, test_support::new_thread("Thread", "Text"), false).unwrap();
+                test_support::backdate(db, thread.id, 1000 + n as i64);
+                thread.id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn threads_past_the_limit_are_deleted_and_pinned_ones_stay() {
+        let db = test_support::temp_db();
+        let ids = board(&db, 5);
+        insert_reply(&db, ids[1], test_support::new_reply("Goes too"), false, 0, 0, false).unwrap();
+        moderation::set_sticky(&db, ids[0], Some(crate::Sticky::Forever)).unwrap();
+
+        assert_eq!(prune_past_limit(&db, 2, PruneMode::Delete, false), 2);
+        let left: Vec<i32> = ids.iter().copied().filter(|id| get_thread(&db, *id).is_some()).collect();
+        assert_eq!(left, [ids[0], ids[3], ids[4]]);
+        assert!(get_replies(&db, ids[1]).is_empty());
+        assert_eq!(prune_past_limit(&db, 2, PruneMode::Delete, false), 0);
+    }
+
+    #[test]
+    fn archived_threads_stay_readable_and_only_a_full_run_looks_past_them() {
+        let db = test_support::temp_db();
+        let ids = board(&db, 4);
+        assert_eq!(prune_past_limit(&db, 2, PruneMode::Archive, false), 2);
+        let archived: Vec<bool> = ids.iter().map(|id| get_thread(&db, *id).unwrap().archived).collect();
+        assert_eq!(archived, [true, true, false, false]);
+
+        // Unarchived behind an archived thread, thread 1 is only found by a full run
+        moderation::update_thread(&db, ids[0], |thread| thread.archived = false).unwrap();
+        assert_eq!(prune_past_limit(&db, 2, PruneMode::Archive, false), 0);
+        assert_eq!(prune_past_limit(&db, 2, PruneMode::Archive, true), 1);
+        assert!(get_thread(&db, ids[0]).unwrap().archived);
+    }
+
+    #[test]
+    fn unknown_prune_modes_delete() {
+        assert_eq!(PruneMode::parse(" Archive "), PruneMode::Archive);
+        assert_eq!(PruneMode::parse("delete"), PruneMode::Delete);
+        assert_eq!(PruneMode::parse("shred"), PruneMode::Delete);
+    }
+
+    #[actix_web::test]
+    async fn a_new_thread_pushes_the_last_one_off_the_board() {
+        let state = test_support::state(&[("MAX_PAGES", "1"), ("THREADS_PER_PAGE", "2"), ("THREAD_COOLDOWN", "0")]);
+        let ids = board(&state.db, 2);
+        let app = init_service(crate::app(&state)).await;
+
+        let post = form_post("/thread", &[("title", b"Newest"), ("message", b"Pushes one out")]);
+        assert!(call_service(&app, post.to_request()).await.status().is_redirection());
+        assert!(get_thread(&state.db, ids[0]).is_none());
+        assert!(get_thread(&state.db, ids[1]).is_some());
+        assert_eq!(crate::get_all_threads(&state.db).len(), 2);
+    }
+}
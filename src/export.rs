@@ -0,0 +1,116 @@
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures_util::stream::{self, Stream};
+use serde::Serialize;
+use sled::Db;
+use std::sync::Arc;
+
+use crate::{Reply, Thread};
+
+// A reply in the export, which needs the thread it belongs to next to it
+#[derive(Serialize)]
+struct ExportedReply {
+    thread_id: i32,
+    #[serde(flatten)]
+    reply: Reply,
+}
+
+// The whole board as {"threads": [...], "replies": [...]}, produced record by
+// record straight from sled iterators so memory use doesn't grow with the
+// database. Both lists are in key order, and records that don't parse are
+// skipped. Poster hashes are included: this is a backup, not a public dump.
+pub fn export_stream(db: Arc<Db>) -> impl Stream<Item = Result<Bytes, Error>> {
+    let threads = db
+        .scan_prefix(b"thread_")
+        .values()
+        .flatten()
+        .filter_map(|value| serde_json::from_slice::<Thread>(&value).ok())
+        .map(|thread| serde_json::to_vec(&thread));
+    let replies = db
+        .scan_prefix(b"reply_")
+        .flatten()
+        .filter_map(|(key, value)| {
+            let thread_id = std::str::from_utf8(&key).ok()?.strip_prefix("reply_")?.split('_').next()?.parse().ok()?;
+            let reply = serde_json::from_slice::<Reply>(&value).ok()?;
+            Some(ExportedReply { thread_id, reply })
+        })
+        .map(|reply| serde_json::to_vec(&reply));
+
+    let chunks = std::iter::once(Ok(b"{\"threads\":[".to_vec()))
+        .chain(json_array_items(threads))
+        .chain(std::iter::once(Ok(b"],\"replies\":[".to_vec())))
+        .chain(json_array_items(replies))
+        .chain(std::iter::once(Ok(b"]}\n".to_vec())));
+
+    stream::iter(chunks.map(|chunk| chunk.map(Bytes::from).map_err(Error::from)))
+}
+
+// Serialized records with the commas an array needs between them
+fn json_array_items(
+    records: impl Iterator<Item = serde_json::Result<Vec<u8>>>,
+) -> impl Iterator<Item = serde_json::Result<Vec<u8>>> {
+    records.enumerate().map(|(index, record)| {
+        let mut record = record?;
+        if index > 0 {
+            record.insert(0, b',');
+        }
+        Ok(record)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::{insert_reply, insert_thread};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use futures_util::StreamExt;
+
+    async fn exported(db: Arc<Db>) -> serde_json::Value {
+        let chunks: Vec<_> = export_stream(db).collect().await;
+        let bytes: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.unwrap().to_vec()).collect();
+        serde_json::from_slice(&bytes).expect("the export is not valid JSON")
+    }
+
+    #[actix_web::test]
+    async fn the_export_parses_back_into_the_records() {
+        let db = test_support::temp_db();
+        assert_eq!(exported(db.clone()).await, serde_json::json!({ "threads": [], "replies": [] }));
+
+        let mut first = test_support::new_thread("First", "One");
+        first.poster_hash = Some("abc".to_string());
+        let first = insert_thread(&db, first, false).unwrap();
+        let second = insert_thread(&db, test_support::new_thread("Second", "Two"), false).unwrap();
+        for (thread, message) in [(first.id, "First reply"), (second.id, "Other reply"), (first.id, "Second reply")] {
+            insert_reply(&db, thread, test_support::new_reply(message), true, 0, 0, false).unwrap();
+        }
+
+        let export = exported(db.clone()).await;
+        let threads: Vec<Thread> = serde_json::from_value(export["threads"].clone()).unwrap();
+        let titles: Vec<_> = threads.iter().map(|thread| (thread.id, thread.title.as_str())).collect();
+        assert_eq!(titles, [(first.id, "First"), (second.id, "Second")]);
+        assert_eq!(threads[0].poster_hash.as_deref(), Some("abc"));
+        let replies: Vec<_> = export["replies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|reply| (reply["thread_id"].as_i64().unwrap() as i32, reply["id"].as_i64().unwrap() as i32))
+            .collect();
+        assert_eq!(replies, [(first.id, 1), (first.id, 2), (second.id, 1)]);
+        assert_eq!(export["replies"][1]["message"], "Second reply");
+    }
+
+    #[actix_web::test]
+    async fn the_admin_export_is_a_download() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        insert_thread(&state.db, test_support::new_thread("Saved", "Hi"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let request = TestRequest::get().uri("/admin/export").insert_header(("Authorization", "Bearer hunter2"));
+        let response = call_service(&app, request.to_request()).await;
+        assert_eq!(response.status(), 200);
+        let disposition = response.headers().get("content-disposition").unwrap().to_str().unwrap();
+        assert!(disposition.starts_with("attachment; filename=\"export-"), "{}", disposition);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["threads"][0]["title"], "Saved");
+    }
+}
@@ -0,0 +1,108 @@
+// Custom askama filters, picked up by name from the templates
+use chrono::{DateTime, Utc};
+
+use crate::format::QuoteLinks;
+
+// Render a Unix timestamp relative to now, e.g. "5 minutes ago"
+pub fn reltime(timestamp: &i64) -> askama::Result<String> {
+    Ok(relative_time(*timestamp, Utc::now().timestamp()))
+}
+
+// Render a Unix timestamp as an absolute UTC time for hover titles
+pub fn abstime(timestamp: &i64) -> askama::Result<String> {
+    Ok(DateTime::from_timestamp(*timestamp, 0)
+        .map(|date| date.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_default())
+}
+
+// Render a Unix timestamp in RFC 3339 for <time datetime="...">
+pub fn isotime(timestamp: &i64) -> askama::Result<String> {
+    Ok(DateTime::from_timestamp(*timestamp, 0)
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_default())
+}
+
+// Render a post message as HTML (see format.rs); pipe through |safe afterwards
+pub fn markup(message: &str, thread_id: &i32, links: &QuoteLinks) -> askama::Result<String> {
+    Ok(crate::format::format_message(message, *thread_id, links))
+}
+
+const UNITS: &[(i64, &str)] = &[
+    (365 * 24 * 60 * 60, "year"),
+    (30 * 24 * 60 * 60, "month"),
+    (24 * 60 * 60, "day"),
+    (60 * 60, "hour"),
+    (60, "minute"),
+];
+
+pub fn relative_time(timestamp: i64, now: i64) -> String {
+    if timestamp <= 0 {
+        return "a while ago".to_string();
+    }
+
+    let delta = now - timestamp;
+    // Anything under a minute either way (including small clock skew) is "just now"
+    if delta.abs() < 60 {
+        return "just now".to_string();
+    }
+
+    let seconds = delta.abs();
+    let (size, unit) = UNITS
+        .iter()
+        .find(|(size, _)| seconds >= *size)
+        .copied()
+        .unwrap_or((60, "minute"));
+    let count = seconds / size;
+    let plural = if count == 1 { "" } else { "s" };
+
+    if delta > 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn deltas_round_down_to_the_largest_unit() {
+        let cases = [
+            (0, "just now"),
+            (59, "just now"),
+            (60, "1 minute ago"),
+            (119, "1 minute ago"),
+            (120, "2 minutes ago"),
+            (3599, "59 minutes ago"),
+            (3600, "1 hour ago"),
+            (24 * 3600 - 1, "23 hours ago"),
+            (24 * 3600, "1 day ago"),
+            (30 * 24 * 3600 - 1, "29 days ago"),
+            (30 * 24 * 3600, "1 month ago"),
+            (365 * 24 * 3600 - 1, "12 months ago"),
+            (365 * 24 * 3600, "1 year ago"),
+            (10 * 365 * 24 * 3600, "10 years ago"),
+        ];
+        for (delta, expected) in cases {
+            assert_eq!(relative_time(NOW - delta, NOW), expected, "{} seconds", delta);
+        }
+    }
+
+    #[test]
+    fn future_and_missing_times_are_handled() {
+        assert_eq!(relative_time(NOW + 30, NOW), "just now");
+        assert_eq!(relative_time(NOW + 60, NOW), "in 1 minute");
+        assert_eq!(relative_time(NOW + 2 * 24 * 3600, NOW), "in 2 days");
+        assert_eq!(relative_time(0, NOW), "a while ago");
+        assert_eq!(relative_time(-5, NOW), "a while ago");
+    }
+
+    #[test]
+    fn absolute_times_are_in_utc() {
+        assert_eq!(abstime(&NOW).unwrap(), "2023-11-14 22:13:20 UTC");
+        assert_eq!(isotime(&NOW).unwrap(), "2023-11-14T22:13:20+00:00");
+    }
+}
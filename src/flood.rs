@@ -0,0 +1,138 @@
+use log::{error, warn};
+use sled::Db;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{modlog, moderation};
+
+// Locks a thread that gets more than FLOOD_LOCK_REPLIES replies within
+// FLOOD_LOCK_WINDOW seconds, whoever sends them, so a flood stops at the lock
+// until a moderator looks at it. Reply times are kept in memory only, like the
+// cooldowns, so a restart starts counting afresh.
+pub struct ReplyFlood {
+    limit: usize,
+    window: Duration,
+    replies: Mutex<HashMap<i32, VecDeque<Instant>>>,
+}
+
+impl ReplyFlood {
+    // A limit of zero turns the check off
+    pub fn new(limit: usize, seconds: u64) -> Self {
+        ReplyFlood {
+            limit,
+            window: Duration::from_secs(seconds),
+            replies: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Count a reply just stored in `thread_id`, and lock the thread when it
+    // tips the thread over the limit
+    pub fn record(&self, db: &Db, thread_id: i32) {
+        if self.limit == 0 || !self.flooded(thread_id) {
+            return;
+        }
+        match moderation::update_thread(db, thread_id, |thread| thread.locked = true) {
+            Ok(Some(previous)) if !previous.locked => {
+                let detail = format!(
+                    "Thread {} ({} replies within {}s)",
+                    thread_id,
+                    self.limit + 1,
+                    self.window.as_secs()
+                );
+                warn!("Locked a flooded thread: {}", detail);
+                modlog::record(db, "auto-lock", detail);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to lock flooded thread {}: {:?}", thread_id, e),
+        }
+    }
+
+    // Whether the reply just made brings `thread_id` over the limit
+    fn flooded(&self, thread_id: i32) -> bool {
+        let now = Instant::now();
+        let mut replies = self.replies.lock().expect("reply flood map poisoned");
+        // Forget threads that have gone quiet, so the map stays small
+        replies.retain(|_, times| times.back().is_some_and(|last| now.duration_since(*last) < self.window));
+
+        let times = replies.entry(thread_id).or_default();
+        times.push_back(now);
+        while times.front().is_some_and(|first| now.duration_since(*first) >= self.window) {
+            times.pop_front();
+        }
+        if times.len() <= self.limit {
+            return false;
+        }
+        replies.remove(&thread_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use crate::{get_thread, insert_thread};
+    use actix_web::test::{call_service, init_service};
+
+    #[test]
+    fn a_thread_locks_once_it_passes_the_limit_within_the_window() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Busy", "Flood me"), false).unwrap();
+        let quiet = insert_thread(&db, test_support::new_thread("Quiet", "Leave me"), false).unwrap();
+        let flood = ReplyFlood::new(3, 60);
+        for _ in 0..3 {
+            flood.record(&db, thread.id);
+            flood.record(&db, quiet.id);
+        }
+        assert!(!get_thread(&db, thread.id).unwrap().locked);
+
+        flood.record(&db, thread.id);
+        assert!(get_thread(&db, thread.id).unwrap().locked);
+        assert!(!get_thread(&db, quiet.id).unwrap().locked);
+        let entries = modlog::recent(&db, 10);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].action, "auto-lock");
+        assert_eq!(entries[0].detail, format!("Thread {} (4 replies within 60s)", thread.id));
+    }
+
+    #[test]
+    fn replies_spread_past_the_window_or_with_no_limit_never_lock() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Steady", "Chat"), false).unwrap();
+        let flood = ReplyFlood::new(1, 1);
+        flood.record(&db, thread.id);
+        std::thread::sleep(Duration::from_millis(1100));
+        flood.record(&db, thread.id);
+        assert!(!get_thread(&db, thread.id).unwrap().locked);
+
+        let off = ReplyFlood::new(0, 60);
+        for _ in 0..20 {
+            off.record(&db, thread.id);
+        }
+        assert!(!get_thread(&db, thread.id).unwrap().locked);
+        assert!(modlog::recent(&db, 10).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn replies_from_many_addresses_lock_the_thread_and_later_ones_are_refused() {
+        let state = test_support::state(&[("FLOOD_LOCK_REPLIES", "2")]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Raid", "Target"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let reply = |address: u8| {
+            let parent = thread.id.to_string();
+            form_post("/reply", &[("parent_id", parent.as_bytes()), ("message", b"Flood")])
+                .peer_addr(format!("192.0.2.{}:4000", address).parse().unwrap())
+                .to_request()
+        };
+
+        for address in 1..=2 {
+            assert!(call_service(&app, reply(address)).await.status().is_redirection());
+        }
+        assert!(!get_thread(&state.db, thread.id).unwrap().locked);
+        assert!(call_service(&app, reply(3)).await.status().is_redirection());
+        assert!(get_thread(&state.db, thread.id).unwrap().locked);
+        assert_eq!(call_service(&app, reply(4)).await.status(), 403);
+        assert_eq!(crate::get_replies(&state.db, thread.id).len(), 3);
+    }
+}
@@ -0,0 +1,459 @@
+// Post message formatting: HTML escaping, greentext, quote links and a small
+// set of inline markup (**bold**, *italic*, `code`, [spoiler]...[/spoiler])
+// and links for bare http(s):// URLs. With MEDIA_EMBEDS, links to videos on
+// known hosts become click-to-load players instead (see embed.rs).
+//
+// Quote links are resolved before rendering (see QuoteLinks), so a >>N that
+// points nowhere can be shown as dead instead of as a broken link.
+//
+// The message is escaped first and everything else works on the escaped text,
+// so user input can never produce tags of its own. Anything that does not pair
+// up cleanly is left as literal text.
+
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::embed;
+
+const SPOILER_OPEN: &str = "[spoiler]";
+const SPOILER_CLOSE: &str = "[/spoiler]";
+const QUOTE_PREFIX: &str = "&gt;&gt;";
+// Links longer than this show shortened, the href always has the full URL
+const MAX_LINK_TEXT: usize = 60;
+
+// What a >>N in some thread refers to
+#[derive(Clone, Copy)]
+enum QuoteTarget {
+    // Reply N of that same thread
+    Reply,
+    // Thread N, anywhere on the board
+    Thread,
+    // Reply N of another thread, known from its board-wide post number
+    ReplyIn(i32),
+    Dead,
+}
+
+// The targets of the quote links on one page. With board-wide post numbers a
+// >>N is found through the post number index. Otherwise, and for posts from
+// before the index, it is looked up as a reply in the thread it was posted in,
+// then as a thread of its own; anything else is dead.
+pub struct QuoteLinks {
+    base_path: String,
+    targets: HashMap<(i32, u32), QuoteTarget>,
+    // MEDIA_EMBEDS, for the same pages
+    embeds: bool,
+}
+
+impl QuoteLinks {
+    // Resolve every >>N in `posts`, given as (thread the post is in, message)
+    pub fn resolve<'a>(
+        base_path: &str,
+        posts: impl IntoIterator<Item = (i32, &'a str)>,
+        post_thread: impl Fn(i32) -> Option<i32>,
+        reply_exists: impl Fn(i32, i32) -> bool,
+        thread_exists: impl Fn(i32) -> bool,
+    ) -> Self {
+        let mut targets = HashMap::new();
+        for (thread_id, message) in posts {
+            for number in quoted_numbers(message) {
+                let id = i32::try_from(number).ok();
+                targets.entry((thread_id, number)).or_insert_with(|| match (id, id.and_then(&post_thread)) {
+                    (Some(id), Some(post_thread)) if post_thread == id => QuoteTarget::Thread,
+                    (_, Some(post_thread)) if post_thread == thread_id => QuoteTarget::Reply,
+                    (_, Some(post_thread)) => QuoteTarget::ReplyIn(post_thread),
+                    (Some(id), None) if reply_exists(thread_id, id) => QuoteTarget::Reply,
+                    (Some(id), None) if thread_exists(id) => QuoteTarget::Thread,
+                    _ => QuoteTarget::Dead,
+                });
+            }
+        }
+        QuoteLinks {
+            base_path: base_path.to_string(),
+            targets,
+            embeds: false,
+        }
+    }
+
+    // Render video links on these pages as embeds
+    pub fn with_embeds(mut self, embeds: bool) -> Self {
+        self.embeds = embeds;
+        self
+    }
+
+    // Where >>`number` posted in `thread_id` should link to, None if it is dead.
+    // Numbers that weren't resolved are taken as replies in the same thread.
+    fn href(&self, thread_id: i32, number: u32) -> Option<String> {
+        match self.targets.get(&(thread_id, number)).copied().unwrap_or(QuoteTarget::Reply) {
+            QuoteTarget::Reply => Some(format!("{}/thread/{}#p{}", self.base_path, thread_id, number)),
+            QuoteTarget::Thread => Some(format!("{}/thread/{}", self.base_path, number)),
+            QuoteTarget::ReplyIn(other) => Some(format!("{}/thread/{}#p{}", self.base_path, other, number)),
+            QuoteTarget::Dead => None,
+        }
+    }
+}
+
+// Render a message posted in thread `thread_id` as HTML
+pub fn format_message(message: &str, thread_id: i32, links: &QuoteLinks) -> String {
+    escape_html(message)
+        .split('\n')
+        .map(|line| format_line(line, thread_id, links))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Every post number the message quotes with >>N
+fn quoted_numbers(message: &str) -> Vec<u32> {
+    let escaped = escape_html(message);
+    escaped
+        .match_indices(QUOTE_PREFIX)
+        .filter_map(|(start, _)| quote_number(&escaped[start..]).map(|(number, _)| number))
+        .collect()
+}
+
+// The text a reply starts with when it quotes another post. The thread page and
+// /api/thread/{id}/quote/{rid} both use this so they always agree.
+pub fn quote_text(post_id: i32) -> String {
+    format!(">>{}\n", post_id)
+}
+
+// The first `limit` user-perceived characters of `text`, or None if it is no
+// longer than that. Cut between grapheme clusters, so an emoji or a letter with
+// combining accents is never split.
+pub fn preview(text: &str, limit: usize) -> Option<&str> {
+    let (end, _) = text.grapheme_indices(true).nth(limit)?;
+    Some(text[..end].trim_end())
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn format_line(line: &str, thread_id: i32, links: &QuoteLinks) -> String {
+    let body = format_inline(line, thread_id, links);
+    // A line starting with ">" is greentext, unless it opens with a quote link
+    if line.starts_with("&gt;") && quote_number(line).is_none() {
+        format!("<span class=\"greentext\">{}</span>", body)
+    } else {
+        body
+    }
+}
+
+fn format_inline(text: &str, thread_id: i32, links: &QuoteLinks) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some((number, len)) = quote_number(rest) {
+            out.push_str(&match links.href(thread_id, number) {
+                Some(href) => format!("<a href=\"{}\" class=\"quotelink\">&gt;&gt;{}</a>", href, number),
+                None => format!("<span class=\"quotelink dead\">&gt;&gt;{}</span>", number),
+            });
+            rest = &rest[len..];
+            continue;
+        }
+
+        if let Some(len) = url_length(rest) {
+            out.push_str(&link(&rest[..len], links.embeds));
+            rest = &rest[len..];
+            continue;
+        }
+
+        if c == '`' {
+            // Code spans are taken verbatim, nothing inside them is formatted
+            if let Some(inner) = delimited(rest, "`", "`") {
+                out.push_str(&format!("<code>{}</code>", inner));
+                rest = &rest[inner.len() + 2..];
+                continue;
+            }
+        } else if rest.starts_with(SPOILER_OPEN) {
+            if let Some(inner) = delimited(rest, SPOILER_OPEN, SPOILER_CLOSE) {
+                out.push_str(&format!(
+                    "<span class=\"spoiler\" tabindex=\"0\">{}</span>",
+                    format_inline(inner, thread_id, links)
+                ));
+                rest = &rest[SPOILER_OPEN.len() + inner.len() + SPOILER_CLOSE.len()..];
+                continue;
+            }
+        } else if rest.starts_with("***") {
+            // Runs of three or more are literal, which also keeps censored words
+            // ("***") from turning into markup
+            let run = rest.bytes().take_while(|&b| b == b'*').count();
+            out.push_str(&rest[..run]);
+            rest = &rest[run..];
+            continue;
+        } else if rest.starts_with("**") {
+            if let Some(inner) = emphasis(rest, "**") {
+                out.push_str(&format!("<strong>{}</strong>", format_inline(inner, thread_id, links)));
+                rest = &rest[inner.len() + 4..];
+                continue;
+            }
+            // An unmatched "**" stays literal as a whole, so it can't open an italic
+            out.push_str("**");
+            rest = &rest[2..];
+            continue;
+        } else if c == '*' {
+            if let Some(inner) = emphasis(rest, "*") {
+                out.push_str(&format!("<em>{}</em>", format_inline(inner, thread_id, links)));
+                rest = &rest[inner.len() + 2..];
+                continue;
+            }
+        }
+
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+// If `text` starts with an escaped ">>" followed by digits, return the post
+// number and how many bytes the link covers
+fn quote_number(text: &str) -> Option<(u32, usize)> {
+    let digits = text.strip_prefix(QUOTE_PREFIX)?;
+    let len = digits.bytes().take_while(u8::is_ascii_digit).count();
+    let number = digits[..len].parse().ok()?;
+    Some((number, QUOTE_PREFIX.len() + len))
+}
+
+// Length of the URL at the start of `text`, if there is one. Works on escaped
+// text: "&amp;" is part of a URL, any other entity (quotes, < and >) ends it,
+// and trailing punctuation such as a full stop is left out.
+fn url_length(text: &str) -> Option<usize> {
+    let scheme = ["https://", "http://"]
+        .iter()
+        .find(|scheme| {
+            text.len() >= scheme.len() && text.as_bytes()[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes())
+        })?
+        .len();
+
+    let mut end = scheme;
+    let bytes = text.as_bytes();
+    while end < bytes.len() {
+        let b = bytes[end];
+        if text[end..].starts_with("&amp;") {
+            end += 5;
+        } else if b != b'&' && (b.is_ascii_alphanumeric() || b"-._~:/?#[]@!$()*+,;=%".contains(&b)) {
+            end += 1;
+        } else {
+            break;
+        }
+    }
+
+    // Sentence punctuation after a URL belongs to the sentence, and so does a
+    // closing bracket without a matching opening one inside the URL
+    loop {
+        let url = &text[..end];
+        let last = url.as_bytes()[end - 1];
+        if b".,;:!?*".contains(&last) || (last == b')' && url.matches('(').count() < url.matches(')').count())
+        {
+            end -= 1;
+        } else {
+            break;
+        }
+    }
+
+    (end > scheme).then_some(end)
+}
+
+fn link(url: &str, embeds: bool) -> String {
+    let full = url.replace("&amp;", "&");
+    if let Some(video) = embeds.then(|| embed::find(&full)).flatten() {
+        // A link until clicked; script.js swaps in the player then
+        return format!(
+            "<a href=\"{}\" class=\"media-embed\" data-embed-src=\"{}\" rel=\"noopener noreferrer nofollow\" \
+             target=\"_blank\">&#9654; {} video {}</a>",
+            url,
+            video.player_src(),
+            video.site,
+            video.id
+        );
+    }
+    let text = if full.chars().count() > MAX_LINK_TEXT {
+        format!("{}…", full.chars().take(MAX_LINK_TEXT - 1).collect::<String>())
+    } else {
+        full
+    };
+    format!(
+        "<a href=\"{}\" rel=\"noopener noreferrer nofollow\" target=\"_blank\">{}</a>",
+        url,
+        escape_html(&text)
+    )
+}
+
+// The non-empty text between `open` at the start of `text` and the next `close`
+fn delimited<'a>(text: &'a str, open: &str, close: &str) -> Option<&'a str> {
+    let body = text.strip_prefix(open)?;
+    let end = body.find(close)?;
+    (end > 0).then(|| &body[..end])
+}
+
+// Like `delimited`, but the content may not start or end with whitespace, so
+// "2 * 3 * 4" stays as typed. For single "*", a "**" pair inside is skipped
+// over rather than taken as the closing delimiter.
+fn emphasis<'a>(text: &'a str, delim: &str) -> Option<&'a str> {
+    let body = text.strip_prefix(delim)?;
+    let mut search = 0;
+    let end = loop {
+        let found = search + body[search..].find(delim)?;
+        if delim == "*" && body[found..].starts_with("**") {
+            search = found + 2;
+            continue;
+        }
+        break found;
+    };
+    let inner = &body[..end];
+    let trimmed = !inner.is_empty()
+        && !inner.starts_with(char::is_whitespace)
+        && !inner.ends_with(char::is_whitespace);
+    trimmed.then_some(inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every >>N is taken as a reply in the same thread
+    fn render(message: &str) -> String {
+        let links = QuoteLinks::resolve("", [], |_| None, |_, _| true, |_| false);
+        format_message(message, 1, &links)
+    }
+
+    #[test]
+    fn each_inline_construct_renders() {
+        assert_eq!(render("**bold**"), "<strong>bold</strong>");
+        assert_eq!(render("*italic*"), "<em>italic</em>");
+        assert_eq!(render("`let x = 1;`"), "<code>let x = 1;</code>");
+        assert_eq!(render("[spoiler]ending[/spoiler]"), "<span class=\"spoiler\" tabindex=\"0\">ending</span>");
+        assert_eq!(render("**a *b* c**"), "<strong>a <em>b</em> c</strong>");
+        assert_eq!(
+            render("[spoiler]**big** news[/spoiler]"),
+            "<span class=\"spoiler\" tabindex=\"0\"><strong>big</strong> news</span>"
+        );
+        assert_eq!(render("> **green**"), "<span class=\"greentext\">&gt; <strong>green</strong></span>");
+    }
+
+    #[test]
+    fn code_spans_are_taken_verbatim() {
+        assert_eq!(render("`**not bold** <b>`"), "<code>**not bold** &lt;b&gt;</code>");
+    }
+
+    #[test]
+    fn malformed_markup_stays_literal() {
+        for text in ["**open", "*open", "`open", "[spoiler]open", "2 * 3 * 4", "``", "[spoiler][/spoiler]", "* a*"] {
+            assert_eq!(render(text), text);
+        }
+        // Three or more stars are literal, censored words included
+        assert_eq!(render("a *** word"), "a *** word");
+        assert_eq!(render("****x****"), "****x****");
+        assert_eq!(render("**unclosed *italic*"), "**unclosed <em>italic</em>");
+        assert_eq!(render("[/spoiler] first"), "[/spoiler] first");
+    }
+
+    #[test]
+    fn markup_never_lets_html_through() {
+        assert_eq!(render("**<script>**"), "<strong>&lt;script&gt;</strong>");
+        assert_eq!(
+            render("[spoiler]\"x\" & 'y'[/spoiler]"),
+            "<span class=\"spoiler\" tabindex=\"0\">&quot;x&quot; &amp; &#x27;y&#x27;</span>"
+        );
+    }
+
+    fn anchor(url: &str) -> String {
+        format!("<a href=\"{}\" rel=\"noopener noreferrer nofollow\" target=\"_blank\">{}</a>", url, url)
+    }
+
+    #[test]
+    fn urls_leave_surrounding_punctuation_out() {
+        let linked = anchor("https://example.org/a?b=1&amp;c=2");
+        assert_eq!(render("See https://example.org/a?b=1&c=2."), format!("See {}.", linked));
+        assert_eq!(render("(https://example.org)"), format!("({})", anchor("https://example.org")));
+        assert_eq!(
+            render("https://en.wikipedia.org/wiki/Rust_(language), ok?"),
+            format!("{}, ok?", anchor("https://en.wikipedia.org/wiki/Rust_(language)"))
+        );
+        assert_eq!(render("\"http://example.org\"!"), format!("&quot;{}&quot;!", anchor("http://example.org")));
+        assert_eq!(render("<https://example.org>"), format!("&lt;{}&gt;", anchor("https://example.org")));
+    }
+
+    #[test]
+    fn urls_link_inside_greentext_and_never_nest() {
+        assert_eq!(
+            render(">read https://example.org/x"),
+            format!("<span class=\"greentext\">&gt;read {}</span>", anchor("https://example.org/x"))
+        );
+        // Not inside code, and a quote link right after a URL stays a quote link
+        assert_eq!(render("`https://example.org`"), "<code>https://example.org</code>");
+        let rendered = render("https://example.org>>2");
+        assert_eq!(rendered.matches("<a ").count(), 2);
+        assert!(rendered.ends_with("<a href=\"/thread/1#p2\" class=\"quotelink\">&gt;&gt;2</a>"));
+        assert_eq!(render("http:// alone"), "http:// alone");
+    }
+
+    #[test]
+    fn long_urls_show_shortened() {
+        let url = format!("https://example.org/{}", "a".repeat(100));
+        let rendered = render(&url);
+        assert!(rendered.starts_with(&format!("<a href=\"{}\"", url)));
+        let text = rendered.split('>').nth(1).unwrap().trim_end_matches("</a");
+        assert_eq!(text.chars().count(), MAX_LINK_TEXT);
+        assert!(text.ends_with('…'));
+    }
+
+    #[test]
+    fn quote_links_resolve_locally_across_threads_or_dead() {
+        // Post numbers 4 and 5 are in thread 1, 2 is a thread and 7 a reply in it
+        let index = HashMap::from([(1, 1), (4, 1), (5, 1), (2, 2), (7, 2)]);
+        let message = ">>5 >>7 >>2 >>9 >>3";
+        let links = QuoteLinks::resolve(
+            "/b",
+            [(1, message)],
+            |number| index.get(&number).copied(),
+            // Reply 3 of thread 1 is from before the index
+            |thread, reply| (thread, reply) == (1, 3),
+            |thread| thread == 1 || thread == 2,
+        );
+        let quote = |href: &str, number: u32| {
+            format!("<a href=\"{}\" class=\"quotelink\">&gt;&gt;{}</a>", href, number)
+        };
+        let expected = [
+            quote("/b/thread/1#p5", 5),
+            quote("/b/thread/2#p7", 7),
+            quote("/b/thread/2", 2),
+            "<span class=\"quotelink dead\">&gt;&gt;9</span>".to_string(),
+            quote("/b/thread/1#p3", 3),
+        ];
+        assert_eq!(format_message(message, 1, &links), expected.join(" "));
+
+        // Seen from thread 2, >>7 is local and >>5 is the cross-thread one
+        let post_thread = |number| index.get(&number).copied();
+        let links = QuoteLinks::resolve("", [(2, ">>7 >>5")], post_thread, |_, _| false, |_| false);
+        let expected = [quote("/thread/2#p7", 7), quote("/thread/1#p5", 5)];
+        assert_eq!(format_message(">>7 >>5", 2, &links), expected.join(" "));
+    }
+
+    #[test]
+    fn previews_cut_between_graphemes() {
+        assert_eq!(preview("short", 10), None);
+        assert_eq!(preview("exactly10!", 10), None);
+        assert_eq!(preview("eleven chars", 10), Some("eleven cha"));
+        // The space the cut lands after is dropped
+        assert_eq!(preview("abcd efgh", 5), Some("abcd"));
+        // An accent made of two code points and a family emoji made of five each count once
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let text = format!("cafe\u{301}{}{}!", family, family);
+        assert_eq!(preview(&text, 5), Some(&text[..text.len() - family.len() - 1]));
+        assert_eq!(preview(&text, 4), Some("cafe\u{301}"));
+    }
+}
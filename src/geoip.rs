@@ -0,0 +1,441 @@
+// Poster country flags (GEOIP_DB). New posts look up the poster's IP in a
+// MaxMind DB country database, such as GeoLite2-Country.mmdb, and keep the
+// two-letter country code; pages show it as /static/flags/{code}.png. Without
+// a database nothing is looked up and no flags are shown.
+//
+// The file is read whole at startup and searched in memory. Only the parts of
+// the MaxMind DB format a country lookup needs are decoded: the search tree
+// and the data section's values, turned into serde_json values.
+
+use actix_web::{web, HttpRequest};
+use log::{error, info};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use crate::client_ip;
+use crate::settings::Settings;
+
+// Metadata starts after the last occurrence of this
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// More remembered addresses than this and the cache starts over
+const MAX_ENTRIES: usize = 10_000;
+// Deepest a value may nest, counting pointers, before the file is taken to be
+// broken; a pointer back into its own map would otherwise never end
+const MAX_DEPTH: usize = 32;
+
+pub struct GeoIp {
+    db: Option<MaxMindDb>,
+    seen: Mutex<HashMap<IpAddr, Option<String>>>,
+}
+
+impl GeoIp {
+    // A database that is missing or unreadable is logged and leaves lookups off
+    pub fn open(path: Option<&str>) -> Self {
+        let db = path.and_then(|path| match std::fs::read(path).map_err(|e| e.to_string()).and_then(MaxMindDb::new) {
+            Ok(db) => {
+                info!("Loaded GeoIP database {}", path);
+                Some(db)
+            }
+            Err(e) => {
+                error!("Failed to load GeoIP database {}: {}", path, e);
+                None
+            }
+        });
+        GeoIp {
+            db,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Country code of `ip`, like "DE", remembered per address
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let db = self.db.as_ref()?;
+        let mut seen = self.seen.lock().expect("GeoIP cache poisoned");
+        if let Some(country) = seen.get(&ip) {
+            return country.clone();
+        }
+        if seen.len() >= MAX_ENTRIES {
+            seen.clear();
+        }
+        let country = db.lookup(ip).as_ref().and_then(country_code);
+        seen.insert(ip, country.clone());
+        country
+    }
+}
+
+// Country of the poster behind `req`, for storing on a new post
+pub fn poster_country(req: &HttpRequest, settings: &Settings) -> Option<String> {
+    let geoip = req.app_data::<web::Data<GeoIp>>()?;
+    geoip.country(client_ip(req, settings).parse().ok()?)
+}
+
+// The country of a lookup result, or the country its network is registered
+// in when the record has no other. Anything but two letters is ignored, as it
+// ends up in a file name.
+fn country_code(record: &Value) -> Option<String> {
+    ["country", "registered_country"]
+        .iter()
+        .find_map(|field| record.get(field)?.get("iso_code")?.as_str())
+        .filter(|code| code.len() == 2 && code.bytes().all(|byte| byte.is_ascii_alphabetic()))
+        .map(str::to_uppercase)
+}
+
+struct MaxMindDb {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    // Where the data section starts, after the search tree and its 16 byte separator
+    data_start: usize,
+}
+
+impl MaxMindDb {
+    fn new(bytes: Vec<u8>) -> Result<Self, String> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("not a MaxMind DB file")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder { data: &bytes[metadata_start..] }.decode(0)?;
+        let field = |name: &str| metadata.get(name).and_then(Value::as_u64).ok_or(format!("metadata lacks {}", name));
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("unsupported record size {}", record_size));
+        }
+        let data_start = node_count * record_size / 4 + 16;
+        if data_start > metadata_start {
+            return Err("search tree runs past the end of the file".to_string());
+        }
+        Ok(MaxMindDb {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    // The data record for the network `ip` is in, if the database has one
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (address, bits) = match ip {
+            // IPv4 addresses live at ::a.b.c.d in an IPv6 tree
+            IpAddr::V4(ip) if self.ip_version == 6 => (u128::from(u32::from(ip)), 128),
+            IpAddr::V4(ip) => (u128::from(u32::from(ip)), 32),
+            IpAddr::V6(ip) if self.ip_version == 6 => (u128::from(ip), 128),
+            IpAddr::V6(ip) => (u128::from(u32::from(ip.to_ipv4_mapped()?)), 32),
+        };
+        let mut node = 0;
+        for bit in (0..bits).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (address >> bit) & 1 == 1)?;
+        }
+        // node_count itself means no data for the address, and a record
+        // pointing into the 16 byte separator is broken
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = node.checked_sub(self.node_count + 16)?;
+        let data = self.bytes.get(self.data_start..)?;
+        Decoder { data }.decode(offset).ok().map(|(value, _)| value)
+    }
+
+    // The left or right record of search tree node `node`
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let size = self.record_size / 4;
+        let bytes = self.bytes.get(node * size..node * size + size)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize);
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[0..3]),
+            (24, true) => be(&bytes[3..6]),
+            // The middle byte holds the top four bits of both records
+            (28, false) => (bytes[3] as usize & 0xf0) << 20 | be(&bytes[0..3]),
+            (28, true) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..7]),
+            (_, false) => be(&bytes[0..4]),
+            (_, true) => be(&bytes[4..8]),
+        })
+    }
+}
+
+// Reads values out of a data section (or the metadata, which has the same
+// format); offsets are from its start
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    // The value at `offset`, and the offset just past it
+    fn decode(&self, offset: usize) -> Result<(Value, usize), String> {
+        self.decode_nested(offset, 0)
+    }
+
+    // `depth` is how many maps, arrays and pointers the value is inside of
+    fn decode_nested(&self, offset: usize, depth: usize) -> Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("values nest too deeply".to_string());
+        }
+        let control = *self.byte(offset)?;
+        let mut type_id = control >> 5;
+        let mut next = offset + 1;
+        if type_id == 1 {
+            let (target, next) = self.pointer(control, next)?;
+            let (value, _) = self.decode_nested(target, depth + 1)?;
+            return Ok((value, next));
+        }
+        if type_id == 0 {
+            type_id = 7 + *self.byte(next)?;
+            next += 1;
+        }
+        let (size, mut next) = self.size(control, next)?;
+        let value = match type_id {
+            2 => Value::String(String::from_utf8_lossy(self.slice(next, size)?).into_owned()),
+            3 => {
+                let bytes: [u8; 8] = self.slice(next, 8)?.try_into().map_err(|_| "bad double")?;
+                Value::from(f64::from_be_bytes(bytes))
+            }
+            // Bytes and 128 bit integers aren't needed for a country, so they stay opaque
+            4 | 10 => Value::Null,
+            5 | 6 | 9 => Value::from(self.unsigned(next, size)?),
+            8 => Value::from(self.unsigned(next, size)? as u32 as i32),
+            7 => {
+                let mut map = Map::new();
+                for _ in 0..size {
+                    let (key, after_key) = self.decode_nested(next, depth + 1)?;
+                    let (value, after_value) = self.decode_nested(after_key, depth + 1)?;
+                    map.insert(key.as_str().ok_or("map key is not a string")?.to_string(), value);
+                    next = after_value;
+                }
+                return Ok((Value::Object(map), next));
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(1024));
+                for _ in 0..size {
+                    let (item, after) = self.decode_nested(next, depth + 1)?;
+                    items.push(item);
+                    next = after;
+                }
+                return Ok((Value::Array(items), next));
+            }
+            14 => return Ok((Value::Bool(size != 0), next)),
+            15 => {
+                let bytes: [u8; 4] = self.slice(next, 4)?.try_into().map_err(|_| "bad float")?;
+                Value::from(f32::from_be_bytes(bytes))
+            }
+            other => return Err(format!("unknown data type {}", other)),
+        };
+        Ok((value, next + size))
+    }
+
+    // A pointer's target offset, and the offset just past the pointer
+    fn pointer(&self, control: u8, next: usize) -> Result<(usize, usize), String> {
+        let length = ((control >> 3) & 0x3) as usize + 1;
+        let low = (control & 0x7) as usize;
+        let value = self.unsigned(next, length)? as usize;
+        let target = match length {
+            1 => low << 8 | value,
+            2 => (low << 16 | value) + 2048,
+            3 => (low << 24 | value) + 526_336,
+            _ => value,
+        };
+        Ok((target, next + length))
+    }
+
+    // The size in the control byte, read further from the bytes after it when
+    // it doesn't fit
+    fn size(&self, control: u8, next: usize) -> Result<(usize, usize), String> {
+        let size = (control & 0x1f) as usize;
+        let extra = size.saturating_sub(28);
+        let value = self.unsigned(next, extra)? as usize;
+        let size = match extra {
+            0 => size,
+            1 => 29 + value,
+            2 => 285 + value,
+            _ => 65_821 + value,
+        };
+        Ok((size, next + extra))
+    }
+
+    fn unsigned(&self, offset: usize, length: usize) -> Result<u64, String> {
+        Ok(self.slice(offset, length.min(8))?.iter().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn byte(&self, offset: usize) -> Result<&u8, String> {
+        self.data.get(offset).ok_or_else(|| "data ends early".to_string())
+    }
+
+    fn slice(&self, offset: usize, length: usize) -> Result<&[u8], String> {
+        self.data.get(offset..offset + length).ok_or_else(|| "data ends early".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    // Data section values, encoded by hand
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = vec![2 << 5 | text.len() as u8];
+        bytes.extend_from_slice(text.as_bytes());
+        bytes
+    }
+
+    fn uint16(value: u16) -> Vec<u8> {
+        let mut bytes = vec![5 << 5 | 2];
+        bytes.extend_from_slice(&value.to_be_bytes());
+        bytes
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend_from_slice(value);
+        }
+        bytes
+    }
+
+    // A pointer to `target`, below 2048
+    fn pointer(target: usize) -> Vec<u8> {
+        vec![1 << 5 | (target >> 8) as u8, target as u8]
+    }
+
+    fn country(field: &str, code: &str) -> Vec<u8> {
+        map(&[(field, map(&[("iso_code", string(code))]))])
+    }
+
+    // An IPv4 database with 24 bit records, giving each /24 in `networks` the
+    // data at an offset into `data`
+    fn database(networks: &[([u8; 3], usize)], data: &[u8]) -> Vec<u8> {
+        let mut nodes: Vec<[Option<usize>; 2]> = vec![[None, None]];
+        let mut leaves: Vec<(usize, bool, usize)> = Vec::new();
+        for (network, offset) in networks {
+            let prefix = u32::from_be_bytes([network[0], network[1], network[2], 0]);
+            let mut node = 0;
+            for bit in (9..32).rev() {
+                let side = prefix >> bit & 1;
+                node = match nodes[node][side as usize] {
+                    Some(child) => child,
+                    None => {
+                        nodes.push([None, None]);
+                        nodes[node][side as usize] = Some(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+            leaves.push((node, prefix >> 8 & 1 == 1, *offset));
+        }
+        let node_count = nodes.len();
+        let mut records: Vec<[usize; 2]> =
+            nodes.iter().map(|node| node.map(|child| child.unwrap_or(node_count))).collect();
+        for (node, right, offset) in leaves {
+            records[node][right as usize] = node_count + 16 + offset;
+        }
+
+        let mut bytes = Vec::new();
+        for record in records.iter().flatten() {
+            bytes.extend_from_slice(&(*record as u32).to_be_bytes()[1..]);
+        }
+        bytes.extend_from_slice(&[0; 16]);
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(METADATA_MARKER);
+        let metadata = [
+            ("node_count", uint16(node_count as u16)),
+            ("record_size", uint16(24)),
+            ("ip_version", uint16(4)),
+        ];
+        bytes.extend(map(&metadata));
+        bytes
+    }
+
+    fn geoip(bytes: Vec<u8>) -> GeoIp {
+        GeoIp {
+            db: Some(MaxMindDb::new(bytes).unwrap()),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // 192.0.2.0/24 is in the Netherlands, 198.51.100.0/24 only registered in
+    // Germany, through a pointer to a shared record
+    fn sample() -> Vec<u8> {
+        let netherlands = country("country", "nl");
+        let germany = map(&[("iso_code", string("DE"))]);
+        let registered = map(&[("registered_country", pointer(netherlands.len()))]);
+        let data = [netherlands.clone(), germany.clone(), registered].concat();
+        database(&[([192, 0, 2], 0), ([198, 51, 100], netherlands.len() + germany.len())], &data)
+    }
+
+    #[test]
+    fn lookups_follow_the_search_tree_to_a_country() {
+        let geoip = geoip(sample());
+        let country = |ip: &str| geoip.country(ip.parse().unwrap());
+        assert_eq!(country("192.0.2.77").as_deref(), Some("NL"));
+        assert_eq!(country("198.51.100.1").as_deref(), Some("DE"));
+        assert_eq!(country("::ffff:192.0.2.1").as_deref(), Some("NL"));
+        assert_eq!(country("203.0.113.1"), None);
+        assert_eq!(country("2001:db8::1"), None);
+        assert_eq!(geoip.seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn broken_databases_fail_without_panicking() {
+        assert!(MaxMindDb::new(b"just some bytes".to_vec()).is_err());
+
+        // A map whose value points back at the map itself
+        let looped = [vec![7 << 5 | 1], string("country"), pointer(0)].concat();
+        let db = MaxMindDb::new(database(&[([192, 0, 2], 0)], &looped)).unwrap();
+        assert!(Decoder { data: &looped }.decode(0).is_err());
+        assert_eq!(db.lookup("192.0.2.1".parse().unwrap()), None);
+
+        // A record pointing into the separator instead of the data section; the
+        // last node is the one the network ends in
+        let mut bytes = database(&[([192, 0, 2], 0)], &country("country", "nl"));
+        let node_count = MaxMindDb::new(bytes.clone()).unwrap().node_count;
+        let last = (node_count - 1) * 6;
+        bytes[last..last + 3].copy_from_slice(&(node_count as u32 + 3).to_be_bytes()[1..]);
+        bytes[last + 3..last + 6].copy_from_slice(&(node_count as u32 + 3).to_be_bytes()[1..]);
+        let db = MaxMindDb::new(bytes).unwrap();
+        assert_eq!(db.lookup("192.0.2.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn only_two_letter_codes_are_kept() {
+        for (code, kept) in [("fr", Some("FR")), ("FRA", None), ("F1", None), ("", None)] {
+            let record = serde_json::json!({ "country": { "iso_code": code } });
+            assert_eq!(country_code(&record).as_deref(), kept, "{:?}", code);
+        }
+        assert_eq!(country_code(&serde_json::json!({ "continent": { "code": "EU" } })), None);
+    }
+
+    #[actix_web::test]
+    async fn posts_show_the_flag_of_the_lookup() {
+        // The file doesn't exist; the lookup is swapped for the sample below
+        let mut state = test_support::state(&[("GEOIP_DB", "/nonexistent.mmdb"), ("THREAD_COOLDOWN", "0")]);
+        state.geoip = web::Data::new(geoip(sample()));
+        let app = init_service(crate::app(&state)).await;
+
+        let thread = test_support::form_post("/thread", &[("title", b"Hallo"), ("message", b"From 192.0.2.1")]);
+        assert!(call_service(&app, thread.to_request()).await.status().is_redirection());
+        let reply = test_support::form_post("/reply", &[("parent_id", b"1"), ("message", b"Elsewhere")])
+            .peer_addr("203.0.113.9:4000".parse().unwrap());
+        assert!(call_service(&app, reply.to_request()).await.status().is_redirection());
+        assert_eq!(crate::get_thread(&state.db, 1).unwrap().country.as_deref(), Some("NL"));
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert_eq!(page.matches("/static/flags/nl.png").count(), 1);
+        assert!(!page.contains("/static/flags/None"));
+
+        // Without GEOIP_DB no flags are shown, even for posts that have one
+        let plain = test_support::state_with_db(state.db.clone(), &[]);
+        let app = init_service(crate::app(&plain)).await;
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        assert!(!String::from_utf8(page.to_vec()).unwrap().contains("/static/flags/"));
+    }
+}
@@ -0,0 +1,185 @@
+use serde::Serialize;
+use sled::Db;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::{get_all_threads, get_replies, Reply, Thread, THUMB_DIR, UPLOAD_DIR};
+
+// How long a file's existence is trusted before it is looked up again
+const CHECK_TTL: Duration = Duration::from_secs(5 * 60);
+// More remembered files than this and the stale ones are forgotten
+const MAX_ENTRIES: usize = 10_000;
+
+// With CHECK_IMAGE_FILES, pages show "[image unavailable]" instead of a broken
+// image when a post's file is gone from disk. Whether a file exists is
+// remembered for CHECK_TTL, so a busy page doesn't stat its images on every view.
+pub struct ImageFiles {
+    enabled: bool,
+    seen: Mutex<HashMap<String, (bool, Instant)>>,
+}
+
+impl ImageFiles {
+    pub fn new(enabled: bool) -> Self {
+        ImageFiles {
+            enabled,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Set image_missing on the threads whose image or shown thumbnail is gone
+    pub fn check_threads(&self, threads: &mut [Thread]) {
+        if self.enabled {
+            for thread in threads.iter_mut().filter(|thread| thread.image_url.is_some()) {
+                thread.image_missing =
+                    self.is_missing(thread.image_url.as_deref()) || self.is_missing(Some(thread.thumb_src()));
+            }
+        }
+    }
+
+    pub fn check_replies(&self, replies: &mut [Reply]) {
+        if self.enabled {
+            for reply in replies.iter_mut().filter(|reply| reply.image_url.is_some()) {
+                reply.image_missing =
+                    self.is_missing(reply.image_url.as_deref()) || self.is_missing(Some(reply.thumb_src()));
+            }
+        }
+    }
+
+    fn is_missing(&self, url: Option<&str>) -> bool {
+        let path = match url.and_then(file_path) {
+            Some(path) => path,
+            None => return false,
+        };
+        let mut seen = self.seen.lock().expect("image file map poisoned");
+        if let Some((exists, checked)) = seen.get(&path) {
+            if checked.elapsed() < CHECK_TTL {
+                return !exists;
+            }
+        }
+        if seen.len() >= MAX_ENTRIES {
+            seen.retain(|_, (_, checked)| checked.elapsed() < CHECK_TTL);
+        }
+        let exists = Path::new(&path).exists();
+        seen.insert(path, (exists, Instant::now()));
+        !exists
+    }
+}
+
+// A post with files missing from disk, for the admin report
+#[derive(Serialize)]
+pub struct MissingFiles {
+    pub thread: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply: Option<i32>,
+    pub image_url: String,
+    // The image, thumbnails and custom thumbnail files that are gone
+    pub missing: Vec<String>,
+}
+
+// Every post whose image files aren't all on disk, looked up afresh
+pub fn find_missing(db: &Db) -> Vec<MissingFiles> {
+    let mut report = Vec::new();
+    for thread in get_all_threads(db) {
+        if let Some(image_url) = &thread.image_url {
+            let mut files: Vec<&String> = std::iter::once(image_url).chain(&thread.thumbnails).collect();
+            if let Some(custom) = &thread.custom_thumbnail {
+                files.push(&custom.image_url);
+                files.extend(&custom.thumbnails);
+            }
+            report.extend(missing_files(thread.id, None, image_url, files));
+        }
+        for reply in get_replies(db, thread.id) {
+            if let Some(image_url) = &reply.image_url {
+                let files = std::iter::once(image_url).chain(&reply.thumbnails).collect();
+                report.extend(missing_files(thread.id, Some(reply.id), image_url, files));
+            }
+        }
+    }
+    report
+}
+
+fn missing_files(thread: i32, reply: Option<i32>, image_url: &str, files: Vec<&String>) -> Option<MissingFiles> {
+    let missing: Vec<String> = files
+        .into_iter()
+        .filter(|url| file_path(url).is_some_and(|path| !Path::new(&path).exists()))
+        .cloned()
+        .collect();
+    (!missing.is_empty()).then(|| MissingFiles {
+        thread,
+        reply,
+        image_url: image_url.to_string(),
+        missing,
+    })
+}
+
+// Where the file behind an /uploads/ or /thumbs/ URL lives
+fn file_path(url: &str) -> Option<String> {
+    let (dir, name) = match url.strip_prefix("/uploads/") {
+        Some(name) => (UPLOAD_DIR, name),
+        None => (THUMB_DIR, url.strip_prefix("/thumbs/")?),
+    };
+    (!name.is_empty() && !name.contains('/')).then(|| format!("{}{}", dir, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use crate::get_thread;
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest};
+    use serde_json::Value;
+
+    #[actix_web::test]
+    async fn a_post_whose_file_was_removed_shows_a_placeholder_and_is_reported() {
+        let _files = test_support::files().await;
+        for check in [true, false] {
+            let enabled = if check { "true" } else { "false" };
+            let state = test_support::state(&[("CHECK_IMAGE_FILES", enabled), ("ADMIN_TOKEN", "hunter2")]);
+            let app = init_service(crate::app(&state)).await;
+            let jpeg = test_support::jpeg(64, 48);
+            let thread = form_post("/thread", &[("title", b"Gone"), ("message", b"Soon"), ("image", &jpeg)]);
+            assert!(call_service(&app, thread.to_request()).await.status().is_redirection());
+            let reply = form_post("/reply", &[("parent_id", b"1"), ("message", b"Stays"), ("image", &jpeg)])
+                .peer_addr("192.0.2.2:4000".parse().unwrap());
+            assert!(call_service(&app, reply.to_request()).await.status().is_redirection());
+            let image_url = get_thread(&state.db, 1).unwrap().image_url.unwrap();
+            std::fs::remove_file(file_path(&image_url).unwrap()).unwrap();
+
+            for uri in ["/", "/thread/1"] {
+                let page = call_and_read_body(&app, TestRequest::get().uri(uri).to_request()).await;
+                let page = String::from_utf8(page.to_vec()).unwrap();
+                let placeholders = page.matches("[image unavailable]").count();
+                assert_eq!(placeholders, check as usize, "{} with CHECK_IMAGE_FILES={}", uri, check);
+            }
+
+            let report = TestRequest::get()
+                .uri("/admin/missing-images")
+                .insert_header(("Authorization", "Bearer hunter2"))
+                .to_request();
+            let report: Value = call_and_read_body_json(&app, report).await;
+            let posts = report["posts"].as_array().unwrap();
+            assert_eq!(posts.len(), 1, "{}", report);
+            assert_eq!(posts[0]["thread"], 1);
+            assert!(posts[0].get("reply").is_none());
+            assert_eq!(posts[0]["missing"], serde_json::json!([image_url]));
+        }
+    }
+
+    #[actix_web::test]
+    async fn a_files_existence_is_remembered_for_a_while() {
+        let _files = test_support::files().await;
+        let files = ImageFiles::new(true);
+        let mut threads = vec![test_support::new_thread("Cached", "Picture").to_thread(1, 0)];
+        threads[0].image_url = Some("/uploads/arrives-late.jpg".to_string());
+        files.check_threads(&mut threads);
+        assert!(threads[0].image_missing);
+
+        std::fs::write(format!("{}arrives-late.jpg", UPLOAD_DIR), b"late").unwrap();
+        files.check_threads(&mut threads);
+        assert!(threads[0].image_missing);
+        assert!(!ImageFiles::new(true).is_missing(threads[0].image_url.as_deref()));
+        std::fs::remove_file(format!("{}arrives-late.jpg", UPLOAD_DIR)).unwrap();
+    }
+}
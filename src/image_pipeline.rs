@@ -0,0 +1,553 @@
+// Everything done to the contents of an upload, from the bytes the poster sent
+// to the file that is stored and its thumbnails. Nothing here touches the disk
+// or the database: upload.rs receives the bytes, runs them through
+// process_image, and names, stores and accounts for the result.
+//
+// The stages, in order:
+// 1. detect      the format from the contents, which must be allowed and match
+//                what the file's name claims
+// 2. pixel check width times height from the header, before anything is
+//                decoded, so a small file can't claim a huge canvas
+// 3. normalize   JPEGs with EXIF are turned upright and re-encoded, which
+//                strips the metadata; PNGs lose their metadata chunks, and with
+//                PNG_OPTIMIZE are recompressed; other formats are kept as sent
+// 4. decode      the stored image completely, once, for the stages below; a
+//                truncated file still has a valid header
+// 5. dhash       for REPOST_CHECK, when it is on
+// 6. thumbnails  in THUMB_FORMAT, one per THUMB_SIZES box the image exceeds
+
+use image::{DynamicImage, ImageFormat};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+use crate::repost::{self, RepostMode};
+use crate::settings::Settings;
+use crate::upload::{ImageType, ThumbFormat, THUMB_SIZES};
+
+// Quality used whenever we have to re-encode a JPEG
+const JPEG_QUALITY: u8 = 90;
+
+// PNG chunks that only carry metadata (text, EXIF, timestamps) and are
+// stripped, rather than re-encoding the image the way JPEGs are
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"tIME"];
+
+// The settings process_image goes by, owned so it can run on a blocking thread
+#[derive(Clone)]
+pub struct PipelineConfig {
+    // ALLOWED_IMAGE_TYPES
+    pub allowed_types: Vec<ImageType>,
+    // MAX_IMAGE_PIXELS
+    pub max_pixels: u64,
+    // PNG_OPTIMIZE
+    pub optimize_png: bool,
+    // THUMB_FORMAT
+    pub thumb_format: ThumbFormat,
+    // Whether to compute the dHash, which only REPOST_CHECK needs
+    pub dhash: bool,
+}
+
+impl PipelineConfig {
+    pub fn new(settings: &Settings) -> Self {
+        PipelineConfig {
+            allowed_types: settings.allowed_image_types.clone(),
+            max_pixels: settings.max_image_pixels,
+            optimize_png: settings.png_optimize,
+            thumb_format: settings.thumb_format,
+            dhash: settings.repost_check != RepostMode::Off,
+        }
+    }
+}
+
+// An upload that went through every stage
+pub struct ProcessedImage {
+    pub image_type: ImageType,
+    // The file to store: what was sent, or its normalized rewrite
+    pub bytes: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    // Of `bytes`, so it names the stored file and not the upload
+    pub sha256: String,
+    // Encoded thumbnails with the box each was made for, smallest first
+    pub thumbnails: Vec<(u32, Vec<u8>)>,
+    pub dhash: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    // Not an allowed format, or not the format the file's name claims
+    UnsupportedType,
+    // Width times height is over MAX_IMAGE_PIXELS
+    TooManyPixels { limit: u64 },
+    // The header or the pixels don't decode, or the normalized image couldn't
+    // be encoded again
+    InvalidImage(image::ImageError),
+}
+
+impl From<image::ImageError> for PipelineError {
+    fn from(e: image::ImageError) -> Self {
+        PipelineError::InvalidImage(e)
+    }
+}
+
+// Run an upload through every stage. `claimed` is the format the file's name
+// gives, if it came with one; otherwise the contents alone decide.
+pub fn process_image(
+    bytes: Vec<u8>,
+    claimed: Option<ImageType>,
+    config: &PipelineConfig,
+) -> Result<ProcessedImage, PipelineError> {
+    let image_type = detect(&bytes, claimed, &config.allowed_types)?;
+    check_pixels(&bytes, image_type, config.max_pixels)?;
+    let (bytes, image) = normalize(bytes, image_type, config.optimize_png)?;
+    let dhash = config.dhash.then(|| repost::dhash(&image));
+    let thumbnails = match thumbnails(&image, config.thumb_format) {
+        Ok(thumbnails) => thumbnails,
+        Err(e) => {
+            // The post can still show the full image, so this isn't fatal
+            log::warn!("Failed to create thumbnails: {}", e);
+            Vec::new()
+        }
+    };
+    Ok(ProcessedImage {
+        image_type,
+        sha256: format!("{:x}", Sha256::digest(&bytes)),
+        bytes,
+        width: image.width(),
+        height: image.height(),
+        thumbnails,
+        dhash,
+    })
+}
+
+// Stage 1: the format of the contents, if it is allowed and the one claimed
+fn detect(bytes: &[u8], claimed: Option<ImageType>, allowed: &[ImageType]) -> Result<ImageType, PipelineError> {
+    ImageType::sniff(bytes)
+        .filter(|image_type| claimed.is_none_or(|claimed| claimed == *image_type))
+        .filter(|image_type| allowed.contains(image_type))
+        .ok_or(PipelineError::UnsupportedType)
+}
+
+// Stage 2: refuse an image whose header claims more than `limit` pixels
+fn check_pixels(bytes: &[u8], image_type: ImageType, limit: u64) -> Result<(), PipelineError> {
+    let reader = image::io::Reader::with_format(Cursor::new(bytes), image_format(image_type));
+    let (width, height) = reader.into_dimensions()?;
+    if width as u64 * height as u64 > limit {
+        return Err(PipelineError::TooManyPixels { limit });
+    }
+    Ok(())
+}
+
+// Stages 3 and 4: the bytes to store, and the image they decode to
+fn normalize(
+    bytes: Vec<u8>,
+    image_type: ImageType,
+    optimize_png: bool,
+) -> Result<(Vec<u8>, DynamicImage), PipelineError> {
+    let rewritten = match image_type {
+        ImageType::Jpeg => normalize_jpeg(&bytes)?,
+        ImageType::Png => normalize_png(&bytes, optimize_png)?,
+        _ => None,
+    };
+    match rewritten {
+        Some(rewritten) => Ok(rewritten),
+        None => {
+            let image = image::load_from_memory_with_format(&bytes, image_format(image_type))?;
+            Ok((bytes, image))
+        }
+    }
+}
+
+// If a JPEG carries EXIF data, bake its orientation into the pixels and
+// re-encode it, which also strips the metadata (camera details, GPS and so on).
+// None when it had no EXIF and is stored as sent.
+fn normalize_jpeg(bytes: &[u8]) -> Result<Option<(Vec<u8>, DynamicImage)>, image::ImageError> {
+    let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+        Ok(exif) => exif,
+        Err(_) => return Ok(None),
+    };
+    let orientation = exif
+        .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+
+    let image = image::load_from_memory_with_format(bytes, ImageFormat::Jpeg)?;
+    let image = apply_orientation(image, orientation);
+
+    let mut encoded = Vec::new();
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).encode_image(&image)?;
+    info!("Re-encoded a JPEG to strip EXIF (orientation {})", orientation);
+    Ok(Some((encoded, image)))
+}
+
+// Strip metadata chunks from a PNG. This is lossless: the pixel data,
+// transparency included, is left alone. With PNG_OPTIMIZE the image data is
+// also recompressed (still losslessly) and every PNG goes through it.
+// None when the file is stored as sent.
+fn normalize_png(bytes: &[u8], optimize: bool) -> Result<Option<(Vec<u8>, DynamicImage)>, image::ImageError> {
+    if !optimize && !png_has_metadata(bytes) {
+        return Ok(None);
+    }
+
+    let mut options = if optimize {
+        oxipng::Options::from_preset(2)
+    } else {
+        // Only drop chunks; leave the image data exactly as it is
+        oxipng::Options {
+            idat_recoding: false,
+            bit_depth_reduction: false,
+            color_type_reduction: false,
+            palette_reduction: false,
+            grayscale_reduction: false,
+            interlace: None,
+            ..oxipng::Options::from_preset(0)
+        }
+    };
+    options.strip = oxipng::StripChunks::Safe;
+    options.timeout = Some(std::time::Duration::from_secs(10));
+
+    let optimized = match oxipng::optimize_from_memory(bytes, &options) {
+        Ok(optimized) if optimized.len() < bytes.len() => optimized,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            log::warn!("Could not optimize a PNG: {}", e);
+            return Ok(None);
+        }
+    };
+    let image = image::load_from_memory_with_format(&optimized, ImageFormat::Png)?;
+    info!("Rewrote a PNG losslessly ({} -> {} bytes)", bytes.len(), optimized.len());
+    Ok(Some((optimized, image)))
+}
+
+// Whether any chunk of a PNG is one of PNG_METADATA_CHUNKS
+fn png_has_metadata(bytes: &[u8]) -> bool {
+    // Chunks follow the 8-byte signature: length, type, data, CRC
+    let mut offset = 8;
+    while let Some(header) = bytes.get(offset..offset + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if PNG_METADATA_CHUNKS.iter().any(|name| header[4..8] == name[..]) {
+            return true;
+        }
+        offset = offset.saturating_add(12).saturating_add(length);
+    }
+    false
+}
+
+// Turn the pixels so the image displays upright without its EXIF orientation tag
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        // Transpose: mirror across the top-left to bottom-right diagonal
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        // Transverse: mirror across the top-right to bottom-left diagonal
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+// Stage 6: the scaled-down variants of `image`, one per THUMB_SIZES box. Sizes
+// the image already fits into are skipped rather than upscaled, so a small
+// image may have one thumbnail or none and posts fall back to the original.
+pub fn thumbnails(image: &DynamicImage, format: ThumbFormat) -> Result<Vec<(u32, Vec<u8>)>, image::ImageError> {
+    let mut thumbnails = Vec::new();
+    for size in THUMB_SIZES {
+        if image.width() <= size && image.height() <= size {
+            break;
+        }
+        let thumbnail = image.resize(size, size, image::imageops::FilterType::Triangle);
+        thumbnails.push((size, encode_thumbnail(thumbnail, format)?));
+    }
+    Ok(thumbnails)
+}
+
+// PNG and WebP keep transparency, JPEG puts it on white
+fn encode_thumbnail(image: DynamicImage, format: ThumbFormat) -> Result<Vec<u8>, image::ImageError> {
+    let mut encoded = Vec::new();
+    match format {
+        ThumbFormat::Jpeg => {
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY)
+                .encode_image(&flatten(image))?;
+        }
+        ThumbFormat::Png | ThumbFormat::Webp => {
+            // Both encoders take 8-bit RGB(A), not the 16-bit or float layouts
+            let image = match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => DynamicImage::ImageRgb8(image.to_rgb8()),
+            };
+            let output = match format {
+                ThumbFormat::Png => image::ImageOutputFormat::Png,
+                _ => image::ImageOutputFormat::WebP,
+            };
+            image.write_to(&mut Cursor::new(&mut encoded), output)?;
+        }
+    }
+    Ok(encoded)
+}
+
+// JPEG has no alpha channel, so transparent images are put on a white background
+fn flatten(image: DynamicImage) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+    let mut flat = image::RgbImage::new(image.width(), image.height());
+    for (x, y, pixel) in image.to_rgba8().enumerate_pixels() {
+        let [r, g, b, a] = pixel.0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        flat.put_pixel(x, y, image::Rgb([blend(r), blend(g), blend(b)]));
+    }
+    DynamicImage::ImageRgb8(flat)
+}
+
+fn image_format(image_type: ImageType) -> ImageFormat {
+    match image_type {
+        ImageType::Jpeg => ImageFormat::Jpeg,
+        ImageType::Png => ImageFormat::Png,
+        ImageType::Gif => ImageFormat::Gif,
+        ImageType::Webp => ImageFormat::WebP,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use image::{GenericImageView, Rgb, RgbImage};
+
+    fn config(vars: &[(&str, &str)]) -> PipelineConfig {
+        PipelineConfig::new(&test_support::settings(vars))
+    }
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const BLUE: [u8; 3] = [0, 0, 255];
+    const WHITE: [u8; 3] = [255, 255, 255];
+
+    // 40x20, a colour per quadrant: red, green on top, blue, white below
+    fn quadrants() -> RgbImage {
+        RgbImage::from_fn(40, 20, |x, y| {
+            Rgb(match (x < 20, y < 10) {
+                (true, true) => RED,
+                (false, true) => GREEN,
+                (true, false) => BLUE,
+                (false, false) => WHITE,
+            })
+        })
+    }
+
+    // A JPEG of `image` with an APP1 EXIF segment holding just the orientation
+    fn jpeg_with_orientation(image: &RgbImage, orientation: u16) -> Vec<u8> {
+        let mut plain = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut plain, 95).encode_image(image).unwrap();
+
+        // TIFF header, then one IFD entry: Orientation, SHORT, count 1
+        let mut tiff = b"II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+        tiff.extend_from_slice(&orientation.to_le_bytes());
+        tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        let payload = [b"Exif\0\0".as_slice(), &tiff].concat();
+
+        let mut jpeg = plain[..2].to_vec();
+        jpeg.extend_from_slice(&[0xFF, 0xE1]);
+        jpeg.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(&payload);
+        jpeg.extend_from_slice(&plain[2..]);
+        jpeg
+    }
+
+    // The colour nearest to the pixel in the middle of each quadrant, clockwise
+    // from the top left
+    fn corners(image: &DynamicImage) -> [[u8; 3]; 4] {
+        let rgb = image.to_rgb8();
+        let (w, h) = (rgb.width(), rgb.height());
+        let nearest = |x: u32, y: u32| {
+            let pixel = rgb.get_pixel(x, y).0;
+            let distance = |c: &[u8; 3]| (0..3).map(|i| (pixel[i] as i32 - c[i] as i32).abs()).sum::<i32>();
+            *[RED, GREEN, BLUE, WHITE].iter().min_by_key(|c| distance(c)).unwrap()
+        };
+        [nearest(w / 4, h / 4), nearest(w * 3 / 4, h / 4), nearest(w * 3 / 4, h * 3 / 4), nearest(w / 4, h * 3 / 4)]
+    }
+
+    #[test]
+    fn every_exif_orientation_comes_out_upright() {
+        // What a viewer shows for each orientation, clockwise from the top left
+        let expected = [
+            (1, (40, 20), [RED, GREEN, WHITE, BLUE]),
+            (2, (40, 20), [GREEN, RED, BLUE, WHITE]),
+            (3, (40, 20), [WHITE, BLUE, RED, GREEN]),
+            (4, (40, 20), [BLUE, WHITE, GREEN, RED]),
+            (5, (20, 40), [RED, BLUE, WHITE, GREEN]),
+            (6, (20, 40), [BLUE, RED, GREEN, WHITE]),
+            (7, (20, 40), [WHITE, GREEN, RED, BLUE]),
+            (8, (20, 40), [GREEN, WHITE, BLUE, RED]),
+        ];
+        for (orientation, (width, height), colours) in expected {
+            let jpeg = jpeg_with_orientation(&quadrants(), orientation);
+            let processed = process_image(jpeg, Some(ImageType::Jpeg), &config(&[])).unwrap();
+            assert_eq!((processed.width, processed.height), (width, height), "orientation {}", orientation);
+            let stored = image::load_from_memory_with_format(&processed.bytes, ImageFormat::Jpeg).unwrap();
+            assert_eq!((stored.width(), stored.height()), (width, height), "orientation {}", orientation);
+            assert_eq!(corners(&stored), colours, "orientation {}", orientation);
+        }
+    }
+
+    // 300x200 with alpha running from clear at the left to opaque at the right
+    fn translucent_png() -> (image::RgbaImage, Vec<u8>) {
+        let image = image::RgbaImage::from_fn(300, 200, |x, y| image::Rgba([200, y as u8, 40, (x * 255 / 299) as u8]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image.clone()).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        (image, png)
+    }
+
+    #[test]
+    fn transparent_pngs_keep_their_alpha() {
+        let (original, png) = translucent_png();
+        for optimize in ["false", "true"] {
+            let config = config(&[("ALLOWED_IMAGE_TYPES", "png"), ("PNG_OPTIMIZE", optimize), ("THUMB_FORMAT", "png")]);
+            let processed = process_image(png.clone(), Some(ImageType::Png), &config).unwrap();
+            if optimize == "false" {
+                // Nothing to strip, so the file is stored as sent
+                assert_eq!(processed.bytes, png);
+            }
+            let stored = image::load_from_memory_with_format(&processed.bytes, ImageFormat::Png).unwrap();
+            assert!(stored.color().has_alpha(), "PNG_OPTIMIZE={}", optimize);
+            assert_eq!(stored.to_rgba8(), original, "PNG_OPTIMIZE={}", optimize);
+
+            for (size, thumbnail) in &processed.thumbnails {
+                let thumbnail = image::load_from_memory_with_format(thumbnail, ImageFormat::Png).unwrap().to_rgba8();
+                assert_eq!(thumbnail.width(), *size);
+                let (left, right) = (thumbnail.get_pixel(0, 0)[3], thumbnail.get_pixel(size - 1, 0)[3]);
+                assert!(left < 10 && right > 245, "thumbnail alpha runs {} to {}", left, right);
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_sources_make_thumbnails_in_every_format() {
+        let (_, png) = translucent_png();
+        let formats = [("jpeg", ImageFormat::Jpeg), ("png", ImageFormat::Png), ("webp", ImageFormat::WebP)];
+        for (format, image_format) in formats {
+            let config = config(&[("ALLOWED_IMAGE_TYPES", "png"), ("THUMB_FORMAT", format)]);
+            let processed = process_image(png.clone(), Some(ImageType::Png), &config).unwrap();
+            assert_eq!(processed.thumbnails.len(), 2, "THUMB_FORMAT={}", format);
+
+            for (size, thumbnail) in &processed.thumbnails {
+                let thumbnail = image::load_from_memory_with_format(thumbnail, image_format).unwrap();
+                assert_eq!(thumbnail.width(), *size, "THUMB_FORMAT={}", format);
+                let thumbnail = thumbnail.to_rgba8();
+                let (clear, opaque) = (thumbnail.get_pixel(0, 0).0, thumbnail.get_pixel(size - 1, 0).0);
+                if format == "jpeg" {
+                    // No alpha channel: the clear corner is put on white
+                    assert!(clear.iter().all(|&c| c > 245), "clear corner came out {:?}", clear);
+                    assert!(opaque[3] == 255 && opaque[0] > 180 && opaque[2] < 70, "opaque corner {:?}", opaque);
+                } else {
+                    assert!(clear[3] < 10 && opaque[3] > 245, "{} alpha runs {} to {}", format, clear[3], opaque[3]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn the_contents_must_be_the_claimed_and_an_allowed_type() {
+        let both = config(&[("ALLOWED_IMAGE_TYPES", "jpeg,png")]);
+        let png = test_support::png(16, 16, 255);
+        let refused = process_image(png.clone(), Some(ImageType::Jpeg), &both);
+        assert!(matches!(refused, Err(PipelineError::UnsupportedType)));
+        // JPEG only, by default
+        let refused = process_image(png.clone(), None, &config(&[]));
+        assert!(matches!(refused, Err(PipelineError::UnsupportedType)));
+        let refused = process_image(b"GIF? no, just text".to_vec(), None, &both);
+        assert!(matches!(refused, Err(PipelineError::UnsupportedType)));
+        assert_eq!(process_image(png, None, &both).unwrap().image_type, ImageType::Png);
+    }
+
+    #[test]
+    fn the_pixel_limit_is_checked_before_decoding() {
+        // A 16x16 JPEG whose frame header claims 60000x60000, which would take
+        // gigabytes to decode
+        let mut bomb = test_support::jpeg(16, 16);
+        let sof = bomb.windows(2).position(|marker| marker == [0xFF, 0xC0]).expect("no baseline frame header");
+        bomb[sof + 5..sof + 9].copy_from_slice(&[0xEA, 0x60, 0xEA, 0x60]);
+        let refused = process_image(bomb, Some(ImageType::Jpeg), &config(&[]));
+        assert!(matches!(refused, Err(PipelineError::TooManyPixels { .. })));
+
+        let limited = config(&[("MAX_IMAGE_PIXELS", "1000")]);
+        let refused = process_image(test_support::jpeg(40, 30), None, &limited);
+        assert!(matches!(refused, Err(PipelineError::TooManyPixels { limit: 1000 })));
+        assert!(process_image(test_support::jpeg(40, 25), None, &limited).is_ok());
+    }
+
+    #[test]
+    fn exif_is_stripped_and_plain_jpegs_are_stored_as_sent() {
+        let tagged = jpeg_with_orientation(&quadrants(), 1);
+        assert!(exif::Reader::new().read_from_container(&mut Cursor::new(&tagged)).is_ok());
+        let processed = process_image(tagged.clone(), Some(ImageType::Jpeg), &config(&[])).unwrap();
+        assert_ne!(processed.bytes, tagged);
+        assert!(exif::Reader::new().read_from_container(&mut Cursor::new(&processed.bytes)).is_err());
+        assert_eq!(processed.sha256, format!("{:x}", Sha256::digest(&processed.bytes)));
+
+        let plain = test_support::jpeg(40, 20);
+        assert_eq!(process_image(plain.clone(), None, &config(&[])).unwrap().bytes, plain);
+    }
+
+    // CRC-32 as PNG chunks use it
+    fn crc32(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, &byte| {
+            (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 == 1 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 })
+        })
+    }
+
+    #[test]
+    fn png_metadata_chunks_are_stripped_losslessly() {
+        let (original, png) = translucent_png();
+        // A tEXt chunk right after IHDR, which ends at byte 33
+        let text = b"tEXtComment\0taken at home, 52.37N 4.89E";
+        let mut chunk = (text.len() as u32 - 4).to_be_bytes().to_vec();
+        chunk.extend_from_slice(text);
+        chunk.extend_from_slice(&crc32(text).to_be_bytes());
+        let tagged = [&png[..33], &chunk, &png[33..]].concat();
+        assert!(png_has_metadata(&tagged) && !png_has_metadata(&png));
+
+        let pngs = config(&[("ALLOWED_IMAGE_TYPES", "png")]);
+        let processed = process_image(tagged, Some(ImageType::Png), &pngs).unwrap();
+        assert!(!png_has_metadata(&processed.bytes));
+        assert!(!processed.bytes.windows(7).any(|window| window == b"Comment"));
+        let stored = image::load_from_memory_with_format(&processed.bytes, ImageFormat::Png).unwrap();
+        assert_eq!(stored.to_rgba8(), original);
+    }
+
+    #[test]
+    fn thumbnails_skip_the_boxes_an_image_already_fits() {
+        let small = process_image(test_support::jpeg(100, 80), None, &config(&[])).unwrap();
+        assert!(small.thumbnails.is_empty());
+        let middle = process_image(test_support::jpeg(200, 100), None, &config(&[])).unwrap();
+        assert_eq!(middle.thumbnails.iter().map(|(size, _)| *size).collect::<Vec<_>>(), [125]);
+
+        let formats = [("jpeg", ImageFormat::Jpeg), ("png", ImageFormat::Png), ("webp", ImageFormat::WebP)];
+        for (format, image_format) in formats {
+            let config = config(&[("THUMB_FORMAT", format)]);
+            let large = process_image(test_support::jpeg(600, 300), None, &config).unwrap();
+            let sizes: Vec<(u32, u32)> = large
+                .thumbnails
+                .iter()
+                .map(|(_, bytes)| image::load_from_memory_with_format(bytes, image_format).unwrap().dimensions())
+                .collect();
+            assert_eq!(sizes, [(125, 63), (250, 125)], "THUMB_FORMAT={}", format);
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_invalid() {
+        let jpeg = test_support::jpeg(64, 64);
+        let refused = process_image(jpeg[..jpeg.len() / 2].to_vec(), None, &config(&[]));
+        assert!(matches!(refused, Err(PipelineError::InvalidImage(_))));
+        let pngs = config(&[("ALLOWED_IMAGE_TYPES", "png")]);
+        let png = test_support::png(64, 64, 255);
+        let refused = process_image(png[..png.len() - 20].to_vec(), None, &pngs);
+        assert!(matches!(refused, Err(PipelineError::InvalidImage(_))));
+        let refused = process_image(png[..20].to_vec(), None, &pngs);
+        assert!(matches!(refused, Err(PipelineError::InvalidImage(_))));
+    }
+}
@@ -1,21 +1,93 @@
 
+mod accounts;
+mod admin;
+mod api;
+mod compat;
+mod cooldown;
+mod counter_check;
+mod embed;
+mod expiry;
+mod export;
+mod filters;
+mod flood;
+mod format;
+mod geoip;
+mod image_check;
+mod image_pipeline;
+mod maintenance;
+mod migrations;
+mod moderation;
+mod modlog;
+mod openapi;
+mod post_form;
+mod post_numbers;
+mod post_options;
+mod post_rule;
+mod pwa;
+mod reports;
+mod repository;
+mod repost;
+mod request_id;
+mod request_timeout;
+mod security;
+mod seo;
+mod settings;
+mod sqlite;
+mod tags;
+mod thread_state;
+mod tls;
+mod trailing_slash;
+mod upload;
+mod validation;
+mod word_filter;
+#[cfg(test)]
+mod test_support;
+
 use actix_files as fs;
 use actix_multipart::Multipart;
 use actix_web::{
-    web, App, HttpResponse, HttpServer, Responder, middleware, Error,
+    guard, http::header, mime, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, middleware, Error,
 };
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
 use askama::Template;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::transaction::{ConflictableTransactionError, TransactionResult};
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
 use sled::Db;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use log::{error, info};
-use futures_util::stream::StreamExt;
-use std::io::Write;
 use uuid::Uuid;
 
+use cooldown::{Cooldown, Cooldowns, RecentThreads};
+use flood::ReplyFlood;
+use format::QuoteLinks;
+use geoip::GeoIp;
+use image_check::ImageFiles;
+use maintenance::Maintenance;
+use post_form::PostForm;
+use post_rule::Field;
+use post_options::PostOptions;
+use repository::{RepoError, ReplySummary, Repository, SledRepository, ThreadOrder};
+use settings::Settings;
+use sqlite::SqliteRepository;
+use thread_state::ReplyRefusal;
+use upload::{ImageSize, ImageType};
+use validation::ValidationError;
+
 const UPLOAD_DIR: &str = "./uploads/";
 const THUMB_DIR: &str = "./thumbs/";
+const STATIC_DIR: &str = "./static/";
+// Upload filenames are UUIDs and never rewritten, so they can be cached for a year
+const UPLOAD_CACHE_MAX_AGE: u32 = 31_536_000;
+// Tiles per catalog page; they are small, so a page holds far more than the index
+const CATALOG_PER_PAGE: usize = 60;
+// Refusal for ALLOW_IMAGE_REPLY, shared with the JSON API
+const REPLY_IMAGES_DISABLED: &str = "Images are not allowed in replies";
 
 #[derive(Template)]
 #[template(path = "homepage.html")]
@@ -23,6 +95,77 @@ struct HomepageTemplate<'a> {
     threads: &'a [Thread],
     current_page: i32,
     total_pages: i32,
+    // "bump" or "created"
+    order: &'a str,
+    // TITLES_ENABLED, and which form fields THREAD_RULE makes required
+    titles_enabled: bool,
+    require_title: bool,
+    require_message: bool,
+    require_image: bool,
+    // MAX_TAGS, 0 when the form has no tags field
+    max_tags: usize,
+    // Country flags, with GEOIP_DB
+    flags: bool,
+    // BLUR_THUMBNAILS, or only the posts marked NSFW
+    blur_thumbnails: bool,
+    // Link to the account page (ACCOUNTS)
+    accounts: bool,
+    read_only: bool,
+    announcement: String,
+    base_path: &'a str,
+    theme_color: &'a str,
+    quote_links: &'a QuoteLinks,
+    // Allowed upload formats, as labels and as the file input's accept list
+    image_types: String,
+    image_accept: String,
+    reply_summaries: HashMap<i32, ReplySummary>,
+    // PREVIEW_CHARS
+    preview_chars: usize,
+    canonical: String,
+}
+
+impl HomepageTemplate<'_> {
+    fn reply_summary(&self, thread_id: &i32) -> ReplySummary {
+        self.reply_summaries.get(thread_id).copied().unwrap_or_default()
+    }
+
+    // The start of a long opening post, when PREVIEW_CHARS cuts it short
+    fn preview<'t>(&self, thread: &'t Thread) -> Option<&'t str> {
+        match self.preview_chars {
+            0 => None,
+            limit => format::preview(&thread.message, limit),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "catalog.html")]
+struct CatalogTemplate<'a> {
+    threads: &'a [Thread],
+    current_page: i32,
+    total_pages: i32,
+    // The title search, empty when the whole catalog is shown
+    query: &'a str,
+    // On /tag/{tag}, the tag whose threads are shown
+    tag: Option<&'a str>,
+    // Where the page links go: /catalog, or /tag/{tag}
+    listing: String,
+    // Without titles (TITLES_ENABLED) the search looks through messages
+    titles_enabled: bool,
+    // BLUR_THUMBNAILS, or only the threads marked NSFW
+    blur_thumbnails: bool,
+    read_only: bool,
+    announcement: String,
+    base_path: &'a str,
+    theme_color: &'a str,
+    reply_summaries: HashMap<i32, ReplySummary>,
+    canonical: String,
+}
+
+impl CatalogTemplate<'_> {
+    fn reply_summary(&self, thread_id: &i32) -> ReplySummary {
+        self.reply_summaries.get(thread_id).copied().unwrap_or_default()
+    }
 }
 
 #[derive(Template)]
@@ -30,6 +173,59 @@ struct HomepageTemplate<'a> {
 struct ThreadTemplate<'a> {
     thread: &'a Thread,
     replies: &'a [Reply],
+    poster_count: usize,
+    // SHOW_SAGE
+    show_sage: bool,
+    // POST_NUMBERING=global: "No. N" on every post, the opening post included
+    global_numbers: bool,
+    // Country flags, with GEOIP_DB
+    flags: bool,
+    // BLUR_THUMBNAILS, or only the posts marked NSFW
+    blur_thumbnails: bool,
+    allow_image: bool,
+    // Which form fields REPLY_RULE makes required
+    require_message: bool,
+    require_image: bool,
+    // Link to the account page (ACCOUNTS)
+    accounts: bool,
+    read_only: bool,
+    announcement: String,
+    base_path: &'a str,
+    theme_color: &'a str,
+    quote_links: &'a QuoteLinks,
+    image_types: String,
+    image_accept: String,
+    // Starting text of the reply box, set by ?quote= for browsers without JS
+    quote: String,
+    // Older replies left out of `replies` by THREAD_LAST_REPLIES
+    omitted: usize,
+    // ?all=true, kept by the order links
+    show_all: bool,
+    // "asc" or "desc", see ReplyOrder
+    order: &'static str,
+    // Replies that will still bump the thread, when BUMP_LIMIT is set
+    bumps_left: Option<usize>,
+    // Why the thread takes no replies, shown instead of the reply form
+    closed: Option<ReplyRefusal>,
+    canonical: String,
+}
+
+// One reply as rendered on the thread page, for posting without a reload
+#[derive(Template)]
+#[template(path = "reply.html")]
+struct ReplyTemplate<'a> {
+    thread: &'a Thread,
+    reply: &'a Reply,
+    // SHOW_SAGE
+    show_sage: bool,
+    // POST_NUMBERING=global: "No. N" on every post, the opening post included
+    global_numbers: bool,
+    // Country flags, with GEOIP_DB
+    flags: bool,
+    // BLUR_THUMBNAILS, or only the posts marked NSFW
+    blur_thumbnails: bool,
+    base_path: &'a str,
+    quote_links: &'a QuoteLinks,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -39,23 +235,342 @@ struct Thread {
     message: String,
     last_updated: i64, // Unix timestamp
     image_url: Option<String>, // Image URL for threads
+    #[serde(default)]
+    thumbnails: Vec<String>, // Thumbnail URLs, smallest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_size: Option<ImageSize>, // Size of the full image, backfilled by migrations
+    #[serde(default)]
+    created_at: i64, // Unix timestamp, backfilled by migrations for old records
+    #[serde(default)]
+    email: Option<String>, // Sanitized email/options field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    poster_hash: Option<String>, // Salted hash of the poster's IP, never shown publicly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    custom_thumbnail: Option<CustomThumbnail>, // Shown instead of the thumbnails, set from /admin
+    #[serde(default)]
+    locked: bool, // No new replies, set from /admin
+    #[serde(default)]
+    archived: bool, // Kept for reading but closed to replies, set from /admin
+    #[serde(default)]
+    image_removed: bool, // The image was deleted from /admin and a placeholder shows instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sticky: Option<Sticky>, // Pinned to the top of the board index, set from /admin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>, // Account the poster was logged in to, with ACCOUNTS
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>, // Normalized tags given by the opening post, with MAX_TAGS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    country: Option<String>, // Poster's country code from GEOIP_DB, e.g. "DE"
+    #[serde(default)]
+    nsfw: bool, // Marked NSFW by the poster, so the thumbnail is blurred until clicked
+    #[serde(skip)]
+    image_missing: bool, // The image file is gone from disk, found by CHECK_IMAGE_FILES; never stored
+}
+
+// How long a thread stays pinned above the others on the board index
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Sticky {
+    Forever,
+    // Unix timestamp after which the thread goes back to bump order
+    Until(i64),
+}
+
+// An image a moderator uploaded to stand in for a thread's own thumbnails. The
+// image link still opens the thread's real image.
+#[derive(Serialize, Deserialize, Clone)]
+struct CustomThumbnail {
+    image_url: String,
+    #[serde(default)]
+    thumbnails: Vec<String>, // Thumbnail URLs, smallest first
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Reply {
     id: i32,
     message: String,
+    #[serde(default)]
+    created_at: i64, // Unix timestamp, backfilled by migrations for old records
+    #[serde(default)]
+    email: Option<String>, // Sanitized email/options field
+    #[serde(default)]
+    image_url: Option<String>, // Image URL for replies
+    #[serde(default)]
+    thumbnails: Vec<String>, // Thumbnail URLs, smallest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_size: Option<ImageSize>, // Size of the full image, backfilled by migrations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    poster_hash: Option<String>, // Salted hash of the poster's IP, never shown publicly
+    #[serde(default)]
+    image_removed: bool, // The image was deleted from /admin and a placeholder shows instead
+    #[serde(default)]
+    saged: bool, // Posted with sage, so it didn't bump the thread
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>, // Account the poster was logged in to, with ACCOUNTS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    country: Option<String>, // Poster's country code from GEOIP_DB, e.g. "DE"
+    #[serde(default)]
+    nsfw: bool, // Marked NSFW by the poster, so the thumbnail is blurred until clicked
+    #[serde(skip)]
+    image_missing: bool, // The image file is gone from disk, found by CHECK_IMAGE_FILES; never stored
+}
+
+impl Thread {
+    // Whether the thread is pinned right now; a pin past its expiry counts as gone
+    fn is_sticky(&self) -> bool {
+        match self.sticky {
+            Some(Sticky::Forever) => true,
+            Some(Sticky::Until(until)) => until > Utc::now().timestamp(),
+            None => false,
+        }
+    }
+
+    // Email to render as a mailto: link, skipping option keywords like sage
+    fn mailto(&self) -> Option<&str> {
+        self.email.as_deref().filter(|email| post_options::is_real_email(email))
+    }
+
+    // Name shown on the post: the poster's username, or Anonymous
+    fn display_name(&self) -> &str {
+        self.username.as_deref().unwrap_or("Anonymous")
+    }
+
+    // What the post listing shows: the smallest thumbnail, or the image itself.
+    // A custom thumbnail takes the place of both.
+    fn thumb_src(&self) -> &str {
+        if let Some(custom) = &self.custom_thumbnail {
+            return custom.thumbnails.first().unwrap_or(&custom.image_url);
+        }
+        self.thumbnails
+            .first()
+            .or(self.image_url.as_ref())
+            .map_or("", String::as_str)
+    }
+
+    fn srcset(&self, base_path: &str) -> Option<String> {
+        if let Some(custom) = &self.custom_thumbnail {
+            return upload::srcset(base_path, &custom.image_url, &custom.thumbnails);
+        }
+        upload::srcset(base_path, self.image_url.as_deref()?, &self.thumbnails)
+    }
+
+    // What lists of threads call it: the title, or its number on boards without titles
+    fn heading(&self) -> String {
+        match self.title.is_empty() {
+            true => format!("Thread No. {}", self.id),
+            false => self.title.clone(),
+        }
+    }
+
+    // Links to every upload the thread shows: its image, the thumbnails and any
+    // custom thumbnail
+    fn file_urls(&self) -> impl Iterator<Item = &str> {
+        let custom = self.custom_thumbnail.iter().flat_map(|custom| {
+            std::iter::once(&custom.image_url).chain(&custom.thumbnails)
+        });
+        self.image_url.iter().chain(&self.thumbnails).chain(custom).map(String::as_str)
+    }
+
+    // Copy safe to hand to API clients, with links that include BASE_PATH
+    fn public(mut self, settings: &Settings) -> Self {
+        self.poster_hash = None;
+        self.image_url = self.image_url.map(|url| settings.url(&url));
+        self.thumbnails = self.thumbnails.iter().map(|url| settings.url(url)).collect();
+        self.custom_thumbnail = self.custom_thumbnail.map(|custom| CustomThumbnail {
+            image_url: settings.url(&custom.image_url),
+            thumbnails: custom.thumbnails.iter().map(|url| settings.url(url)).collect(),
+        });
+        self
+    }
+}
+
+impl Reply {
+    // Email to render as a mailto: link, skipping option keywords like sage
+    fn mailto(&self) -> Option<&str> {
+        self.email.as_deref().filter(|email| post_options::is_real_email(email))
+    }
+
+    // Name shown on the post: the poster's username, or Anonymous
+    fn display_name(&self) -> &str {
+        self.username.as_deref().unwrap_or("Anonymous")
+    }
+
+    // What the post listing shows: the smallest thumbnail, or the image itself
+    fn thumb_src(&self) -> &str {
+        self.thumbnails
+            .first()
+            .or(self.image_url.as_ref())
+            .map_or("", String::as_str)
+    }
+
+    fn srcset(&self, base_path: &str) -> Option<String> {
+        upload::srcset(base_path, self.image_url.as_deref()?, &self.thumbnails)
+    }
+
+    // Links to the reply's image and its thumbnails
+    fn file_urls(&self) -> impl Iterator<Item = &str> {
+        self.image_url.iter().chain(&self.thumbnails).map(String::as_str)
+    }
+
+    // Copy safe to hand to API clients, with links that include BASE_PATH
+    fn public(mut self, settings: &Settings) -> Self {
+        self.poster_hash = None;
+        self.image_url = self.image_url.map(|url| settings.url(&url));
+        self.thumbnails = self.thumbnails.iter().map(|url| settings.url(url)).collect();
+        self
+    }
+}
+
+// Address of the client; proxy headers are only believed when TRUST_PROXY is set
+fn client_ip(req: &HttpRequest, settings: &Settings) -> String {
+    if settings.trust_proxy {
+        if let Some(ip) = req.connection_info().realip_remote_addr() {
+            return ip.to_string();
+        }
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+// Salted hash identifying a poster without storing their IP
+fn poster_hash(req: &HttpRequest, settings: &Settings) -> Option<String> {
+    let ip = client_ip(req, settings);
+    if ip.is_empty() {
+        return None;
+    }
+    let digest = Sha256::new()
+        .chain_update(settings.poster_salt.as_bytes())
+        .chain_update(ip.as_bytes())
+        .finalize();
+    Some(format!("{:x}", digest)[..16].to_string())
+}
+
+// User-supplied fields of a thread that is about to be stored
+struct NewThread {
+    title: String,
+    message: String,
+    image_url: Option<String>,
+    thumbnails: Vec<String>,
+    image_size: Option<ImageSize>,
+    email: Option<String>,
+    poster_hash: Option<String>,
+    username: Option<String>,
+    tags: Vec<String>,
+    country: Option<String>,
+    nsfw: bool,
+}
+
+// User-supplied fields of a reply that is about to be stored
+struct NewReply {
+    message: String,
+    image_url: Option<String>,
+    thumbnails: Vec<String>,
+    image_size: Option<ImageSize>,
+    email: Option<String>,
+    poster_hash: Option<String>,
+    username: Option<String>,
+    country: Option<String>,
+    nsfw: bool,
+}
+
+impl NewThread {
+    // The record of the thread once it has its id, posted at `now`
+    fn to_thread(&self, id: i32, now: i64) -> Thread {
+        Thread {
+            id,
+            title: self.title.clone(),
+            message: self.message.clone(),
+            last_updated: now,
+            image_url: self.image_url.clone(),
+            thumbnails: self.thumbnails.clone(),
+            image_size: self.image_size,
+            created_at: now,
+            email: self.email.clone(),
+            poster_hash: self.poster_hash.clone(),
+            custom_thumbnail: None,
+            locked: false,
+            archived: false,
+            image_removed: false,
+            sticky: None,
+            username: self.username.clone(),
+            tags: self.tags.clone(),
+            country: self.country.clone(),
+            nsfw: self.nsfw,
+            image_missing: false,
+        }
+    }
+}
+
+impl NewReply {
+    // The record of the reply once it has its id, posted at `now`
+    fn to_reply(&self, id: i32, now: i64) -> Reply {
+        Reply {
+            id,
+            message: self.message.clone(),
+            created_at: now,
+            email: self.email.clone(),
+            image_url: self.image_url.clone(),
+            thumbnails: self.thumbnails.clone(),
+            image_size: self.image_size,
+            poster_hash: self.poster_hash.clone(),
+            image_removed: false,
+            saged: PostOptions::parse(self.email.as_deref()).sage,
+            username: self.username.clone(),
+            country: self.country.clone(),
+            nsfw: self.nsfw,
+            image_missing: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ThreadParams {
+    // Reply to quote into the reply box, the no-JS version of clicking its number
+    quote: Option<i32>,
+    // Show every reply even when THREAD_LAST_REPLIES would collapse the older ones
+    #[serde(default)]
+    all: bool,
+    // "desc" for newest replies first
+    order: Option<String>,
+}
+
+// Order of the replies on a thread page; the opening post always stays on top
+#[derive(Clone, Copy, PartialEq)]
+enum ReplyOrder {
+    OldestFirst,
+    NewestFirst,
+}
+
+impl ReplyOrder {
+    // Anything but "desc" is the usual oldest first
+    fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("desc") => ReplyOrder::NewestFirst,
+            _ => ReplyOrder::OldestFirst,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ReplyOrder::OldestFirst => "asc",
+            ReplyOrder::NewestFirst => "desc",
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct PaginationParams {
     page: Option<i32>,
+    // "bump" (default) or "created"
+    order: Option<String>,
 }
 
 #[derive(Deserialize)]
-struct ReplyForm {
-    parent_id: i32,
-    message: String,
+struct CatalogParams {
+    page: Option<i32>,
+    // Only threads whose title contains this, ignoring case
+    q: Option<String>,
 }
 
 #[actix_web::main]
@@ -70,39 +585,303 @@ async fn main() -> std::io::Result<()> {
         }
     }
 
+    let mut settings = Settings::from_env();
+    // Refuse to start on a backend that doesn't exist rather than quietly
+    // writing the posts somewhere else
+    let sqlite = match settings.db_backend.as_str() {
+        "sled" => None,
+        "sqlite" => {
+            let path = std::path::Path::new(&settings.sqlite_path);
+            let (global, bump_limit, ttl) = (settings.global_post_numbers, settings.bump_limit, settings.thread_ttl);
+            let opened = SqliteRepository::open(path, global, bump_limit, ttl);
+            match opened {
+                Ok(repository) => Some(repository),
+                Err(e) => {
+                    error!("Failed to open the sqlite database {}: {}", settings.sqlite_path, e);
+                    return Err(std::io::Error::other(e));
+                }
+            }
+        }
+        backend => {
+            let message = format!("DB_BACKEND={:?} is not available; use sled or sqlite", backend);
+            error!("{}", message);
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message));
+        }
+    };
+    // Expiry, pruning and the flood lock change the sled records only, so they
+    // would silently do nothing to posts kept elsewhere
+    let sled_only = settings.sled_only_options();
+    if !sled_only.is_empty() {
+        let message = format!("{} can't be used with DB_BACKEND={}", sled_only.join(", "), settings.db_backend);
+        error!("{}", message);
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, message));
+    }
+    let tls = match tls::load(settings.tls_cert.as_deref(), settings.tls_key.as_deref()) {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("{}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()));
+        }
+    };
+
     // Initialize sled database
     let sled_db = Arc::new(sled::open("sled_db").expect("Failed to open sled database"));
 
-    // Start Actix server
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(sled_db.clone()))
-            .wrap(middleware::Logger::default())
-            .service(fs::Files::new("/static", "./static").show_files_listing())
-            .service(fs::Files::new("/uploads", UPLOAD_DIR).show_files_listing()) // Serve uploaded images
-            .route("/", web::get().to(homepage))
-            .route("/thread/{id}", web::get().to(view_thread))
-            .route("/thread", web::post().to(create_thread))
-            .route("/reply", web::post().to(create_reply))
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    // Bring stored records up to the current schema before serving requests
+    migrations::run(&sled_db).expect("Failed to migrate sled database");
+    if settings.counter_check && settings.posts_in_sled() {
+        let repaired = counter_check::repair(&sled_db).expect("Failed to check the id counters");
+        if repaired > 0 {
+            info!("Repaired {} id counters that were behind stored posts", repaired);
+        }
+    }
+
+    if settings.poster_salt.is_empty() {
+        settings.poster_salt = load_poster_salt(&sled_db).expect("Failed to load poster hash salt");
+    }
+    let settings = web::Data::new(settings);
+    if let Some(keep) = settings.kept_threads() {
+        let pruned = expiry::prune_past_limit(&sled_db, keep, settings.prune_mode, true);
+        if pruned > 0 {
+            info!("Pruned {} threads past MAX_PAGES={}", pruned, settings.max_pages);
+        }
+    }
+    if settings.global_post_numbers {
+        post_numbers::seed_counter(&sled_db).expect("Failed to seed the post number counter");
+    }
+    let mut state = AppState::new(sled_db.clone(), settings.clone());
+    if let Some(repository) = sqlite {
+        let repository: Arc<dyn Repository> = Arc::new(repository);
+        state.repository = web::Data::from(repository);
+    }
+
+    // Periodically delete API uploads that were never attached to a post
+    let token_db = sled_db.clone();
+    let token_ttl = settings.upload_token_ttl;
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let db = token_db.clone();
+            let _ = web::block(move || upload::purge_expired_tokens(&db, token_ttl)).await;
+        }
+    });
+
+    // Forget logins that ran out, if accounts are on
+    if settings.accounts {
+        let session_db = sled_db.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(60 * 60));
+            loop {
+                interval.tick().await;
+                let db = session_db.clone();
+                let _ = web::block(move || accounts::purge_expired_sessions(&db)).await;
+            }
+        });
+    }
+
+    // Delete threads past THREAD_TTL, if a retention period is set
+    if settings.thread_ttl > 0 {
+        let expiry_db = sled_db.clone();
+        let thread_ttl = settings.thread_ttl;
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let db = expiry_db.clone();
+                let _ = web::block(move || expiry::purge_expired_threads(&db, thread_ttl)).await;
+            }
+        });
+    }
+
+    let server = HttpServer::new(move || app(&state))
+        .client_request_timeout(request_timeout::HEADER_TIMEOUT)
+        .keep_alive(request_timeout::KEEP_ALIVE);
+    let server = match tls {
+        Some(config) => {
+            info!("Serving HTTPS with TLS_CERT {}", settings.tls_cert.as_deref().unwrap_or_default());
+            server.bind_rustls_0_23(("0.0.0.0", 8080), config)?
+        }
+        None => server.bind(("0.0.0.0", 8080))?,
+    };
+    server.run().await
+}
+
+// Everything the handlers find in app_data, built once at startup and shared by
+// every worker
+#[derive(Clone)]
+struct AppState {
+    db: Arc<Db>,
+    settings: web::Data<Settings>,
+    repository: web::Data<dyn Repository>,
+    stats_cache: web::Data<admin::StatsCache>,
+    maintenance: web::Data<Maintenance>,
+    cooldowns: web::Data<Cooldowns>,
+    reply_flood: web::Data<ReplyFlood>,
+    image_files: web::Data<ImageFiles>,
+    geoip: web::Data<GeoIp>,
+}
+
+impl AppState {
+    fn new(db: Arc<Db>, settings: web::Data<Settings>) -> Self {
+        let repository = SledRepository::new(
+            db.clone(),
+            settings.global_post_numbers,
+            settings.bump_limit,
+            settings.thread_ttl,
+        );
+        let repository: Arc<dyn Repository> = Arc::new(repository);
+        AppState {
+            repository: web::Data::from(repository),
+            stats_cache: web::Data::new(admin::StatsCache::default()),
+            maintenance: web::Data::new(Maintenance::new(settings.read_only, &db, &settings.announcement)),
+            cooldowns: web::Data::new(Cooldowns {
+                threads: Cooldown::new(settings.thread_cooldown),
+                recent_threads: RecentThreads::new(settings.duplicate_thread_window),
+                accounts: Cooldown::new(settings.account_cooldown),
+            }),
+            reply_flood: web::Data::new(ReplyFlood::new(settings.flood_lock_replies, settings.flood_lock_window)),
+            image_files: web::Data::new(ImageFiles::new(settings.check_image_files)),
+            geoip: web::Data::new(GeoIp::open(settings.geoip_db.as_deref())),
+            db,
+            settings,
+        }
+    }
+}
+
+// The board's routes and middleware, for one worker
+fn app(
+    state: &AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = Error,
+        InitError = (),
+    >,
+> {
+    // JSON posts may carry a base64 image, so leave room for the largest one
+    let json_limit = (state.settings.max_upload_bytes.div_ceil(3) * 4 + 64 * 1024) as usize;
+    let base_path = state.settings.base_path.clone();
+    App::new()
+        .app_data(web::Data::new(state.db.clone()))
+        .app_data(state.repository.clone())
+        .app_data(web::JsonConfig::default().limit(json_limit))
+        .app_data(state.settings.clone())
+        .app_data(state.stats_cache.clone())
+        .app_data(state.maintenance.clone())
+        .app_data(state.cooldowns.clone())
+        .app_data(state.reply_flood.clone())
+        .app_data(state.image_files.clone())
+        .app_data(state.geoip.clone())
+        .wrap(middleware::from_fn(request_timeout::body_deadline))
+        .wrap(middleware::from_fn(maintenance::read_only_guard))
+        .wrap(middleware::from_fn(trailing_slash::redirect_trailing_slash))
+        .wrap(middleware::from_fn(security::security_headers))
+        .wrap(middleware::from_fn(request_id::request_id))
+        .wrap(middleware::Logger::new(request_id::LOG_FORMAT))
+        .configure(|cfg| {
+            // Behind a proxy at /board, send /board on to the board index
+            if !base_path.is_empty() {
+                cfg.service(web::redirect(base_path.clone(), format!("{}/", base_path)));
+            }
+        })
+        .service(
+            web::scope(&base_path)
+                .route("/favicon.ico", get_or_head().to(favicon))
+                .route("/manifest.json", get_or_head().to(pwa::manifest))
+                .route("/static/sw.js", get_or_head().to(pwa::service_worker))
+                .service(fs::Files::new("/static", STATIC_DIR).default_handler(web::to(not_found)))
+                .route("/uploads/{filename}", get_or_head().to(serve_upload)) // Serve uploaded images
+                .route("/thumbs/{filename}", get_or_head().to(serve_thumbnail))
+                .route("/", get_or_head().to(homepage))
+                .route("/catalog", get_or_head().to(catalog))
+                .route("/tag/{tag}", get_or_head().to(tagged))
+                .route("/catalog.json", get_or_head().to(compat::catalog))
+                .route("/thread/{id}.json", get_or_head().to(compat::thread))
+                .route("/thread/{id}", get_or_head().to(view_thread))
+                // The target of the reply number links, for quoting without JS
+                .route("/thread/{id}/reply", get_or_head().to(view_thread))
+                .route("/thread/{id}/report", web::post().to(reports::report_post))
+                .route("/thread", web::post().to(create_thread))
+                .route("/reply", web::post().to(create_reply))
+                .route("/robots.txt", get_or_head().to(seo::robots_txt))
+                .route("/sitemap.xml", get_or_head().to(seo::sitemap_xml))
+                .route("/sitemap-{page}.xml", get_or_head().to(seo::sitemap_page))
+                .route("/account", get_or_head().to(accounts::account_page))
+                .route("/register", web::post().to(accounts::register))
+                .route("/login", web::post().to(accounts::login))
+                .route("/logout", web::post().to(accounts::logout))
+                .route("/admin", web::get().to(admin::dashboard))
+                .route("/admin/login", web::post().to(admin::login))
+                .route("/admin/export", web::get().to(admin::export))
+                .route("/admin/missing-images", web::get().to(admin::missing_images))
+                .route("/admin/upload-stats", web::get().to(admin::upload_stats))
+                .route("/admin/settings", web::get().to(admin::settings_page))
+                .route("/admin/reports/dismiss", web::post().to(admin::dismiss_report))
+                .route("/admin/gc", web::post().to(admin::collect_garbage))
+                .route("/admin/rebuild-thumbnails", web::post().to(admin::rebuild_thumbnails))
+                .route("/admin/delete-by-poster", web::post().to(admin::delete_by_poster))
+                .route("/admin/wipe", web::post().to(admin::wipe))
+                .route("/admin/read-only", web::post().to(admin::set_read_only))
+                .route("/admin/announcement", web::post().to(admin::set_announcement))
+                .route("/admin/thread/{id}/merge", web::post().to(admin::merge_thread))
+                .route("/admin/thread/{id}/thumbnail", web::post().to(admin::set_thumbnail))
+                .route("/admin/thread/{id}/lock", web::post().to(admin::lock_thread))
+                .route("/admin/thread/{id}/archive", web::post().to(admin::archive_thread))
+                .route("/admin/thread/{id}/sticky", web::post().to(admin::sticky_thread))
+                .route("/admin/thread/{id}/delete-image", web::post().to(admin::delete_thread_image))
+                .route(
+                    "/admin/thread/{id}/reply/{rid}/delete-image",
+                    web::post().to(admin::delete_reply_image),
+                )
+                .route("/api/thread", web::post().to(api::create_thread))
+                .route("/api/reply", web::post().to(api::create_reply))
+                .route("/api/upload", web::post().to(api::upload_image))
+                .route("/api/openapi.json", web::get().to(openapi::serve))
+                .route("/api/threads", web::get().to(api::list_threads))
+                .route("/api/thread/{id}", web::get().to(api::get_thread))
+                .route("/api/thread/{id}/reply", web::post().to(create_reply_fragment))
+                .route("/api/thread/{id}/reply/{rid}", web::get().to(api::get_reply))
+                .route("/api/thread/{id}/quote/{rid}", web::get().to(api::quote_reply)),
+        )
+}
+
+// GET, plus HEAD for crawlers and monitors. HEAD runs the same handler so the
+// headers (Content-Length included) match; actix leaves the body out.
+fn get_or_head() -> actix_web::Route {
+    web::route().guard(guard::Any(guard::Get()).or(guard::Head()))
+}
+
+// Salt for poster hashes, generated once and kept in sled so hashes stay stable
+fn load_poster_salt(db: &Db) -> sled::Result<String> {
+    const SALT_KEY: &[u8] = b"poster_hash_salt";
+    if let Some(salt) = db.get(SALT_KEY)? {
+        return Ok(String::from_utf8_lossy(&salt).to_string());
+    }
+    let salt = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    db.insert(SALT_KEY, salt.as_bytes())?;
+    Ok(salt)
 }
 
 // Homepage handler
 async fn homepage(
-    db: web::Data<Arc<Db>>,
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    image_files: web::Data<ImageFiles>,
     query: web::Query<PaginationParams>,
 ) -> impl Responder {
-    let page_size = 10;
+    let page_size = settings.threads_per_page;
     let page_number = query.page.unwrap_or(1);
 
-    let mut threads = get_all_threads(&db);
-    threads.sort_by(|a, b| b.last_updated.cmp(&a.last_updated));
-
-    let total_threads = threads.len() as i32;
-    let total_pages = (total_threads as f64 / page_size as f64).ceil() as i32;
+    let total_threads = repo.count_threads() as i32;
+    let mut total_pages = (total_threads as f64 / page_size as f64).ceil() as i32;
+    // Archived threads past MAX_PAGES are still stored, but no longer listed
+    if settings.max_pages > 0 {
+        total_pages = total_pages.min(settings.max_pages as i32);
+    }
 
     let page_number = if page_number < 1 {
         1
@@ -113,13 +892,164 @@ async fn homepage(
     };
 
     let start_index = ((page_number - 1) * page_size) as usize;
-    let end_index = (start_index + page_size as usize).min(threads.len());
-    let threads = &threads[start_index..end_index];
+    let order = ThreadOrder::parse(query.order.as_deref());
+    let mut threads = match order {
+        // In bump order the pinned threads come first and count towards the
+        // pages they fill, so every page holds page_size threads
+        ThreadOrder::Bump => {
+            let sticky = repo.list_sticky();
+            let pinned: Vec<i32> = sticky.iter().map(|thread| thread.id).collect();
+            let mut threads: Vec<Thread> = sticky.into_iter().skip(start_index).take(page_size as usize).collect();
+            let offset = start_index.saturating_sub(pinned.len());
+            threads.extend(repo.list_unpinned(&pinned, offset, page_size as usize - threads.len()));
+            threads
+        }
+        ThreadOrder::Created => repo.list_threads(order, start_index, page_size as usize),
+    };
+    image_files.check_threads(&mut threads);
+    let quote_links = resolve_quotes(
+        repo.get_ref(),
+        &settings,
+        threads.iter().map(|thread| (thread.id, thread.message.as_str())),
+    );
+    let thread_ids: Vec<i32> = threads.iter().map(|thread| thread.id).collect();
 
     let tmpl = HomepageTemplate {
-        threads,
+        threads: &threads,
+        current_page: page_number,
+        total_pages,
+        order: order.as_str(),
+        titles_enabled: settings.titles_enabled,
+        require_title: settings.thread_rule.requires(Field::Title),
+        require_message: settings.thread_rule.requires(Field::Message),
+        require_image: settings.thread_rule.requires(Field::Image),
+        max_tags: settings.max_tags,
+        flags: settings.geoip_db.is_some(),
+        blur_thumbnails: settings.blur_thumbnails,
+        accounts: settings.accounts,
+        read_only: maintenance.is_read_only(),
+        announcement: maintenance.announcement(),
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+        quote_links: &quote_links,
+        image_types: settings.image_type_labels(),
+        image_accept: settings.image_accept(),
+        reply_summaries: repo.reply_summaries(&thread_ids),
+        preview_chars: settings.preview_chars,
+        canonical: canonical_url(&settings, "/", page_number),
+    };
+
+    match tmpl.render() {
+        Ok(rendered) => HttpResponse::Ok().content_type("text/html").body(rendered),
+        Err(e) => {
+            error!("Template rendering error: {}", e);
+            HttpResponse::InternalServerError().body("Error rendering page")
+        }
+    }
+}
+
+// Every thread as a tile in bump order, optionally narrowed down to titles
+// matching ?q=. The search runs before paging, so the pages count matches only.
+async fn catalog(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    image_files: web::Data<ImageFiles>,
+    query: web::Query<CatalogParams>,
+) -> impl Responder {
+    let search = query.q.as_deref().unwrap_or("").trim();
+    let page_size = CATALOG_PER_PAGE;
+
+    let matching: Option<Vec<Thread>> = (!search.is_empty()).then(|| {
+        let needle = search.to_lowercase();
+        repo.list_threads(ThreadOrder::Bump, 0, usize::MAX)
+            .into_iter()
+            .filter(|thread| {
+                let searched = if settings.titles_enabled { &thread.title } else { &thread.message };
+                searched.to_lowercase().contains(&needle)
+            })
+            .collect()
+    });
+    let mut total_threads = matching.as_ref().map_or_else(|| repo.count_threads(), Vec::len);
+    if let Some(keep) = settings.kept_threads().filter(|_| search.is_empty()) {
+        total_threads = total_threads.min(keep + repo.list_sticky().len());
+    }
+    let total_pages = total_threads.div_ceil(page_size).max(1) as i32;
+    let page_number = query.page.unwrap_or(1).clamp(1, total_pages);
+
+    let start_index = (page_number - 1) as usize * page_size;
+    let mut threads: Vec<Thread> = match matching {
+        Some(matching) => matching.into_iter().skip(start_index).take(page_size).collect(),
+        None => repo.list_threads(ThreadOrder::Bump, start_index, page_size),
+    };
+    image_files.check_threads(&mut threads);
+    let thread_ids: Vec<i32> = threads.iter().map(|thread| thread.id).collect();
+
+    let tmpl = CatalogTemplate {
+        threads: &threads,
+        current_page: page_number,
+        total_pages,
+        query: search,
+        tag: None,
+        listing: "/catalog".to_string(),
+        titles_enabled: settings.titles_enabled,
+        blur_thumbnails: settings.blur_thumbnails,
+        read_only: maintenance.is_read_only(),
+        announcement: maintenance.announcement(),
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+        reply_summaries: repo.reply_summaries(&thread_ids),
+        canonical: canonical_url(&settings, "/catalog", page_number),
+    };
+
+    match tmpl.render() {
+        Ok(rendered) => HttpResponse::Ok().content_type("text/html").body(rendered),
+        Err(e) => {
+            error!("Template rendering error: {}", e);
+            HttpResponse::InternalServerError().body("Error rendering page")
+        }
+    }
+}
+
+// The threads carrying one tag, as catalog tiles in bump order
+async fn tagged(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    image_files: web::Data<ImageFiles>,
+    path: web::Path<String>,
+    query: web::Query<CatalogParams>,
+) -> impl Responder {
+    let tag = match tags::normalize(&path) {
+        Some(tag) if settings.max_tags > 0 => tag,
+        _ => return HttpResponse::NotFound().body("Not found"),
+    };
+    let page_size = CATALOG_PER_PAGE;
+    let matching = repo.list_tagged(&tag);
+    let total_pages = matching.len().div_ceil(page_size).max(1) as i32;
+    let page_number = query.page.unwrap_or(1).clamp(1, total_pages);
+
+    let start_index = (page_number - 1) as usize * page_size;
+    let mut threads: Vec<Thread> = matching.into_iter().skip(start_index).take(page_size).collect();
+    image_files.check_threads(&mut threads);
+    let thread_ids: Vec<i32> = threads.iter().map(|thread| thread.id).collect();
+
+    let listing = format!("/tag/{}", tag);
+    let tmpl = CatalogTemplate {
+        threads: &threads,
         current_page: page_number,
         total_pages,
+        query: "",
+        tag: Some(&tag),
+        titles_enabled: settings.titles_enabled,
+        blur_thumbnails: settings.blur_thumbnails,
+        read_only: maintenance.is_read_only(),
+        announcement: maintenance.announcement(),
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+        reply_summaries: repo.reply_summaries(&thread_ids),
+        canonical: canonical_url(&settings, &listing, page_number),
+        listing,
     };
 
     match tmpl.render() {
@@ -131,6 +1061,16 @@ async fn homepage(
     }
 }
 
+// Canonical link of a listing page: sort order and search are left out, so
+// every variant of a page points search engines at the plain one
+fn canonical_url(settings: &Settings, path: &str, page: i32) -> String {
+    if page > 1 {
+        settings.absolute_url(&format!("{}?page={}", path, page))
+    } else {
+        settings.absolute_url(path)
+    }
+}
+
 // Fetch all threads from sled
 fn get_all_threads(db: &Db) -> Vec<Thread> {
     db.scan_prefix(b"thread_")
@@ -144,32 +1084,182 @@ fn get_all_threads(db: &Db) -> Vec<Thread> {
         .collect()
 }
 
-// Count total number of threads in sled
+// Fetch one page of threads in bump order via a ranged scan over the bump index
+fn get_threads_page(db: &Db, offset: usize, limit: usize) -> Vec<Thread> {
+    db.scan_prefix(b"bump_")
+        .values()
+        .skip(offset)
+        .take(limit)
+        .filter_map(|res| res.ok().map(|value| decode_counter(&value)))
+        .filter_map(|thread_id| get_thread(db, thread_id))
+        .collect()
+}
+
+// Like get_threads_page, with the threads in `pinned` skipped before counting
+// the offset. Only their ids are read, from the bump index.
+fn get_unpinned_page(db: &Db, pinned: &[i32], offset: usize, limit: usize) -> Vec<Thread> {
+    db.scan_prefix(b"bump_")
+        .values()
+        .filter_map(|res| res.ok().map(|value| decode_counter(&value)))
+        .filter(|thread_id| !pinned.contains(thread_id))
+        .skip(offset)
+        .take(limit)
+        .filter_map(|thread_id| get_thread(db, thread_id))
+        .collect()
+}
+
+// Index entry of a pinned thread
+fn sticky_key(thread_id: i32) -> Vec<u8> {
+    format!("sticky_{}", thread_id).into_bytes()
+}
+
+// Pinned threads, most recently bumped first. Entries of threads that are gone
+// or whose pin has run out are dropped from the index on the way.
+fn get_sticky_threads(db: &Db) -> Vec<Thread> {
+    let mut threads = Vec::new();
+    for key in db.scan_prefix(b"sticky_").keys().flatten() {
+        let thread = std::str::from_utf8(&key[b"sticky_".len()..])
+            .ok()
+            .and_then(|id| id.parse().ok())
+            .and_then(|id| get_thread(db, id));
+        match thread.filter(Thread::is_sticky) {
+            Some(thread) => threads.push(thread),
+            None => {
+                let _ = db.remove(&key);
+            }
+        }
+    }
+    threads.sort_by_key(|thread| std::cmp::Reverse((thread.last_updated, thread.id)));
+    threads
+}
+
+// Fetch the threads that come after the given (last_updated, id) in bump order,
+// or from the top without one. Unlike an offset, the position doesn't shift
+// when threads are created or bumped in between.
+fn get_threads_after(db: &Db, after: Option<(i64, i32)>, limit: usize) -> Vec<Thread> {
+    let start = after.map_or_else(|| b"bump_".to_vec(), |(last_updated, id)| bump_key(last_updated, id));
+    db.range(start.clone()..)
+        .filter_map(Result::ok)
+        .skip_while(|(key, _)| key.as_ref() == start.as_slice())
+        .take_while(|(key, _)| key.starts_with(b"bump_"))
+        .take(limit)
+        .filter_map(|(_, value)| get_thread(db, decode_counter(&value)))
+        .collect()
+}
+
+// Fetch one page of threads newest first. Thread ids only ever go up, so this
+// walks down from the thread counter instead of needing an index of its own.
+fn get_threads_by_creation(db: &Db, offset: usize, limit: usize) -> Vec<Thread> {
+    let newest = db
+        .get(THREAD_COUNTER_KEY)
+        .ok()
+        .flatten()
+        .map(|value| decode_counter(&value))
+        .unwrap_or(0);
+    (1..=newest)
+        .rev()
+        .filter_map(|thread_id| get_thread(db, thread_id))
+        .skip(offset)
+        .take(limit)
+        .collect()
+}
+
+// Thread records read on this thread, so tests can check what a page costs
+#[cfg(test)]
+thread_local! {
+    static THREAD_READS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// Fetch a single thread from sled
+fn get_thread(db: &Db, thread_id: i32) -> Option<Thread> {
+    #[cfg(test)]
+    THREAD_READS.with(|reads| reads.set(reads.get() + 1));
+    db.get(format!("thread_{}", thread_id))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok())
+}
+
+// Fetch a single reply from sled by its key, without scanning the thread
+fn get_reply(db: &Db, thread_id: i32, reply_id: i32) -> Option<Reply> {
+    db.get(reply_key(thread_id, reply_id))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok())
+}
+
+// Count total number of threads in sled, from the count kept with the records
 fn count_threads(db: &Db) -> i32 {
-    db.scan_prefix(b"thread_").count() as i32
+    db.get(THREAD_COUNT_KEY).ok().flatten().map_or(0, |value| decode_counter(&value))
 }
 
 // Thread viewing handler
 async fn view_thread(
-    db: web::Data<Arc<Db>>,
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    maintenance: web::Data<Maintenance>,
+    image_files: web::Data<ImageFiles>,
     path: web::Path<(i32,)>,
+    query: web::Query<ThreadParams>,
 ) -> impl Responder {
     let thread_id = path.into_inner().0;
-    let thread_key = format!("thread_{}", thread_id).into_bytes();
-    let thread: Option<Thread> = db.get(&thread_key).ok().flatten().and_then(|value| {
-        serde_json::from_slice(&value).ok()
-    });
+    let mut thread = match repo.get_thread(thread_id) {
+        Some(thread) => thread,
+        None => return HttpResponse::NotFound().body("Thread not found"),
+    };
+    image_files.check_threads(std::slice::from_mut(&mut thread));
 
-    if thread.is_none() {
-        return HttpResponse::NotFound().body("Thread not found");
+    let replies = repo.list_replies(thread_id);
+    let omitted = match settings.thread_last_replies {
+        0 => 0,
+        _ if query.all => 0,
+        last => replies.len().saturating_sub(last),
+    };
+    let order = ReplyOrder::parse(query.order.as_deref());
+    let mut shown = replies[omitted..].to_vec();
+    // The omitted replies are the oldest either way, so newest first only reverses what's shown
+    if order == ReplyOrder::NewestFirst {
+        shown.reverse();
     }
-
-    let thread = thread.unwrap();
-    let replies = get_replies(&db, thread_id);
+    image_files.check_replies(&mut shown);
+    let messages = std::iter::once(thread.message.as_str()).chain(shown.iter().map(|reply| reply.message.as_str()));
+    let quote_links = resolve_quotes(repo.get_ref(), &settings, messages.map(|message| (thread_id, message)));
 
     let tmpl = ThreadTemplate {
         thread: &thread,
-        replies: &replies,
+        replies: &shown,
+        poster_count: count_posters(&thread, &replies),
+        show_sage: settings.show_sage,
+        global_numbers: settings.global_post_numbers,
+        flags: settings.geoip_db.is_some(),
+        blur_thumbnails: settings.blur_thumbnails,
+        allow_image: settings.allow_image_reply,
+        require_message: settings.reply_rule.requires(Field::Message),
+        require_image: settings.reply_rule.requires(Field::Image),
+        accounts: settings.accounts,
+        read_only: maintenance.is_read_only(),
+        announcement: maintenance.announcement(),
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+        quote_links: &quote_links,
+        image_types: settings.image_type_labels(),
+        image_accept: settings.image_accept(),
+        quote: query
+            .quote
+            .filter(|&number| {
+                (settings.global_post_numbers && number == thread.id) || replies.iter().any(|reply| reply.id == number)
+            })
+            .map(format::quote_text)
+            .unwrap_or_default(),
+        omitted,
+        show_all: query.all,
+        order: order.as_str(),
+        bumps_left: match settings.bump_limit {
+            0 => None,
+            limit => Some(limit.saturating_sub(replies.len())),
+        },
+        closed: thread_state::can_reply(Some(&thread), settings.thread_ttl, Utc::now().timestamp()).err(),
+        canonical: settings.absolute_url(&format!("/thread/{}", thread_id)),
     };
 
     match tmpl.render() {
@@ -181,143 +1271,565 @@ async fn view_thread(
     }
 }
 
-// Create thread handler with image upload
-async fn create_thread(
-    db: web::Data<Arc<Db>>,
-    mut payload: Multipart,
-) -> Result<HttpResponse, Error> {
-    let mut title = String::new();
-    let mut message = String::new();
-    let mut image_url: Option<String> = None;
-
-    while let Some(item) = payload.next().await {
-        let mut field = item?;
-        let content_disposition = field.content_disposition();
-
-        let name = if let Some(name) = content_disposition.get_name() {
-            name
-        } else {
-            continue;
-        };
+// Look up what the >>N links in the given (thread, message) pairs point to
+fn resolve_quotes<'a>(
+    repo: &dyn Repository,
+    settings: &Settings,
+    posts: impl IntoIterator<Item = (i32, &'a str)>,
+) -> QuoteLinks {
+    QuoteLinks::resolve(
+        &settings.base_path,
+        posts,
+        |number| repo.post_thread(number),
+        |thread_id, reply_id| repo.reply_exists(thread_id, reply_id),
+        |thread_id| repo.get_thread(thread_id).is_some(),
+    )
+    .with_embeds(settings.media_embeds)
+}
 
-        match name {
-            "title" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    title.push_str(&String::from_utf8_lossy(&data));
-                }
-            }
-            "message" => {
-                while let Some(chunk) = field.next().await {
-                    let data = chunk?;
-                    message.push_str(&String::from_utf8_lossy(&data));
-                }
-            }
-            "image" => {
-                // Handle image upload
-                if let Some(filename) = content_disposition.get_filename() {
-                    // Skip processing if filename is empty
-                    if filename.trim().is_empty() {
-                        continue;
-                    }
+// Plain 404 for missing static assets, instead of an error bubbling up from the file service
+async fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Not found")
+}
 
-                    // Validate file extension
-                    if !filename.to_lowercase().ends_with(".jpg") && !filename.to_lowercase().ends_with(".jpeg") {
-                        return Ok(HttpResponse::BadRequest().body("Only JPEG images are allowed"));
-                    }
+// Favicon handler; browsers request /favicon.ico on every page regardless of markup
+async fn favicon(req: HttpRequest, settings: web::Data<Settings>) -> HttpResponse {
+    let content_type = if settings.favicon.to_lowercase().ends_with(".png") {
+        mime::IMAGE_PNG
+    } else {
+        "image/x-icon".parse().expect("image/x-icon is a valid MIME type")
+    };
 
-                    // Generate a unique filename
-                    let unique_id = Uuid::new_v4().to_string();
-                    let sanitized_filename = format!("{}.jpg", unique_id);
-                    let filepath = format!("{}{}", UPLOAD_DIR, sanitized_filename);
+    match fs::NamedFile::open_async(format!("{}{}", STATIC_DIR, settings.favicon)).await {
+        Ok(file) => file.set_content_type(content_type).into_response(&req),
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
 
-                    // Save the file with a move closure to capture ownership
-                    let mut f = web::block(move || std::fs::File::create(&filepath)).await??;
+// Distinct posters in a thread; legacy posts without a hash aren't counted
+fn count_posters(thread: &Thread, replies: &[Reply]) -> usize {
+    std::iter::once(thread.poster_hash.as_deref())
+        .chain(replies.iter().map(|reply| reply.poster_hash.as_deref()))
+        .flatten()
+        .collect::<std::collections::HashSet<_>>()
+        .len()
+}
 
-                    while let Some(chunk) = field.next().await {
-                        let data = chunk?;
-                        f = web::block(move || f.write_all(&data).map(|_| f)).await??;
-                    }
+// Map a stored upload filename to the MIME type of its normalized format
+fn upload_mime(filename: &str) -> Option<mime::Mime> {
+    let extension = filename.rsplit_once('.')?.1;
+    ImageType::parse(extension)?.mime().parse().ok()
+}
 
-                    image_url = Some(format!("/uploads/{}", sanitized_filename));
-                }
-            }
-            _ => {}
-        }
-    }
+// Uploaded image handler with exact Content-Type and immutable caching
+async fn serve_upload(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    serve_image(&req, UPLOAD_DIR, &path.into_inner()).await
+}
 
-    // Ensure that title and message are not empty
-    if title.trim().is_empty() || message.trim().is_empty() {
-        return Ok(HttpResponse::BadRequest().body("Title and Message cannot be empty"));
-    }
+// Thumbnails are named after their upload and never rewritten either
+async fn serve_thumbnail(req: HttpRequest, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    serve_image(&req, THUMB_DIR, &path.into_inner()).await
+}
 
-    let thread_id = count_threads(&db) + 1;
-    let thread = Thread {
-        id: thread_id,
-        title: title.trim().to_string(),
-        message: message.trim().to_string(),
-        last_updated: Utc::now().timestamp(),
-        image_url,
+async fn serve_image(req: &HttpRequest, dir: &str, filename: &str) -> Result<HttpResponse, Error> {
+    // Only plain generated names are served; this also rules out path traversal
+    let is_safe_name = !filename.starts_with('.')
+        && filename
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    let content_type = match upload_mime(filename) {
+        Some(content_type) if is_safe_name => content_type,
+        _ => return Ok(HttpResponse::NotFound().body("File not found")),
     };
 
-    let key = format!("thread_{}", thread_id).into_bytes();
-    let value = serde_json::to_vec(&thread).expect("Failed to serialize thread");
+    let file = match fs::NamedFile::open_async(format!("{}{}", dir, filename)).await {
+        Ok(file) => file,
+        Err(_) => return Ok(HttpResponse::NotFound().body("File not found")),
+    };
 
-    if db.insert(key, value).is_ok() {
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", "/"))
-            .finish())
-    } else {
-        error!("Failed to insert thread into sled db");
-        Ok(HttpResponse::InternalServerError().body("Failed to create thread"))
+    // The name is unique and the file behind it never changes, so the name
+    // itself is a strong ETag and a client holding it gets a bodiless 304
+    let etag = header::EntityTag::new_strong(filename.to_string());
+    let cache_control = header::CacheControl(vec![
+        header::CacheDirective::Public,
+        header::CacheDirective::MaxAge(UPLOAD_CACHE_MAX_AGE),
+        header::CacheDirective::Extension("immutable".to_string(), None),
+    ]);
+    let not_modified = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(header::ETag(etag))
+            .insert_header(cache_control)
+            .finish());
     }
+
+    let mut response = file.use_etag(false).set_content_type(content_type).into_response(req);
+    response.headers_mut().insert(header::ETAG, etag.to_string().parse().expect("ETag value is valid"));
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        cache_control.to_string().parse().expect("Cache-Control value is valid"),
+    );
+    Ok(response)
 }
 
-// Create reply handler without image upload
-async fn create_reply(
+// Create thread handler with image upload. Every answer says where the poster
+// stands with THREAD_COOLDOWN in X-RateLimit-* headers, so clients can wait
+// without being turned away first.
+async fn create_thread(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
     db: web::Data<Arc<Db>>,
-    form: web::Form<ReplyForm>,
-) -> Result<HttpResponse, Error> {
-    let parent_id = form.parent_id;
-    let message = form.message.trim().to_string();
+    settings: web::Data<Settings>,
+    cooldowns: web::Data<Cooldowns>,
+    payload: Multipart,
+) -> HttpResponse {
+    let ip = client_ip(&req, &settings);
+    let mut response = start_thread(&req, repo, db, &settings, &cooldowns, &ip, payload).await;
+    cooldowns.threads.add_rate_limit_headers(&ip, response.headers_mut());
+    response
+}
+
+async fn start_thread(
+    req: &HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: &Settings,
+    cooldowns: &Cooldowns,
+    ip: &str,
+    mut payload: Multipart,
+) -> HttpResponse {
+    // Checked before the body is read, so a throttled poster can't make us store
+    // an image. A poster who just started a thread may be sending it again, so
+    // their form is still read to check for that before they are turned away.
+    let throttled = cooldowns.threads.remaining(ip);
+    if let Some(seconds) = throttled {
+        if settings.duplicate_thread_window == 0 {
+            return cooldown::too_many_requests(seconds, "starting another thread");
+        }
+    }
+
+    let poster = poster_hash(req, settings);
+    if let Err(e) = upload::check_declared_length(req, settings, &db, poster.as_deref()) {
+        return e.to_response();
+    }
+    let mut form = match PostForm::read(&mut payload, settings, &db, None, poster.as_deref()).await {
+        Ok(form) => form,
+        Err(e) => return e.to_response(),
+    };
+
+    let image_sha256 = form.image.as_ref().map(|meta| meta.sha256.as_str());
+    let key = RecentThreads::key(ip, &form.title, &form.message, image_sha256);
+    if let Some(thread) = cooldowns.recent_threads.find(&key).and_then(|id| repo.get_thread(id)) {
+        form.discard(&db);
+        return HttpResponse::SeeOther()
+            .append_header(("Location", settings.url(&format!("/thread/{}", thread.id))))
+            .finish();
+    }
+    if let Some(seconds) = throttled {
+        form.discard(&db);
+        return cooldown::too_many_requests(seconds, "starting another thread");
+    }
+
+    let new_thread = match validate_thread(req, &db, settings, &form) {
+        Ok(new_thread) => new_thread,
+        Err(errors) => {
+            form.discard(&db);
+            return validation::error_page(settings, &errors, "/");
+        }
+    };
+
+    match repo.create_thread(new_thread) {
+        Ok(thread) => {
+            form.commit();
+            cooldowns.threads.record(ip);
+            cooldowns.recent_threads.record(key, thread.id);
+            prune_pages(&db, settings).await;
+            let mut response = HttpResponse::SeeOther()
+                .append_header(("Location", post_redirect(settings, &thread.email, thread.id)))
+                .finish();
+            if let Some(meta) = &form.image {
+                meta.add_received_header(response.headers_mut());
+            }
+            response
+        }
+        Err(e) => {
+            error!("Failed to insert thread into sled db: {}", e);
+            form.discard(&db);
+            HttpResponse::InternalServerError().body("Failed to create thread")
+        }
+    }
+}
 
-    // Ensure that message is not empty
-    if message.is_empty() {
-        return Ok(HttpResponse::BadRequest().body("Message cannot be empty"));
+// Check a submitted thread form and turn it into the thread to store
+fn validate_thread(
+    req: &HttpRequest,
+    db: &Db,
+    settings: &Settings,
+    form: &PostForm,
+) -> Result<NewThread, Vec<ValidationError>> {
+    let errors = validation::thread_errors(settings, &form.title, &form.message, form.image.is_some());
+    if !errors.is_empty() {
+        return Err(errors);
     }
 
-    let reply_id = count_replies(&db, parent_id) + 1;
-    let reply = Reply {
-        id: reply_id,
+    let filter = &settings.word_filter;
+    let blocked = |_| vec![ValidationError::BlockedWord];
+    let title = match settings.titles_enabled {
+        true => filter.apply(form.title.trim()).map_err(blocked)?,
+        false => String::new(),
+    };
+    let message = filter.apply(form.message.trim()).map_err(blocked)?;
+
+    Ok(NewThread {
+        title,
         message,
+        image_url: form.image_url(),
+        thumbnails: form.thumbnails(),
+        image_size: form.image_size(),
+        email: post_options::sanitize_email(&form.email),
+        poster_hash: poster_hash(req, settings),
+        username: accounts::current_user(req, db, settings),
+        tags: tags::parse(&form.tags, settings.max_tags),
+        country: geoip::poster_country(req, settings),
+        nsfw: form.nsfw,
+    })
+}
+
+// Prune the threads a new thread pushed past MAX_PAGES
+async fn prune_pages(db: &Arc<Db>, settings: &Settings) {
+    let keep = match settings.kept_threads() {
+        Some(keep) => keep,
+        None => return,
+    };
+    let (db, mode) = (db.clone(), settings.prune_mode);
+    if let Err(e) = web::block(move || expiry::prune_past_limit(&db, keep, mode, false)).await {
+        error!("Failed to prune threads past MAX_PAGES: {}", e);
+    }
+}
+
+// Store a new thread under the next thread id together with its bump index entry.
+// With global post numbers the id is the next post number instead.
+fn insert_thread(db: &Db, new_thread: NewThread, global_numbers: bool) -> TransactionResult<Thread, ()> {
+    db.transaction(|tx| {
+        let thread_id = if global_numbers {
+            post_numbers::next_number(tx, |number| number)?
+        } else {
+            tx.get(THREAD_COUNTER_KEY)?.map(|value| decode_counter(&value)).unwrap_or(0) + 1
+        };
+        let thread = new_thread.to_thread(thread_id, Utc::now().timestamp());
+
+        tx.insert(
+            format!("thread_{}", thread_id).into_bytes(),
+            serde_json::to_vec(&thread).expect("Failed to serialize thread"),
+        )?;
+        tx.insert(THREAD_COUNTER_KEY, &thread_id.to_be_bytes())?;
+        adjust_thread_count(tx, 1)?;
+        tx.insert(bump_key(thread.last_updated, thread_id), &thread_id.to_be_bytes())?;
+        tags::index(tx, &thread)?;
+
+        Ok(thread)
+    })
+}
+
+// Create reply handler with optional image upload
+async fn create_reply(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    post_reply(&req, repo.get_ref(), &db, &settings, &mut payload, None, |_, _, location| {
+        HttpResponse::SeeOther()
+            .append_header(("Location", location))
+            .finish()
+    })
+    .await
+}
+
+// The reply form posted by script.js: stores the reply just like create_reply,
+// then answers with the new reply's HTML for the page to append instead of a
+// redirect. The thread comes from the path, not the form's parent_id.
+async fn create_reply_fragment(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    mut payload: Multipart,
+) -> HttpResponse {
+    let thread_id = path.into_inner();
+    post_reply(&req, repo.get_ref(), &db, &settings, &mut payload, Some(thread_id), |thread_id, reply, _| {
+        let thread = match repo.get_thread(thread_id) {
+            Some(thread) => thread,
+            None => return HttpResponse::NotFound().body("Thread not found"),
+        };
+        let quote_links = resolve_quotes(repo.get_ref(), &settings, [(thread_id, reply.message.as_str())]);
+        let tmpl = ReplyTemplate {
+            thread: &thread,
+            reply,
+            show_sage: settings.show_sage,
+            global_numbers: settings.global_post_numbers,
+            flags: settings.geoip_db.is_some(),
+            blur_thumbnails: settings.blur_thumbnails,
+            base_path: &settings.base_path,
+            quote_links: &quote_links,
+        };
+        match tmpl.render() {
+            Ok(rendered) => HttpResponse::Created().content_type("text/html").body(rendered),
+            Err(e) => {
+                error!("Template rendering error: {}", e);
+                HttpResponse::InternalServerError().body("Error rendering reply")
+            }
+        }
+    })
+    .await
+}
+
+// Read, check and store a reply form, then answer with `respond`, which gets
+// the thread id, the stored reply and where the browser should go next.
+// `thread_id` overrides the form's parent_id when given.
+async fn post_reply(
+    req: &HttpRequest,
+    repo: &dyn Repository,
+    db: &Db,
+    settings: &Settings,
+    payload: &mut Multipart,
+    thread_id: Option<i32>,
+    respond: impl FnOnce(i32, &Reply, String) -> HttpResponse,
+) -> HttpResponse {
+    let poster = poster_hash(req, settings);
+    if let Err(e) = upload::check_declared_length(req, settings, db, poster.as_deref()) {
+        return e.to_response();
+    }
+    let image_refusal = (!settings.allow_image_reply).then_some(REPLY_IMAGES_DISABLED);
+    let mut form = match PostForm::read(payload, settings, db, image_refusal, poster.as_deref()).await {
+        Ok(form) => form,
+        Err(e) => return e.to_response(),
+    };
+    if let Some(thread_id) = thread_id {
+        form.parent_id = thread_id.to_string();
+    }
+
+    let (parent_id, new_reply) = match validate_reply(req, db, settings, &form) {
+        Ok(reply) => reply,
+        Err(errors) => {
+            form.discard(db);
+            let back = form.parent_id.trim().parse::<i32>().map_or("/".to_string(), |id| format!("/thread/{}", id));
+            return validation::error_page(settings, &errors, &back);
+        }
     };
+    let bump = should_bump(settings, new_reply.email.as_deref());
+    let location = post_redirect(settings, &new_reply.email, parent_id);
 
-    let key = format!("reply_{}_{}", parent_id, reply_id).into_bytes();
-    let value = serde_json::to_vec(&reply).expect("Failed to serialize reply");
-
-    if db.insert(key, value).is_ok() {
-        // Update thread's last_updated
-        let thread_key = format!("thread_{}", parent_id).into_bytes();
-        if let Some(thread_bytes) = db.get(&thread_key).ok().flatten() {
-            if let Ok(mut thread) = serde_json::from_slice::<Thread>(&thread_bytes) {
-                thread.last_updated = Utc::now().timestamp();
-                let updated = serde_json::to_vec(&thread).expect("Failed to serialize updated thread");
-                db.insert(thread_key, updated).ok();
+    match repo.create_reply(parent_id, new_reply, bump) {
+        Ok(reply) => {
+            form.commit();
+            if let Some(reply_flood) = req.app_data::<web::Data<ReplyFlood>>() {
+                reply_flood.record(db, parent_id);
             }
+            let mut response = respond(parent_id, &reply, location);
+            if let Some(meta) = &form.image {
+                meta.add_received_header(response.headers_mut());
+            }
+            response
+        }
+        Err(RepoError::Refused(reason)) => {
+            form.discard(db);
+            reason.to_response()
         }
+        Err(e) => {
+            error!("Failed to insert reply into sled db: {}", e);
+            form.discard(db);
+            HttpResponse::InternalServerError().body("Failed to post reply")
+        }
+    }
+}
+
+// Check a submitted reply form, returning the thread it goes to and the reply
+fn validate_reply(
+    req: &HttpRequest,
+    db: &Db,
+    settings: &Settings,
+    form: &PostForm,
+) -> Result<(i32, NewReply), Vec<ValidationError>> {
+    let parent_id = form.parent_id.trim().parse::<i32>().ok();
+    let mut errors = Vec::new();
+    if parent_id.is_none() {
+        errors.push(ValidationError::InvalidThreadId);
+    }
+    errors.extend(validation::reply_errors(settings, &form.message, form.image.is_some()));
+    let parent_id = match parent_id {
+        Some(parent_id) if errors.is_empty() => parent_id,
+        _ => return Err(errors),
+    };
+    let message = settings
+        .word_filter
+        .apply(form.message.trim())
+        .map_err(|_| vec![ValidationError::BlockedWord])?;
+
+    Ok((
+        parent_id,
+        NewReply {
+            message,
+            image_url: form.image_url(),
+            thumbnails: form.thumbnails(),
+            image_size: form.image_size(),
+            email: post_options::sanitize_email(&form.email),
+            poster_hash: poster_hash(req, settings),
+            username: accounts::current_user(req, db, settings),
+            country: geoip::poster_country(req, settings),
+            nsfw: form.nsfw,
+        },
+    ))
+}
 
-        Ok(HttpResponse::SeeOther()
-            .append_header(("Location", format!("/thread/{}", parent_id)))
-            .finish())
+// Where the browser goes after posting: the thread with "noko", the board otherwise
+fn post_redirect(settings: &Settings, email: &Option<String>, thread_id: i32) -> String {
+    if PostOptions::parse(email.as_deref()).noko {
+        settings.url(&format!("/thread/{}", thread_id))
     } else {
-        error!("Failed to insert reply into sled db");
-        Ok(HttpResponse::InternalServerError().body("Failed to post reply"))
+        settings.url("/")
     }
 }
 
-// Fetch replies for a thread from sled
+// Whether a reply with this email/options field should bump its thread
+fn should_bump(settings: &Settings, email: Option<&str>) -> bool {
+    settings.bump_on_reply && !PostOptions::parse(email).sage
+}
+
+#[derive(Debug)]
+enum ReplyError {
+    Refused(ReplyRefusal),
+}
+
+// Store a reply, advance the thread's reply counter and count and bump the thread
+// in one transaction, so a crash or a concurrent reply can never leave them
+// disagreeing. A saged reply (bump = false) leaves the thread's position untouched,
+// and so does every reply after the first `bump_limit` (0 for no limit).
+// Threads that take no replies, locked or past `thread_ttl` for example, are
+// refused with the reason from thread_state::can_reply.
+// With global post numbers the reply id is the next post number.
+fn insert_reply(
+    db: &Db,
+    parent_id: i32,
+    new_reply: NewReply,
+    bump: bool,
+    bump_limit: usize,
+    thread_ttl: i64,
+    global_numbers: bool,
+) -> TransactionResult<Reply, ReplyError> {
+    let thread_key = format!("thread_{}", parent_id).into_bytes();
+    let counter_key = reply_counter_key(parent_id);
+    let count_key = reply_count_key(parent_id);
+
+    db.transaction(|tx| {
+        let refused = |reason| ConflictableTransactionError::Abort(ReplyError::Refused(reason));
+        let now = Utc::now().timestamp();
+        let thread: Option<Thread> = tx.get(&thread_key)?.and_then(|value| serde_json::from_slice(&value).ok());
+        thread_state::can_reply(thread.as_ref(), thread_ttl, now).map_err(refused)?;
+        let mut thread = thread.ok_or(refused(ReplyRefusal::Deleted))?;
+
+        let reply_id = if global_numbers {
+            post_numbers::next_number(tx, |_| parent_id)?
+        } else {
+            tx.get(&counter_key)?.map(|value| decode_counter(&value)).unwrap_or(0) + 1
+        };
+        let count = tx.get(&count_key)?.map(|value| decode_counter(&value)).unwrap_or(0) + 1;
+        let reply = new_reply.to_reply(reply_id, now);
+
+        tx.insert(
+            reply_key(parent_id, reply_id),
+            serde_json::to_vec(&reply).expect("Failed to serialize reply"),
+        )?;
+        tx.insert(counter_key.as_slice(), &reply_id.to_be_bytes())?;
+        tx.insert(count_key.as_slice(), &count.to_be_bytes())?;
+
+        if bump && (bump_limit == 0 || count as usize <= bump_limit) {
+            tx.remove(bump_key(thread.last_updated, thread.id))?;
+            thread.last_updated = now;
+            tx.insert(bump_key(thread.last_updated, thread.id), &thread.id.to_be_bytes())?;
+            tx.insert(
+                thread_key.as_slice(),
+                serde_json::to_vec(&thread).expect("Failed to serialize updated thread"),
+            )?;
+        }
+
+        Ok(reply)
+    })
+}
+
+// Key holding the highest thread id handed out
+const THREAD_COUNTER_KEY: &[u8] = b"counter_thread";
+
+// Key holding how many threads are stored, so paging the index needs no scan
+const THREAD_COUNT_KEY: &[u8] = b"count_thread";
+
+// Change the thread count along with a thread record written or removed in `tx`
+fn adjust_thread_count(tx: &TransactionalTree, change: i32) -> Result<(), UnabortableTransactionError> {
+    let count = tx.get(THREAD_COUNT_KEY)?.map_or(0, |value| decode_counter(&value));
+    tx.insert(THREAD_COUNT_KEY, &(count + change).max(0).to_be_bytes())?;
+    Ok(())
+}
+
+// Bump index key: fixed-width inverted timestamp and id, so a forward scan over the
+// bump_ prefix yields the most recently bumped thread first (newest id on ties)
+fn bump_key(last_updated: i64, thread_id: i32) -> Vec<u8> {
+    format!("bump_{:020}_{:010}", i64::MAX - last_updated, i32::MAX - thread_id).into_bytes()
+}
+
+// Key of a reply's record; reply {rid} of thread {tid} is always here
+fn reply_key(parent_id: i32, reply_id: i32) -> Vec<u8> {
+    format!("reply_{}_{}", parent_id, reply_id).into_bytes()
+}
+
+// Key holding the highest reply id handed out for a thread
+fn reply_counter_key(parent_id: i32) -> Vec<u8> {
+    format!("counter_reply_{}", parent_id).into_bytes()
+}
+
+// Key holding how many replies a thread has. Without global post numbers this
+// equals the reply counter, with them reply ids have gaps.
+fn reply_count_key(parent_id: i32) -> Vec<u8> {
+    format!("count_reply_{}", parent_id).into_bytes()
+}
+
+fn decode_counter(value: &[u8]) -> i32 {
+    <[u8; 4]>::try_from(value).map(i32::from_be_bytes).unwrap_or(0)
+}
+
+// Reply counts and newest reply times for a set of threads. Usually this takes
+// three point lookups per thread (the reply count, the reply counter and the
+// reply it names) instead of a scan over every reply. When that reply is gone,
+// its poster's posts deleted for example, it falls back to scanning all of the
+// thread's replies for the newest one left.
+fn get_reply_summaries(db: &Db, thread_ids: &[i32]) -> HashMap<i32, ReplySummary> {
+    thread_ids
+        .iter()
+        .filter_map(|&thread_id| {
+            let read = |key: Vec<u8>| db.get(key).ok().flatten().map(|value| decode_counter(&value));
+            let newest = read(reply_counter_key(thread_id))?;
+            let count = read(reply_count_key(thread_id)).unwrap_or(newest);
+            let last_reply_at = get_reply(db, thread_id, newest)
+                .or_else(|| get_replies(db, thread_id).pop())
+                .map(|reply| reply.created_at);
+            let summary = ReplySummary {
+                count: count.max(0) as usize,
+                last_reply_at,
+            };
+            Some((thread_id, summary))
+        })
+        .collect()
+}
+
+// Fetch replies for a thread from sled, oldest first
 fn get_replies(db: &Db, parent_id: i32) -> Vec<Reply> {
-    db.scan_prefix(format!("reply_{}", parent_id).as_bytes())
+    let mut replies = db
+        .scan_prefix(format!("reply_{}_", parent_id).as_bytes())
         .filter_map(|res| {
             if let Ok((_, value)) = res {
                 serde_json::from_slice(&value).ok()
@@ -325,10 +1837,1094 @@ fn get_replies(db: &Db, parent_id: i32) -> Vec<Reply> {
                 None
             }
         })
-        .collect::<Vec<Reply>>()
+        .collect::<Vec<Reply>>();
+
+    // Keys sort lexicographically (reply_1_10 before reply_1_2), so order by id
+    replies.sort_by_key(|reply| reply.id);
+    replies
 }
 
-// Count total number of replies for a thread in sled
-fn count_replies(db: &Db, parent_id: i32) -> i32 {
-    db.scan_prefix(format!("reply_{}", parent_id).as_bytes()).count() as i32
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, read_body};
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn uploads_are_served_with_their_type_and_cached_forever() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("ALLOWED_IMAGE_TYPES", "jpeg,png")]);
+        let app = init_service(app(&state)).await;
+
+        let images = [(test_support::jpeg(64, 48), "image/jpeg"), (test_support::png(64, 48, 255), "image/png")];
+        for (id, (image, content_type)) in (1..).zip(images) {
+            let req = form_post("/thread", &[("title", b"Pictures"), ("message", b"A picture"), ("image", &image)])
+                .peer_addr(format!("192.0.2.{}:4000", id).parse().unwrap());
+            let resp = call_service(&app, req.to_request()).await;
+            let status = resp.status();
+            assert!(status.is_redirection(), "posting failed with {}: {:?}", status, read_body(resp).await);
+            let thread = get_thread(&state.db, id).expect("thread was not stored");
+            let image_url = thread.image_url.expect("thread has no image");
+
+            let resp = call_service(&app, TestRequest::get().uri(&image_url).to_request()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), content_type);
+            assert_eq!(
+                resp.headers().get(header::CACHE_CONTROL).unwrap(),
+                &format!("public, max-age={}, immutable", UPLOAD_CACHE_MAX_AGE)
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn uploads_without_a_known_extension_are_not_served() {
+        let _files = test_support::files().await;
+        std::fs::write(format!("{}notes.txt", UPLOAD_DIR), "hello").unwrap();
+        let state = test_support::state(&[]);
+        let app = init_service(app(&state)).await;
+
+        for uri in ["/uploads/notes.txt", "/uploads/missing.png"] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), 404, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn concurrent_replies_keep_the_count_and_bump_consistent() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Busy", "Reply here"), false).unwrap();
+
+        std::thread::scope(|scope| {
+            for worker in 0..8 {
+                let db = &db;
+                scope.spawn(move || {
+                    for n in 0..25 {
+                        let reply = test_support::new_reply(&format!("{} {}", worker, n));
+                        insert_reply(db, thread.id, reply, true, 0, 0, false).unwrap();
+                    }
+                });
+            }
+        });
+
+        let replies = get_replies(&db, thread.id);
+        let ids: Vec<i32> = replies.iter().map(|reply| reply.id).collect();
+        assert_eq!(ids, (1..=200).collect::<Vec<_>>());
+        let summary = get_reply_summaries(&db, &[thread.id])[&thread.id];
+        assert_eq!(summary.count, 200);
+
+        // The thread was bumped to its newest reply and has exactly one index entry
+        let stored = get_thread(&db, thread.id).unwrap();
+        let newest = replies.iter().map(|reply| reply.created_at).max().unwrap();
+        assert_eq!(stored.last_updated, newest);
+        let entries: Vec<_> = db.scan_prefix(b"bump_").map(|res| res.unwrap().0).collect();
+        assert_eq!(entries, vec![sled::IVec::from(bump_key(stored.last_updated, thread.id))]);
+    }
+
+    // A page of summaries against the per-thread reply scans they replaced. A
+    // benchmark, not a check: cargo test --release -- --ignored --nocapture summaries
+    #[test]
+    #[ignore]
+    fn bench_reply_summaries_against_per_thread_scans() {
+        let db = test_support::temp_db();
+        let message = "A reply of some length. ".repeat(20);
+        let mut thread_ids = Vec::new();
+        for n in 0..15 {
+            let thread = insert_thread(&db, test_support::new_thread(&format!("Thread {}", n), "Opening"), false);
+            let thread = thread.unwrap();
+            for _ in 0..200 {
+                insert_reply(&db, thread.id, test_support::new_reply(&message), true, 0, 0, false).unwrap();
+            }
+            thread_ids.push(thread.id);
+        }
+        let scanned = |db: &Db, thread_ids: &[i32]| -> HashMap<i32, ReplySummary> {
+            thread_ids
+                .iter()
+                .map(|&thread_id| {
+                    let replies = get_replies(db, thread_id);
+                    let last_reply_at = replies.iter().map(|reply| reply.created_at).max();
+                    (thread_id, ReplySummary { count: replies.len(), last_reply_at })
+                })
+                .collect()
+        };
+        let rounds = 100;
+
+        let started = std::time::Instant::now();
+        for _ in 0..rounds {
+            std::hint::black_box(get_reply_summaries(&db, &thread_ids));
+        }
+        let batched = started.elapsed() / rounds;
+        let started = std::time::Instant::now();
+        for _ in 0..rounds {
+            std::hint::black_box(scanned(&db, &thread_ids));
+        }
+        let per_thread = started.elapsed() / rounds;
+
+        println!("15 threads of 200 replies: batched {:?}, per-thread scans {:?}", batched, per_thread);
+    }
+
+    #[actix_web::test]
+    async fn pinned_threads_fill_the_first_page_without_shortening_later_ones() {
+        let state = test_support::state(&[("THREADS_PER_PAGE", "2")]);
+        // Thread 5 is the newest, thread 1 would come last without its pin
+        for n in 1..=5 {
+            insert_thread(&state.db, test_support::new_thread(&format!("Thread-{}", n), "Opening"), false).unwrap();
+        }
+        moderation::set_sticky(&state.db, 1, Some(Sticky::Forever)).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let mut pages = Vec::new();
+        for page in 1..=3 {
+            let body = call_and_read_body(&app, TestRequest::get().uri(&format!("/?page={}", page)).to_request()).await;
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let mut shown: Vec<(usize, i32)> =
+                (1..=5).filter_map(|n| body.find(&format!("Thread-{}<", n)).map(|at| (at, n))).collect();
+            shown.sort();
+            pages.push(shown.into_iter().map(|(_, n)| n).collect::<Vec<_>>());
+        }
+        assert_eq!(pages, [vec![1, 5], vec![4, 3], vec![2]]);
+    }
+
+    #[test]
+    fn the_thread_count_follows_new_deleted_and_merged_threads() {
+        let db = test_support::temp_db();
+        crate::migrations::run(&db).unwrap();
+        assert_eq!(count_threads(&db), 0);
+        for n in 0..4 {
+            insert_thread(&db, test_support::new_thread(&format!("Thread {}", n), "Opening"), false).unwrap();
+        }
+        assert_eq!(count_threads(&db), 4);
+
+        expiry::delete_thread(&db, 1, i64::MAX).unwrap();
+        // Already gone, so nothing more to take off
+        expiry::delete_thread(&db, 1, i64::MAX).unwrap();
+        assert_eq!(count_threads(&db), 3);
+        moderation::merge_threads(&db, 2, 3, false).unwrap();
+        assert_eq!(count_threads(&db), 2);
+        assert_eq!(count_threads(&db) as usize, get_all_threads(&db).len());
+
+        moderation::wipe(&db, false).unwrap();
+        assert_eq!(count_threads(&db), 0);
+    }
+
+    // The homepage must read only the threads it shows, not deserialize every
+    // thread again to sort them
+    #[test]
+    fn reading_a_page_does_not_cost_a_full_scan() {
+        let db = test_support::temp_db();
+        for n in 0..100 {
+            insert_thread(&db, test_support::new_thread(&format!("Thread {}", n), "Opening"), false).unwrap();
+        }
+
+        THREAD_READS.with(|reads| reads.set(0));
+        let page = get_threads_page(&db, 40, 10);
+        let ids: Vec<i32> = page.iter().map(|thread| thread.id).collect();
+        assert_eq!(ids, (51..=60).rev().collect::<Vec<_>>());
+        assert_eq!(THREAD_READS.with(|reads| reads.get()), 10);
+    }
+
+    #[test]
+    fn bump_index_matches_a_full_sort_after_random_operations() {
+        let db = test_support::temp_db();
+        // xorshift, so a failure replays the same way
+        let mut seed: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut random = move |below: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % below
+        };
+
+        for _ in 0..400 {
+            let ids: Vec<i32> = get_all_threads(&db).iter().map(|thread| thread.id).collect();
+            let target = (!ids.is_empty()).then(|| ids[random(ids.len() as u64) as usize]);
+            match (random(4), target) {
+                (0, _) | (_, None) => {
+                    let thread = insert_thread(&db, test_support::new_thread("New", "Hello"), false).unwrap();
+                    test_support::backdate(&db, thread.id, 1_000_000 + random(1000) as i64);
+                }
+                (1, Some(id)) => {
+                    insert_reply(&db, id, test_support::new_reply("Bump"), true, 0, 0, false).unwrap();
+                }
+                (2, Some(id)) => {
+                    let mut sage = test_support::new_reply("No bump");
+                    sage.email = Some("sage".to_string());
+                    insert_reply(&db, id, sage, false, 0, 0, false).unwrap();
+                }
+                (_, Some(id)) => {
+                    expiry::delete_thread(&db, id, i64::MAX).unwrap();
+                }
+            }
+
+            let mut sorted = get_all_threads(&db);
+            sorted.sort_by_key(|thread| std::cmp::Reverse((thread.last_updated, thread.id)));
+            let sorted: Vec<i32> = sorted.iter().map(|thread| thread.id).collect();
+            let indexed: Vec<i32> = get_threads_page(&db, 0, usize::MAX).iter().map(|thread| thread.id).collect();
+            assert_eq!(indexed, sorted);
+            assert_eq!(db.scan_prefix(b"bump_").count(), sorted.len());
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_email_field_carries_sage_and_real_addresses() {
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Options", "Reply"), false).unwrap();
+        test_support::backdate(&state.db, thread.id, 1000);
+        let app = init_service(app(&state)).await;
+        let reply = |email: &'static str| {
+            form_post("/reply", &[("parent_id", b"1"), ("message", b"Hi"), ("email", email.as_bytes())])
+                .to_request()
+        };
+
+        assert!(call_service(&app, reply("  SAGE ")).await.status().is_redirection());
+        assert_eq!(get_thread(&state.db, thread.id).unwrap().last_updated, 1000);
+        assert!(call_service(&app, reply("anon@example.org")).await.status().is_redirection());
+        assert!(get_thread(&state.db, thread.id).unwrap().last_updated > 1000);
+        assert!(call_service(&app, reply("")).await.status().is_redirection());
+
+        let replies = get_replies(&state.db, thread.id);
+        let stored: Vec<_> = replies.iter().map(|reply| (reply.email.as_deref(), reply.saged)).collect();
+        assert_eq!(stored, [(Some("SAGE"), true), (Some("anon@example.org"), false), (None, false)]);
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert_eq!(page.matches("href=\"mailto:").count(), 1);
+        assert!(page.contains("href=\"mailto:anon@example.org\""));
+    }
+
+    #[actix_web::test]
+    async fn posts_report_the_bytes_their_image_took() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("MAX_UPLOAD_BYTES", "100000")]);
+        let app = init_service(app(&state)).await;
+        let jpeg = test_support::jpeg(80, 60);
+
+        let req = form_post("/thread", &[("title", b"Sizes"), ("message", b"Hi"), ("image", &jpeg)]);
+        let resp = call_service(&app, req.to_request()).await;
+        assert!(resp.status().is_redirection());
+        assert_eq!(resp.headers().get(upload::RECEIVED_HEADER).unwrap(), &jpeg.len().to_string());
+
+        let req = form_post("/reply", &[("parent_id", b"1"), ("message", b"Text only")]);
+        let resp = call_service(&app, req.to_request()).await;
+        assert!(resp.status().is_redirection());
+        assert!(resp.headers().get(upload::RECEIVED_HEADER).is_none());
+
+        // Refused on its Content-Length alone, before the body is read
+        let req = form_post("/reply", &[("parent_id", b"1"), ("message", b"Hi"), ("image", &jpeg)])
+            .insert_header((header::CONTENT_LENGTH, 10_000_000))
+            .peer_addr("192.0.2.9:4000".parse().unwrap());
+        assert_eq!(call_service(&app, req.to_request()).await.status(), 413);
+        assert_eq!(get_replies(&state.db, 1).len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn the_favicon_is_served_as_an_icon() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[]);
+        let app = init_service(app(&state)).await;
+        let resp = call_service(&app, TestRequest::get().uri("/favicon.ico").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "image/x-icon");
+        let expected = std::fs::read(format!("{}favicon.ico", STATIC_DIR)).unwrap();
+        assert_eq!(read_body(resp).await, expected);
+    }
+
+    #[actix_web::test]
+    async fn missing_icons_and_assets_are_plain_404s() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("FAVICON", "board.png")]);
+        let app = init_service(app(&state)).await;
+        for uri in ["/favicon.ico", "/static/missing.css"] {
+            let resp = call_service(&app, TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(resp.status(), 404, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn bump_on_reply_decides_whether_replies_bump() {
+        for (bump_on_reply, bumps) in [("true", true), ("false", false)] {
+            let state = test_support::state(&[("BUMP_ON_REPLY", bump_on_reply)]);
+            let thread = insert_thread(&state.db, test_support::new_thread("Bumps", "Reply"), false).unwrap();
+            test_support::backdate(&state.db, thread.id, 1000);
+            let app = init_service(app(&state)).await;
+
+            let req = form_post("/reply", &[("parent_id", b"1"), ("message", b"Hi")]);
+            assert!(call_service(&app, req.to_request()).await.status().is_redirection());
+            let last_updated = get_thread(&state.db, thread.id).unwrap().last_updated;
+            assert_eq!(last_updated > 1000, bumps, "BUMP_ON_REPLY={}", bump_on_reply);
+            assert_eq!(get_replies(&state.db, thread.id).len(), 1);
+        }
+    }
+
+    #[test]
+    fn sage_never_bumps_and_bump_on_reply_can_turn_bumps_off() {
+        let bumping = test_support::settings(&[]);
+        let no_bump = test_support::settings(&[("BUMP_ON_REPLY", "false")]);
+        assert!(should_bump(&bumping, None));
+        assert!(should_bump(&bumping, Some("noko")));
+        assert!(!should_bump(&bumping, Some("sage")));
+        assert!(!should_bump(&no_bump, None));
+        assert!(!should_bump(&no_bump, Some("sage")));
+    }
+
+    #[actix_web::test]
+    async fn images_stop_at_the_quota_while_text_posts_go_on() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("UPLOAD_QUOTA_BYTES", "1000000"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(app(&state)).await;
+        let jpeg = test_support::jpeg(80, 60);
+        let reply = |fields: &[(&str, &[u8])]| form_post("/reply", fields).to_request();
+
+        // Startup measured whatever other tests left in the upload directory
+        let measured = upload::usage(&state.db);
+        let req = form_post("/thread", &[("title", b"Quota"), ("message", b"Hi"), ("image", &jpeg)]);
+        assert!(call_service(&app, req.to_request()).await.status().is_redirection());
+        let stored = upload::usage(&state.db) - measured;
+        assert!(stored > 0);
+
+        // Just short of the quota, an image that doesn't fit is refused and its file goes
+        upload::set_usage(&state.db, 1_000_000 - stored + 1).unwrap();
+        let files_before = std::fs::read_dir(UPLOAD_DIR).unwrap().count();
+        let refused = call_service(&app, reply(&[("parent_id", b"1"), ("message", b"Hi"), ("image", &jpeg)])).await;
+        assert_eq!(refused.status(), 507);
+        assert_eq!(std::fs::read_dir(UPLOAD_DIR).unwrap().count(), files_before);
+        assert_eq!(upload::usage(&state.db), 1_000_000 - stored + 1);
+
+        // One byte less and it fits exactly
+        upload::set_usage(&state.db, 1_000_000 - stored).unwrap();
+        let fits = call_service(&app, reply(&[("parent_id", b"1"), ("message", b"Hi"), ("image", &jpeg)])).await;
+        assert!(fits.status().is_redirection());
+        assert_eq!(upload::usage(&state.db), 1_000_000);
+
+        let full = call_service(&app, reply(&[("parent_id", b"1"), ("message", b"Hi"), ("image", &jpeg)])).await;
+        assert_eq!(full.status(), 507);
+        let text = call_service(&app, reply(&[("parent_id", b"1"), ("message", b"Text only")])).await;
+        assert!(text.status().is_redirection());
+        assert_eq!(get_replies(&state.db, 1).len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn the_poster_count_covers_distinct_ips_only() {
+        let state = test_support::state(&[]);
+        let app = init_service(app(&state)).await;
+        let page = || async {
+            let body = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        let req = form_post("/thread", &[("title", b"Count"), ("message", b"Who is here")]);
+        assert!(call_service(&app, req.to_request()).await.status().is_redirection());
+        assert!(page().await.contains("| 1 poster |"));
+
+        for ip in ["192.0.2.1", "192.0.2.2", "192.0.2.2"] {
+            let req = form_post("/reply", &[("parent_id", b"1"), ("message", b"Me")])
+                .peer_addr(format!("{}:4000", ip).parse().unwrap());
+            assert!(call_service(&app, req.to_request()).await.status().is_redirection());
+        }
+        // Posted before poster hashes existed
+        insert_reply(&state.db, 1, test_support::new_reply("Legacy"), true, 0, 0, false).unwrap();
+        assert!(page().await.contains("| 2 posters |"));
+    }
+
+    #[actix_web::test]
+    async fn the_board_lists_threads_by_bump_or_by_creation() {
+        let state = test_support::state(&[]);
+        for title in ["First", "Second", "Third"] {
+            insert_thread(&state.db, test_support::new_thread(title, "Hi"), false).unwrap();
+        }
+        for (id, created_at) in [(1, 100), (2, 200), (3, 300)] {
+            let mut thread = get_thread(&state.db, id).unwrap();
+            thread.created_at = created_at;
+            state.db.insert(format!("thread_{}", id), serde_json::to_vec(&thread).unwrap()).unwrap();
+        }
+        // The oldest thread has the latest bump, the newest the earliest
+        for (id, bumped) in [(1, 3000), (2, 2000), (3, 1000)] {
+            test_support::backdate(&state.db, id, bumped);
+        }
+        let app = init_service(app(&state)).await;
+        let titles = |uri: &'static str| async {
+            let page = call_and_read_body(&app, TestRequest::get().uri(uri).to_request()).await;
+            let page = String::from_utf8(page.to_vec()).unwrap();
+            let titles = ["First", "Second", "Third"];
+            let mut found: Vec<_> = titles.iter().filter_map(|title| page.find(title).map(|at| (at, *title))).collect();
+            found.sort();
+            (found.into_iter().map(|(_, title)| title).collect::<Vec<_>>(), page)
+        };
+
+        let (bumped, page) = titles("/").await;
+        assert_eq!(bumped, ["First", "Second", "Third"]);
+        assert!(page.contains("<span class=\"current\">Last bump</span>"));
+        let (created, page) = titles("/?order=created").await;
+        assert_eq!(created, ["Third", "Second", "First"]);
+        assert!(page.contains("<span class=\"current\">Creation date</span>"));
+        assert_eq!(titles("/?order=sideways").await.0, bumped);
+    }
+
+    #[actix_web::test]
+    async fn image_requirements_hold_for_each_combination() {
+        let _files = test_support::files().await;
+        let jpeg = test_support::jpeg(64, 48);
+        for require_op in ["true", "false"] {
+            for allow_reply in ["true", "false"] {
+                let state = test_support::state(&[
+                    ("REQUIRE_IMAGE_OP", require_op),
+                    ("ALLOW_IMAGE_REPLY", allow_reply),
+                    ("THREAD_COOLDOWN", "0"),
+                ]);
+                let app = init_service(app(&state)).await;
+                let combination = format!("REQUIRE_IMAGE_OP={} ALLOW_IMAGE_REPLY={}", require_op, allow_reply);
+                let status = |fields: &[(&str, &[u8])], uri: &str| {
+                    let request = form_post(uri, fields).to_request();
+                    async { call_service(&app, request).await.status().as_u16() }
+                };
+
+                let bare = status(&[("title", b"Bare"), ("message", b"No image")], "/thread").await;
+                assert_eq!(bare, if require_op == "true" { 400 } else { 303 }, "{}", combination);
+                let pictured = status(&[("title", b"Pictured"), ("message", b"Hi"), ("image", &jpeg)], "/thread").await;
+                assert_eq!(pictured, 303, "{}", combination);
+
+                let parent = insert_thread(&state.db, test_support::new_thread("Parent", "Hi"), false).unwrap();
+                let parent = parent.id.to_string();
+                let text = status(&[("parent_id", parent.as_bytes()), ("message", b"Text")], "/reply").await;
+                assert_eq!(text, 303, "{}", combination);
+                let image = [("parent_id", parent.as_bytes()), ("message", b"Pic"), ("image", &jpeg)];
+                let expected = if allow_reply == "true" { 303 } else { 400 };
+                assert_eq!(status(&image, "/reply").await, expected, "{}", combination);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn noko_keeps_the_poster_in_the_thread() {
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Parent", "Hi"), false).unwrap();
+        let app = init_service(app(&state)).await;
+        let parent = thread.id.to_string();
+        let location = |fields: &[(&str, &[u8])], uri: &str| {
+            let request = form_post(uri, fields).to_request();
+            async {
+                let response = call_service(&app, request).await;
+                assert_eq!(response.status(), 303);
+                response.headers().get("location").unwrap().to_str().unwrap().to_string()
+            }
+        };
+
+        assert_eq!(location(&[("title", b"Plain"), ("message", b"Hi")], "/thread").await, "/");
+        let noko = location(&[("title", b"Noko"), ("message", b"Hi"), ("email", b"noko")], "/thread").await;
+        let created: i32 = noko.strip_prefix("/thread/").unwrap().parse().unwrap();
+        assert_eq!(get_thread(&state.db, created).unwrap().title, "Noko");
+
+        assert_eq!(location(&[("parent_id", parent.as_bytes()), ("message", b"Plain")], "/reply").await, "/");
+        let noko = location(&[("parent_id", parent.as_bytes()), ("message", b"Hi"), ("email", b"sage noko")], "/reply");
+        assert_eq!(noko.await, format!("/thread/{}", thread.id));
+    }
+
+    #[actix_web::test]
+    async fn the_form_fields_may_come_in_any_order() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let app = init_service(app(&state)).await;
+        let jpeg = test_support::jpeg(64, 48);
+        let fields: [(&str, &[u8]); 3] = [("image", &jpeg), ("message", b"Image first"), ("title", b"Reversed")];
+
+        let response = call_service(&app, form_post("/thread", &fields).to_request()).await;
+        assert_eq!(response.status(), 303);
+        let thread = get_threads_page(&state.db, 0, 1).pop().unwrap();
+        assert_eq!((thread.title.as_str(), thread.message.as_str()), ("Reversed", "Image first"));
+        let image_url = thread.image_url.expect("the image was dropped");
+        assert!(std::fs::metadata(format!("{}{}", UPLOAD_DIR, image_url.rsplit('/').next().unwrap())).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn a_refused_post_leaves_no_image_behind() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let app = init_service(app(&state)).await;
+        let listing = || {
+            let names = [UPLOAD_DIR, THUMB_DIR].into_iter().flat_map(|dir| std::fs::read_dir(dir).unwrap());
+            let mut names: Vec<_> = names.map(|entry| entry.unwrap().path()).collect();
+            names.sort();
+            names
+        };
+        let before = listing();
+        let measured = upload::usage(&state.db);
+
+        // The image is stored by the time the missing title is noticed
+        let jpeg = test_support::jpeg(300, 200);
+        let fields: [(&str, &[u8]); 3] = [("image", &jpeg), ("message", b"No title"), ("title", b"")];
+        let response = call_service(&app, form_post("/thread", &fields).to_request()).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(listing(), before);
+        assert_eq!(upload::usage(&state.db), measured);
+        assert_eq!(state.db.scan_prefix(b"thread_").count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn links_and_redirects_carry_the_base_path() {
+        let state = test_support::state(&[("BASE_PATH", "board/"), ("THREAD_COOLDOWN", "0")]);
+        let mut thread = test_support::new_thread("Prefixed", "Hi");
+        thread.image_url = Some("/uploads/prefixed.jpg".to_string());
+        let thread = insert_thread(&state.db, thread, false).unwrap();
+        let app = init_service(app(&state)).await;
+
+        let board = call_and_read_body(&app, TestRequest::get().uri("/board/").to_request()).await;
+        let board = String::from_utf8(board.to_vec()).unwrap();
+        assert!(board.contains(&format!("href=\"/board/thread/{}\"", thread.id)), "{}", board);
+        assert!(board.contains("/board/uploads/prefixed.jpg"));
+        assert!(board.contains("/board/static/style.css"));
+        assert!(!board.contains("\"/thread/") && !board.contains("\"/uploads/") && !board.contains("\"/static/"));
+
+        let page = TestRequest::get().uri(&format!("/board/thread/{}", thread.id)).to_request();
+        let page = String::from_utf8(call_and_read_body(&app, page).await.to_vec()).unwrap();
+        assert!(page.contains("action=\"/board/reply\""), "{}", page);
+
+        let parent = thread.id.to_string();
+        let reply = form_post("/board/reply", &[("parent_id", parent.as_bytes()), ("message", b"Hi")]).to_request();
+        let response = call_service(&app, reply).await;
+        assert_eq!(response.headers().get("location").unwrap(), "/board/");
+        let redirect = call_service(&app, TestRequest::get().uri("/board").to_request()).await;
+        assert_eq!(redirect.headers().get("location").unwrap(), "/board/");
+        let unprefixed = TestRequest::get().uri(&format!("/thread/{}", thread.id)).to_request();
+        assert_eq!(call_service(&app, unprefixed).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn a_second_thread_waits_out_the_thread_cooldown() {
+        let _files = test_support::files().await;
+        for window in ["0", "60"] {
+            let state = test_support::state(&[("THREAD_COOLDOWN", "60"), ("DUPLICATE_THREAD_WINDOW", window)]);
+            let app = init_service(app(&state)).await;
+            let jpeg = test_support::jpeg(64, 48);
+            let post = |title: &'static str| form_post("/thread", &[("title", title.as_bytes()), ("message", b"Hi")]);
+
+            assert_eq!(call_service(&app, post("First").to_request()).await.status(), 303);
+            let measured = upload::usage(&state.db);
+            let second = form_post("/thread", &[("title", b"Second"), ("message", b"Hi"), ("image", &jpeg)]);
+            let response = call_service(&app, second.to_request()).await;
+            assert_eq!(response.status(), 429, "DUPLICATE_THREAD_WINDOW={}", window);
+            let retry_after: u64 = response.headers().get("retry-after").unwrap().to_str().unwrap().parse().unwrap();
+            assert!((59..=60).contains(&retry_after));
+            assert_eq!(upload::usage(&state.db), measured);
+
+            let elsewhere = post("Elsewhere").peer_addr("192.0.2.2:4000".parse().unwrap());
+            assert_eq!(call_service(&app, elsewhere.to_request()).await.status(), 303);
+            let parent = get_threads_page(&state.db, 0, 1)[0].id.to_string();
+            let reply = form_post("/reply", &[("parent_id", parent.as_bytes()), ("message", b"Replies still go")]);
+            assert_eq!(call_service(&app, reply.to_request()).await.status(), 303);
+        }
+    }
+
+    #[actix_web::test]
+    async fn posting_works_with_plain_form_submissions() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let app = init_service(app(&state)).await;
+        let page = |uri: String| {
+            let request = TestRequest::get().uri(&uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+
+        // The board's form posts the thread as multipart, with the fields it names
+        let board = page("/".to_string()).await;
+        assert!(board.contains("action=\"/thread\" method=\"post\" enctype=\"multipart/form-data\""));
+        for field in ["title", "message", "image", "email"] {
+            assert!(board.contains(&format!("name=\"{}\"", field)), "the board form has no {}", field);
+        }
+        let jpeg = test_support::jpeg(300, 200);
+        let fields: [(&str, &[u8]); 4] =
+            [("title", b"No JS"), ("message", b"Hi"), ("email", b"noko"), ("image", &jpeg)];
+        let response = call_service(&app, form_post("/thread", &fields).to_request()).await;
+        let location = response.headers().get("location").unwrap().to_str().unwrap().to_string();
+        let thread_id: i32 = location.strip_prefix("/thread/").unwrap().parse().unwrap();
+
+        // The thread's reply form carries the thread in a hidden field, and the
+        // image is a plain link to the full file
+        let thread = page(location.clone()).await;
+        assert!(thread.contains("action=\"/reply\" method=\"post\" enctype=\"multipart/form-data\""));
+        assert!(thread.contains(&format!("name=\"parent_id\" value=\"{}\"", thread_id)));
+        let image_url = get_thread(&state.db, thread_id).unwrap().image_url.unwrap();
+        assert!(thread.contains(&format!("<a href=\"{}\" class=\"image-link", image_url)));
+        let parent = thread_id.to_string();
+        let reply = form_post("/reply", &[("parent_id", parent.as_bytes()), ("message", b"Reply")]).to_request();
+        let response = call_service(&app, reply).await;
+        assert_eq!(response.status(), 303);
+        let reply = get_replies(&state.db, thread_id).pop().unwrap();
+
+        // Quoting is a link that fills the reply box server-side
+        let thread = page(location).await;
+        let quote_link = format!("/thread/{}/reply?quote={}#message", thread_id, reply.id);
+        assert!(thread.contains(&format!("href=\"{}\"", quote_link)), "{}", thread);
+        let quoting = page(quote_link.split('#').next().unwrap().to_string()).await;
+        assert!(quoting.contains(&format!("aria-label=\"Message\">&gt;&gt;{}\n</textarea>", reply.id)), "{}", quoting);
+    }
+
+    #[actix_web::test]
+    async fn only_the_allowed_image_types_are_offered_and_taken() {
+        let _files = test_support::files().await;
+        let png = test_support::png(64, 48, 255);
+        let jpeg = test_support::jpeg(64, 48);
+        for (allowed, png_status, jpeg_status) in [("jpeg", 400, 303), ("png,gif", 303, 400)] {
+            let state = test_support::state(&[("ALLOWED_IMAGE_TYPES", allowed), ("THREAD_COOLDOWN", "0")]);
+            let app = init_service(app(&state)).await;
+            let post = |image: &[u8]| {
+                let request = form_post("/thread", &[("title", b"Typed"), ("message", b"Hi"), ("image", image)]);
+                call_service(&app, request.to_request())
+            };
+            assert_eq!(post(&png).await.status(), png_status, "{}", allowed);
+            assert_eq!(post(&jpeg).await.status(), jpeg_status, "{}", allowed);
+
+            let board = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+            let board = String::from_utf8(board.to_vec()).unwrap();
+            let accept = format!("accept=\"{}\"", state.settings.image_accept());
+            assert!(board.contains(&accept) && board.contains(&state.settings.image_type_labels()), "{}", board);
+        }
+    }
+
+    #[actix_web::test]
+    async fn head_requests_answer_with_headers_only() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Headed", "Hi"), false).unwrap();
+        let get_app = init_service(app(&state)).await;
+        // The body is left out on the wire, so this takes a real server
+        let server_state = state.clone();
+        let server = HttpServer::new(move || app(&server_state)).workers(1).bind(("127.0.0.1", 0)).unwrap();
+        let address = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        for uri in ["/".to_string(), format!("/thread/{}", thread.id)] {
+            let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+            let request = format!("HEAD {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", uri);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+            assert!(head.contains("content-type: text/html"), "{}", head);
+            assert_eq!(body, "", "HEAD {} sent a body", uri);
+            let page = call_and_read_body(&get_app, TestRequest::get().uri(&uri).to_request()).await;
+            assert!(head.contains(&format!("content-length: {}", page.len())), "{}", head);
+        }
+        handle.stop(true).await;
+    }
+
+    #[actix_web::test]
+    async fn the_catalog_search_narrows_the_tiles() {
+        let state = test_support::state(&[]);
+        for title in ["Rust tips", "rusty nails", "Python", "Trust issues"] {
+            insert_thread(&state.db, test_support::new_thread(title, "Hi"), false).unwrap();
+        }
+        let app = init_service(app(&state)).await;
+        let catalog = |query: &str| {
+            let request = TestRequest::get().uri(&format!("/catalog{}", query)).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        let tiles = |page: &str| page.matches("class=\"catalog-tile\"").count();
+
+        assert_eq!(tiles(&catalog("").await), 4);
+        let page = catalog("?q=+RUST+").await;
+        assert_eq!(tiles(&page), 3);
+        assert!(!page.contains("Python"));
+        assert!(page.contains("Threads matching <span class=\"current\">RUST</span>"));
+        assert!(page.contains("href=\"/catalog\" class=\"clear-search\""));
+
+        let page = catalog("?q=haskell").await;
+        assert_eq!(tiles(&page), 0);
+        assert!(page.contains("No thread titles match \"haskell\"."));
+    }
+
+    #[actix_web::test]
+    async fn a_repeated_conditional_image_request_gets_304() {
+        let _files = test_support::files().await;
+        let jpeg = test_support::jpeg(64, 48);
+        std::fs::write(format!("{}etag-test.jpg", UPLOAD_DIR), &jpeg).unwrap();
+        let app = init_service(app(&test_support::state(&[]))).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/uploads/etag-test.jpg").to_request()).await;
+        assert_eq!(response.status(), 200);
+        let etag = response.headers().get("etag").unwrap().clone();
+        assert_eq!(etag, "\"etag-test.jpg\"");
+        assert!(response.headers().get("cache-control").unwrap().to_str().unwrap().contains("immutable"));
+        assert_eq!(read_body(response).await, jpeg);
+
+        let again = TestRequest::get().uri("/uploads/etag-test.jpg").insert_header(("if-none-match", etag.clone()));
+        let response = call_service(&app, again.to_request()).await;
+        assert_eq!(response.status(), 304);
+        assert_eq!(response.headers().get("etag").unwrap(), &etag);
+        assert!(read_body(response).await.is_empty());
+
+        let stale = TestRequest::get().uri("/uploads/etag-test.jpg").insert_header(("if-none-match", "\"other.jpg\""));
+        assert_eq!(call_service(&app, stale.to_request()).await.status(), 200);
+        std::fs::remove_file(format!("{}etag-test.jpg", UPLOAD_DIR)).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn long_threads_collapse_their_older_replies() {
+        let state = test_support::state(&[("THREAD_LAST_REPLIES", "3")]);
+        let long = insert_thread(&state.db, test_support::new_thread("Long", "Hi"), false).unwrap();
+        let short = insert_thread(&state.db, test_support::new_thread("Short", "Hi"), false).unwrap();
+        for (thread, count) in [(long.id, 5), (short.id, 3)] {
+            for n in 1..=count {
+                let reply = test_support::new_reply(&format!("Reply {}", n));
+                insert_reply(&state.db, thread, reply, true, 0, 0, false).unwrap();
+            }
+        }
+        let app = init_service(app(&state)).await;
+        let page = |uri: String| {
+            let request = TestRequest::get().uri(&uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        let shown = |page: &str| (1..=5).filter(|id| page.contains(&format!("id=\"p{}\"", id))).collect::<Vec<_>>();
+
+        let collapsed = page(format!("/thread/{}", long.id)).await;
+        assert!(collapsed.contains("2 replies omitted. <a href=\"?all=true\">View all</a>"), "{}", collapsed);
+        assert_eq!(shown(&collapsed), [3, 4, 5]);
+        let all = page(format!("/thread/{}?all=true", long.id)).await;
+        assert!(!all.contains("omitted"));
+        assert_eq!(shown(&all), [1, 2, 3, 4, 5]);
+
+        let short = page(format!("/thread/{}", short.id)).await;
+        assert!(!short.contains("omitted"));
+        assert_eq!(shown(&short), [1, 2, 3]);
+    }
+
+    #[actix_web::test]
+    async fn the_reply_endpoint_returns_the_rendered_reply() {
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Fragments", "Hi"), false).unwrap();
+        let app = init_service(app(&state)).await;
+        let uri = format!("/api/thread/{}/reply", thread.id);
+
+        let message = b"<script>alert(1)</script> & \"quotes\"";
+        let response = call_service(&app, form_post(&uri, &[("message", message)]).to_request()).await;
+        assert_eq!(response.status(), 201);
+        let fragment = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(fragment.trim_start().starts_with("<div class=\"post reply-post\" id=\"p1\">"), "{}", fragment);
+        assert!(fragment.contains("&lt;script&gt;alert(1)&lt;/script&gt; &amp; &quot;quotes&quot;"), "{}", fragment);
+        assert!(!fragment.contains("<script>") && !fragment.contains("<html"));
+        assert_eq!(get_replies(&state.db, thread.id).len(), 1);
+
+        // The same checks as the form, with the errors instead of a fragment
+        let response = call_service(&app, form_post(&uri, &[("message", b"  ")]).to_request()).await;
+        assert_eq!(response.status(), 400);
+        let missing = form_post(&format!("/api/thread/{}/reply", thread.id + 1), &[("message", b"Hi")]);
+        assert!(call_service(&app, missing.to_request()).await.status().is_client_error());
+        assert_eq!(get_replies(&state.db, thread.id).len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn submitting_a_thread_twice_makes_one() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[]);
+        let app = init_service(app(&state)).await;
+        let jpeg = test_support::jpeg(64, 48);
+        let post = |message: &[u8]| {
+            form_post("/thread", &[("title", b"Twice"), ("message", message), ("image", &jpeg)])
+        };
+
+        let first = call_service(&app, post(b"Same thing").to_request()).await;
+        assert_eq!(first.status(), 303);
+        let thread = get_threads_page(&state.db, 0, 1).pop().unwrap();
+        let measured = upload::usage(&state.db);
+
+        let again = call_service(&app, post(b"  Same   thing ").to_request()).await;
+        assert_eq!(again.status(), 303);
+        assert_eq!(again.headers().get("location").unwrap(), format!("/thread/{}", thread.id).as_str());
+        assert_eq!(state.db.scan_prefix(b"thread_").count(), 1);
+        assert_eq!(upload::usage(&state.db), measured);
+
+        // Not a resubmission, so the cooldown applies
+        assert_eq!(call_service(&app, post(b"Something else").to_request()).await.status(), 429);
+        let elsewhere = post(b"Same thing").peer_addr("192.0.2.2:4000".parse().unwrap());
+        assert_eq!(call_service(&app, elsewhere.to_request()).await.status(), 303);
+        assert_eq!(state.db.scan_prefix(b"thread_").count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn quoting_prefills_the_reply_box_with_replies_of_the_thread_only() {
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Thread", "Hi"), false).unwrap();
+        let other = insert_thread(&state.db, test_support::new_thread("Other", "Hi"), false).unwrap();
+        for _ in 0..2 {
+            insert_reply(&state.db, thread.id, test_support::new_reply("Reply"), true, 0, 0, false).unwrap();
+        }
+        for _ in 0..3 {
+            insert_reply(&state.db, other.id, test_support::new_reply("Elsewhere"), true, 0, 0, false).unwrap();
+        }
+        let app = init_service(app(&state)).await;
+        let reply_box = |quote: &str| {
+            let request = TestRequest::get().uri(&format!("/thread/{}/reply?quote={}", thread.id, quote)).to_request();
+            async {
+                let page = String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap();
+                let start = page.find("aria-label=\"Message\">").unwrap() + "aria-label=\"Message\">".len();
+                page[start..start + page[start..].find("</textarea>").unwrap()].to_string()
+            }
+        };
+
+        assert_eq!(reply_box("2").await, "&gt;&gt;2\n");
+        // Reply 3 exists, but in the other thread
+        assert_eq!(reply_box("3").await, "");
+        assert_eq!(reply_box("0").await, "");
+        let response = call_service(&app, TestRequest::get().uri("/thread/1/reply?quote=two").to_request()).await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn the_thread_page_counts_down_to_the_bump_limit() {
+        for (limit, replies, shown) in [
+            ("3", 0, Some("3 until bump limit")),
+            ("3", 2, Some("1 until bump limit")),
+            ("3", 3, None),
+            ("3", 5, None),
+            ("0", 5, None),
+        ] {
+            let state = test_support::state(&[("BUMP_LIMIT", limit)]);
+            let thread = insert_thread(&state.db, test_support::new_thread("Thread", "Hi"), false).unwrap();
+            for _ in 0..replies {
+                insert_reply(&state.db, thread.id, test_support::new_reply("Reply"), true, 3, 0, false).unwrap();
+            }
+            let app = init_service(app(&state)).await;
+            let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+            let page = String::from_utf8(page.to_vec()).unwrap();
+            assert_eq!(page.contains("until bump limit"), shown.is_some(), "{} replies, limit {}", replies, limit);
+            if let Some(shown) = shown {
+                assert!(page.contains(shown), "{} replies: {}", replies, page);
+            }
+            let reached = "<span class=\"bump-limit\">Bump limit reached &mdash; thread will no longer rise.</span>";
+            assert_eq!(page.contains(reached), limit != "0" && replies >= 3, "{} replies, limit {}", replies, limit);
+        }
+    }
+
+    #[actix_web::test]
+    async fn long_opening_posts_are_cut_on_the_homepage_only() {
+        let state = test_support::state(&[("PREVIEW_CHARS", "20")]);
+        let long = format!("{} the end", "word ".repeat(30));
+        insert_thread(&state.db, test_support::new_thread("Long", &long), false).unwrap();
+        insert_thread(&state.db, test_support::new_thread("Short", "Just this"), false).unwrap();
+        let app = init_service(app(&state)).await;
+        let page = |uri: &'static str| {
+            let request = TestRequest::get().uri(uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+
+        let homepage = page("/").await;
+        let read_more = "<span class=\"read-more\">&hellip; <a href=\"/thread/1\">(read more)</a></span>";
+        let cut = format!("<div class=\"message\">word word word word{}</div>", read_more);
+        assert!(homepage.contains(&cut), "{}", homepage);
+        assert!(!homepage.contains("the end"));
+        assert!(homepage.contains("<div class=\"message\">Just this</div>"));
+        assert_eq!(homepage.matches("(read more)").count(), 1);
+
+        let thread = page("/thread/1").await;
+        assert!(thread.contains(&format!("<div class=\"message\">{}</div>", long)), "{}", thread);
+        assert!(!thread.contains("(read more)"));
+    }
+
+    #[actix_web::test]
+    async fn the_index_shows_the_last_reply_or_else_the_last_bump() {
+        let state = test_support::state(&[]);
+        let now = Utc::now().timestamp();
+        let answered = insert_thread(&state.db, test_support::new_thread("Answered", "Hi"), false).unwrap();
+        for minutes in [20, 5] {
+            let reply = test_support::new_reply("Hi");
+            let mut reply = insert_reply(&state.db, answered.id, reply, true, 0, 0, false).unwrap();
+            reply.created_at = now - minutes * 60;
+            state.db.insert(reply_key(answered.id, reply.id), serde_json::to_vec(&reply).unwrap()).unwrap();
+        }
+        let quiet = insert_thread(&state.db, test_support::new_thread("Quiet", "Hi"), false).unwrap();
+        test_support::backdate(&state.db, quiet.id, now - 3 * 60 * 60);
+        let app = init_service(app(&state)).await;
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        let count = |thread: i32| {
+            let start = page.find(&format!("href=\"/thread/{}\" class=\"reply-link\"", thread)).unwrap();
+            let start = page[..start].rfind("<span class=\"reply-count\">").unwrap();
+            page[start..start + page[start..].find("</span></span>").unwrap()].to_string()
+        };
+        let answered = count(answered.id);
+        assert!(answered.starts_with("<span class=\"reply-count\">2 replies, last reply <span title="), "{}", answered);
+        assert!(answered.ends_with(">5 minutes ago"), "{}", answered);
+        let quiet = count(quiet.id);
+        assert!(quiet.starts_with("<span class=\"reply-count\">0 replies, bumped <span title="), "{}", quiet);
+        assert!(quiet.ends_with(">3 hours ago"), "{}", quiet);
+    }
+
+    #[actix_web::test]
+    async fn posts_carry_both_image_urls_and_the_full_size() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[]);
+        let app = init_service(app(&state)).await;
+        let (op, reply) = (test_support::jpeg(300, 200), test_support::jpeg(160, 400));
+        let thread: [(&str, &[u8]); 3] = [("title", b"Images"), ("message", b"Hi"), ("image", &op)];
+        assert_eq!(call_service(&app, form_post("/thread", &thread).to_request()).await.status(), 303);
+        let answer: [(&str, &[u8]); 3] = [("parent_id", b"1"), ("message", b"Hi"), ("image", &reply)];
+        assert_eq!(call_service(&app, form_post("/reply", &answer).to_request()).await.status(), 303);
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        let thread = get_thread(&state.db, 1).unwrap();
+        let reply = get_replies(&state.db, 1).pop().unwrap();
+        let posts = [
+            (thread.image_url.as_ref().unwrap(), &thread.thumbnails[0], (300, 200)),
+            (reply.image_url.as_ref().unwrap(), &reply.thumbnails[0], (160, 400)),
+        ];
+        for (full, thumb, (width, height)) in posts {
+            assert_ne!(full, thumb);
+            // A plain link to the full image around the thumbnail, and both for the script
+            let link = format!("<a href=\"{}\" class=\"image-link\" target=\"_blank\"", full);
+            let data = format!(
+                "data-thumb-src=\"{}\" data-full-src=\"{}\" data-full-width=\"{}\" data-full-height=\"{}\">\
+                 <img src=\"{}\"",
+                thumb, full, width, height, thumb
+            );
+            assert!(page.contains(&format!("{} {}", link, data)), "{}", page);
+        }
+        for url in [&thread.image_url, &reply.image_url] {
+            upload::delete_image(&state.db, url.as_ref().unwrap());
+        }
+    }
+
+    #[actix_web::test]
+    async fn replies_come_oldest_or_newest_first_below_the_opening_post() {
+        let state = test_support::state(&[("THREAD_LAST_REPLIES", "3")]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Ordered", "Opening post"), false).unwrap();
+        for n in 1..=5 {
+            let reply = test_support::new_reply(&format!("Reply {}", n));
+            insert_reply(&state.db, thread.id, reply, true, 0, 0, false).unwrap();
+        }
+        let app = init_service(app(&state)).await;
+        let page = |query: &str| {
+            let request = TestRequest::get().uri(&format!("/thread/{}{}", thread.id, query)).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        // Reply ids in the order the page shows them, after checking the opening post comes first
+        let shown = |page: &str| {
+            let opening = page.find("Opening post").unwrap();
+            let mut at: Vec<(usize, i32)> =
+                (1..=5).filter_map(|id| page.find(&format!("id=\"p{}\"", id)).map(|at| (at, id))).collect();
+            at.sort_unstable();
+            assert!(at.iter().all(|(at, _)| *at > opening));
+            at.into_iter().map(|(_, id)| id).collect::<Vec<_>>()
+        };
+
+        let oldest = page("").await;
+        assert_eq!(shown(&oldest), [3, 4, 5]);
+        assert_eq!(oldest, page("?order=asc").await);
+        let toggle = "<span class=\"current\">Oldest first</span> | <a href=\"?order=desc\">Newest first</a>";
+        assert!(oldest.contains(toggle));
+        assert!(oldest.find("2 replies omitted").unwrap() < oldest.find("id=\"p3\"").unwrap());
+
+        let newest = page("?order=desc").await;
+        assert_eq!(shown(&newest), [5, 4, 3]);
+        assert!(newest.contains("<a href=\"?\">Oldest first</a> | <span class=\"current\">Newest first</span>"));
+        // The omitted replies are still the oldest, now listed last
+        let omitted = "2 older replies omitted. <a href=\"?order=desc&amp;all=true\">View all</a>";
+        assert!(newest.find(omitted).unwrap() > newest.find("id=\"p3\"").unwrap(), "{}", newest);
+
+        let everything = page("?order=desc&all=true").await;
+        assert_eq!(shown(&everything), [5, 4, 3, 2, 1]);
+        assert!(everything.contains("<a href=\"?all=true\">Oldest first</a>"));
+        assert_eq!(shown(&page("?all=true").await), [1, 2, 3, 4, 5]);
+        assert_eq!(shown(&page("?order=sideways").await), [3, 4, 5]);
+    }
+
+    #[actix_web::test]
+    async fn saged_replies_are_marked_only_with_show_sage() {
+        for show_sage in [true, false] {
+            let state = test_support::state(&[("SHOW_SAGE", if show_sage { "true" } else { "false" })]);
+            let thread = insert_thread(&state.db, test_support::new_thread("Sage", "Marked"), false).unwrap();
+            let app = init_service(app(&state)).await;
+            let parent = thread.id.to_string();
+            let reply = |email: &[u8], address: u8| {
+                form_post("/reply", &[("parent_id", parent.as_bytes()), ("message", b"Hi"), ("email", email)])
+                    .peer_addr(format!("192.0.2.{}:4000", address).parse().unwrap())
+                    .to_request()
+            };
+
+            assert!(call_service(&app, reply(b"sage", 1)).await.status().is_redirection());
+            assert!(call_service(&app, reply(b"", 2)).await.status().is_redirection());
+            let uri = format!("/api/thread/{}/reply", thread.id);
+            let fragment = form_post(&uri, &[("message", b"Hi"), ("email", b"sage noko")])
+                .peer_addr("192.0.2.3:4000".parse().unwrap());
+            let response = call_service(&app, fragment.to_request()).await;
+            assert_eq!(response.status(), 201);
+            let fragment = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert_eq!(fragment.contains("(sage)"), show_sage, "SHOW_SAGE={}", show_sage);
+
+            let saged: Vec<bool> = get_replies(&state.db, thread.id).iter().map(|reply| reply.saged).collect();
+            assert_eq!(saged, [true, false, true]);
+            let page = TestRequest::get().uri(&format!("/thread/{}", thread.id)).to_request();
+            let page = String::from_utf8(call_and_read_body(&app, page).await.to_vec()).unwrap();
+            let markers = page.matches("<span class=\"sage-label\">(sage)</span>").count();
+            assert_eq!(markers, if show_sage { 2 } else { 0 }, "SHOW_SAGE={}", show_sage);
+        }
+    }
+
+    #[actix_web::test]
+    async fn boards_without_titles_drop_them_everywhere() {
+        let vars = [("TITLES_ENABLED", "false"), ("THREAD_COOLDOWN", "0"), ("API_KEY", "sesame")];
+        let state = test_support::state(&vars);
+        let app = init_service(app(&state)).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let homepage = String::from_utf8(call_and_read_body(&app, get("/")).await.to_vec()).unwrap();
+        assert!(!homepage.contains("name=\"title\""));
+        let sent = form_post("/thread", &[("title", b"Dropped"), ("message", b"Openings and endgames")]);
+        assert!(call_service(&app, sent.to_request()).await.status().is_redirection());
+        let title_only = form_post("/thread", &[("title", b"Only a title"), ("message", b"")]);
+        assert_eq!(call_service(&app, title_only.to_request()).await.status(), 400);
+        let api = TestRequest::post()
+            .uri("/api/thread")
+            .insert_header(("Authorization", "Bearer sesame"))
+            .set_json(serde_json::json!({ "title": "Dropped too", "message": "From a script" }));
+        assert_eq!(call_service(&app, api.to_request()).await.status(), 201);
+        let titles: Vec<String> = get_all_threads(&state.db).into_iter().map(|thread| thread.title).collect();
+        assert_eq!(titles, ["", ""]);
+
+        let thread = String::from_utf8(call_and_read_body(&app, get("/thread/1")).await.to_vec()).unwrap();
+        assert!(!thread.contains("class=\"title\"") && !thread.contains("Dropped"));
+        let catalog = String::from_utf8(call_and_read_body(&app, get("/catalog?q=endgames")).await.to_vec()).unwrap();
+        assert!(catalog.contains("Thread No. 1") && !catalog.contains("Thread No. 2"), "{}", catalog);
+        let json: serde_json::Value = call_and_read_body_json(&app, get("/thread/1.json")).await;
+        assert!(json["posts"][0].get("sub").is_none());
+    }
+
+    #[actix_web::test]
+    async fn nsfw_images_render_blurred() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("THREAD_COOLDOWN", "0")]);
+        let app = init_service(app(&state)).await;
+        let get = |uri: &str| TestRequest::get().uri(uri).to_request();
+
+        let [first, second, third] = [300, 310, 320].map(|width| test_support::jpeg(width, 200));
+        let thread = [("title", b"Marked".as_slice()), ("message", b"Hi"), ("image", &first), ("nsfw", b"on")];
+        let thread = form_post("/thread", &thread);
+        assert!(call_service(&app, thread.to_request()).await.status().is_redirection());
+        let plain = form_post("/reply", &[("parent_id", b"1"), ("message", b"Safe"), ("image", &second)]);
+        assert!(call_service(&app, plain.to_request()).await.status().is_redirection());
+        let marked = [("parent_id", b"1".as_slice()), ("message", b"Not safe"), ("image", &third), ("nsfw", b"on")];
+        let marked = form_post("/reply", &marked);
+        assert!(call_service(&app, marked.to_request()).await.status().is_redirection());
+        assert!(get_thread(&state.db, 1).unwrap().nsfw);
+        assert_eq!(get_replies(&state.db, 1).iter().map(|reply| reply.nsfw).collect::<Vec<_>>(), [false, true]);
+
+        let page = String::from_utf8(call_and_read_body(&app, get("/thread/1")).await.to_vec()).unwrap();
+        assert_eq!(page.matches("class=\"image-link nsfw\"").count(), 2);
+        assert_eq!(page.matches("class=\"image-link\"").count(), 1);
+        let index = String::from_utf8(call_and_read_body(&app, get("/")).await.to_vec()).unwrap();
+        assert!(index.contains("class=\"image-link nsfw\""));
+        let catalog = String::from_utf8(call_and_read_body(&app, get("/catalog")).await.to_vec()).unwrap();
+        assert!(catalog.contains("class=\"nsfw\""));
+
+        // BLUR_THUMBNAILS blurs the unmarked reply as well
+        let blurred = test_support::state_with_db(state.db.clone(), &[("BLUR_THUMBNAILS", "true")]);
+        let app = init_service(crate::app(&blurred)).await;
+        let page = String::from_utf8(call_and_read_body(&app, get("/thread/1")).await.to_vec()).unwrap();
+        assert_eq!(page.matches("class=\"image-link nsfw\"").count(), 3);
+    }
 }
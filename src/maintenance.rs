@@ -0,0 +1,152 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use sled::Db;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use crate::settings::Settings;
+
+// Holds the announcement last saved from /admin; it outlives restarts
+const ANNOUNCEMENT_KEY: &[u8] = b"announcement";
+
+// Board state an admin changes at runtime: the read-only switch for backups and
+// migrations, flipped from /admin/read-only, and the site-wide announcement set
+// from /admin/announcement
+pub struct Maintenance {
+    read_only: AtomicBool,
+    announcement: RwLock<String>,
+}
+
+impl Maintenance {
+    // The announcement saved from /admin wins over ANNOUNCEMENT, even when it
+    // was cleared there
+    pub fn new(read_only: bool, db: &Db, announcement: &str) -> Self {
+        let announcement = match db.get(ANNOUNCEMENT_KEY) {
+            Ok(Some(stored)) => String::from_utf8_lossy(&stored).into_owned(),
+            _ => announcement.to_string(),
+        };
+        Maintenance {
+            read_only: AtomicBool::new(read_only),
+            announcement: RwLock::new(announcement),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    // Empty when there is none
+    pub fn announcement(&self) -> String {
+        self.announcement.read().expect("announcement lock poisoned").clone()
+    }
+
+    pub fn set_announcement(&self, db: &Db, text: &str) -> sled::Result<()> {
+        let text = text.trim();
+        db.insert(ANNOUNCEMENT_KEY, text.as_bytes())?;
+        *self.announcement.write().expect("announcement lock poisoned") = text.to_string();
+        Ok(())
+    }
+}
+
+// Refuse every mutating request while read-only mode is on. GET/HEAD keep working,
+// and /admin stays reachable so the mode can be switched off again.
+pub async fn read_only_guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let read_only = req
+        .app_data::<web::Data<Maintenance>>()
+        .is_some_and(|maintenance| maintenance.is_read_only());
+    let mutating = !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+
+    let admin_path = req
+        .app_data::<web::Data<Settings>>()
+        .map_or_else(|| "/admin".to_string(), |settings| settings.url("/admin"));
+
+    if read_only && mutating && !req.path().starts_with(&admin_path) {
+        let response = HttpResponse::ServiceUnavailable()
+            .append_header(("Retry-After", "300"))
+            .body("The board is in maintenance mode; posting is temporarily disabled");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    #[actix_web::test]
+    async fn read_only_mode_refuses_writes_until_it_is_switched_off() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        let switch = |enabled: &str| {
+            TestRequest::post()
+                .uri("/admin/read-only")
+                .insert_header(("Authorization", "Bearer hunter2"))
+                .set_form([("enabled", enabled)])
+                .to_request()
+        };
+        let thread = || test_support::form_post("/thread", &[("title", b"Backup"), ("message", b"Hi")]).to_request();
+
+        assert!(call_service(&app, switch("true")).await.status().is_redirection());
+        let refused = call_service(&app, thread()).await;
+        assert_eq!(refused.status(), 503);
+        assert_eq!(refused.headers().get("retry-after").unwrap(), "300");
+        let reply = test_support::form_post("/reply", &[("parent_id", b"1"), ("message", b"Hi")]);
+        assert_eq!(call_service(&app, reply.to_request()).await.status(), 503);
+        assert!(state.db.scan_prefix(b"thread_").next().is_none());
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains("maintenance-banner"));
+
+        assert!(call_service(&app, switch("false")).await.status().is_redirection());
+        assert!(call_service(&app, thread()).await.status().is_redirection());
+        let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(!String::from_utf8(page.to_vec()).unwrap().contains("maintenance-banner"));
+    }
+
+    #[actix_web::test]
+    async fn the_announcement_shows_on_every_page_until_cleared() {
+        let state = test_support::state(&[("ADMIN_TOKEN", "hunter2")]);
+        crate::insert_thread(&state.db, test_support::new_thread("Board", "Hi"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let announce = |text: &str| {
+            TestRequest::post()
+                .uri("/admin/announcement")
+                .insert_header(("Authorization", "Bearer hunter2"))
+                .set_form([("text", text)])
+                .to_request()
+        };
+        let pages = || async {
+            let mut pages = Vec::new();
+            for uri in ["/", "/catalog", "/thread/1"] {
+                let page = call_and_read_body(&app, TestRequest::get().uri(uri).to_request()).await;
+                pages.push(String::from_utf8(page.to_vec()).unwrap());
+            }
+            pages
+        };
+
+        assert!(call_service(&app, announce("Read the <b>rules</b>")).await.status().is_redirection());
+        for page in pages().await {
+            assert!(page.contains("<div class=\"announcement\">Read the &lt;b&gt;rules&lt;/b&gt;</div>"), "{}", page);
+        }
+        // Saved, so a restart keeps it
+        assert_eq!(Maintenance::new(false, &state.db, "").announcement(), "Read the <b>rules</b>");
+
+        assert!(call_service(&app, announce("  ")).await.status().is_redirection());
+        for page in pages().await {
+            assert!(!page.contains("class=\"announcement\""));
+        }
+    }
+}
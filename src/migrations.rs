@@ -0,0 +1,285 @@
+use log::info;
+use sled::Db;
+
+use std::collections::HashMap;
+
+use crate::post_options::PostOptions;
+use crate::{upload, UPLOAD_DIR};
+use crate::{bump_key, reply_count_key, reply_counter_key, Reply, Thread, THREAD_COUNTER_KEY, THREAD_COUNT_KEY};
+
+const SCHEMA_VERSION_KEY: &[u8] = b"schema_version";
+
+type Migration = fn(&Db) -> sled::Result<()>;
+
+// Ordered migrations; running entry N brings the database to schema version N + 1.
+// Only ever append to this list, never reorder or remove entries.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("backfill created_at", backfill_created_at),
+    ("seed reply counters", seed_reply_counters),
+    ("build bump index and thread counter", build_bump_index),
+    ("measure upload disk usage", measure_upload_usage),
+    ("count replies per thread", count_replies),
+    ("record image sizes", record_image_sizes),
+    ("mark saged replies", mark_saged_replies),
+    ("count threads", count_threads),
+];
+
+// Apply every migration newer than the stored schema version, recording each one as it lands
+pub fn run(db: &Db) -> sled::Result<()> {
+    let current = schema_version(db)?;
+
+    if current > MIGRATIONS.len() {
+        panic!(
+            "Database schema version {} is newer than this build supports ({})",
+            current,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (index, (name, migration)) in MIGRATIONS.iter().enumerate().skip(current) {
+        let version = index + 1;
+        info!("Applying migration {} ({})", version, name);
+        migration(db)?;
+        db.insert(SCHEMA_VERSION_KEY, &(version as u32).to_be_bytes())?;
+    }
+
+    db.flush()?;
+    Ok(())
+}
+
+// Read the stored schema version, treating a missing key as a pre-versioning database
+fn schema_version(db: &Db) -> sled::Result<usize> {
+    Ok(db
+        .get(SCHEMA_VERSION_KEY)?
+        .and_then(|value| <[u8; 4]>::try_from(value.as_ref()).ok())
+        .map(|bytes| u32::from_be_bytes(bytes) as usize)
+        .unwrap_or(0))
+}
+
+// Version 1: give threads and replies a creation time. Old threads only know their
+// last bump, and old replies only know their parent, so those are the best estimates.
+fn backfill_created_at(db: &Db) -> sled::Result<()> {
+    for res in db.scan_prefix(b"thread_") {
+        let (key, value) = res?;
+        if let Ok(mut thread) = serde_json::from_slice::<Thread>(&value) {
+            if thread.created_at == 0 {
+                thread.created_at = thread.last_updated;
+                let updated = serde_json::to_vec(&thread).expect("Failed to serialize thread");
+                db.insert(key, updated)?;
+            }
+        }
+    }
+
+    for res in db.scan_prefix(b"reply_") {
+        let (key, value) = res?;
+        let mut reply = match serde_json::from_slice::<Reply>(&value) {
+            Ok(reply) if reply.created_at == 0 => reply,
+            _ => continue,
+        };
+
+        // Keys look like reply_{parent_id}_{reply_id}
+        let parent_id = String::from_utf8_lossy(&key)
+            .split('_')
+            .nth(1)
+            .map(str::to_string)
+            .unwrap_or_default();
+        let parent_updated = db
+            .get(format!("thread_{}", parent_id))?
+            .and_then(|value| serde_json::from_slice::<Thread>(&value).ok())
+            .map(|thread| thread.last_updated);
+
+        if let Some(created_at) = parent_updated {
+            reply.created_at = created_at;
+            let updated = serde_json::to_vec(&reply).expect("Failed to serialize reply");
+            db.insert(key, updated)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Version 2: reply ids come from a per-thread counter instead of counting keys, so
+// seed each counter with the highest reply id already stored for that thread
+fn seed_reply_counters(db: &Db) -> sled::Result<()> {
+    let mut highest: HashMap<i32, i32> = HashMap::new();
+
+    for res in db.scan_prefix(b"reply_") {
+        let (key, _) = res?;
+        let key = String::from_utf8_lossy(&key).to_string();
+        let mut parts = key.split('_').skip(1).map(|part| part.parse::<i32>());
+        if let (Some(Ok(parent_id)), Some(Ok(reply_id))) = (parts.next(), parts.next()) {
+            let entry = highest.entry(parent_id).or_insert(0);
+            *entry = (*entry).max(reply_id);
+        }
+    }
+
+    for (parent_id, reply_id) in highest {
+        db.insert(reply_counter_key(parent_id), &reply_id.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Version 3: the homepage reads threads through the bump index and new thread ids
+// come from a counter, so index every existing thread and seed the counter
+fn build_bump_index(db: &Db) -> sled::Result<()> {
+    let mut highest = 0;
+
+    for res in db.scan_prefix(b"thread_") {
+        let (_, value) = res?;
+        if let Ok(thread) = serde_json::from_slice::<Thread>(&value) {
+            db.insert(bump_key(thread.last_updated, thread.id), &thread.id.to_be_bytes())?;
+            highest = highest.max(thread.id);
+        }
+    }
+
+    db.insert(THREAD_COUNTER_KEY, &highest.to_be_bytes())?;
+    Ok(())
+}
+
+// Version 4: uploads count against a quota, so start the running total from what
+// is already on disk
+fn measure_upload_usage(db: &Db) -> sled::Result<()> {
+    let bytes = std::fs::read_dir(UPLOAD_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.metadata().ok())
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+                .sum()
+        })
+        .unwrap_or(0);
+    upload::set_usage(db, bytes)
+}
+
+// Version 5: reply ids can have gaps once posts are numbered board-wide, so the
+// reply counter no longer doubles as the count; store each thread's count
+fn count_replies(db: &Db) -> sled::Result<()> {
+    let mut counts: HashMap<i32, i32> = HashMap::new();
+
+    for res in db.scan_prefix(b"reply_") {
+        let (key, _) = res?;
+        let parent_id = String::from_utf8_lossy(&key).split('_').nth(1).and_then(|part| part.parse().ok());
+        if let Some(parent_id) = parent_id {
+            *counts.entry(parent_id).or_insert(0) += 1;
+        }
+    }
+
+    for (parent_id, count) in counts {
+        db.insert(reply_count_key(parent_id), &count.to_be_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Version 6: posts store the size of their image, so read it from the header
+// of each stored image. Posts whose image is gone are left without one.
+fn record_image_sizes(db: &Db) -> sled::Result<()> {
+    for res in db.scan_prefix(b"thread_") {
+        let (key, value) = res?;
+        if let Ok(mut thread) = serde_json::from_slice::<Thread>(&value) {
+            if thread.image_size.is_none() {
+                thread.image_size = thread.image_url.as_deref().and_then(upload::stored_image_size);
+                if thread.image_size.is_some() {
+                    db.insert(key, serde_json::to_vec(&thread).expect("Failed to serialize thread"))?;
+                }
+            }
+        }
+    }
+
+    for res in db.scan_prefix(b"reply_") {
+        let (key, value) = res?;
+        if let Ok(mut reply) = serde_json::from_slice::<Reply>(&value) {
+            if reply.image_size.is_none() {
+                reply.image_size = reply.image_url.as_deref().and_then(upload::stored_image_size);
+                if reply.image_size.is_some() {
+                    db.insert(key, serde_json::to_vec(&reply).expect("Failed to serialize reply"))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Version 7: flag the replies posted with sage, which only their email field recorded
+fn mark_saged_replies(db: &Db) -> sled::Result<()> {
+    for res in db.scan_prefix(b"reply_") {
+        let (key, value) = res?;
+        if let Ok(mut reply) = serde_json::from_slice::<Reply>(&value) {
+            if !reply.saged && PostOptions::parse(reply.email.as_deref()).sage {
+                reply.saged = true;
+                db.insert(key, serde_json::to_vec(&reply).expect("Failed to serialize reply"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Version 8: the index pages read the number of threads from a stored count
+// instead of counting the records on every request, so start it off
+fn count_threads(db: &Db) -> sled::Result<()> {
+    let count = db.scan_prefix(b"thread_").count() as i32;
+    db.insert(THREAD_COUNT_KEY, &count.to_be_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn stored_i32(db: &Db, key: &[u8]) -> Option<i32> {
+        let value = db.get(key).unwrap()?;
+        Some(i32::from_be_bytes(value.as_ref().try_into().unwrap()))
+    }
+
+    #[test]
+    fn old_records_are_brought_up_to_date() {
+        let db = test_support::temp_db();
+        // A thread and replies as the first release stored them, before any versioning
+        db.insert(
+            "thread_3",
+            r#"{"id":3,"title":"Old","message":"From before","last_updated":1000,"image_url":null}"#,
+        )
+        .unwrap();
+        db.insert("reply_3_1", r#"{"id":1,"message":"First","email":null,"image_url":null}"#).unwrap();
+        db.insert("reply_3_2", r#"{"id":2,"message":"Second","email":"sage","image_url":null}"#).unwrap();
+
+        run(&db).unwrap();
+
+        let thread: Thread = serde_json::from_slice(&db.get("thread_3").unwrap().unwrap()).unwrap();
+        assert_eq!(thread.created_at, 1000);
+        let first: Reply = serde_json::from_slice(&db.get("reply_3_1").unwrap().unwrap()).unwrap();
+        let second: Reply = serde_json::from_slice(&db.get("reply_3_2").unwrap().unwrap()).unwrap();
+        assert_eq!(first.created_at, 1000);
+        assert!(!first.saged);
+        assert!(second.saged);
+
+        assert_eq!(stored_i32(&db, &reply_counter_key(3)), Some(2));
+        assert_eq!(stored_i32(&db, &reply_count_key(3)), Some(2));
+        assert_eq!(stored_i32(&db, THREAD_COUNTER_KEY), Some(3));
+        assert_eq!(stored_i32(&db, THREAD_COUNT_KEY), Some(1));
+        assert_eq!(stored_i32(&db, &bump_key(1000, 3)), Some(3));
+        assert_eq!(schema_version(&db).unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn applied_migrations_are_not_run_again() {
+        let db = test_support::temp_db();
+        run(&db).unwrap();
+        // Counting replies again would set this back to 1
+        db.insert("reply_9_1", r#"{"id":1,"message":"Hi","email":null,"image_url":null}"#).unwrap();
+        db.insert(reply_count_key(9), &5i32.to_be_bytes()).unwrap();
+        run(&db).unwrap();
+        assert_eq!(stored_i32(&db, &reply_count_key(9)), Some(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "newer than this build supports")]
+    fn a_newer_schema_is_refused() {
+        let db = test_support::temp_db();
+        db.insert(SCHEMA_VERSION_KEY, &(MIGRATIONS.len() as u32 + 1).to_be_bytes()).unwrap();
+        let _ = run(&db);
+    }
+}
@@ -0,0 +1,407 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sled::transaction::{ConflictableTransactionError, TransactionError, TransactionResult};
+use sled::{Batch, Db};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::post_numbers::{self, post_key};
+use crate::{expiry, tags, upload};
+use crate::{adjust_thread_count, bump_key, decode_counter, get_replies, reply_count_key, reply_counter_key};
+use crate::{reply_key, sticky_key};
+use crate::{Reply, Sticky, Thread};
+use crate::{THUMB_DIR, UPLOAD_DIR};
+
+// Everything a wipe removes: the posts and their indexes, the thread, reply and
+// post number counters, the thread and reply counts, the repost index, reports
+// and the upload bookkeeping. Accounts, sessions, the modlog, the announcement,
+// the poster hash salt and the schema version stay.
+const WIPED_PREFIXES: [&[u8]; 13] = [
+    b"thread_",
+    b"reply_",
+    b"bump_",
+    b"sticky_",
+    b"tag_",
+    b"counter_",
+    b"count_",
+    b"post_",
+    b"dhash_",
+    b"repost_",
+    b"report_",
+    b"upload_token_",
+    b"upload_bytes_total",
+];
+
+// Why a merge did not happen
+#[derive(Debug)]
+pub enum MergeError {
+    SameThread,
+    NotFound(i32),
+    // Replies kept arriving in the source thread on every attempt
+    Busy,
+    Storage(sled::Error),
+}
+
+// Merge attempts before giving up on a source thread that keeps getting replies
+const MERGE_ATTEMPTS: usize = 5;
+
+// Aborts inside the merge transaction
+enum Abort {
+    NotFound(i32),
+    // A reply was posted to the source thread after its replies were read
+    Stale,
+}
+
+// Fold thread `source_id` into thread `target_id`. The source's opening post
+// and then its replies, oldest first, are appended to the target as new
+// replies numbered after the target's own, and the source thread is removed.
+// The target is bumped to the later of the two bump times. Quote links inside
+// the moved posts keep their old numbers. With global post numbers the moved
+// posts get new post numbers. Returns how many posts were moved.
+pub fn merge_threads(db: &Db, source_id: i32, target_id: i32, global_numbers: bool) -> Result<usize, MergeError> {
+    if source_id == target_id {
+        return Err(MergeError::SameThread);
+    }
+
+    for _ in 0..MERGE_ATTEMPTS {
+        // Transactions can't scan, so the replies are read up front and the
+        // transaction checks that the reply counter hasn't moved since
+        let read_counter = db.get(reply_counter_key(source_id)).map_err(MergeError::Storage)?;
+        let snapshot = read_counter.map_or(0, |value| decode_counter(&value));
+        let replies = get_replies(db, source_id);
+        let result = db.transaction(|tx| {
+            let read_thread = |id: i32| -> Result<Thread, ConflictableTransactionError<Abort>> {
+                tx.get(format!("thread_{}", id))?
+                    .and_then(|value| serde_json::from_slice(&value).ok())
+                    .ok_or(ConflictableTransactionError::Abort(Abort::NotFound(id)))
+            };
+            let source = read_thread(source_id)?;
+            let mut target = read_thread(target_id)?;
+
+            let source_count = tx.get(reply_counter_key(source_id))?.map_or(0, |value| decode_counter(&value));
+            if source_count != snapshot {
+                return Err(ConflictableTransactionError::Abort(Abort::Stale));
+            }
+
+            let opening_post = Reply {
+                id: source.id,
+                message: match source.title.is_empty() {
+                    true => source.message.clone(),
+                    false => format!("**{}**\n{}", source.title, source.message),
+                },
+                created_at: source.created_at,
+                email: source.email.clone(),
+                image_url: source.image_url.clone(),
+                thumbnails: source.thumbnails.clone(),
+                image_size: source.image_size,
+                poster_hash: source.poster_hash.clone(),
+                image_removed: source.image_removed,
+                saged: false,
+                username: source.username.clone(),
+                country: source.country.clone(),
+                nsfw: source.nsfw,
+                image_missing: false,
+            };
+
+            let mut next_id = tx.get(reply_counter_key(target_id))?.map_or(0, |value| decode_counter(&value));
+            for (index, post) in std::iter::once(&opening_post).chain(&replies).enumerate() {
+                // Drop the old number, unless it predates global numbering and is another post's
+                if tx.get(post_key(post.id))?.is_some_and(|value| decode_counter(&value) == source_id) {
+                    tx.remove(post_key(post.id))?;
+                }
+                next_id = if global_numbers {
+                    post_numbers::next_number(tx, |_| target_id)?
+                } else {
+                    next_id + 1
+                };
+                let moved = Reply { id: next_id, ..post.clone() };
+                tx.insert(
+                    reply_key(target_id, next_id),
+                    serde_json::to_vec(&moved).expect("Failed to serialize reply"),
+                )?;
+                if index > 0 {
+                    tx.remove(reply_key(source_id, post.id))?;
+                }
+            }
+            tx.insert(reply_counter_key(target_id), &next_id.to_be_bytes())?;
+            let target_count = tx.get(reply_count_key(target_id))?.map_or(0, |value| decode_counter(&value));
+            let moved_count = replies.len() as i32 + 1;
+            tx.insert(reply_count_key(target_id), &(target_count + moved_count).to_be_bytes())?;
+
+            tx.remove(format!("thread_{}", source_id).into_bytes())?;
+            adjust_thread_count(tx, -1)?;
+            tx.remove(bump_key(source.last_updated, source.id))?;
+            tx.remove(reply_counter_key(source_id))?;
+            tx.remove(reply_count_key(source_id))?;
+            tags::unindex(tx, &source)?;
+
+            if source.last_updated > target.last_updated {
+                tx.remove(bump_key(target.last_updated, target.id))?;
+                target.last_updated = source.last_updated;
+                tx.insert(bump_key(target.last_updated, target.id), &target.id.to_be_bytes())?;
+                tx.insert(
+                    format!("thread_{}", target_id).into_bytes(),
+                    serde_json::to_vec(&target).expect("Failed to serialize thread"),
+                )?;
+            }
+
+            Ok(replies.len() + 1)
+        });
+
+        return match result {
+            Ok(moved) => Ok(moved),
+            Err(TransactionError::Abort(Abort::Stale)) => continue,
+            Err(TransactionError::Abort(Abort::NotFound(id))) => Err(MergeError::NotFound(id)),
+            Err(TransactionError::Storage(e)) => Err(MergeError::Storage(e)),
+        };
+    }
+    Err(MergeError::Busy)
+}
+
+// What delete_by_poster removed
+pub struct PosterDeletion {
+    // Threads the poster started, which went with all their replies
+    pub threads: usize,
+    // The poster's replies in other threads
+    pub replies: usize,
+}
+
+// Delete every post carrying `poster_hash`, or only those in thread `scope`.
+// A thread the poster started goes with all of its replies. Their replies in
+// other threads are removed one thread per transaction, together with the
+// thread's reply count; reply numbers aren't reused. Images go too.
+pub fn delete_by_poster(db: &Db, poster_hash: &str, scope: Option<i32>) -> TransactionResult<PosterDeletion> {
+    let by_poster = |hash: &Option<String>| hash.as_deref() == Some(poster_hash);
+    let in_scope = |thread_id: i32| scope.is_none_or(|scope| scope == thread_id);
+
+    let threads: Vec<i32> = crate::get_all_threads(db)
+        .into_iter()
+        .filter(|thread| in_scope(thread.id) && by_poster(&thread.poster_hash))
+        .map(|thread| thread.id)
+        .collect();
+    let mut deleted = PosterDeletion { threads: 0, replies: 0 };
+    for thread_id in threads {
+        if expiry::delete_thread(db, thread_id, i64::MAX)?.is_some() {
+            deleted.threads += 1;
+        }
+    }
+
+    let mut replies: HashMap<i32, Vec<Reply>> = HashMap::new();
+    for (key, value) in db.scan_prefix(b"reply_").flatten() {
+        let thread_id = std::str::from_utf8(&key[b"reply_".len()..])
+            .ok()
+            .and_then(|rest| rest.split_once('_'))
+            .and_then(|(thread_id, _)| thread_id.parse().ok());
+        let reply = serde_json::from_slice::<Reply>(&value).ok();
+        if let (Some(thread_id), Some(reply)) = (thread_id, reply) {
+            if in_scope(thread_id) && by_poster(&reply.poster_hash) {
+                replies.entry(thread_id).or_default().push(reply);
+            }
+        }
+    }
+
+    for (thread_id, replies) in replies {
+        let removed = db.transaction(|tx| {
+            let mut removed = Vec::new();
+            for reply in &replies {
+                if tx.remove(reply_key(thread_id, reply.id))?.is_some() {
+                    removed.push(reply);
+                }
+            }
+            let count = tx.get(reply_count_key(thread_id))?.map_or(0, |value| decode_counter(&value));
+            let count = (count - removed.len() as i32).max(0);
+            tx.insert(reply_count_key(thread_id), &count.to_be_bytes())?;
+            Ok::<_, ConflictableTransactionError<()>>(removed)
+        })?;
+
+        for reply in &removed {
+            post_numbers::forget(db, reply.id, thread_id).map_err(TransactionError::Storage)?;
+            if let Some(url) = &reply.image_url {
+                upload::delete_image(db, url);
+            }
+        }
+        deleted.replies += removed.len();
+    }
+
+    Ok(deleted)
+}
+
+// What wipe removed, or would remove on a dry run
+#[derive(Serialize)]
+pub struct Wipe {
+    pub dry_run: bool,
+    pub threads: usize,
+    pub replies: usize,
+    // Uploads and thumbnails, and the bytes they took
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// Delete every thread, reply and upload file and start the counters over, so
+// the next thread is number 1 again. The keys go in one batch; the files only
+// after it is applied. A dry run just counts.
+pub fn wipe(db: &Db, dry_run: bool) -> sled::Result<Wipe> {
+    let mut report = Wipe {
+        dry_run,
+        threads: db.scan_prefix(b"thread_").count(),
+        replies: db.scan_prefix(b"reply_").count(),
+        files: 0,
+        bytes: 0,
+    };
+    let files: Vec<(std::path::PathBuf, u64)> = [UPLOAD_DIR, THUMB_DIR]
+        .into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+            Some((entry.path(), metadata.len()))
+        })
+        .collect();
+    report.files = files.len();
+    report.bytes = files.iter().map(|(_, size)| size).sum();
+    if dry_run {
+        return Ok(report);
+    }
+
+    let mut batch = Batch::default();
+    for prefix in WIPED_PREFIXES {
+        for key in db.scan_prefix(prefix).keys() {
+            batch.remove(key?);
+        }
+    }
+    db.apply_batch(batch)?;
+    db.flush()?;
+    for (path, _) in &files {
+        if let Err(e) = fs::remove_file(path) {
+            log::error!("Failed to delete {} during a wipe: {}", path.display(), e);
+        }
+    }
+    Ok(report)
+}
+
+// Change a thread's record with `change`, reading and writing it in one
+// transaction so a reply bumping the thread meanwhile isn't undone. Returns the
+// thread as it was before, or None if there is no such thread.
+pub fn update_thread(db: &Db, thread_id: i32, change: impl Fn(&mut Thread)) -> TransactionResult<Option<Thread>> {
+    update_record(db, format!("thread_{}", thread_id).into_bytes(), change)
+}
+
+// Pin a thread to the top of the board index, or unpin it with None, keeping
+// the sticky_ index in step. The index is checked against the thread when
+// read, so it may briefly hold a stale entry.
+pub fn set_sticky(db: &Db, thread_id: i32, sticky: Option<Sticky>) -> TransactionResult<Option<Thread>> {
+    let previous = update_thread(db, thread_id, |thread| thread.sticky = sticky)?;
+    if previous.is_some() {
+        let indexed = match sticky {
+            Some(_) => db.insert(sticky_key(thread_id), &[]).map(|_| ()),
+            None => db.remove(sticky_key(thread_id)).map(|_| ()),
+        };
+        indexed.map_err(TransactionError::Storage)?;
+    }
+    Ok(previous)
+}
+
+// Same as update_thread, for reply `reply_id` of thread `thread_id`
+pub fn update_reply(
+    db: &Db,
+    thread_id: i32,
+    reply_id: i32,
+    change: impl Fn(&mut Reply),
+) -> TransactionResult<Option<Reply>> {
+    update_record(db, reply_key(thread_id, reply_id), change)
+}
+
+fn update_record<T: Serialize + DeserializeOwned + Clone>(
+    db: &Db,
+    key: Vec<u8>,
+    change: impl Fn(&mut T),
+) -> TransactionResult<Option<T>> {
+    db.transaction(|tx| {
+        let previous: T = match tx.get(&key)?.and_then(|value| serde_json::from_slice(&value).ok()) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let mut record = previous.clone();
+        change(&mut record);
+        tx.insert(key.as_slice(), serde_json::to_vec(&record).expect("Failed to serialize record"))?;
+        Ok::<_, ConflictableTransactionError<()>>(Some(previous))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use crate::{get_thread, insert_reply, insert_thread};
+
+    #[test]
+    fn merged_posts_keep_their_content_and_order() {
+        let db = test_support::temp_db();
+        let target = insert_thread(&db, test_support::new_thread("Target", "Kept"), false).unwrap();
+        insert_reply(&db, target.id, test_support::new_reply("Target reply"), true, 0, 0, false).unwrap();
+        let source = insert_thread(&db, test_support::new_thread("Source", "Folded in"), false).unwrap();
+        for message in ["First", "Second"] {
+            let mut reply = test_support::new_reply(message);
+            reply.poster_hash = Some(format!("poster of {}", message));
+            insert_reply(&db, source.id, reply, true, 0, 0, false).unwrap();
+        }
+
+        assert_eq!(merge_threads(&db, source.id, target.id, false).unwrap(), 3);
+        let merged: Vec<(i32, String)> =
+            get_replies(&db, target.id).into_iter().map(|reply| (reply.id, reply.message)).collect();
+        let expected = [(1, "Target reply"), (2, "**Source**\nFolded in"), (3, "First"), (4, "Second")];
+        assert_eq!(merged, expected.map(|(id, message)| (id, message.to_string())));
+        let moved = crate::get_reply(&db, target.id, 4).unwrap();
+        assert_eq!(moved.poster_hash.as_deref(), Some("poster of Second"));
+
+        assert!(get_thread(&db, source.id).is_none());
+        assert!(get_replies(&db, source.id).is_empty());
+        let next = insert_reply(&db, target.id, test_support::new_reply("After"), true, 0, 0, false).unwrap();
+        assert_eq!(next.id, 5);
+
+        assert!(matches!(merge_threads(&db, target.id, target.id, false), Err(MergeError::SameThread)));
+        let gone = merge_threads(&db, source.id, target.id, false);
+        assert!(matches!(gone, Err(MergeError::NotFound(id)) if id == source.id));
+    }
+
+    #[test]
+    fn deleting_a_poster_leaves_the_other_posters_alone() {
+        let db = test_support::temp_db();
+        let by = |hash: &str, mut reply: crate::NewReply| {
+            reply.poster_hash = Some(hash.to_string());
+            reply
+        };
+        let mut theirs = test_support::new_thread("Theirs", "Started by the spammer");
+        theirs.poster_hash = Some("spammer".to_string());
+        let theirs = insert_thread(&db, theirs, false).unwrap();
+        let caught = by("regular", test_support::new_reply("Caught in it"));
+        insert_reply(&db, theirs.id, caught, true, 0, 0, false).unwrap();
+        let mut kept = test_support::new_thread("Kept", "Started by a regular");
+        kept.poster_hash = Some("regular".to_string());
+        let kept = insert_thread(&db, kept, false).unwrap();
+        let hello = by("regular", test_support::new_reply("Hello"));
+        let first = insert_reply(&db, kept.id, hello, true, 0, 0, false).unwrap();
+        db.insert(reply_key(kept.id, first.id), serde_json::to_vec(&Reply { created_at: 100, ..first }).unwrap())
+            .unwrap();
+        for message in ["Spam", "More spam"] {
+            insert_reply(&db, kept.id, by("spammer", test_support::new_reply(message)), true, 0, 0, false).unwrap();
+        }
+
+        let deleted = delete_by_poster(&db, "spammer", None).unwrap();
+        assert_eq!((deleted.threads, deleted.replies), (1, 2));
+        assert!(get_thread(&db, theirs.id).is_none());
+        assert!(get_replies(&db, theirs.id).is_empty());
+        assert!(get_thread(&db, kept.id).is_some());
+        let left: Vec<String> = get_replies(&db, kept.id).into_iter().map(|reply| reply.message).collect();
+        assert_eq!(left, ["Hello"]);
+
+        let summary = crate::get_reply_summaries(&db, &[kept.id]).remove(&kept.id).unwrap();
+        assert_eq!((summary.count, summary.last_reply_at), (1, Some(100)));
+
+        // The source's counter still names a deleted reply; the merge goes through regardless
+        let target = insert_thread(&db, test_support::new_thread("Target", "Merged into"), false).unwrap();
+        assert_eq!(merge_threads(&db, kept.id, target.id, false).unwrap(), 2);
+        let merged: Vec<String> = get_replies(&db, target.id).into_iter().map(|reply| reply.message).collect();
+        assert_eq!(merged, ["**Kept**\nStarted by a regular", "Hello"]);
+    }
+}
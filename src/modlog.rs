@@ -0,0 +1,44 @@
+// What moderators did from /admin, newest first on the dashboard.
+//
+// Keys:
+// modlog_{N}   JSON Entry, N a zero-padded sled generated id, so a scan is in order
+
+use log::error;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub at: i64,
+    // Short name of the action, like "delete-image"
+    pub action: String,
+    // What it was done to, in words
+    pub detail: String,
+}
+
+// Add an entry. The action already happened, so a failure is only logged.
+pub fn record(db: &Db, action: &str, detail: String) {
+    let entry = Entry {
+        at: chrono::Utc::now().timestamp(),
+        action: action.to_string(),
+        detail,
+    };
+    let stored = db.generate_id().and_then(|id| {
+        let value = serde_json::to_vec(&entry).expect("Failed to serialize modlog entry");
+        db.insert(format!("modlog_{:020}", id), value)
+    });
+    if let Err(e) = stored {
+        error!("Failed to record {} ({}) in the modlog: {}", entry.action, entry.detail, e);
+    }
+}
+
+// The newest `limit` entries, newest first
+pub fn recent(db: &Db, limit: usize) -> Vec<Entry> {
+    db.scan_prefix(b"modlog_")
+        .values()
+        .rev()
+        .flatten()
+        .filter_map(|value| serde_json::from_slice(&value).ok())
+        .take(limit)
+        .collect()
+}
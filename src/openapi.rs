@@ -0,0 +1,426 @@
+use actix_web::{web, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::settings::Settings;
+
+// OpenAPI 3.0 description of the /api/ endpoints, served at /api/openapi.json.
+// Written by hand next to the handlers in api.rs; a field or route added there
+// has to be added here too.
+pub fn document(settings: &Settings) -> Value {
+    let server = if settings.base_path.is_empty() { "/" } else { settings.base_path.as_str() };
+    let write_note = "Only available when API_KEY is set; every call needs \"Authorization: Bearer <key>\".";
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Imageboard API",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": server }],
+        "paths": {
+            "/api/threads": {
+                "get": {
+                    "summary": "List threads in bump order",
+                    "description": "Paged either by page or by cursor. next_cursor is set while more threads follow; \
+                        walking the cursors doesn't skip or repeat threads.",
+                    "parameters": [
+                        query_param("page", "integer", "Page number, from 1; page and total_pages are then included"),
+                        query_param("per_page", "integer", "Threads per page, 1 to 100"),
+                        query_param("cursor", "string", "next_cursor of the previous response"),
+                    ],
+                    "responses": {
+                        "200": json_response("A page of threads", json!({
+                            "type": "object",
+                            "required": ["threads", "per_page", "next_cursor"],
+                            "properties": {
+                                "threads": { "type": "array", "items": schema_ref("Thread") },
+                                "per_page": { "type": "integer" },
+                                "next_cursor": { "type": "string", "nullable": true },
+                                "page": { "type": "integer" },
+                                "total_pages": { "type": "integer" },
+                            },
+                        })),
+                        "400": error_response("Both page and cursor given, or an invalid cursor"),
+                    },
+                },
+            },
+            "/api/thread/{id}": {
+                "get": {
+                    "summary": "A thread with one page of its replies, oldest first",
+                    "parameters": [
+                        path_param("id", "Thread id"),
+                        query_param("page", "integer", "Page of replies, from 1"),
+                        query_param("per_page", "integer", "Replies per page, 1 to 200 (default 50)"),
+                    ],
+                    "responses": {
+                        "200": json_response("The thread", json!({
+                            "type": "object",
+                            "properties": {
+                                "thread": schema_ref("Thread"),
+                                "replies": { "type": "array", "items": schema_ref("Reply") },
+                                "page": { "type": "integer" },
+                                "per_page": { "type": "integer" },
+                                "total_replies": { "type": "integer" },
+                                "total_pages": { "type": "integer" },
+                            },
+                        })),
+                        "404": error_response("Thread not found"),
+                    },
+                },
+            },
+            "/api/thread/{id}/reply/{rid}": {
+                "get": {
+                    "summary": "A single reply",
+                    "parameters": [path_param("id", "Thread id"), path_param("rid", "Reply id")],
+                    "responses": {
+                        "200": json_response("The reply", schema_ref("Reply")),
+                        "404": error_response("Reply not found"),
+                    },
+                },
+            },
+            "/api/thread/{id}/quote/{rid}": {
+                "get": {
+                    "summary": "Text to put in a new reply when quoting a reply",
+                    "parameters": [path_param("id", "Thread id"), path_param("rid", "Reply id")],
+                    "responses": {
+                        "200": json_response("The quote", json!({
+                            "type": "object",
+                            "properties": {
+                                "thread_id": { "type": "integer" },
+                                "reply_id": { "type": "integer" },
+                                "quote": { "type": "string" },
+                            },
+                        })),
+                        "404": error_response("Reply not found"),
+                    },
+                },
+            },
+            "/api/thread/{id}/reply": {
+                "post": {
+                    "summary": "Post a reply from the thread page's form",
+                    "description": "Takes the same multipart form as /reply and answers with the new reply \
+                        rendered as HTML, for adding it to the page. A refused reply gets an HTML error page.",
+                    "parameters": [path_param("id", "Thread id")],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "multipart/form-data": { "schema": json!({
+                            "type": "object",
+                            "required": ["message"],
+                            "properties": {
+                                "message": { "type": "string" },
+                                "email": { "type": "string" },
+                                "image": { "type": "string", "format": "binary" },
+                            },
+                        }) } },
+                    },
+                    "responses": {
+                        "201": { "description": "The reply", "content": { "text/html": {} } },
+                        "400": { "description": "The reply was refused", "content": { "text/html": {} } },
+                        "403": { "description": "The thread is locked or archived" },
+                        "404": { "description": "Thread not found" },
+                        "410": { "description": "The thread has expired" },
+                    },
+                },
+            },
+            "/api/thread": {
+                "post": {
+                    "summary": "Start a thread",
+                    "description": write_note,
+                    "security": [{ "apiKey": [] }],
+                    "requestBody": json_body(json!({
+                        "type": "object",
+                        "required": ["message"],
+                        "properties": {
+                            "title": {
+                                "type": "string",
+                                "maxLength": 75,
+                                "description": "Ignored on boards without titles",
+                            },
+                            "message": { "type": "string", "maxLength": 8000 },
+                            "email": { "type": "string" },
+                            "tags": {
+                                "type": "string",
+                                "description": "Comma-separated tags; ignored on boards without tags",
+                            },
+                            "nsfw": {
+                                "type": "boolean",
+                                "description": "Blur the image's thumbnail until it is clicked",
+                            },
+                            "image_token": { "type": "string", "description": "Token returned by /api/upload" },
+                            "image_base64": {
+                                "type": "string",
+                                "description": "Or the image itself, base64 encoded (a data: URL works too)",
+                            },
+                        },
+                    })),
+                    "responses": {
+                        "201": json_response("The new thread; Location points at its page", schema_ref("Thread")),
+                        "400": validation_response(),
+                        "401": error_response("Missing or invalid API key"),
+                    },
+                },
+            },
+            "/api/reply": {
+                "post": {
+                    "summary": "Reply to a thread",
+                    "description": write_note,
+                    "security": [{ "apiKey": [] }],
+                    "requestBody": json_body(json!({
+                        "type": "object",
+                        "required": ["parent_id", "message"],
+                        "properties": {
+                            "parent_id": { "type": "integer" },
+                            "message": { "type": "string", "maxLength": 8000 },
+                            "email": { "type": "string" },
+                            "nsfw": {
+                                "type": "boolean",
+                                "description": "Blur the image's thumbnail until it is clicked",
+                            },
+                            "image_token": { "type": "string", "description": "Token returned by /api/upload" },
+                            "image_base64": {
+                                "type": "string",
+                                "description": "Or the image itself, base64 encoded (a data: URL works too)",
+                            },
+                        },
+                    })),
+                    "responses": {
+                        "201": json_response("The new reply; Location points at its thread", schema_ref("Reply")),
+                        "400": validation_response(),
+                        "401": error_response("Missing or invalid API key"),
+                        "403": error_response("The thread is locked or archived"),
+                        "404": error_response("Thread not found"),
+                        "410": error_response("The thread has expired (THREAD_TTL) and is about to be deleted"),
+                    },
+                },
+            },
+            "/api/upload": {
+                "post": {
+                    "summary": "Upload an image for a later /api/thread or /api/reply call",
+                    "description": write_note,
+                    "security": [{ "apiKey": [] }],
+                    "requestBody": {
+                        "required": true,
+                        "content": { "multipart/form-data": { "schema": json!({
+                            "type": "object",
+                            "required": ["image"],
+                            "properties": { "image": { "type": "string", "format": "binary" } },
+                        }) } },
+                    },
+                    "responses": {
+                        "201": json_response("The stored image and its token", json!({
+                            "type": "object",
+                            "properties": {
+                                "image_url": { "type": "string" },
+                                "token": { "type": "string" },
+                            },
+                        })),
+                        "400": error_response("No image, or the image was refused"),
+                        "401": error_response("Missing or invalid API key"),
+                    },
+                },
+            },
+        },
+        "components": {
+            "securitySchemes": {
+                "apiKey": { "type": "http", "scheme": "bearer" },
+            },
+            "schemas": {
+                "Thread": {
+                    "type": "object",
+                    "required": ["id", "title", "message", "last_updated", "created_at"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "title": { "type": "string" },
+                        "message": { "type": "string" },
+                        "last_updated": { "type": "integer", "description": "Unix timestamp of the last bump" },
+                        "created_at": { "type": "integer", "description": "Unix timestamp" },
+                        "image_url": { "type": "string", "nullable": true },
+                        "thumbnails": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Thumbnail URLs, smallest first",
+                        },
+                        "image_size": schema_ref("ImageSize"),
+                        "email": { "type": "string", "nullable": true },
+                        "custom_thumbnail": {
+                            "type": "object",
+                            "description": "Image a moderator set to show instead of the thumbnails",
+                            "properties": {
+                                "image_url": { "type": "string" },
+                                "thumbnails": { "type": "array", "items": { "type": "string" } },
+                            },
+                        },
+                        "image_removed": { "type": "boolean", "description": "A moderator deleted the image" },
+                        "sticky": {
+                            "description": "Pinned from /admin: \"forever\", or until a Unix timestamp",
+                            "oneOf": [
+                                { "type": "string", "enum": ["forever"] },
+                                {
+                                    "type": "object",
+                                    "properties": { "until": { "type": "integer" } },
+                                },
+                            ],
+                        },
+                        "locked": { "type": "boolean", "description": "Set from /admin; takes no new replies" },
+                        "archived": {
+                            "type": "boolean",
+                            "description": "Set from /admin; kept for reading but takes no new replies",
+                        },
+                        "tags": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Lowercase tags, left out when the thread has none",
+                        },
+                        "country": {
+                            "type": "string",
+                            "description": "Poster's two-letter country code, on boards with GeoIP lookups",
+                        },
+                        "nsfw": { "type": "boolean", "description": "Marked NSFW; its thumbnail is shown blurred" },
+                    },
+                },
+                "Reply": {
+                    "type": "object",
+                    "required": ["id", "message", "created_at"],
+                    "properties": {
+                        "id": { "type": "integer" },
+                        "message": { "type": "string" },
+                        "created_at": { "type": "integer", "description": "Unix timestamp" },
+                        "image_url": { "type": "string", "nullable": true },
+                        "thumbnails": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Thumbnail URLs, smallest first",
+                        },
+                        "image_size": schema_ref("ImageSize"),
+                        "email": { "type": "string", "nullable": true },
+                        "image_removed": { "type": "boolean", "description": "A moderator deleted the image" },
+                        "country": {
+                            "type": "string",
+                            "description": "Poster's two-letter country code, on boards with GeoIP lookups",
+                        },
+                        "nsfw": { "type": "boolean", "description": "Marked NSFW; its thumbnail is shown blurred" },
+                    },
+                },
+                "ImageSize": {
+                    "type": "object",
+                    "description": "Width and height of the full image",
+                    "properties": {
+                        "width": { "type": "integer" },
+                        "height": { "type": "integer" },
+                    },
+                },
+                "Error": {
+                    "type": "object",
+                    "required": ["error"],
+                    "properties": {
+                        "error": { "type": "string" },
+                        "errors": {
+                            "type": "array",
+                            "items": { "type": "string" },
+                            "description": "Every problem with a refused post, the first one also being \"error\"",
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "description": description,
+        "schema": { "type": "integer" },
+    })
+}
+
+fn query_param(name: &str, kind: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": { "type": kind } })
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schema } } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn error_response(description: &str) -> Value {
+    json_response(description, schema_ref("Error"))
+}
+
+fn validation_response() -> Value {
+    error_response("The post was refused; errors lists every problem")
+}
+
+pub async fn serve(settings: web::Data<Settings>) -> HttpResponse {
+    HttpResponse::Ok().json(document(&settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    // Every "$ref" in `value`
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(map) => {
+                found.extend(map.get("$ref").and_then(Value::as_str));
+                map.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(items) => items.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_document_is_json_listing_every_api_route() {
+        let state = test_support::state(&[("BASE_PATH", "/board")]);
+        let app = init_service(crate::app(&state)).await;
+        let response = call_service(&app, TestRequest::get().uri("/board/api/openapi.json").to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/json");
+        let document: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+
+        assert_eq!(document["openapi"], "3.0.3");
+        assert_eq!(document["servers"][0]["url"], "/board");
+        let mut paths: Vec<&str> = document["paths"].as_object().unwrap().keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        assert_eq!(
+            paths,
+            [
+                "/api/reply",
+                "/api/thread",
+                "/api/thread/{id}",
+                "/api/thread/{id}/quote/{rid}",
+                "/api/thread/{id}/reply",
+                "/api/thread/{id}/reply/{rid}",
+                "/api/threads",
+                "/api/upload",
+            ]
+        );
+        for (path, operations) in document["paths"].as_object().unwrap() {
+            for (method, operation) in operations.as_object().unwrap() {
+                assert!(["get", "post"].contains(&method.as_str()), "{} {}", method, path);
+                assert!(operation["responses"].as_object().is_some_and(|r| !r.is_empty()), "{} {}", method, path);
+            }
+        }
+
+        let mut found = Vec::new();
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap();
+            assert!(document["components"]["schemas"][name].is_object(), "{} is not defined", reference);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use actix_multipart::{Multipart, MultipartError};
+use actix_web::{web, Error, HttpResponse};
+use futures_util::stream::StreamExt;
+use log::error;
+use sled::Db;
+
+use crate::settings::Settings;
+use crate::upload::{self, ImageSize, UploadError, UploadMeta};
+use crate::validation::MAX_MESSAGE_CHARS;
+
+// The fields of a thread or reply form. The whole multipart body is read before
+// anything is validated, so it doesn't matter which order the fields arrive in.
+// The image is streamed to a temporary file, which is renamed into place with
+// commit() once the post is stored or deleted with discard() if it is refused,
+// so a failed post never leaves a file behind.
+#[derive(Default)]
+pub struct PostForm {
+    pub parent_id: String,
+    pub title: String,
+    pub message: String,
+    pub email: String,
+    // Comma-separated, as typed; see tags::parse
+    pub tags: String,
+    // The "mark as NSFW" checkbox
+    pub nsfw: bool,
+    pub image: Option<UploadMeta>,
+}
+
+// Every field name a post form may carry. Others are skipped, or refused under
+// STRICT_FORM_FIELDS.
+pub const FIELD_NAMES: [&str; 7] = ["parent_id", "title", "message", "email", "tags", "nsfw", "image"];
+
+// Most bytes a text field may collect, repeats of it included: the longest
+// message at four bytes a character, with room to spare for line endings.
+// Nothing longer could pass validation, so it isn't buffered.
+const MAX_TEXT_FIELD_BYTES: usize = MAX_MESSAGE_CHARS * 4 + 1024;
+
+pub enum FormError {
+    // The request body itself could not be read
+    Payload(Error),
+    Upload(UploadError),
+    Refused(&'static str),
+    // A text field longer than MAX_TEXT_FIELD_BYTES
+    TextTooLarge,
+    // A field not in FIELD_NAMES, under STRICT_FORM_FIELDS
+    UnknownField(String),
+}
+
+impl From<Error> for FormError {
+    fn from(e: Error) -> Self {
+        FormError::Payload(e)
+    }
+}
+
+impl From<MultipartError> for FormError {
+    fn from(e: MultipartError) -> Self {
+        FormError::Payload(e.into())
+    }
+}
+
+impl FormError {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            FormError::Payload(e) => e.error_response(),
+            FormError::Upload(e) => e.to_response(),
+            FormError::Refused(reason) => HttpResponse::BadRequest().body(*reason),
+            FormError::TextTooLarge => HttpResponse::PayloadTooLarge()
+                .body(format!("Text fields may be at most {} bytes", MAX_TEXT_FIELD_BYTES)),
+            FormError::UnknownField(name) => HttpResponse::BadRequest().body(format!(
+                "Unexpected form field {:?}; accepted fields are {}",
+                name,
+                FIELD_NAMES.join(", ")
+            )),
+        }
+    }
+}
+
+impl PostForm {
+    // Read every field of the form. `image_refusal` is the reason to give when
+    // this form may not carry an image; an empty file input is fine either way.
+    // `poster` is the poster hash, for the log when the image is refused.
+    pub async fn read(
+        payload: &mut Multipart,
+        settings: &Settings,
+        db: &Db,
+        image_refusal: Option<&'static str>,
+        poster: Option<&str>,
+    ) -> Result<Self, FormError> {
+        let mut form = PostForm::default();
+        match form.read_fields(payload, settings, db, image_refusal, poster).await {
+            Ok(()) => Ok(form),
+            Err(e) => {
+                form.discard(db);
+                Err(e)
+            }
+        }
+    }
+
+    async fn read_fields(
+        &mut self,
+        payload: &mut Multipart,
+        settings: &Settings,
+        db: &Db,
+        image_refusal: Option<&'static str>,
+        poster: Option<&str>,
+    ) -> Result<(), FormError> {
+        while let Some(item) = payload.next().await {
+            let mut field = item?;
+
+            let name = if let Some(name) = field.content_disposition().get_name() {
+                name.to_string()
+            } else {
+                continue;
+            };
+
+            if settings.strict_form_fields && !FIELD_NAMES.contains(&name.as_str()) {
+                return Err(FormError::UnknownField(name));
+            }
+
+            match name.as_str() {
+                "parent_id" => read_text_field(&mut field, &mut self.parent_id).await?,
+                "title" => read_text_field(&mut field, &mut self.title).await?,
+                "message" => read_text_field(&mut field, &mut self.message).await?,
+                "email" => read_text_field(&mut field, &mut self.email).await?,
+                "tags" => read_text_field(&mut field, &mut self.tags).await?,
+                // A checkbox sends a value only when ticked
+                "nsfw" => {
+                    let mut value = String::new();
+                    read_text_field(&mut field, &mut value).await?;
+                    self.nsfw = !value.trim().is_empty();
+                }
+                // Only the first image counts
+                "image" if self.image.is_some() => {}
+                "image" => {
+                    let has_file = field.content_disposition().get_filename().is_some_and(|name| !name.is_empty());
+                    if let (Some(reason), true) = (image_refusal, has_file) {
+                        return Err(FormError::Refused(reason));
+                    }
+                    self.image = upload::save_upload(&mut field, settings, db, poster)
+                        .await
+                        .map_err(FormError::Upload)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn image_url(&self) -> Option<String> {
+        self.image.as_ref().map(UploadMeta::url)
+    }
+
+    pub fn image_size(&self) -> Option<ImageSize> {
+        self.image.as_ref().map(UploadMeta::image_size)
+    }
+
+    pub fn thumbnails(&self) -> Vec<String> {
+        self.image.as_ref().map(|meta| meta.thumbnails.clone()).unwrap_or_default()
+    }
+
+    // Put the image in place once the post referencing it has been stored
+    pub fn commit(&self) {
+        if let Some(meta) = &self.image {
+            if let Err(e) = meta.commit() {
+                error!("Failed to move upload {} into place: {}", meta.filename, e);
+            }
+        }
+    }
+
+    // Delete the stored image of a post that was refused or failed to save
+    pub fn discard(&mut self, db: &Db) {
+        if let Some(meta) = self.image.take() {
+            upload::discard(db, &meta);
+        }
+    }
+}
+
+// Append a text form field to `text`. The bytes are decoded only once the
+// field is complete, since a chunk boundary can fall inside a UTF-8 character.
+async fn read_text_field<S>(field: &mut S, text: &mut String) -> Result<(), FormError>
+where
+    S: futures_util::Stream<Item = Result<web::Bytes, MultipartError>> + Unpin,
+{
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        bytes.extend_from_slice(&chunk?);
+        if text.len() + bytes.len() > MAX_TEXT_FIELD_BYTES {
+            return Err(FormError::TextTooLarge);
+        }
+    }
+    text.push_str(&String::from_utf8_lossy(&bytes));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, read_body};
+    use futures_util::stream;
+
+    #[actix_web::test]
+    async fn a_character_split_across_chunks_decodes_whole() {
+        let chunks = [b"caf\xc3".as_slice(), b"\xa9 \xe6\x97", b"\xa5"];
+        let mut field = stream::iter(chunks.map(|chunk| Ok::<_, MultipartError>(web::Bytes::from_static(chunk))));
+        let mut text = String::new();
+        assert!(read_text_field(&mut field, &mut text).await.is_ok());
+        assert_eq!(text, "café 日");
+
+        // One byte per chunk splits every character of the form
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[]);
+        let fields: [(&str, &[u8]); 2] = [("title", "Grüße".as_bytes()), ("message", "日本語 ✓".as_bytes())];
+        let mut payload = test_support::chunked_form(&fields, 1);
+        let form = PostForm::read(&mut payload, &settings, &db, None, None).await.ok().unwrap();
+        assert_eq!((form.title.as_str(), form.message.as_str()), ("Grüße", "日本語 ✓"));
+    }
+
+    #[actix_web::test]
+    async fn text_fields_stop_at_the_cap() {
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[]);
+        let read = |fields: Vec<(&'static str, Vec<u8>)>| {
+            let fields: Vec<(&str, &[u8])> = fields.iter().map(|(name, value)| (*name, value.as_slice())).collect();
+            let mut payload = test_support::chunked_form(&fields, 4096);
+            let (settings, db) = (&settings, &db);
+            async move { PostForm::read(&mut payload, settings, db, None, None).await }
+        };
+
+        let longest = "é".repeat(MAX_MESSAGE_CHARS).into_bytes();
+        assert_eq!(read(vec![("message", longest)]).await.ok().unwrap().message.chars().count(), MAX_MESSAGE_CHARS);
+
+        let refused = read(vec![("message", vec![b'a'; MAX_TEXT_FIELD_BYTES + 1])]).await;
+        assert!(matches!(refused, Err(FormError::TextTooLarge)));
+        let half = vec![b'a'; MAX_TEXT_FIELD_BYTES / 2 + 1];
+        let refused = read(vec![("title", half.clone()), ("title", half)]).await;
+        assert_eq!(refused.err().unwrap().to_response().status(), 413);
+    }
+
+    #[actix_web::test]
+    async fn unknown_fields_are_skipped_unless_strict() {
+        for strict in ["false", "true"] {
+            let state = test_support::state(&[("STRICT_FORM_FIELDS", strict)]);
+            let app = init_service(crate::app(&state)).await;
+            let fields: [(&str, &[u8]); 3] = [("title", b"Hi"), ("captcha", b"1234"), ("message", b"Hello")];
+            let response = call_service(&app, test_support::form_post("/thread", &fields).to_request()).await;
+            if strict == "true" {
+                assert_eq!(response.status(), 400);
+                let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+                assert!(body.contains("\"captcha\"") && body.contains(&FIELD_NAMES.join(", ")), "{}", body);
+                assert_eq!(state.db.scan_prefix(b"thread_").count(), 0);
+            } else {
+                assert_eq!(response.status(), 303);
+                assert_eq!(state.db.scan_prefix(b"thread_").count(), 1);
+            }
+        }
+
+        // Every accepted field still goes through in strict mode
+        let state = test_support::state(&[("STRICT_FORM_FIELDS", "true"), ("ALLOWED_IMAGE_TYPES", "png")]);
+        let png = test_support::png(20, 20, 255);
+        let settings = &state.settings;
+        let fields: Vec<(&str, &[u8])> = FIELD_NAMES
+            .iter()
+            .map(|&name| (name, if name == "image" { png.as_slice() } else { b"on".as_slice() }))
+            .collect();
+        let _files = test_support::files().await;
+        let mut payload = test_support::chunked_form(&fields, 4096);
+        let form = PostForm::read(&mut payload, settings, &state.db, None, None).await.ok().unwrap();
+        assert_eq!((form.title.as_str(), form.nsfw), ("on", true));
+        crate::upload::discard(&state.db, &form.image.expect("the image was dropped"));
+    }
+}
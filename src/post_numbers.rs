@@ -0,0 +1,176 @@
+// Board-wide post numbers (POST_NUMBERING=global). Normally threads count 1, 2,
+// 3... and every thread counts its replies from 1 again, so ">>3" can mean many
+// posts. With global numbers, opening posts and replies all draw from one
+// counter, and post_{N} records which thread post N is in, so a quote link can
+// find it from anywhere on the board.
+//
+// Keys:
+// counter_post   highest post number handed out
+// post_{N}       id of the thread post N belongs to (N itself for an opening post)
+
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::Db;
+
+use crate::{decode_counter, THREAD_COUNTER_KEY};
+
+pub const POST_COUNTER_KEY: &[u8] = b"counter_post";
+
+pub fn post_key(number: i32) -> Vec<u8> {
+    format!("post_{}", number).into_bytes()
+}
+
+// Start the counter above every thread and reply id already stored, the first
+// time the board runs with global numbers. Posts from before keep their numbers
+// and are resolved the old way.
+pub fn seed_counter(db: &Db) -> sled::Result<()> {
+    if db.contains_key(POST_COUNTER_KEY)? {
+        return Ok(());
+    }
+    let mut highest = db.get(THREAD_COUNTER_KEY)?.map_or(0, |value| decode_counter(&value));
+    for value in db.scan_prefix(b"counter_reply_").values() {
+        highest = highest.max(decode_counter(&value?));
+    }
+    db.insert(POST_COUNTER_KEY, &highest.to_be_bytes())?;
+    Ok(())
+}
+
+// Hand out the next post number and record which thread it's in. The counter
+// is read and written inside the caller's transaction, so concurrent posts
+// conflict and retry instead of sharing a number.
+pub fn next_number<E>(
+    tx: &TransactionalTree,
+    thread_id: impl FnOnce(i32) -> i32,
+) -> Result<i32, ConflictableTransactionError<E>> {
+    let number = tx.get(POST_COUNTER_KEY)?.map_or(0, |value| decode_counter(&value)) + 1;
+    tx.insert(POST_COUNTER_KEY, &number.to_be_bytes())?;
+    tx.insert(post_key(number), &thread_id(number).to_be_bytes())?;
+    Ok(number)
+}
+
+// The thread globally numbered post `number` is in, if it is one
+pub fn thread_of(db: &Db, number: i32) -> Option<i32> {
+    db.get(post_key(number)).ok().flatten().map(|value| decode_counter(&value))
+}
+
+// Drop the entry of post `number` if it still says `thread_id`, once the post
+// is gone. A number from before global numbering may belong to another post.
+pub fn forget(db: &Db, number: i32, thread_id: i32) -> sled::Result<()> {
+    let _ = db.compare_and_swap(post_key(number), Some(&thread_id.to_be_bytes()[..]), None as Option<&[u8]>)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use crate::{get_replies, get_thread, insert_reply, insert_thread, AppState};
+    use actix_web::test::{call_and_read_body, call_and_read_body_json, call_service, init_service, TestRequest};
+
+    #[actix_web::test]
+    async fn threads_and_replies_share_one_sequence() {
+        let state = test_support::state(&[("GLOBAL_POST_NUMBERS", "true"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        let post = |uri: &str, fields: &[(&str, &[u8])]| {
+            let request = form_post(uri, fields).to_request();
+            async { assert_eq!(call_service(&app, request).await.status(), 303) }
+        };
+
+        post("/thread", &[("title", b"First"), ("message", b"Hi")]).await;
+        post("/reply", &[("parent_id", b"1"), ("message", b"Reply")]).await;
+        post("/thread", &[("title", b"Second"), ("message", b"Hi")]).await;
+        post("/reply", &[("parent_id", b"1"), ("message", b"Another")]).await;
+        post("/reply", &[("parent_id", b"3"), ("message", b">>4 >>1 >>2")]).await;
+
+        assert_eq!(get_thread(&state.db, 1).unwrap().title, "First");
+        assert_eq!(get_thread(&state.db, 3).unwrap().title, "Second");
+        let numbers = |thread| get_replies(&state.db, thread).into_iter().map(|reply| reply.id).collect::<Vec<_>>();
+        assert_eq!((numbers(1), numbers(3)), (vec![2, 4], vec![5]));
+        let owners: Vec<_> = (1..=6).map(|number| thread_of(&state.db, number)).collect();
+        assert_eq!(owners, [Some(1), Some(1), Some(3), Some(1), Some(3), None]);
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/3").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        for href in ["/thread/1#p4", "/thread/1", "/thread/1#p2"] {
+            assert!(page.contains(&format!("href=\"{}\" class=\"quotelink\"", href)), "no link to {}", href);
+        }
+    }
+
+    #[test]
+    fn the_counter_starts_above_the_posts_already_stored() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Old", "Hi"), false).unwrap();
+        for _ in 0..3 {
+            insert_reply(&db, thread.id, test_support::new_reply("Old reply"), true, 0, 0, false).unwrap();
+        }
+        seed_counter(&db).unwrap();
+        let thread = insert_thread(&db, test_support::new_thread("New", "Hi"), true).unwrap();
+        assert_eq!(thread.id, 4);
+        // The old replies keep their numbers and have no entry of their own
+        assert_eq!((thread_of(&db, 4), thread_of(&db, 2)), (Some(4), None));
+    }
+
+    // The number on each post, what clicking it quotes, and where ">>N" in a
+    // message leads, read off the thread page
+    async fn shown_numbers(vars: &[(&str, &str)]) -> (AppState, String, Vec<String>) {
+        let state = test_support::state(vars);
+        let global = state.settings.global_post_numbers;
+        let first = insert_thread(&state.db, test_support::new_thread("First", "Hi"), global).unwrap();
+        insert_reply(&state.db, first.id, test_support::new_reply("Reply"), true, 0, 0, global).unwrap();
+        let second = insert_thread(&state.db, test_support::new_thread("Second", "Hi"), global).unwrap();
+        let reply = insert_reply(&state.db, second.id, test_support::new_reply(">>1 >>9"), true, 0, 0, global).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let page = |uri: String| {
+            let request = TestRequest::get().uri(&uri).to_request();
+            async { String::from_utf8(call_and_read_body(&app, request).await.to_vec()).unwrap() }
+        };
+        let thread = page(format!("/thread/{}", second.id)).await;
+        let quoting = page(format!("/thread/{}/reply?quote={}", second.id, reply.id)).await;
+        let op_quoting = page(format!("/thread/{}/reply?quote={}", second.id, second.id)).await;
+        (state, thread, vec![quoting, op_quoting])
+    }
+
+    #[actix_web::test]
+    async fn per_thread_numbers_show_as_reply_numbers() {
+        let (state, thread, quoting) = shown_numbers(&[("POST_NUMBERING", "thread")]).await;
+        assert!(!state.settings.global_post_numbers);
+        // Thread 2's reply is its first, and its ">>1" means itself
+        let number = "class=\"post-number\" data-thread-id=\"2\" data-reply-id=\"1\" title=\"Quote this reply\">1</a>";
+        let link = format!("Reply <a href=\"/thread/2/reply?quote=1#message\" {}", number);
+        assert!(thread.contains(&link), "{}", thread);
+        assert!(!thread.contains("No. "));
+        assert!(thread.contains("href=\"/thread/2#p1\" class=\"quotelink\""), "{}", thread);
+        assert!(thread.contains("<span class=\"quotelink dead\">&gt;&gt;9</span>"), "{}", thread);
+        assert!(quoting[0].contains("aria-label=\"Message\">&gt;&gt;1\n</textarea>"));
+        // The opening post has no number of its own to quote
+        assert!(quoting[1].contains("aria-label=\"Message\"></textarea>"));
+    }
+
+    #[actix_web::test]
+    async fn global_numbers_show_on_every_post_and_quote_the_same() {
+        let (state, thread, quoting) = shown_numbers(&[("POST_NUMBERING", "global")]).await;
+        assert!(state.settings.global_post_numbers);
+        // Posts 1 and 2 are thread 1 and its reply; thread 3's reply is post 4
+        let op = "class=\"post-number\" data-thread-id=\"3\" data-reply-id=\"3\" title=\"Quote this post\">3</a>";
+        assert!(thread.contains(&format!("No. <a href=\"/thread/3/reply?quote=3#message\" {}", op)), "{}", thread);
+        let reply = "class=\"post-number\" data-thread-id=\"3\" data-reply-id=\"4\" title=\"Quote this reply\">4</a>";
+        assert!(thread.contains(&format!("No. <a href=\"/thread/3/reply?quote=4#message\" {}", reply)), "{}", thread);
+        assert!(!thread.contains("Reply <a"));
+        assert!(thread.contains("href=\"/thread/1\" class=\"quotelink\""), "{}", thread);
+        assert!(thread.contains("<span class=\"quotelink dead\">&gt;&gt;9</span>"), "{}", thread);
+        assert!(quoting[0].contains("aria-label=\"Message\">&gt;&gt;4\n</textarea>"));
+        assert!(quoting[1].contains("aria-label=\"Message\">&gt;&gt;3\n</textarea>"));
+
+        let app = init_service(crate::app(&state)).await;
+        let quote = TestRequest::get().uri("/api/thread/3/quote/3").to_request();
+        let body: serde_json::Value = call_and_read_body_json(&app, quote).await;
+        assert_eq!(body["quote"], ">>3\n");
+    }
+
+    #[test]
+    fn the_older_setting_still_selects_global_numbers() {
+        assert!(test_support::settings(&[("GLOBAL_POST_NUMBERS", "true")]).global_post_numbers);
+        let settings = test_support::settings(&[("GLOBAL_POST_NUMBERS", "true"), ("POST_NUMBERING", "thread")]);
+        assert!(!settings.global_post_numbers);
+        assert!(!test_support::settings(&[("POST_NUMBERING", "sideways")]).global_post_numbers);
+    }
+}
@@ -0,0 +1,97 @@
+// The email field doubles as an options channel, as on classic imageboards:
+// known keywords change how a post is handled instead of being shown as an address.
+
+const MAX_EMAIL_LENGTH: usize = 100;
+const OPTION_KEYWORDS: &[&str] = &["sage", "nofollow", "noko"];
+
+#[derive(Default)]
+pub struct PostOptions {
+    // Reply without bumping the thread
+    pub sage: bool,
+    // Go to the thread after posting instead of back to the board
+    pub noko: bool,
+}
+
+impl PostOptions {
+    pub fn parse(email: Option<&str>) -> Self {
+        let mut options = PostOptions::default();
+        for word in email.unwrap_or_default().split_whitespace() {
+            if word.eq_ignore_ascii_case("sage") {
+                options.sage = true;
+            } else if word.eq_ignore_ascii_case("noko") {
+                options.noko = true;
+            }
+        }
+        options
+    }
+}
+
+// Trim the raw field, drop control characters and cap its length; empty becomes None
+pub fn sanitize_email(raw: &str) -> Option<String> {
+    let cleaned: String = raw
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_EMAIL_LENGTH)
+        .collect();
+
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+// Whether a stored email field should be rendered as a mailto: link
+pub fn is_real_email(email: &str) -> bool {
+    if OPTION_KEYWORDS.iter().any(|keyword| email.eq_ignore_ascii_case(keyword)) {
+        return false;
+    }
+
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && email
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "@._+-".contains(c))
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sage_is_an_option_not_an_address() {
+        let email = sanitize_email(" sage\n").unwrap();
+        assert_eq!(email, "sage");
+        assert!(PostOptions::parse(Some(&email)).sage);
+        assert!(PostOptions::parse(Some("SaGe noko")).noko);
+        assert!(!is_real_email(&email));
+        assert!(!is_real_email("NOKO"));
+    }
+
+    #[test]
+    fn real_addresses_become_links() {
+        let email = sanitize_email("anon+board@example.org").unwrap();
+        assert!(is_real_email(&email));
+        assert!(!PostOptions::parse(Some(&email)).sage);
+        for not_an_address in ["anon@localhost", "@example.org", "anon@.org", "anon@example.org.", "a b@example.org"] {
+            assert!(!is_real_email(not_an_address), "{}", not_an_address);
+        }
+    }
+
+    #[test]
+    fn empty_values_are_dropped() {
+        assert_eq!(sanitize_email(""), None);
+        assert_eq!(sanitize_email(" \t\u{7}"), None);
+        let options = PostOptions::parse(None);
+        assert!(!options.sage && !options.noko);
+        assert_eq!(sanitize_email(&"x".repeat(500)).unwrap().len(), MAX_EMAIL_LENGTH);
+    }
+}
@@ -0,0 +1,269 @@
+use std::str::FromStr;
+
+// Which fields a post has to fill in, set by THREAD_RULE and REPLY_RULE as an
+// expression like "title AND (image OR message)". AND binds tighter than OR,
+// and the words are case-insensitive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PostRule {
+    Field(Field),
+    All(Vec<PostRule>),
+    Any(Vec<PostRule>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Title,
+    Message,
+    Image,
+}
+
+impl Field {
+    fn described(self) -> &'static str {
+        match self {
+            Field::Title => "a title",
+            Field::Message => "a message",
+            Field::Image => "an image",
+        }
+    }
+}
+
+// The fields a submitted post filled in
+#[derive(Clone, Copy)]
+pub struct Filled {
+    pub title: bool,
+    pub message: bool,
+    pub image: bool,
+}
+
+impl Filled {
+    fn has(self, field: Field) -> bool {
+        match field {
+            Field::Title => self.title,
+            Field::Message => self.message,
+            Field::Image => self.image,
+        }
+    }
+}
+
+impl PostRule {
+    pub fn is_met(&self, filled: Filled) -> bool {
+        match self {
+            PostRule::Field(field) => filled.has(*field),
+            PostRule::All(rules) => rules.iter().all(|rule| rule.is_met(filled)),
+            PostRule::Any(rules) => rules.iter().any(|rule| rule.is_met(filled)),
+        }
+    }
+
+    // Whether no post without `field` meets the rule, so the form can mark the
+    // field required
+    pub fn requires(&self, field: Field) -> bool {
+        !(0..8u8).any(|bits| {
+            let filled = Filled {
+                title: bits & 1 != 0,
+                message: bits & 2 != 0,
+                image: bits & 4 != 0,
+            };
+            !filled.has(field) && self.is_met(filled)
+        })
+    }
+
+    // The parts of the rule a post fails, each worth its own error: the unmet
+    // terms of a top-level AND, or else the whole rule
+    pub fn unmet(&self, filled: Filled) -> Vec<&PostRule> {
+        match self {
+            PostRule::All(rules) => rules.iter().filter(|rule| !rule.is_met(filled)).collect(),
+            rule if !rule.is_met(filled) => vec![rule],
+            _ => Vec::new(),
+        }
+    }
+
+    // The rule with `field` taken out, for a board that doesn't have it; None
+    // when nothing is left
+    pub fn without(&self, field: Field) -> Option<PostRule> {
+        match self {
+            PostRule::Field(own) if *own == field => None,
+            PostRule::Field(_) => Some(self.clone()),
+            PostRule::All(rules) => {
+                let rules: Vec<PostRule> = rules.iter().filter_map(|rule| rule.without(field)).collect();
+                (!rules.is_empty()).then(|| flatten(rules, PostRule::All))
+            }
+            PostRule::Any(rules) => {
+                let rules: Vec<PostRule> = rules.iter().filter_map(|rule| rule.without(field)).collect();
+                (!rules.is_empty()).then(|| flatten(rules, PostRule::Any))
+            }
+        }
+    }
+
+    // The rule in words, like "a title and either an image or a message"
+    pub fn describe(&self) -> String {
+        self.describe_at(false)
+    }
+
+    fn describe_at(&self, nested: bool) -> String {
+        let (rules, word, lead) = match self {
+            PostRule::Field(field) => return field.described().to_string(),
+            PostRule::All(rules) => (rules, "and", if rules.len() == 2 { "both" } else { "all of" }),
+            PostRule::Any(rules) => (rules, "or", if rules.len() == 2 { "either" } else { "one of" }),
+        };
+        let parts: Vec<String> = rules.iter().map(|rule| rule.describe_at(true)).collect();
+        let (last, rest) = parts.split_last().expect("rules are never empty");
+        let list = format!("{} {} {}", rest.join(", "), word, last);
+        if nested {
+            format!("{} {}", lead, list)
+        } else {
+            list
+        }
+    }
+}
+
+impl FromStr for PostRule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let spaced = text.replace('(', " ( ").replace(')', " ) ").to_lowercase();
+        let mut tokens = spaced.split_whitespace().peekable();
+        let rule = parse_any(&mut tokens)?;
+        match tokens.next() {
+            None => Ok(rule),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+}
+
+type Tokens<'a> = std::iter::Peekable<std::str::SplitWhitespace<'a>>;
+
+// term OR term ...
+fn parse_any(tokens: &mut Tokens) -> Result<PostRule, String> {
+    let mut rules = vec![parse_all(tokens)?];
+    while tokens.next_if_eq(&"or").is_some() {
+        rules.push(parse_all(tokens)?);
+    }
+    Ok(flatten(rules, PostRule::Any))
+}
+
+// factor AND factor ...
+fn parse_all(tokens: &mut Tokens) -> Result<PostRule, String> {
+    let mut rules = vec![parse_factor(tokens)?];
+    while tokens.next_if_eq(&"and").is_some() {
+        rules.push(parse_factor(tokens)?);
+    }
+    Ok(flatten(rules, PostRule::All))
+}
+
+// A field name or a parenthesised rule
+fn parse_factor(tokens: &mut Tokens) -> Result<PostRule, String> {
+    match tokens.next() {
+        Some("title") => Ok(PostRule::Field(Field::Title)),
+        Some("message") => Ok(PostRule::Field(Field::Message)),
+        Some("image") => Ok(PostRule::Field(Field::Image)),
+        Some("(") => {
+            let rule = parse_any(tokens)?;
+            match tokens.next() {
+                Some(")") => Ok(rule),
+                _ => Err("missing )".to_string()),
+            }
+        }
+        Some(token) => Err(format!("expected title, message, image or (, found {:?}", token)),
+        None => Err("the rule ends too early".to_string()),
+    }
+}
+
+fn flatten(mut rules: Vec<PostRule>, join: fn(Vec<PostRule>) -> PostRule) -> PostRule {
+    if rules.len() == 1 {
+        rules.remove(0)
+    } else {
+        join(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    fn filled(title: bool, message: bool, image: bool) -> Filled {
+        Filled { title, message, image }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_words_ignore_case() {
+        let rule: PostRule = "title and IMAGE Or message".parse().unwrap();
+        let expected = PostRule::Any(vec![
+            PostRule::All(vec![PostRule::Field(Field::Title), PostRule::Field(Field::Image)]),
+            PostRule::Field(Field::Message),
+        ]);
+        assert_eq!(rule, expected);
+        assert!(rule.is_met(filled(false, true, false)));
+        assert!(rule.is_met(filled(true, false, true)));
+        assert!(!rule.is_met(filled(true, false, false)));
+
+        let grouped: PostRule = "title AND (image OR message)".parse().unwrap();
+        assert!(grouped.is_met(filled(true, false, true)));
+        assert!(!grouped.is_met(filled(false, true, true)));
+        assert_eq!(grouped.describe(), "a title and either an image or a message");
+        assert_eq!(grouped.unmet(filled(false, false, false)).len(), 2);
+        assert!(grouped.requires(Field::Title));
+        assert!(!grouped.requires(Field::Image));
+    }
+
+    #[test]
+    fn malformed_rules_are_refused() {
+        for text in ["", "title AND", "(title", "title)", "title video", "OR message"] {
+            assert!(text.parse::<PostRule>().is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn dropping_a_field_keeps_the_rest_of_the_rule() {
+        let rule: PostRule = "title AND (image OR message)".parse().unwrap();
+        assert_eq!(rule.without(Field::Title), Some("image OR message".parse().unwrap()));
+        assert_eq!(rule.without(Field::Image), Some("title AND message".parse().unwrap()));
+        assert_eq!(PostRule::Field(Field::Title).without(Field::Title), None);
+    }
+
+    #[test]
+    fn settings_pick_the_rule_or_fall_back_to_the_default() {
+        let rule = |vars: &[(&str, &str)]| test_support::settings(vars).thread_rule.describe();
+        assert_eq!(rule(&[]), "a title and a message");
+        assert_eq!(rule(&[("REQUIRE_IMAGE_OP", "true")]), "a title, a message and an image");
+        assert_eq!(rule(&[("THREAD_RULE", "image OR message"), ("REQUIRE_IMAGE_OP", "true")]), "an image or a message");
+        assert_eq!(rule(&[("THREAD_RULE", "title AND")]), "a title and a message");
+        assert_eq!(rule(&[("TITLES_ENABLED", "false")]), "a message");
+        assert_eq!(rule(&[("TITLES_ENABLED", "false"), ("THREAD_RULE", "title")]), "a message");
+
+        let replies = test_support::settings(&[("REPLY_RULE", "message OR image")]).reply_rule;
+        assert_eq!(replies.describe(), "a message or an image");
+        assert_eq!(test_support::settings(&[("REPLY_RULE", "video")]).reply_rule.describe(), "a message");
+    }
+
+    #[actix_web::test]
+    async fn the_forms_follow_the_configured_rules() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("THREAD_RULE", "image OR message"), ("REPLY_RULE", "image")]);
+        let app = init_service(crate::app(&state)).await;
+        let jpeg = test_support::jpeg(32, 32);
+        let post = |uri: &str, fields: &[(&str, &[u8])], address: u8| {
+            form_post(uri, fields).peer_addr(format!("192.0.2.{}:4000", address).parse().unwrap()).to_request()
+        };
+
+        let homepage = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        let homepage = String::from_utf8(homepage.to_vec()).unwrap();
+        assert!(homepage.contains("(optional)"));
+        assert!(!homepage.contains("placeholder=\"Title\" required"));
+
+        let untitled = call_service(&app, post("/thread", &[("title", b""), ("message", b"No title")], 1)).await;
+        assert!(untitled.status().is_redirection());
+        let empty = call_service(&app, post("/thread", &[("title", b"Only a title"), ("message", b"")], 2)).await;
+        assert_eq!(empty.status(), 400);
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("(required)"));
+        let text = call_service(&app, post("/reply", &[("parent_id", b"1"), ("message", b"Just text")], 3)).await;
+        assert_eq!(text.status(), 400);
+        let image = post("/reply", &[("parent_id", b"1"), ("message", b""), ("image", &jpeg)], 4);
+        assert!(call_service(&app, image).await.status().is_redirection());
+        assert_eq!(crate::get_replies(&state.db, 1).len(), 1);
+    }
+}
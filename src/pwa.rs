@@ -0,0 +1,157 @@
+use actix_files as fs;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use crate::settings::Settings;
+use crate::STATIC_DIR;
+
+// The web manifest that lets browsers install the board as an app
+#[derive(Serialize)]
+struct Manifest<'a> {
+    name: &'a str,
+    short_name: &'a str,
+    start_url: String,
+    scope: String,
+    display: &'static str,
+    background_color: &'a str,
+    theme_color: &'a str,
+    icons: Vec<Icon>,
+}
+
+#[derive(Serialize)]
+struct Icon {
+    src: String,
+    // "192x192", or "any" when the file can't be measured
+    sizes: String,
+    #[serde(rename = "type")]
+    mime_type: &'static str,
+}
+
+// manifest.json handler
+pub async fn manifest(settings: web::Data<Settings>) -> HttpResponse {
+    let icons = if settings.app_icons.is_empty() {
+        vec![icon(&settings.favicon, settings.url("/favicon.ico"))]
+    } else {
+        settings
+            .app_icons
+            .iter()
+            .map(|file| icon(file, settings.url(&format!("/static/{}", file))))
+            .collect()
+    };
+    let manifest = Manifest {
+        name: &settings.app_name,
+        short_name: &settings.app_short_name,
+        start_url: settings.url("/"),
+        scope: settings.url("/"),
+        display: "standalone",
+        background_color: &settings.theme_color,
+        theme_color: &settings.theme_color,
+        icons,
+    };
+    HttpResponse::Ok()
+        .content_type("application/manifest+json")
+        .json(manifest)
+}
+
+// Service worker handler. It lives in ./static with the other scripts, but
+// has to control the whole board to cache the board index, which the
+// Service-Worker-Allowed header permits.
+pub async fn service_worker(req: HttpRequest, settings: web::Data<Settings>) -> HttpResponse {
+    match fs::NamedFile::open_async(format!("{}sw.js", STATIC_DIR)).await {
+        Ok(file) => {
+            let mut response = file.into_response(&req);
+            let scope = HeaderValue::from_str(&settings.url("/")).expect("the board URL is a valid header value");
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static("service-worker-allowed"), scope);
+            response
+        }
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+// `file` inside ./static, served at `src`
+fn icon(file: &str, src: String) -> Icon {
+    let extension = file.rsplit_once('.').map(|(_, extension)| extension.to_lowercase());
+    let mime_type = match extension.as_deref() {
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        _ => "image/x-icon",
+    };
+    let sizes = image::image_dimensions(format!("{}{}", STATIC_DIR, file))
+        .map_or_else(|_| "any".to_string(), |(width, height)| format!("{}x{}", width, height));
+    Icon { src, sizes, mime_type }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body_json, call_service, init_service, read_body, TestRequest};
+    use serde_json::{json, Value};
+
+    #[actix_web::test]
+    async fn the_manifest_describes_the_board_under_its_base_path() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[
+            ("BASE_PATH", "/board"),
+            ("APP_NAME", "Chess Board"),
+            ("APP_SHORT_NAME", "Chess"),
+            ("THEME_COLOR", "#123456"),
+            ("APP_ICONS", "/icon-192.png,../icon.svg"),
+        ]);
+        let app = init_service(crate::app(&state)).await;
+
+        let request = TestRequest::get().uri("/board/manifest.json").to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.headers().get("content-type").unwrap(), "application/manifest+json");
+        let manifest: Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        let expected = json!({
+            "name": "Chess Board",
+            "short_name": "Chess",
+            "start_url": "/board/",
+            "scope": "/board/",
+            "display": "standalone",
+            "background_color": "#123456",
+            "theme_color": "#123456",
+            "icons": [
+                { "src": "/board/static/icon-192.png", "sizes": "any", "type": "image/png" },
+                { "src": "/board/static/icon.svg", "sizes": "any", "type": "image/svg+xml" },
+            ],
+        });
+        assert_eq!(manifest, expected);
+    }
+
+    #[actix_web::test]
+    async fn without_icons_the_manifest_offers_the_favicon() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[]);
+        let app = init_service(crate::app(&state)).await;
+
+        let manifest: Value =
+            call_and_read_body_json(&app, TestRequest::get().uri("/manifest.json").to_request()).await;
+        assert_eq!(manifest["name"], manifest["short_name"]);
+        assert_eq!(manifest["theme_color"], "#EEF2FF");
+        let icons = manifest["icons"].as_array().unwrap();
+        assert_eq!(icons.len(), 1);
+        assert_eq!(icons[0]["src"], "/favicon.ico");
+        assert_eq!(icons[0]["type"], "image/x-icon");
+        assert_eq!(icons[0]["sizes"], "32x32");
+    }
+
+    #[actix_web::test]
+    async fn the_service_worker_may_control_the_whole_board() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("BASE_PATH", "/board")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let response = call_service(&app, TestRequest::get().uri("/board/static/sw.js").to_request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers().get("service-worker-allowed").unwrap(), "/board/");
+        let script = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        assert!(script.contains("addEventListener"));
+    }
+}
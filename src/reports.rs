@@ -0,0 +1,193 @@
+// Posts readers reported to the moderators, listed on the admin dashboard
+// until they are dismissed.
+//
+// Keys:
+// report_{thread}_{reply}   JSON Report; reply is "op" for the opening post
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+use std::sync::Arc;
+
+use crate::poster_hash;
+use crate::repository::Repository;
+use crate::settings::Settings;
+
+// Reporters remembered per post, so the same poster doesn't count twice
+const MAX_REPORTERS: usize = 50;
+
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+    pub thread: i32,
+    // None for the opening post
+    pub reply: Option<i32>,
+    pub count: usize,
+    pub first_at: i64,
+    pub last_at: i64,
+    // Poster hashes of who reported it
+    reporters: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ReportForm {
+    // The reported reply; the opening post without it
+    reply: Option<i32>,
+}
+
+fn report_key(thread: i32, reply: Option<i32>) -> String {
+    match reply {
+        Some(reply) => format!("report_{}_{}", thread, reply),
+        None => format!("report_{}_op", thread),
+    }
+}
+
+// Count a report of the post. A poster reporting it again changes nothing.
+pub fn file(db: &Db, thread: i32, reply: Option<i32>, reporter: Option<&str>) -> sled::Result<()> {
+    let now = chrono::Utc::now().timestamp();
+    db.fetch_and_update(report_key(thread, reply), |old| {
+        let mut report = old.and_then(|value| serde_json::from_slice(value).ok()).unwrap_or(Report {
+            thread,
+            reply,
+            count: 0,
+            first_at: now,
+            last_at: now,
+            reporters: Vec::new(),
+        });
+        if let Some(reporter) = reporter {
+            if report.reporters.iter().any(|seen| seen == reporter) {
+                return Some(serde_json::to_vec(&report).expect("Failed to serialize report"));
+            }
+            if report.reporters.len() < MAX_REPORTERS {
+                report.reporters.push(reporter.to_string());
+            }
+        }
+        report.count += 1;
+        report.last_at = now;
+        Some(serde_json::to_vec(&report).expect("Failed to serialize report"))
+    })?;
+    Ok(())
+}
+
+// Reports of posts that still exist, most recently reported first. Reports of
+// posts deleted since are dropped on the way.
+pub fn open(db: &Db, repo: &dyn Repository) -> Vec<Report> {
+    let mut reports = Vec::new();
+    for (key, value) in db.scan_prefix(b"report_").flatten() {
+        let Ok(report) = serde_json::from_slice::<Report>(&value) else {
+            continue;
+        };
+        if post_exists(repo, report.thread, report.reply) {
+            reports.push(report);
+        } else if let Err(e) = db.remove(key) {
+            error!("Failed to drop the report of a deleted post: {}", e);
+        }
+    }
+    reports.sort_by_key(|report| std::cmp::Reverse(report.last_at));
+    reports
+}
+
+fn post_exists(repo: &dyn Repository, thread: i32, reply: Option<i32>) -> bool {
+    match reply {
+        Some(reply) => repo.reply_exists(thread, reply),
+        None => repo.get_thread(thread).is_some(),
+    }
+}
+
+pub fn dismiss(db: &Db, thread: i32, reply: Option<i32>) -> sled::Result<bool> {
+    Ok(db.remove(report_key(thread, reply))?.is_some())
+}
+
+// Report handler: /thread/{id}/report, with `reply` for a reply
+pub async fn report_post(
+    req: HttpRequest,
+    repo: web::Data<dyn Repository>,
+    db: web::Data<Arc<Db>>,
+    settings: web::Data<Settings>,
+    path: web::Path<i32>,
+    form: web::Form<ReportForm>,
+) -> impl Responder {
+    let thread_id = path.into_inner();
+    if !post_exists(repo.get_ref(), thread_id, form.reply) {
+        return HttpResponse::NotFound().body("Post not found");
+    }
+
+    let reporter = poster_hash(&req, &settings);
+    if let Err(e) = file(&db, thread_id, form.reply, reporter.as_deref()) {
+        error!("Failed to store the report of thread {}: {}", thread_id, e);
+        return HttpResponse::InternalServerError().body("Failed to send the report");
+    }
+    let anchor = form.reply.map(|reply| format!("#p{}", reply)).unwrap_or_default();
+    HttpResponse::SeeOther()
+        .append_header(("Location", settings.url(&format!("/thread/{}{}", thread_id, anchor))))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::SledRepository;
+    use crate::test_support;
+    use crate::{insert_reply, insert_thread};
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    fn repo(db: &Arc<Db>) -> SledRepository {
+        SledRepository::new(db.clone(), false, 0, 0)
+    }
+
+    #[test]
+    fn a_poster_reporting_twice_counts_once() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Spam", "Buy now"), false).unwrap();
+        file(&db, thread.id, None, Some("a")).unwrap();
+        file(&db, thread.id, None, Some("a")).unwrap();
+        file(&db, thread.id, None, Some("b")).unwrap();
+
+        let reports = open(&db, &repo(&db));
+        assert_eq!(reports.len(), 1);
+        assert_eq!((reports[0].thread, reports[0].reply, reports[0].count), (thread.id, None, 2));
+    }
+
+    #[test]
+    fn reports_of_deleted_posts_drop_out() {
+        let db = test_support::temp_db();
+        let thread = insert_thread(&db, test_support::new_thread("Spam", "Buy now"), false).unwrap();
+        let reply = insert_reply(&db, thread.id, test_support::new_reply("More"), true, 0, 0, false).unwrap();
+        file(&db, thread.id, Some(reply.id), None).unwrap();
+        file(&db, thread.id, Some(reply.id + 1), None).unwrap();
+
+        let reports = open(&db, &repo(&db));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].reply, Some(reply.id));
+        assert_eq!(db.scan_prefix(b"report_").count(), 1);
+
+        assert!(dismiss(&db, thread.id, Some(reply.id)).unwrap());
+        assert!(!dismiss(&db, thread.id, Some(reply.id)).unwrap());
+        assert!(open(&db, &repo(&db)).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn reporting_a_post_leads_back_to_it() {
+        let state = test_support::state(&[]);
+        let thread = insert_thread(&state.db, test_support::new_thread("Spam", "Buy now"), false).unwrap();
+        let reply = insert_reply(&state.db, thread.id, test_support::new_reply("More"), true, 0, 0, false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let report = |thread: i32, form: &[(&str, String)]| {
+            TestRequest::post()
+                .uri(&format!("/thread/{}/report", thread))
+                .peer_addr("192.0.2.1:4000".parse().unwrap())
+                .set_form(form)
+                .to_request()
+        };
+
+        let response = call_service(&app, report(thread.id, &[("reply", reply.id.to_string())])).await;
+        assert_eq!(response.status(), 303);
+        let location = format!("/thread/{}#p{}", thread.id, reply.id);
+        assert_eq!(response.headers().get("location").unwrap(), location.as_str());
+        assert!(call_service(&app, report(thread.id, &[])).await.status().is_redirection());
+        assert_eq!(open(&state.db, state.repository.get_ref()).len(), 2);
+
+        assert_eq!(call_service(&app, report(thread.id + 1, &[])).await.status(), 404);
+        assert_eq!(call_service(&app, report(thread.id, &[("reply", "99".to_string())])).await.status(), 404);
+    }
+}
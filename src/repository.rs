@@ -0,0 +1,357 @@
+use sled::transaction::TransactionError;
+use sled::Db;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::{post_numbers, tags};
+use crate::thread_state::ReplyRefusal;
+use crate::{NewReply, NewThread, Reply, ReplyError, Thread};
+
+#[derive(Debug)]
+pub enum RepoError {
+    // The thread takes no replies
+    Refused(ReplyRefusal),
+    Storage(String),
+}
+
+impl std::fmt::Display for RepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::Refused(reason) => write!(f, "{}", reason.message()),
+            RepoError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Reply activity of a thread, for index pages that don't show the replies
+#[derive(Clone, Copy, Default)]
+pub struct ReplySummary {
+    pub count: usize,
+    // created_at of the newest reply
+    pub last_reply_at: Option<i64>,
+}
+
+// How the board index is sorted
+#[derive(Clone, Copy, PartialEq)]
+pub enum ThreadOrder {
+    // Most recently bumped first, the normal board view
+    Bump,
+    // Newest thread first, regardless of bumps or sage
+    Created,
+}
+
+impl ThreadOrder {
+    // The ?order= value; anything unknown falls back to bump order
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("created") => ThreadOrder::Created,
+            _ => ThreadOrder::Bump,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThreadOrder::Bump => "bump",
+            ThreadOrder::Created => "created",
+        }
+    }
+}
+
+// Storage operations the post handlers need. Handlers take a
+// web::Data<dyn Repository> instead of the sled handle, so they can be run
+// against another implementation (an in-memory one in tests, for example).
+pub trait Repository: Send + Sync {
+    // One page of threads in the given order
+    fn list_threads(&self, order: ThreadOrder, offset: usize, limit: usize) -> Vec<Thread>;
+    // Threads in bump order following the one at (last_updated, id), for cursors
+    fn list_threads_after(&self, after: Option<(i64, i32)>, limit: usize) -> Vec<Thread>;
+    fn count_threads(&self) -> usize;
+    // Threads pinned right now, most recently bumped first
+    fn list_sticky(&self) -> Vec<Thread>;
+    // One page of threads in bump order leaving out `pinned`, the threads the
+    // index lists ahead of the rest
+    fn list_unpinned(&self, pinned: &[i32], offset: usize, limit: usize) -> Vec<Thread>;
+    // Every thread carrying `tag`, most recently bumped first
+    fn list_tagged(&self, tag: &str) -> Vec<Thread>;
+    fn get_thread(&self, thread_id: i32) -> Option<Thread>;
+    fn create_thread(&self, new_thread: NewThread) -> Result<Thread, RepoError>;
+    // Replies of a thread, oldest first
+    fn list_replies(&self, thread_id: i32) -> Vec<Reply>;
+    // One reply, looked up directly; None if the thread or reply doesn't exist
+    fn get_reply(&self, thread_id: i32, reply_id: i32) -> Option<Reply>;
+    fn reply_exists(&self, thread_id: i32, reply_id: i32) -> bool;
+    // The thread post `number` is in, when posts have board-wide numbers
+    fn post_thread(&self, number: i32) -> Option<i32>;
+    // Summaries for a whole page of threads at once; threads without replies
+    // may be missing from the map
+    fn reply_summaries(&self, thread_ids: &[i32]) -> HashMap<i32, ReplySummary>;
+    // Fails with Refused when the thread doesn't exist or takes no replies
+    fn create_reply(&self, thread_id: i32, new_reply: NewReply, bump: bool) -> Result<Reply, RepoError>;
+    // Replies on the whole board
+    fn count_replies(&self) -> usize;
+    // Names of the files in UPLOAD_DIR and THUMB_DIR that any post links to,
+    // for /admin/gc
+    fn referenced_files(&self) -> HashSet<String>;
+}
+
+// Add the file names `urls` end in to `names`
+pub fn add_file_names<'a>(names: &mut HashSet<String>, urls: impl IntoIterator<Item = &'a str>) {
+    names.extend(urls.into_iter().filter_map(|url| url.rsplit('/').next()).map(str::to_string));
+}
+
+// The real store, backed by the sled key layout described in main.rs
+pub struct SledRepository {
+    db: Arc<Db>,
+    // POST_NUMBERING=global
+    global_numbers: bool,
+    // BUMP_LIMIT
+    bump_limit: usize,
+    // THREAD_TTL
+    thread_ttl: i64,
+}
+
+impl SledRepository {
+    pub fn new(db: Arc<Db>, global_numbers: bool, bump_limit: usize, thread_ttl: i64) -> Self {
+        SledRepository {
+            db,
+            global_numbers,
+            bump_limit,
+            thread_ttl,
+        }
+    }
+}
+
+impl Repository for SledRepository {
+    fn list_threads(&self, order: ThreadOrder, offset: usize, limit: usize) -> Vec<Thread> {
+        match order {
+            ThreadOrder::Bump => crate::get_threads_page(&self.db, offset, limit),
+            ThreadOrder::Created => crate::get_threads_by_creation(&self.db, offset, limit),
+        }
+    }
+
+    fn list_threads_after(&self, after: Option<(i64, i32)>, limit: usize) -> Vec<Thread> {
+        crate::get_threads_after(&self.db, after, limit)
+    }
+
+    fn count_threads(&self) -> usize {
+        crate::count_threads(&self.db) as usize
+    }
+
+    fn list_sticky(&self) -> Vec<Thread> {
+        crate::get_sticky_threads(&self.db)
+    }
+
+    fn list_unpinned(&self, pinned: &[i32], offset: usize, limit: usize) -> Vec<Thread> {
+        crate::get_unpinned_page(&self.db, pinned, offset, limit)
+    }
+
+    fn list_tagged(&self, tag: &str) -> Vec<Thread> {
+        tags::tagged_threads(&self.db, tag)
+    }
+
+    fn get_thread(&self, thread_id: i32) -> Option<Thread> {
+        crate::get_thread(&self.db, thread_id)
+    }
+
+    fn create_thread(&self, new_thread: NewThread) -> Result<Thread, RepoError> {
+        crate::insert_thread(&self.db, new_thread, self.global_numbers).map_err(|e| RepoError::Storage(format!("{:?}", e)))
+    }
+
+    fn list_replies(&self, thread_id: i32) -> Vec<Reply> {
+        crate::get_replies(&self.db, thread_id)
+    }
+
+    fn get_reply(&self, thread_id: i32, reply_id: i32) -> Option<Reply> {
+        crate::get_reply(&self.db, thread_id, reply_id)
+    }
+
+    fn reply_exists(&self, thread_id: i32, reply_id: i32) -> bool {
+        self.db.contains_key(crate::reply_key(thread_id, reply_id)).unwrap_or(false)
+    }
+
+    fn post_thread(&self, number: i32) -> Option<i32> {
+        // Entries left from a time the option was on are ignored once it's off,
+        // since thread ids may then reuse their numbers
+        self.global_numbers.then(|| post_numbers::thread_of(&self.db, number)).flatten()
+    }
+
+    fn reply_summaries(&self, thread_ids: &[i32]) -> HashMap<i32, ReplySummary> {
+        crate::get_reply_summaries(&self.db, thread_ids)
+    }
+
+    fn create_reply(&self, thread_id: i32, new_reply: NewReply, bump: bool) -> Result<Reply, RepoError> {
+        let inserted = crate::insert_reply(
+            &self.db,
+            thread_id,
+            new_reply,
+            bump,
+            self.bump_limit,
+            self.thread_ttl,
+            self.global_numbers,
+        );
+        inserted.map_err(|e| match e {
+            TransactionError::Abort(ReplyError::Refused(reason)) => RepoError::Refused(reason),
+            e => RepoError::Storage(format!("{:?}", e)),
+        })
+    }
+
+    fn count_replies(&self) -> usize {
+        self.db.scan_prefix(b"reply_").count()
+    }
+
+    fn referenced_files(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        for value in self.db.scan_prefix(b"thread_").values().flatten() {
+            if let Ok(thread) = serde_json::from_slice::<Thread>(&value) {
+                add_file_names(&mut names, thread.file_urls());
+            }
+        }
+        for value in self.db.scan_prefix(b"reply_").values().flatten() {
+            if let Ok(reply) = serde_json::from_slice::<Reply>(&value) {
+                add_file_names(&mut names, reply.file_urls());
+            }
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::SqliteRepository;
+    use crate::test_support::{self, MemoryRepository};
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+    use actix_web::web;
+
+    #[actix_web::test]
+    async fn the_post_handlers_run_against_any_repository() {
+        let mut state = test_support::state(&[]);
+        let memory = Arc::new(MemoryRepository::default());
+        state.repository = web::Data::from(memory.clone() as Arc<dyn Repository>);
+        let app = init_service(crate::app(&state)).await;
+
+        let thread = test_support::form_post("/thread", &[("title", b"In memory"), ("message", b"No sled here")]);
+        assert!(call_service(&app, thread.to_request()).await.status().is_redirection());
+        let reply = test_support::form_post("/reply", &[("parent_id", b"1"), ("message", b"Nor here")]);
+        assert!(call_service(&app, reply.to_request()).await.status().is_redirection());
+
+        assert_eq!(memory.count_threads(), 1);
+        assert_eq!(memory.list_replies(1)[0].message, "Nor here");
+        assert!(crate::get_thread(&state.db, 1).is_none());
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains("In memory"));
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("No sled here") && page.contains("Nor here"));
+
+        let missing = test_support::form_post("/reply", &[("parent_id", b"2"), ("message", b"Hello?")]);
+        assert_eq!(call_service(&app, missing.to_request()).await.status(), 404);
+    }
+
+    // What every backend has to agree on. Replies bump up to BUMP_LIMIT=1 and
+    // thread ids and reply ids are counted separately.
+    fn check_contract(repository: &dyn Repository) {
+        let mut tagged = test_support::new_thread("Tagged", "Second");
+        tagged.tags = vec!["chess".to_string()];
+        let first = repository.create_thread(test_support::new_thread("First", "One")).unwrap();
+        let second = repository.create_thread(tagged).unwrap();
+        let third = repository.create_thread(test_support::new_thread("Third", "Three")).unwrap();
+        assert_eq!((first.id, second.id, third.id), (1, 2, 3));
+        assert_eq!(repository.create_reply(3, test_support::new_reply("Early"), true).unwrap().id, 1);
+        let before = repository.get_thread(3).unwrap().last_updated;
+
+        // Bumps only show across a second boundary
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        let bump = repository.create_reply(1, test_support::new_reply("Bump"), true).unwrap();
+        let sage = repository.create_reply(2, test_support::new_reply("Sage"), false).unwrap();
+        let late = repository.create_reply(3, test_support::new_reply("Past the limit"), true).unwrap();
+        assert_eq!((bump.id, sage.id, late.id), (1, 1, 2));
+        assert!(repository.get_thread(1).unwrap().last_updated > before);
+        assert_eq!(repository.get_thread(2).unwrap().last_updated, second.last_updated);
+        assert_eq!(repository.get_thread(3).unwrap().last_updated, before);
+
+        let ids = |threads: Vec<Thread>| threads.iter().map(|thread| thread.id).collect::<Vec<_>>();
+        assert_eq!(ids(repository.list_threads(ThreadOrder::Bump, 0, 10)), [1, 3, 2]);
+        assert_eq!(ids(repository.list_threads(ThreadOrder::Bump, 1, 1)), [3]);
+        assert_eq!(ids(repository.list_threads(ThreadOrder::Created, 0, 10)), [3, 2, 1]);
+        assert_eq!(ids(repository.list_threads_after(Some((before, 3)), 10)), [2]);
+        assert_eq!(ids(repository.list_threads_after(None, 2)), [1, 3]);
+        assert_eq!(repository.count_threads(), 3);
+        assert_eq!(repository.count_replies(), 4);
+        assert_eq!(ids(repository.list_tagged("chess")), [2]);
+        assert!(repository.list_tagged("go").is_empty());
+        assert!(repository.list_sticky().is_empty());
+        assert_eq!(ids(repository.list_unpinned(&[3], 0, 10)), [1, 2]);
+        assert_eq!(ids(repository.list_unpinned(&[1], 1, 1)), [2]);
+
+        let messages = repository.list_replies(3).into_iter().map(|reply| reply.message).collect::<Vec<_>>();
+        assert_eq!(messages, ["Early", "Past the limit"]);
+        assert_eq!(repository.get_reply(2, 1).unwrap().message, "Sage");
+        assert!(repository.reply_exists(3, 2) && !repository.reply_exists(3, 3) && !repository.reply_exists(9, 1));
+        assert_eq!(repository.post_thread(1), None);
+
+        let summaries = repository.reply_summaries(&[1, 3, 9]);
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[&3].count, 2);
+        assert_eq!(summaries[&3].last_reply_at, Some(late.created_at));
+
+        let missing = repository.create_reply(9, test_support::new_reply("Hello?"), true);
+        assert!(matches!(missing, Err(RepoError::Refused(ReplyRefusal::Deleted))));
+    }
+
+    // POST_NUMBERING=global: threads and replies share one sequence
+    fn check_global_numbers(repository: &dyn Repository) {
+        assert_eq!(repository.create_thread(test_support::new_thread("First", "One")).unwrap().id, 1);
+        assert_eq!(repository.create_reply(1, test_support::new_reply("Two"), true).unwrap().id, 2);
+        assert_eq!(repository.create_thread(test_support::new_thread("Second", "Three")).unwrap().id, 3);
+        assert_eq!(repository.create_reply(1, test_support::new_reply("Four"), true).unwrap().id, 4);
+        assert_eq!([2, 3, 4, 5].map(|number| repository.post_thread(number)), [Some(1), Some(3), Some(1), None]);
+    }
+
+    fn sled(global_numbers: bool) -> SledRepository {
+        let db = test_support::temp_db();
+        crate::migrations::run(&db).unwrap();
+        if global_numbers {
+            post_numbers::seed_counter(&db).unwrap();
+        }
+        SledRepository::new(db, global_numbers, 1, 0)
+    }
+
+    fn sqlite(global_numbers: bool) -> SqliteRepository {
+        let connection = rusqlite::Connection::open_in_memory().unwrap();
+        SqliteRepository::with_connection(connection, global_numbers, 1, 0).unwrap()
+    }
+
+    #[test]
+    fn sled_keeps_the_contract() {
+        check_contract(&sled(false));
+        check_global_numbers(&sled(true));
+    }
+
+    #[test]
+    fn sqlite_keeps_the_contract() {
+        check_contract(&sqlite(false));
+        check_global_numbers(&sqlite(true));
+    }
+
+    #[actix_web::test]
+    async fn the_board_runs_on_sqlite() {
+        let mut state = test_support::state(&[]);
+        let sqlite = Arc::new(sqlite(false));
+        state.repository = web::Data::from(sqlite.clone() as Arc<dyn Repository>);
+        let app = init_service(crate::app(&state)).await;
+
+        let thread = test_support::form_post("/thread", &[("title", b"In SQLite"), ("message", b"A table row")]);
+        assert!(call_service(&app, thread.to_request()).await.status().is_redirection());
+        let reply = test_support::form_post("/reply", &[("parent_id", b"1"), ("message", b"Another row")]);
+        assert!(call_service(&app, reply.to_request()).await.status().is_redirection());
+        assert_eq!(sqlite.list_replies(1)[0].message, "Another row");
+        assert!(crate::get_thread(&state.db, 1).is_none());
+
+        let page = call_and_read_body(&app, TestRequest::get().uri("/").to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains("In SQLite"));
+        let page = call_and_read_body(&app, TestRequest::get().uri("/thread/1").to_request()).await;
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains("Another row"));
+    }
+}
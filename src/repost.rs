@@ -0,0 +1,192 @@
+// Spotting reposts of an image that was re-encoded, resized or recompressed on
+// the way, which the sha256 doesn't survive. Every stored upload gets a dHash:
+// the image shrunk to 9x8 grey pixels, one bit per pair of neighbours telling
+// whether the left one is brighter. Similar images differ in only a few of
+// the 64 bits.
+//
+// Keys:
+// dhash_{filename}    dHash of a stored upload
+// repost_{filename}   JSON Flag for an upload let through as a likely repost
+
+use image::imageops::FilterType;
+use image::DynamicImage;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+// What to do with an upload that looks like one already stored (REPOST_CHECK)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RepostMode {
+    Off,
+    // Store it, and list it on the admin dashboard
+    Flag,
+    Reject,
+}
+
+impl RepostMode {
+    // Unknown modes turn the check off with a warning
+    pub fn parse(name: &str) -> Self {
+        match name.trim().to_lowercase().as_str() {
+            "off" => RepostMode::Off,
+            "flag" => RepostMode::Flag,
+            "reject" => RepostMode::Reject,
+            _ => {
+                warn!("Ignoring unknown REPOST_CHECK {:?}, turning the check off", name);
+                RepostMode::Off
+            }
+        }
+    }
+}
+
+// An upload that was let through although it resembles an earlier one
+#[derive(Serialize, Deserialize)]
+pub struct Flag {
+    pub filename: String,
+    // The stored upload it resembles
+    pub earlier: String,
+    pub flagged_at: i64,
+}
+
+fn hash_key(filename: &str) -> Vec<u8> {
+    format!("dhash_{}", filename).into_bytes()
+}
+
+fn flag_key(filename: &str) -> Vec<u8> {
+    format!("repost_{}", filename).into_bytes()
+}
+
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+// How many of the 64 bits two hashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// The stored upload whose hash is closest to `hash`, if one is within
+// `max_distance` bits
+pub fn find_similar(db: &Db, hash: u64, max_distance: u32) -> Option<String> {
+    db.scan_prefix(b"dhash_")
+        .flatten()
+        .filter_map(|(key, value)| {
+            let stored = u64::from_be_bytes(value.as_ref().try_into().ok()?);
+            let filename = String::from_utf8(key[b"dhash_".len()..].to_vec()).ok()?;
+            Some((distance(hash, stored), filename))
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, filename)| filename)
+}
+
+pub fn record(db: &Db, filename: &str, hash: u64) -> sled::Result<()> {
+    db.insert(hash_key(filename), &hash.to_be_bytes())?;
+    Ok(())
+}
+
+pub fn flag(db: &Db, filename: &str, earlier: &str) -> sled::Result<()> {
+    let flag = Flag {
+        filename: filename.to_string(),
+        earlier: earlier.to_string(),
+        flagged_at: chrono::Utc::now().timestamp(),
+    };
+    db.insert(flag_key(filename), serde_json::to_vec(&flag).expect("Failed to serialize repost flag"))?;
+    Ok(())
+}
+
+// Drop the hash and any flag of an upload that was deleted
+pub fn forget(db: &Db, filename: &str) {
+    let _ = db.remove(hash_key(filename));
+    let _ = db.remove(flag_key(filename));
+}
+
+// The newest `limit` flags, newest first
+pub fn flags(db: &Db, limit: usize) -> Vec<Flag> {
+    let mut flags: Vec<Flag> = db
+        .scan_prefix(b"repost_")
+        .values()
+        .flatten()
+        .filter_map(|value| serde_json::from_slice(&value).ok())
+        .collect();
+    flags.sort_by_key(|flag| std::cmp::Reverse(flag.flagged_at));
+    flags.truncate(limit);
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use actix_web::test::{call_service, init_service};
+    use image::ImageOutputFormat;
+    use std::io::Cursor;
+
+    // The image made smaller and saved again at a low JPEG quality
+    fn reencoded(jpeg: &[u8]) -> Vec<u8> {
+        let image = image::load_from_memory(jpeg).unwrap().resize(240, 160, FilterType::Triangle);
+        let mut bytes = Cursor::new(Vec::new());
+        image.to_rgb8().write_to(&mut bytes, ImageOutputFormat::Jpeg(40)).unwrap();
+        assert_ne!(bytes.get_ref().as_slice(), jpeg);
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn a_reencoded_image_hashes_alike_and_a_different_one_does_not() {
+        let original = test_support::jpeg(300, 200);
+        let hash = dhash(&image::load_from_memory(&original).unwrap());
+        let again = dhash(&image::load_from_memory(&reencoded(&original)).unwrap());
+        assert!(distance(hash, again) <= 6, "re-encoding moved {} bits", distance(hash, again));
+        let mirrored = image::load_from_memory(&original).unwrap().fliph();
+        assert!(distance(hash, dhash(&mirrored)) > 32);
+
+        let db = test_support::temp_db();
+        record(&db, "original.jpg", hash).unwrap();
+        record(&db, "mirrored.jpg", dhash(&mirrored)).unwrap();
+        assert_eq!(find_similar(&db, again, 6).as_deref(), Some("original.jpg"));
+        assert_eq!(find_similar(&db, hash ^ 0x5555_5555_5555_5555, 6), None);
+    }
+
+    #[actix_web::test]
+    async fn reposts_are_flagged_or_refused_as_set() {
+        let _files = test_support::files().await;
+        let original = test_support::jpeg(300, 200);
+        let repost = reencoded(&original);
+        for mode in ["flag", "reject"] {
+            let vars = [("REPOST_CHECK", mode), ("THREAD_COOLDOWN", "0")];
+            let state = test_support::state(&vars);
+            let app = init_service(crate::app(&state)).await;
+            let post = |title: &'static [u8], image: &[u8]| {
+                let fields: [(&str, &[u8]); 3] = [("title", title), ("message", b"Look"), ("image", image)];
+                call_service(&app, form_post("/thread", &fields).to_request())
+            };
+            assert_eq!(post(b"First", &original).await.status(), 303);
+            let second = post(b"Again", &repost).await;
+
+            let stored: Vec<_> = state.db.scan_prefix(b"dhash_").keys().flatten().collect();
+            match mode {
+                "flag" => {
+                    assert_eq!(second.status(), 303);
+                    assert_eq!(stored.len(), 2);
+                    let flagged = flags(&state.db, 10);
+                    assert_eq!(flagged.len(), 1);
+                    assert_ne!(flagged[0].filename, flagged[0].earlier);
+                }
+                _ => {
+                    assert_eq!(second.status(), 400);
+                    assert_eq!(stored.len(), 1);
+                    assert!(flags(&state.db, 10).is_empty());
+                }
+            }
+        }
+    }
+}
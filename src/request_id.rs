@@ -0,0 +1,86 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+// Logger format: the default actix format with the request id appended
+pub const LOG_FORMAT: &str = r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T id=%{x-request-id}o"#;
+
+// Reuse a sane incoming X-Request-Id (so ids line up across a proxy) or mint a new
+// one, and echo it back on the response
+pub async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_sane(value))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let mut response = next.call(req).await?;
+
+    // Tie handler failures to the access log line carrying the same id
+    if response.status().is_server_error() {
+        log::error!("Request {} failed with status {}", id, response.status());
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    Ok(response)
+}
+
+// Short, printable and free of anything that could forge extra log fields
+fn is_sane(id: &str) -> bool {
+    (1..=64).contains(&id.len())
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    #[actix_web::test]
+    async fn every_response_carries_one_request_id() {
+        let state = test_support::state(&[]);
+        let app = init_service(crate::app(&state)).await;
+
+        let first = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        let ids: Vec<_> = first.headers().get_all(&REQUEST_ID_HEADER).collect();
+        assert_eq!(ids.len(), 1);
+        let first_id = ids[0].to_str().unwrap().to_string();
+        assert!(Uuid::parse_str(&first_id).is_ok());
+
+        // Error responses get one too, and every request a new one
+        let missing = call_service(&app, TestRequest::get().uri("/thread/999").to_request()).await;
+        let missing_id = missing.headers().get(&REQUEST_ID_HEADER).unwrap();
+        assert_ne!(missing_id.to_str().unwrap(), first_id);
+    }
+
+    #[actix_web::test]
+    async fn a_sane_incoming_id_is_kept() {
+        let state = test_support::state(&[]);
+        let app = init_service(crate::app(&state)).await;
+        let with_id = |id: &str| TestRequest::get().uri("/").insert_header((REQUEST_ID_HEADER, id)).to_request();
+
+        let kept = call_service(&app, with_id("proxy-1234.abc_DEF")).await;
+        assert_eq!(kept.headers().get(&REQUEST_ID_HEADER).unwrap(), "proxy-1234.abc_DEF");
+
+        for forged in ["spaces are not allowed", "quote\"d", &"x".repeat(65)] {
+            let replaced = call_service(&app, with_id(forged)).await;
+            let id = replaced.headers().get(&REQUEST_ID_HEADER).unwrap().to_str().unwrap();
+            assert!(Uuid::parse_str(id).is_ok(), "{:?} was kept", forged);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::middleware::Next;
+use actix_web::rt::time::{timeout, Instant};
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+use futures_util::stream::{self, StreamExt};
+use log::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::settings::Settings;
+
+// How long a client gets to send the request line and headers, the part a
+// slow-loris attack drips out. The body is bounded by REQUEST_TIMEOUT instead.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// How long an idle keep-alive connection stays open
+pub const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+// Give every request REQUEST_TIMEOUT seconds to send its whole body. A body
+// still arriving then ends with an error, so a stalled upload fails like any
+// broken one: the handler deletes the partial file and the client gets 408.
+pub async fn body_deadline(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limit = req
+        .app_data::<web::Data<Settings>>()
+        .map_or(0, |settings| settings.request_timeout);
+    if limit == 0 {
+        return next.call(req).await.map(ServiceResponse::map_into_left_body);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(limit);
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let flag = timed_out.clone();
+    let body = stream::unfold(Some(req.take_payload()), move |payload| {
+        let flag = flag.clone();
+        async move {
+            let mut payload = payload?;
+            match timeout(deadline.saturating_duration_since(Instant::now()), payload.next()).await {
+                Ok(Some(chunk)) => Some((chunk, Some(payload))),
+                Ok(None) => None,
+                Err(_) => {
+                    flag.store(true, Ordering::Relaxed);
+                    let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "request body took too long");
+                    Some((Err(PayloadError::Io(error)), None))
+                }
+            }
+        }
+    });
+    req.set_payload(Payload::from(body.fuse().boxed_local()));
+
+    let path = req.path().to_string();
+    let response = next.call(req).await?;
+    if timed_out.load(Ordering::Relaxed) {
+        info!("Gave up on the body of a request to {} after {}s", path, limit);
+        let (req, _) = response.into_parts();
+        let response = HttpResponse::RequestTimeout().body("The request took too long to send");
+        return Ok(ServiceResponse::new(req, response).map_into_right_body());
+    }
+    Ok(response.map_into_left_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use crate::{upload, UPLOAD_DIR};
+    use actix_web::dev::Payload;
+    use actix_web::error::PayloadError;
+    use actix_web::test::{call_service, init_service, read_body};
+    use actix_web::web;
+    use futures_util::stream::{self, StreamExt};
+
+    #[actix_web::test]
+    async fn a_stalled_upload_is_cut_off_and_cleaned_up() {
+        let _files = test_support::files().await;
+        let state = test_support::state(&[("REQUEST_TIMEOUT", "1")]);
+        let measured = upload::usage(&state.db);
+        let uploads = || std::fs::read_dir(UPLOAD_DIR).unwrap().count();
+        let before = uploads();
+        let app = init_service(crate::app(&state)).await;
+
+        // Half of the image arrives, then nothing more
+        let jpeg = test_support::jpeg(600, 400);
+        let fields: [(&str, &[u8]); 3] = [("title", b"Slow"), ("message", b"Hi"), ("image", &jpeg)];
+        let (_, body) = test_support::multipart(&fields);
+        let sent = web::Bytes::copy_from_slice(&body[..body.len() - jpeg.len() / 2]);
+        let stalled = stream::once(async move { Ok::<_, PayloadError>(sent) }).chain(stream::pending());
+        let mut request = test_support::form_post("/thread", &[]).to_request();
+        *request.payload() = Payload::from(stalled.boxed_local());
+
+        let started = std::time::Instant::now();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 408);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(read_body(response).await, "The request took too long to send");
+        assert_eq!(state.db.scan_prefix(b"thread_").count(), 0);
+        assert_eq!(uploads(), before);
+        assert_eq!(upload::usage(&state.db), measured);
+    }
+}
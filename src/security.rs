@@ -0,0 +1,80 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::settings::Settings;
+
+// Add CSP, framing, sniffing and referrer headers to every HTML page. Each policy
+// comes from Settings, and an empty value leaves that header off.
+pub async fn security_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let settings = req.app_data::<web::Data<Settings>>().cloned();
+    let mut response = next.call(req).await?;
+
+    let is_html = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+
+    if let (true, Some(settings)) = (is_html, settings) {
+        let headers = response.headers_mut();
+        let policies = [
+            (header::CONTENT_SECURITY_POLICY, settings.content_security_policy.as_str()),
+            (header::X_FRAME_OPTIONS, settings.frame_options.as_str()),
+            (header::REFERRER_POLICY, settings.referrer_policy.as_str()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ];
+        for (name, value) in policies {
+            insert_header(headers, name, value);
+        }
+    }
+
+    Ok(response)
+}
+
+fn insert_header(headers: &mut actix_web::http::header::HeaderMap, name: HeaderName, value: &str) {
+    if value.is_empty() {
+        return;
+    }
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, TestRequest};
+
+    #[actix_web::test]
+    async fn html_pages_carry_the_security_headers() {
+        let state = test_support::state(&[]);
+        let app = init_service(crate::app(&state)).await;
+
+        let page = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        let headers = page.headers();
+        let csp = headers.get("content-security-policy").unwrap().to_str().unwrap();
+        assert!(csp.contains("script-src 'self'") && csp.contains("img-src 'self'"));
+        assert_eq!(headers.get("x-frame-options").unwrap(), "DENY");
+        assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+        assert_eq!(headers.get("referrer-policy").unwrap(), "same-origin");
+
+        let json = call_service(&app, TestRequest::get().uri("/api/threads").to_request()).await;
+        assert!(json.headers().get("content-security-policy").is_none());
+    }
+
+    #[actix_web::test]
+    async fn the_policies_come_from_the_settings() {
+        let state = test_support::state(&[("X_FRAME_OPTIONS", "SAMEORIGIN"), ("CONTENT_SECURITY_POLICY", "")]);
+        let app = init_service(crate::app(&state)).await;
+
+        let page = call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(page.headers().get("x-frame-options").unwrap(), "SAMEORIGIN");
+        assert!(page.headers().get("content-security-policy").is_none());
+    }
+}
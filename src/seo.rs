@@ -0,0 +1,193 @@
+use actix_web::{web, HttpResponse, Responder};
+use chrono::DateTime;
+use std::fmt::Write;
+
+use crate::repository::{Repository, ThreadOrder};
+use crate::settings::Settings;
+
+// The sitemap protocol caps a single sitemap file at 50,000 URLs
+const SITEMAP_MAX_URLS: usize = 50_000;
+
+// robots.txt handler
+pub async fn robots_txt(settings: web::Data<Settings>) -> impl Responder {
+    let mut body = String::from("User-agent: *\n");
+    for path in &settings.robots_allow {
+        let _ = writeln!(body, "Allow: {}", path);
+    }
+    for path in &settings.robots_disallow {
+        let _ = writeln!(body, "Disallow: {}", path);
+    }
+    if settings.robots_allow.is_empty() && settings.robots_disallow.is_empty() {
+        body.push_str("Disallow:\n");
+    }
+    let _ = writeln!(body, "Sitemap: {}", settings.absolute_url("/sitemap.xml"));
+
+    HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(body)
+}
+
+// sitemap.xml handler; switches to a sitemap index once one file can't hold every URL
+pub async fn sitemap_xml(repo: web::Data<dyn Repository>, settings: web::Data<Settings>) -> impl Responder {
+    let entries = sitemap_entries(repo.get_ref(), &settings);
+
+    let body = if entries.len() <= SITEMAP_MAX_URLS {
+        render_urlset(&entries)
+    } else {
+        let pages = entries.len().div_ceil(SITEMAP_MAX_URLS);
+        let mut body = String::from(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sitemapindex xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+        );
+        for page in 1..=pages {
+            let _ = writeln!(
+                body,
+                "  <sitemap><loc>{}</loc></sitemap>",
+                xml_escape(&settings.absolute_url(&format!("/sitemap-{}.xml", page)))
+            );
+        }
+        body.push_str("</sitemapindex>\n");
+        body
+    };
+
+    HttpResponse::Ok().content_type("application/xml; charset=utf-8").body(body)
+}
+
+// Numbered sitemap page handler, referenced from the sitemap index
+pub async fn sitemap_page(
+    repo: web::Data<dyn Repository>,
+    settings: web::Data<Settings>,
+    path: web::Path<(usize,)>,
+) -> impl Responder {
+    let page = path.into_inner().0;
+    let entries = sitemap_entries(repo.get_ref(), &settings);
+
+    let start = page.saturating_sub(1) * SITEMAP_MAX_URLS;
+    if page == 0 || start >= entries.len() {
+        return HttpResponse::NotFound().body("Sitemap page not found");
+    }
+    let end = (start + SITEMAP_MAX_URLS).min(entries.len());
+
+    HttpResponse::Ok()
+        .content_type("application/xml; charset=utf-8")
+        .body(render_urlset(&entries[start..end]))
+}
+
+struct SitemapEntry {
+    loc: String,
+    lastmod: Option<String>,
+}
+
+// The homepage followed by every thread in id order, so page boundaries stay stable
+fn sitemap_entries(repo: &dyn Repository, settings: &Settings) -> Vec<SitemapEntry> {
+    let mut threads = repo.list_threads(ThreadOrder::Created, 0, repo.count_threads());
+    threads.sort_by_key(|thread| thread.id);
+
+    let mut entries = vec![SitemapEntry {
+        loc: settings.absolute_url("/"),
+        lastmod: threads.iter().map(|thread| thread.last_updated).max().and_then(w3c_date),
+    }];
+    entries.extend(threads.iter().map(|thread| SitemapEntry {
+        loc: settings.absolute_url(&format!("/thread/{}", thread.id)),
+        lastmod: w3c_date(thread.last_updated),
+    }));
+    entries
+}
+
+fn render_urlset(entries: &[SitemapEntry]) -> String {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for entry in entries {
+        body.push_str("  <url><loc>");
+        body.push_str(&xml_escape(&entry.loc));
+        body.push_str("</loc>");
+        if let Some(lastmod) = &entry.lastmod {
+            let _ = write!(body, "<lastmod>{}</lastmod>", lastmod);
+        }
+        body.push_str("</url>\n");
+    }
+    body.push_str("</urlset>\n");
+    body
+}
+
+fn w3c_date(timestamp: i64) -> Option<String> {
+    DateTime::from_timestamp(timestamp, 0).map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_service, init_service, read_body, TestRequest};
+
+    fn locs_and_lastmods(xml: &str) -> Vec<(String, Option<String>)> {
+        let doc = roxmltree::Document::parse(xml).expect("sitemap is not well-formed XML");
+        assert_eq!(doc.root_element().tag_name().name(), "urlset");
+        doc.root_element()
+            .children()
+            .filter(|node| node.has_tag_name("url"))
+            .map(|url| {
+                let text = |tag| url.children().find(|node| node.has_tag_name(tag)).and_then(|node| node.text());
+                (text("loc").unwrap().to_string(), text("lastmod").map(str::to_string))
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn sitemap_lists_every_thread_with_its_last_bump() {
+        let state = test_support::state(&[("SITE_URL", "https://example.org/"), ("BASE_PATH", "/b&c")]);
+        for title in ["One", "Two"] {
+            crate::insert_thread(&state.db, test_support::new_thread(title, "Hello"), false).unwrap();
+        }
+        let lastmod = w3c_date(crate::get_thread(&state.db, 2).unwrap().last_updated);
+        let app = init_service(crate::app(&state)).await;
+
+        let req = TestRequest::get().uri("/b&c/sitemap.xml").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/xml; charset=utf-8");
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains("/b&amp;c/"), "{}", body);
+        assert_eq!(
+            locs_and_lastmods(&body),
+            vec![
+                ("https://example.org/b&c/".to_string(), lastmod.clone()),
+                ("https://example.org/b&c/thread/1".to_string(), lastmod.clone()),
+                ("https://example.org/b&c/thread/2".to_string(), lastmod),
+            ]
+        );
+
+        let req = TestRequest::get().uri("/b&c/sitemap-2.xml").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn robots_txt_lists_the_configured_paths() {
+        let state = test_support::state(&[("ROBOTS_ALLOW", "/thread"), ("ROBOTS_DISALLOW", "/admin,/api")]);
+        let app = init_service(crate::app(&state)).await;
+        let req = TestRequest::get().uri("/robots.txt").to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert_eq!(
+            body,
+            "User-agent: *\nAllow: /thread\nDisallow: /admin\nDisallow: /api\n\
+             Sitemap: http://localhost:8080/sitemap.xml\n"
+        );
+    }
+
+    #[test]
+    fn urlsets_escape_what_xml_reserves() {
+        let entries = [SitemapEntry {
+            loc: "https://example.org/?a=1&b=<2>".to_string(),
+            lastmod: None,
+        }];
+        let xml = render_urlset(&entries);
+        assert_eq!(locs_and_lastmods(&xml), vec![("https://example.org/?a=1&b=<2>".to_string(), None)]);
+    }
+}
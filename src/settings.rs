@@ -0,0 +1,431 @@
+use std::cell::RefCell;
+use std::env;
+
+use crate::expiry::PruneMode;
+use crate::post_rule::{Field, PostRule};
+use crate::repost::RepostMode;
+use crate::upload::{FilenameScheme, ImageType, ThumbFormat};
+use crate::word_filter::WordFilter;
+
+// Runtime configuration, read once from the environment at startup
+pub struct Settings {
+    // Storage behind the Repository trait, "sled" or "sqlite" (DB_BACKEND)
+    pub db_backend: String,
+    // The database file with DB_BACKEND=sqlite (SQLITE_PATH)
+    pub sqlite_path: String,
+    // Raise id counters left behind the stored posts at startup (COUNTER_CHECK)
+    pub counter_check: bool,
+    // Public origin used for absolute links, e.g. https://example.org (SITE_URL)
+    pub site_url: String,
+    // Path prefix when the board lives below the site root, e.g. /board (BASE_PATH);
+    // empty when it is served from /
+    pub base_path: String,
+    // Redirect page URLs ending in a slash to the URL without it (TRAILING_SLASH_REDIRECT)
+    pub trailing_slash_redirect: bool,
+    // Path prefixes crawlers may visit (ROBOTS_ALLOW, comma separated)
+    pub robots_allow: Vec<String>,
+    // Path prefixes crawlers should skip (ROBOTS_DISALLOW, comma separated)
+    pub robots_disallow: Vec<String>,
+    // Threads shown per homepage page (THREADS_PER_PAGE)
+    pub threads_per_page: i32,
+    // Pages of threads the board keeps; threads pushed past the last one are
+    // pruned. 0 keeps every thread (MAX_PAGES)
+    pub max_pages: usize,
+    // Whether pruned threads are deleted or archived (PRUNE_MODE)
+    pub prune_mode: PruneMode,
+    // Characters of an opening post shown on the homepage before "read more"; 0 shows it all (PREVIEW_CHARS)
+    pub preview_chars: usize,
+    // Number threads and replies from one board-wide sequence and show "No. N" on
+    // every post, rather than "Reply N" counted per thread (POST_NUMBERING)
+    pub global_post_numbers: bool,
+    // Replies a thread page shows until "View all" is clicked; 0 shows all (THREAD_LAST_REPLIES)
+    pub thread_last_replies: usize,
+    // Largest accepted image upload in bytes (MAX_UPLOAD_BYTES)
+    pub max_upload_bytes: u64,
+    // Largest accepted image area, width times height, checked before decoding (MAX_IMAGE_PIXELS)
+    pub max_image_pixels: u64,
+    // Total bytes all uploads may occupy, 0 for no limit (UPLOAD_QUOTA_BYTES)
+    pub upload_quota_bytes: u64,
+    // Log each refused upload with its reason and poster hash (LOG_UPLOAD_REJECTIONS)
+    pub log_upload_rejections: bool,
+    // Icon file inside ./static served at /favicon.ico (FAVICON)
+    pub favicon: String,
+    // Name of the installed app in the web manifest (APP_NAME) and its short
+    // form under the home screen icon (APP_SHORT_NAME)
+    pub app_name: String,
+    pub app_short_name: String,
+    // Icon files inside ./static listed in the web manifest; the favicon when empty (APP_ICONS)
+    pub app_icons: Vec<String>,
+    // Colour of the browser bar and installed app's title bar (THEME_COLOR)
+    pub theme_color: String,
+    // Whether replies move their thread to the top of the board (BUMP_ON_REPLY)
+    pub bump_on_reply: bool,
+    // Mark replies posted with sage (SHOW_SAGE)
+    pub show_sage: bool,
+    // Replies after which a thread stops being bumped; 0 for no limit (BUMP_LIMIT)
+    pub bump_limit: usize,
+    // Secret for the /admin routes; the admin area does not exist without it (ADMIN_TOKEN)
+    pub admin_token: Option<String>,
+    // Bearer key for the JSON write endpoints, which are off without it (API_KEY)
+    pub api_key: Option<String>,
+    // Let posters register and log in to post under their username (ACCOUNTS)
+    pub accounts: bool,
+    // Seconds a login lasts (SESSION_TTL)
+    pub session_ttl: i64,
+    // Seconds an IP has to wait between two login or registration attempts; 0 turns it off (ACCOUNT_COOLDOWN)
+    pub account_cooldown: u64,
+    // Seconds an /api/upload token stays claimable before the image is deleted (UPLOAD_TOKEN_TTL)
+    pub upload_token_ttl: i64,
+    // Seconds without a bump after which a thread is deleted for good; 0 keeps
+    // threads forever (THREAD_TTL)
+    pub thread_ttl: i64,
+    // Seconds an IP has to wait between starting two threads; 0 turns it off (THREAD_COOLDOWN)
+    pub thread_cooldown: u64,
+    // Seconds a client gets to send a whole request body; 0 for no limit (REQUEST_TIMEOUT)
+    pub request_timeout: u64,
+    // Seconds during which resubmitting the same thread from the same IP leads
+    // to the existing thread instead of a copy; 0 turns it off (DUPLICATE_THREAD_WINDOW)
+    pub duplicate_thread_window: u64,
+    // Lock a thread that gets more than this many replies within
+    // FLOOD_LOCK_WINDOW seconds; 0 turns it off (FLOOD_LOCK_REPLIES)
+    pub flood_lock_replies: usize,
+    pub flood_lock_window: u64,
+    // Start in read-only maintenance mode (READ_ONLY); can be toggled at runtime
+    pub read_only: bool,
+    // Banner text shown above every page until one is saved from /admin (ANNOUNCEMENT)
+    pub announcement: String,
+    // Content-Security-Policy for HTML pages (CONTENT_SECURITY_POLICY)
+    pub content_security_policy: String,
+    // X-Frame-Options for HTML pages (X_FRAME_OPTIONS)
+    pub frame_options: String,
+    // Referrer-Policy for HTML pages (REFERRER_POLICY)
+    pub referrer_policy: String,
+    // Believe X-Forwarded-For/Forwarded for client IPs; only behind a proxy you run (TRUST_PROXY)
+    pub trust_proxy: bool,
+    // Salt for poster hashes (POSTER_HASH_SALT); a random one is kept in sled when unset
+    pub poster_salt: String,
+    // Show a placeholder for images whose file is gone from disk (CHECK_IMAGE_FILES)
+    pub check_image_files: bool,
+    // PEM certificate chain and private key to serve HTTPS with, both or neither (TLS_CERT, TLS_KEY)
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    // MaxMind DB country database to look poster IPs up in, for flags (GEOIP_DB)
+    pub geoip_db: Option<String>,
+    // Whether threads have titles (TITLES_ENABLED). Without them the title field
+    // is gone from the form, titles are stored empty and THREAD_RULE ignores them.
+    pub titles_enabled: bool,
+    // Most tags an opening post may give, 0 for no tags at all (MAX_TAGS)
+    pub max_tags: usize,
+    // Fields a new thread must fill in (THREAD_RULE); REQUIRE_IMAGE_OP adds the
+    // image to the default rule
+    pub thread_rule: PostRule,
+    // Fields a reply must fill in (REPLY_RULE)
+    pub reply_rule: PostRule,
+    // Replies may carry an image (ALLOW_IMAGE_REPLY)
+    pub allow_image_reply: bool,
+    // Image formats uploads may be in, e.g. jpeg,png,gif,webp (ALLOWED_IMAGE_TYPES)
+    pub allowed_image_types: Vec<ImageType>,
+    // Recompress every PNG losslessly, not just strip metadata from it (PNG_OPTIMIZE)
+    pub png_optimize: bool,
+    // How stored uploads are named: uuid, timestamp, hash or sanitized-original (IMAGE_FILENAMES)
+    pub image_filenames: FilenameScheme,
+    // What thumbnails are encoded as: jpeg, png or webp (THUMB_FORMAT)
+    pub thumb_format: ThumbFormat,
+    // Blur every thumbnail until clicked, not just those of posts marked NSFW (BLUR_THUMBNAILS)
+    pub blur_thumbnails: bool,
+    // Refuse post forms carrying fields other than the known ones (STRICT_FORM_FIELDS)
+    pub strict_form_fields: bool,
+    // Most lines a post's message may have; 0 for no limit (MAX_MESSAGE_LINES)
+    pub max_message_lines: usize,
+    // Show links to YouTube and Vimeo videos as click-to-load players (MEDIA_EMBEDS)
+    pub media_embeds: bool,
+    // What to do with an upload resembling a stored one: off, flag or reject (REPOST_CHECK)
+    pub repost_check: RepostMode,
+    // Most bits out of 64 in which two images' dHashes may differ to count as alike (REPOST_DISTANCE)
+    pub repost_distance: u32,
+    // Blocked terms from WORD_FILTER (comma separated) and WORD_FILTER_FILE (one per
+    // line), either rejected or censored depending on WORD_FILTER_MODE
+    pub word_filter: WordFilter,
+    // Every variable read at startup and the value it had, for /admin/settings
+    pub sources: Vec<(String, Option<String>)>,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Settings::from_vars(&|key| env::var(key).ok())
+    }
+
+    // Read the settings from `var`, which looks one up by name; tests pass their own
+    pub fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Self {
+        let vars = Vars(var, RefCell::new(Vec::new()));
+        let media_embeds = vars.bool("MEDIA_EMBEDS", false);
+        let app_name = vars.string("APP_NAME", "Rust Lang is god!");
+        let thread_rule = match vars.bool("REQUIRE_IMAGE_OP", false) {
+            true => "title AND message AND image",
+            false => "title AND message",
+        };
+        let titles_enabled = vars.bool("TITLES_ENABLED", true);
+        let mut thread_rule = post_rule(&vars, "THREAD_RULE", thread_rule);
+        if !titles_enabled {
+            thread_rule = thread_rule.without(Field::Title).unwrap_or_else(|| {
+                log::warn!("THREAD_RULE asks only for a title, which TITLES_ENABLED=false leaves out; using \"message\"");
+                PostRule::Field(Field::Message)
+            });
+        }
+        let mut settings = Settings {
+            db_backend: vars.string("DB_BACKEND", "sled").trim().to_lowercase(),
+            sqlite_path: vars.string("SQLITE_PATH", "board.sqlite3"),
+            counter_check: vars.bool("COUNTER_CHECK", true),
+            site_url: vars.string("SITE_URL", "http://localhost:8080")
+                .trim_end_matches('/')
+                .to_string(),
+            base_path: normalize_base_path(&vars.string("BASE_PATH", "")),
+            trailing_slash_redirect: vars.bool("TRAILING_SLASH_REDIRECT", true),
+            robots_allow: vars.list("ROBOTS_ALLOW"),
+            robots_disallow: vars.list("ROBOTS_DISALLOW"),
+            threads_per_page: vars.parse("THREADS_PER_PAGE", 10).clamp(1, 100),
+            max_pages: vars.parse("MAX_PAGES", 0),
+            prune_mode: PruneMode::parse(&vars.string("PRUNE_MODE", "delete")),
+            preview_chars: vars.parse("PREVIEW_CHARS", 0),
+            thread_last_replies: vars.parse("THREAD_LAST_REPLIES", 0),
+            global_post_numbers: post_numbering(&vars),
+            max_upload_bytes: vars.parse("MAX_UPLOAD_BYTES", 10 * 1024 * 1024),
+            max_image_pixels: vars.parse("MAX_IMAGE_PIXELS", 50_000_000),
+            upload_quota_bytes: vars.parse("UPLOAD_QUOTA_BYTES", 0),
+            log_upload_rejections: vars.bool("LOG_UPLOAD_REJECTIONS", true),
+            favicon: vars.string("FAVICON", "favicon.ico")
+                .trim_start_matches('/')
+                .replace("..", ""),
+            app_name: app_name.clone(),
+            app_short_name: vars.string("APP_SHORT_NAME", &app_name),
+            app_icons: vars.list("APP_ICONS")
+                .into_iter()
+                .map(|icon| icon.replace("..", "").trim_start_matches('/').to_string())
+                .collect(),
+            theme_color: vars.string("THEME_COLOR", "#EEF2FF"),
+            bump_on_reply: vars.bool("BUMP_ON_REPLY", true),
+            show_sage: vars.bool("SHOW_SAGE", false),
+            bump_limit: vars.parse("BUMP_LIMIT", 0),
+            admin_token: vars.var("ADMIN_TOKEN").filter(|token| !token.trim().is_empty()),
+            api_key: vars.var("API_KEY").filter(|key| !key.trim().is_empty()),
+            accounts: vars.bool("ACCOUNTS", false),
+            session_ttl: vars.parse("SESSION_TTL", 30 * 24 * 60 * 60),
+            account_cooldown: vars.parse("ACCOUNT_COOLDOWN", 3),
+            upload_token_ttl: vars.parse("UPLOAD_TOKEN_TTL", 3600),
+            thread_ttl: vars.parse("THREAD_TTL", 0),
+            thread_cooldown: vars.parse("THREAD_COOLDOWN", 60),
+            request_timeout: vars.parse("REQUEST_TIMEOUT", 30),
+            duplicate_thread_window: vars.parse("DUPLICATE_THREAD_WINDOW", 60),
+            flood_lock_replies: vars.parse("FLOOD_LOCK_REPLIES", 0),
+            flood_lock_window: vars.parse("FLOOD_LOCK_WINDOW", 60),
+            read_only: vars.bool("READ_ONLY", false),
+            announcement: vars.string("ANNOUNCEMENT", ""),
+            content_security_policy: vars.string("CONTENT_SECURITY_POLICY", &default_csp(media_embeds)),
+            frame_options: vars.string("X_FRAME_OPTIONS", "DENY"),
+            referrer_policy: vars.string("REFERRER_POLICY", "same-origin"),
+            trust_proxy: vars.bool("TRUST_PROXY", false),
+            poster_salt: vars.string("POSTER_HASH_SALT", ""),
+            check_image_files: vars.bool("CHECK_IMAGE_FILES", false),
+            tls_cert: vars.var("TLS_CERT").filter(|path| !path.trim().is_empty()),
+            tls_key: vars.var("TLS_KEY").filter(|path| !path.trim().is_empty()),
+            geoip_db: vars.var("GEOIP_DB").filter(|path| !path.trim().is_empty()),
+            titles_enabled,
+            max_tags: vars.parse("MAX_TAGS", 0),
+            thread_rule,
+            reply_rule: post_rule(&vars, "REPLY_RULE", "message"),
+            allow_image_reply: vars.bool("ALLOW_IMAGE_REPLY", true),
+            allowed_image_types: image_types(vars.list("ALLOWED_IMAGE_TYPES")),
+            png_optimize: vars.bool("PNG_OPTIMIZE", false),
+            image_filenames: filename_scheme(&vars.string("IMAGE_FILENAMES", "uuid")),
+            thumb_format: thumb_format(&vars.string("THUMB_FORMAT", "jpeg")),
+            blur_thumbnails: vars.bool("BLUR_THUMBNAILS", false),
+            strict_form_fields: vars.bool("STRICT_FORM_FIELDS", false),
+            max_message_lines: vars.parse("MAX_MESSAGE_LINES", 50),
+            media_embeds,
+            repost_check: RepostMode::parse(&vars.string("REPOST_CHECK", "off")),
+            repost_distance: vars.parse("REPOST_DISTANCE", 6),
+            word_filter: WordFilter::new(
+                vars.list("WORD_FILTER"),
+                vars.var("WORD_FILTER_FILE").filter(|path| !path.trim().is_empty()).as_deref(),
+                &vars.string("WORD_FILTER_MODE", "reject"),
+            ),
+            sources: Vec::new(),
+        };
+        settings.sources = vars.1.take();
+        settings
+    }
+}
+
+impl Settings {
+    // Cookies only go over HTTPS when the board is served over it
+    pub fn secure_cookies(&self) -> bool {
+        self.site_url.starts_with("https://") || self.tls_cert.is_some()
+    }
+
+    // Link to a path of the board, e.g. /thread/1 becomes /board/thread/1
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_path, path)
+    }
+
+    // "JPEG, PNG" for the post form and upload errors
+    pub fn image_type_labels(&self) -> String {
+        self.allowed_image_types.iter().map(ImageType::label).collect::<Vec<_>>().join(", ")
+    }
+
+    // The accept attribute of the post form's file input
+    pub fn image_accept(&self) -> String {
+        self.allowed_image_types.iter().map(ImageType::mime).collect::<Vec<_>>().join(",")
+    }
+
+    // The same link as an absolute URL, for sitemaps and robots.txt
+    pub fn absolute_url(&self, path: &str) -> String {
+        format!("{}{}{}", self.site_url, self.base_path, path)
+    }
+
+    // Threads the board keeps under MAX_PAGES, pinned ones not counted; None
+    // without a limit
+    pub fn kept_threads(&self) -> Option<usize> {
+        (self.max_pages > 0).then(|| self.max_pages * self.threads_per_page as usize)
+    }
+
+    // Whether threads and replies live in the sled database, where the admin
+    // tools, pruning and expiry find them
+    pub fn posts_in_sled(&self) -> bool {
+        self.db_backend == "sled"
+    }
+
+    // Options that act on the sled post records, set while the posts are stored
+    // elsewhere; the server won't start with any of them
+    pub fn sled_only_options(&self) -> Vec<&'static str> {
+        if self.posts_in_sled() {
+            return Vec::new();
+        }
+        let options = [
+            ("THREAD_TTL", self.thread_ttl > 0),
+            ("MAX_PAGES", self.max_pages > 0),
+            ("FLOOD_LOCK_REPLIES", self.flood_lock_replies > 0),
+        ];
+        options.into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect()
+    }
+}
+
+// Unknown names are skipped with a warning; nothing usable means JPEG only
+fn image_types(names: Vec<String>) -> Vec<ImageType> {
+    let mut types = Vec::new();
+    for name in names {
+        match ImageType::parse(&name) {
+            Some(image_type) if !types.contains(&image_type) => types.push(image_type),
+            Some(_) => {}
+            None => log::warn!("Ignoring unknown image type {:?} in ALLOWED_IMAGE_TYPES", name),
+        }
+    }
+    if types.is_empty() {
+        types.push(ImageType::Jpeg);
+    }
+    types
+}
+
+// "/board/", "board" and "/board" all become "/board"; "/" and "" become ""
+fn normalize_base_path(raw: &str) -> String {
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", trimmed)
+    }
+}
+
+// Where the settings are read from, the process environment outside of tests,
+// and what was read from it
+struct Vars<'a>(&'a dyn Fn(&str) -> Option<String>, RefCell<Vec<(String, Option<String>)>>);
+
+impl Vars<'_> {
+    fn var(&self, key: &str) -> Option<String> {
+        let value = (self.0)(key);
+        let mut seen = self.1.borrow_mut();
+        if !seen.iter().any(|(name, _)| name == key) {
+            seen.push((key.to_string(), value.clone()));
+        }
+        value
+    }
+
+    fn string(&self, key: &str, default: &str) -> String {
+        self.var(key).unwrap_or_else(|| default.to_string())
+    }
+
+    fn parse<T: std::str::FromStr>(&self, key: &str, default: T) -> T {
+        self.var(key)
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(default)
+    }
+
+    fn bool(&self, key: &str, default: bool) -> bool {
+        match self.var(key).map(|value| value.trim().to_lowercase()) {
+            Some(value) if ["1", "true", "yes", "on"].contains(&value.as_str()) => true,
+            Some(value) if ["0", "false", "no", "off"].contains(&value.as_str()) => false,
+            _ => default,
+        }
+    }
+
+    fn list(&self, key: &str) -> Vec<String> {
+        self.var(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect()
+    }
+}
+
+// Self-only, except that embedded video players may load in frames
+fn default_csp(media_embeds: bool) -> String {
+    let mut policy = "default-src 'self'; script-src 'self'; style-src 'self'; img-src 'self'; \
+                      object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'"
+        .to_string();
+    if media_embeds {
+        policy.push_str(&format!("; frame-src {}", crate::embed::PLAYER_ORIGINS));
+    }
+    policy
+}
+
+// A rule that doesn't parse falls back to `default` with a warning
+fn post_rule(vars: &Vars, key: &str, default: &str) -> PostRule {
+    let default_rule = || default.parse().expect("default post rules parse");
+    match vars.var(key) {
+        Some(text) => text.parse().unwrap_or_else(|e| {
+            log::warn!("Ignoring {} {:?} ({}), using {:?}", key, text, e, default);
+            default_rule()
+        }),
+        None => default_rule(),
+    }
+}
+
+// "thread" or "global"; GLOBAL_POST_NUMBERS=true is the older spelling of
+// "global". Anything else falls back to per-thread numbers with a warning.
+fn post_numbering(vars: &Vars) -> bool {
+    match vars.var("POST_NUMBERING").map(|value| value.trim().to_lowercase()) {
+        Some(value) if value == "global" => true,
+        Some(value) if value == "thread" => false,
+        Some(value) => {
+            log::warn!("Ignoring unknown POST_NUMBERING {:?}, using thread", value);
+            false
+        }
+        None => vars.bool("GLOBAL_POST_NUMBERS", false),
+    }
+}
+
+// An unknown scheme falls back to UUIDs with a warning
+fn filename_scheme(name: &str) -> FilenameScheme {
+    FilenameScheme::parse(name).unwrap_or_else(|| {
+        log::warn!("Ignoring unknown IMAGE_FILENAMES {:?}, using uuid", name);
+        FilenameScheme::Uuid
+    })
+}
+
+// An unknown format falls back to JPEG with a warning
+fn thumb_format(name: &str) -> ThumbFormat {
+    ThumbFormat::parse(name).unwrap_or_else(|| {
+        log::warn!("Ignoring unknown THUMB_FORMAT {:?}, using jpeg", name);
+        ThumbFormat::Jpeg
+    })
+}
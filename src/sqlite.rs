@@ -0,0 +1,364 @@
+// The Repository stored in an SQLite file instead of sled (DB_BACKEND=sqlite).
+// Threads and replies are kept as the same JSON records sled holds, next to
+// the columns the board sorts and looks them up by.
+//
+// Tables:
+// threads        id, bump time, creation time, reply counter and count, JSON Thread
+// replies        thread and reply id, creation time, JSON Reply
+// tags           one row per tag a thread carries
+// post_numbers   the thread each board-wide post number is in (POST_NUMBERING=global)
+// counters       the highest thread id and post number handed out
+
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension, Params, Transaction};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::repository::{self, RepoError, ReplySummary, Repository, ThreadOrder};
+use crate::thread_state::{self, ReplyRefusal};
+use crate::{NewReply, NewThread, Reply, Thread};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS threads (
+        id INTEGER PRIMARY KEY,
+        last_updated INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        reply_counter INTEGER NOT NULL DEFAULT 0,
+        reply_count INTEGER NOT NULL DEFAULT 0,
+        record TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS threads_by_bump ON threads (last_updated DESC, id DESC);
+    CREATE TABLE IF NOT EXISTS replies (
+        thread_id INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        created_at INTEGER NOT NULL,
+        record TEXT NOT NULL,
+        PRIMARY KEY (thread_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS tags (
+        tag TEXT NOT NULL,
+        thread_id INTEGER NOT NULL,
+        PRIMARY KEY (tag, thread_id)
+    );
+    CREATE TABLE IF NOT EXISTS post_numbers (
+        number INTEGER PRIMARY KEY,
+        thread_id INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS counters (
+        name TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+pub struct SqliteRepository {
+    // One connection, so writes never wait on SQLite's own locking
+    connection: Mutex<Connection>,
+    // POST_NUMBERING=global
+    global_numbers: bool,
+    // BUMP_LIMIT
+    bump_limit: usize,
+    // THREAD_TTL
+    thread_ttl: i64,
+}
+
+impl SqliteRepository {
+    // Open the database at `path`, creating it and its tables if need be
+    pub fn open(path: &Path, global_numbers: bool, bump_limit: usize, thread_ttl: i64) -> rusqlite::Result<Self> {
+        Self::with_connection(Connection::open(path)?, global_numbers, bump_limit, thread_ttl)
+    }
+
+    pub fn with_connection(
+        connection: Connection,
+        global_numbers: bool,
+        bump_limit: usize,
+        thread_ttl: i64,
+    ) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteRepository {
+            connection: Mutex::new(connection),
+            global_numbers,
+            bump_limit,
+            thread_ttl,
+        })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("sqlite connection poisoned")
+    }
+
+    // The JSON records a query selecting `record` gives, in its order. Failures
+    // are logged and read as nothing found, as the sled reads do.
+    fn records<T: DeserializeOwned>(&self, sql: &str, params: impl Params) -> Vec<T> {
+        let connection = self.connection();
+        let records = connection.prepare_cached(sql).and_then(|mut statement| {
+            let records = statement.query_map(params, |row| row.get::<_, String>(0))?;
+            records.collect::<rusqlite::Result<Vec<String>>>()
+        });
+        match records {
+            Ok(records) => records.iter().filter_map(|record| serde_json::from_str(record).ok()).collect(),
+            Err(e) => {
+                error!("Failed to read from sqlite: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    // The number a COUNT(*) query gives, 0 if it fails
+    fn count(&self, sql: &str) -> usize {
+        let count = self.connection().query_row(sql, [], |row| row.get::<_, i64>(0));
+        count.map_or_else(
+            |e| {
+                error!("Failed to count rows in sqlite: {}", e);
+                0
+            },
+            |count| count as usize,
+        )
+    }
+}
+
+// Hand out the next value of counter `name`
+fn next_counter(tx: &Transaction, name: &str) -> rusqlite::Result<i32> {
+    tx.query_row(
+        "INSERT INTO counters (name, value) VALUES (?1, 1)
+         ON CONFLICT (name) DO UPDATE SET value = value + 1
+         RETURNING value",
+        [name],
+        |row| row.get(0),
+    )
+}
+
+fn read_thread(tx: &Transaction, thread_id: i32) -> rusqlite::Result<Option<Thread>> {
+    let record: Option<String> = tx
+        .query_row("SELECT record FROM threads WHERE id = ?1", [thread_id], |row| row.get(0))
+        .optional()?;
+    Ok(record.and_then(|record| serde_json::from_str(&record).ok()))
+}
+
+fn storage(e: rusqlite::Error) -> RepoError {
+    RepoError::Storage(e.to_string())
+}
+
+impl Repository for SqliteRepository {
+    fn list_threads(&self, order: ThreadOrder, offset: usize, limit: usize) -> Vec<Thread> {
+        let order = match order {
+            ThreadOrder::Bump => "last_updated DESC, id DESC",
+            // Ids only ever go up, as with sled
+            ThreadOrder::Created => "id DESC",
+        };
+        let sql = format!("SELECT record FROM threads ORDER BY {} LIMIT ?1 OFFSET ?2", order);
+        self.records(&sql, params![limit as i64, offset as i64])
+    }
+
+    fn list_threads_after(&self, after: Option<(i64, i32)>, limit: usize) -> Vec<Thread> {
+        match after {
+            Some((last_updated, id)) => self.records(
+                "SELECT record FROM threads WHERE (last_updated, id) < (?1, ?2)
+                 ORDER BY last_updated DESC, id DESC LIMIT ?3",
+                params![last_updated, id, limit as i64],
+            ),
+            None => self.list_threads(ThreadOrder::Bump, 0, limit),
+        }
+    }
+
+    fn count_threads(&self) -> usize {
+        self.count("SELECT COUNT(*) FROM threads")
+    }
+
+    fn list_sticky(&self) -> Vec<Thread> {
+        let pinned = self.records(
+            "SELECT record FROM threads WHERE json_extract(record, '$.sticky') IS NOT NULL
+             ORDER BY last_updated DESC, id DESC",
+            [],
+        );
+        pinned.into_iter().filter(Thread::is_sticky).collect()
+    }
+
+    fn list_unpinned(&self, pinned: &[i32], offset: usize, limit: usize) -> Vec<Thread> {
+        let pinned = serde_json::to_string(pinned).expect("Failed to serialize thread ids");
+        self.records(
+            "SELECT record FROM threads WHERE id NOT IN (SELECT value FROM json_each(?1))
+             ORDER BY last_updated DESC, id DESC LIMIT ?2 OFFSET ?3",
+            params![pinned, limit as i64, offset as i64],
+        )
+    }
+
+    fn list_tagged(&self, tag: &str) -> Vec<Thread> {
+        self.records(
+            "SELECT record FROM threads JOIN tags ON tags.thread_id = threads.id WHERE tags.tag = ?1
+             ORDER BY last_updated DESC, id DESC",
+            [tag],
+        )
+    }
+
+    fn get_thread(&self, thread_id: i32) -> Option<Thread> {
+        self.records("SELECT record FROM threads WHERE id = ?1", [thread_id]).pop()
+    }
+
+    fn create_thread(&self, new_thread: NewThread) -> Result<Thread, RepoError> {
+        let mut connection = self.connection();
+        let tx = connection.transaction().map_err(storage)?;
+        let thread = (|| {
+            let thread_id = next_counter(&tx, if self.global_numbers { "post" } else { "thread" })?;
+            if self.global_numbers {
+                tx.execute("INSERT INTO post_numbers (number, thread_id) VALUES (?1, ?1)", [thread_id])?;
+            }
+            let thread = new_thread.to_thread(thread_id, chrono::Utc::now().timestamp());
+            tx.execute(
+                "INSERT INTO threads (id, last_updated, created_at, record) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    thread.id,
+                    thread.last_updated,
+                    thread.created_at,
+                    serde_json::to_string(&thread).expect("Failed to serialize thread")
+                ],
+            )?;
+            for tag in &thread.tags {
+                tx.execute("INSERT OR IGNORE INTO tags (tag, thread_id) VALUES (?1, ?2)", params![tag, thread.id])?;
+            }
+            Ok::<_, rusqlite::Error>(thread)
+        })()
+        .map_err(storage)?;
+        tx.commit().map_err(storage)?;
+        Ok(thread)
+    }
+
+    fn list_replies(&self, thread_id: i32) -> Vec<Reply> {
+        self.records("SELECT record FROM replies WHERE thread_id = ?1 ORDER BY id", [thread_id])
+    }
+
+    fn get_reply(&self, thread_id: i32, reply_id: i32) -> Option<Reply> {
+        self.records("SELECT record FROM replies WHERE thread_id = ?1 AND id = ?2", [thread_id, reply_id]).pop()
+    }
+
+    fn reply_exists(&self, thread_id: i32, reply_id: i32) -> bool {
+        self.get_reply(thread_id, reply_id).is_some()
+    }
+
+    fn post_thread(&self, number: i32) -> Option<i32> {
+        if !self.global_numbers {
+            return None;
+        }
+        self.connection()
+            .query_row("SELECT thread_id FROM post_numbers WHERE number = ?1", [number], |row| row.get(0))
+            .optional()
+            .ok()
+            .flatten()
+    }
+
+    fn reply_summaries(&self, thread_ids: &[i32]) -> HashMap<i32, ReplySummary> {
+        let connection = self.connection();
+        let Ok(mut statement) =
+            connection.prepare_cached("SELECT COUNT(*), MAX(created_at) FROM replies WHERE thread_id = ?1")
+        else {
+            return HashMap::new();
+        };
+        thread_ids
+            .iter()
+            .filter_map(|&thread_id| {
+                let (count, last_reply_at): (i64, Option<i64>) =
+                    statement.query_row([thread_id], |row| Ok((row.get(0)?, row.get(1)?))).ok()?;
+                let summary = ReplySummary {
+                    count: count as usize,
+                    last_reply_at,
+                };
+                (count > 0).then_some((thread_id, summary))
+            })
+            .collect()
+    }
+
+    // The same steps as insert_reply, in one SQLite transaction
+    fn create_reply(&self, thread_id: i32, new_reply: NewReply, bump: bool) -> Result<Reply, RepoError> {
+        let mut connection = self.connection();
+        let tx = connection.transaction().map_err(storage)?;
+        let now = chrono::Utc::now().timestamp();
+        let thread = read_thread(&tx, thread_id).map_err(storage)?;
+        thread_state::can_reply(thread.as_ref(), self.thread_ttl, now).map_err(RepoError::Refused)?;
+        let mut thread = thread.ok_or(RepoError::Refused(ReplyRefusal::Deleted))?;
+
+        let reply = (|| {
+            let (counter, count): (i32, i32) = tx.query_row(
+                "SELECT reply_counter, reply_count FROM threads WHERE id = ?1",
+                [thread_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let reply_id = if self.global_numbers {
+                let number = next_counter(&tx, "post")?;
+                tx.execute("INSERT INTO post_numbers (number, thread_id) VALUES (?1, ?2)", [number, thread_id])?;
+                number
+            } else {
+                counter + 1
+            };
+            let count = count + 1;
+            let reply = new_reply.to_reply(reply_id, now);
+            tx.execute(
+                "INSERT INTO replies (thread_id, id, created_at, record) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    thread_id,
+                    reply_id,
+                    reply.created_at,
+                    serde_json::to_string(&reply).expect("Failed to serialize reply")
+                ],
+            )?;
+            tx.execute(
+                "UPDATE threads SET reply_counter = ?2, reply_count = ?3 WHERE id = ?1",
+                [thread_id, reply_id, count],
+            )?;
+
+            if bump && (self.bump_limit == 0 || count as usize <= self.bump_limit) {
+                thread.last_updated = now;
+                tx.execute(
+                    "UPDATE threads SET last_updated = ?2, record = ?3 WHERE id = ?1",
+                    params![
+                        thread_id,
+                        now,
+                        serde_json::to_string(&thread).expect("Failed to serialize updated thread")
+                    ],
+                )?;
+            }
+            Ok::<_, rusqlite::Error>(reply)
+        })()
+        .map_err(storage)?;
+        tx.commit().map_err(storage)?;
+        Ok(reply)
+    }
+
+    fn count_replies(&self) -> usize {
+        self.count("SELECT COUNT(*) FROM replies")
+    }
+
+    fn referenced_files(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        for thread in self.records::<Thread>("SELECT record FROM threads", []) {
+            repository::add_file_names(&mut names, thread.file_urls());
+        }
+        for reply in self.records::<Reply>("SELECT record FROM replies", []) {
+            repository::add_file_names(&mut names, reply.file_urls());
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    #[test]
+    fn posts_survive_reopening_the_file() {
+        let path = std::env::temp_dir().join(format!("chess_board-test-{}.sqlite3", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let repository = SqliteRepository::open(&path, false, 0, 0).unwrap();
+            repository.create_thread(test_support::new_thread("Kept", "On disk")).unwrap();
+            repository.create_reply(1, test_support::new_reply("Also kept"), true).unwrap();
+        }
+
+        let repository = SqliteRepository::open(&path, false, 0, 0).unwrap();
+        assert_eq!(repository.get_thread(1).unwrap().title, "Kept");
+        assert_eq!(repository.create_thread(test_support::new_thread("Next", "Two")).unwrap().id, 2);
+        assert_eq!(repository.create_reply(1, test_support::new_reply("Third"), true).unwrap().id, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+// Thread tags (MAX_TAGS). The opening post may give a comma-separated list of
+// tags, kept on the thread and indexed so /tag/{tag} can list its threads
+// without reading every one.
+//
+// Keys:
+// tag_{tag}_{thread_id}   id of the thread, one entry per tag it carries
+//
+// Tags are lowercase ASCII letters, digits and dashes only, so they go into
+// URLs as they are, and a tag never holds the _ that separates it from the
+// thread id, so one tag's entries never share a prefix with another's.
+
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::Db;
+
+use crate::{decode_counter, get_thread, Thread};
+
+// Longest tag kept, in characters; longer ones are cut short
+const MAX_TAG_CHARS: usize = 24;
+
+fn tag_key(tag: &str, thread_id: i32) -> Vec<u8> {
+    format!("tag_{}_{}", tag, thread_id).into_bytes()
+}
+
+// The tags in a comma-separated list: trimmed, lowercased, spaces turned into
+// dashes and other punctuation dropped, repeats removed, and at most `max`
+pub fn parse(list: &str, max: usize) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in list.split(',').filter_map(normalize) {
+        if tags.len() == max {
+            break;
+        }
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    tags
+}
+
+// One tag in its stored form, or None when nothing is left of it
+pub fn normalize(tag: &str) -> Option<String> {
+    let words: Vec<String> = tag
+        .split_whitespace()
+        .map(|word| word.chars().filter(|c| c.is_ascii_alphanumeric() || *c == '-').collect::<String>())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let tag: String = words.join("-").to_lowercase().chars().take(MAX_TAG_CHARS).collect();
+    let tag = tag.trim_matches('-');
+    (!tag.is_empty()).then(|| tag.to_string())
+}
+
+// Add index entries for the thread's tags, inside the caller's transaction
+pub fn index<E>(tx: &TransactionalTree, thread: &Thread) -> Result<(), ConflictableTransactionError<E>> {
+    for tag in &thread.tags {
+        tx.insert(tag_key(tag, thread.id), &thread.id.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+// Drop the index entries for the thread's tags, once it is gone
+pub fn unindex<E>(tx: &TransactionalTree, thread: &Thread) -> Result<(), ConflictableTransactionError<E>> {
+    for tag in &thread.tags {
+        tx.remove(tag_key(tag, thread.id))?;
+    }
+    Ok(())
+}
+
+// The threads tagged `tag`, most recently bumped first
+pub fn tagged_threads(db: &Db, tag: &str) -> Vec<Thread> {
+    let mut threads: Vec<Thread> = db
+        .scan_prefix(format!("tag_{}_", tag))
+        .values()
+        .filter_map(|value| value.ok().map(|value| decode_counter(&value)))
+        .filter_map(|thread_id| get_thread(db, thread_id))
+        .collect();
+    threads.sort_by_key(|thread| std::cmp::Reverse((thread.last_updated, thread.id)));
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    fn tagged(title: &str, tags: &[&str]) -> crate::NewThread {
+        let mut thread = test_support::new_thread(title, "Hi");
+        thread.tags = tags.iter().map(|tag| tag.to_string()).collect();
+        thread
+    }
+
+    #[test]
+    fn tags_are_normalized_deduplicated_and_capped() {
+        assert_eq!(parse(" Chess , opening theory,CHESS, ,e4!, a_b", 10), ["chess", "opening-theory", "e4", "ab"]);
+        assert_eq!(parse("one, two, three", 2), ["one", "two"]);
+        assert_eq!(normalize("--Sicilian  Najdorf--").as_deref(), Some("sicilian-najdorf"));
+        assert_eq!(normalize(&"x".repeat(40)).unwrap().len(), MAX_TAG_CHARS);
+        assert_eq!(normalize("!!! ___"), None);
+    }
+
+    #[test]
+    fn one_tag_never_lists_another_tags_threads() {
+        let db = test_support::temp_db();
+        let go = crate::insert_thread(&db, tagged("Go", &["go"]), false).unwrap();
+        crate::insert_thread(&db, tagged("Go variants", &["go-2"]), false).unwrap();
+        let both = crate::insert_thread(&db, tagged("Both", &["go", "chess"]), false).unwrap();
+
+        let ids = |tag: &str| tagged_threads(&db, tag).iter().map(|thread| thread.id).collect::<Vec<_>>();
+        assert_eq!(ids("go"), [both.id, go.id]);
+        assert_eq!(ids("chess"), [both.id]);
+
+        crate::expiry::delete_thread(&db, both.id, i64::MAX).unwrap();
+        assert_eq!(ids("go"), [go.id]);
+        assert!(ids("chess").is_empty());
+        assert_eq!(db.scan_prefix(b"tag_chess_").count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn the_tag_page_lists_only_its_threads() {
+        let state = test_support::state(&[("MAX_TAGS", "2"), ("THREAD_COOLDOWN", "0")]);
+        let app = init_service(crate::app(&state)).await;
+        let threads: [(&str, &[u8]); 2] = [("Tagged thread", b"Chess, Endgames, Extra"), ("Plain thread", b"")];
+        for (title, tags) in threads {
+            let fields: [(&str, &[u8]); 3] = [("title", title.as_bytes()), ("message", b"Hi"), ("tags", tags)];
+            let post = test_support::form_post("/thread", &fields);
+            assert!(call_service(&app, post.to_request()).await.status().is_redirection());
+        }
+        assert_eq!(crate::get_thread(&state.db, 1).unwrap().tags, ["chess", "endgames"]);
+
+        let page = |uri: &str| TestRequest::get().uri(uri).to_request();
+        let listing = String::from_utf8(call_and_read_body(&app, page("/tag/Chess")).await.to_vec()).unwrap();
+        assert!(listing.contains("Tagged thread") && !listing.contains("Plain thread"));
+        assert!(listing.contains("#chess"));
+        let empty = String::from_utf8(call_and_read_body(&app, page("/tag/extra")).await.to_vec()).unwrap();
+        assert!(empty.contains("No threads are tagged #extra."));
+        assert_eq!(call_service(&app, page("/tag/%21%21")).await.status(), 404);
+
+        let untagged = test_support::state(&[]);
+        let app = init_service(crate::app(&untagged)).await;
+        assert_eq!(call_service(&app, page("/tag/chess")).await.status(), 404);
+    }
+}
@@ -0,0 +1,332 @@
+// Shared fixtures for the handler tests: a throwaway sled database, settings
+// from a list of variables, and a scratch working directory for uploads
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Once};
+
+use actix_multipart::Multipart;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::test::TestRequest;
+use actix_web::web;
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use sled::Db;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::repository::{self, RepoError, ReplySummary, Repository, ThreadOrder};
+use crate::settings::Settings;
+use crate::thread_state::{self, ReplyRefusal};
+use crate::upload::ImageType;
+use crate::{bump_key, get_thread, AppState, NewReply, NewThread, Reply, Thread};
+
+// Settings as if `vars` were the whole environment. The poster salt is fixed
+// so poster ids come out the same in every run.
+pub fn settings(vars: &[(&str, &str)]) -> Settings {
+    let mut map: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    map.entry("POSTER_HASH_SALT".to_string()).or_insert_with(|| "test-salt".to_string());
+    Settings::from_vars(&|key| map.get(key).cloned())
+}
+
+pub fn temp_db() -> Arc<Db> {
+    Arc::new(sled::Config::new().temporary(true).open().expect("Failed to open a temporary database"))
+}
+
+// State for an app over a fresh database, migrated the way main does it
+pub fn state(vars: &[(&str, &str)]) -> AppState {
+    state_with_db(temp_db(), vars)
+}
+
+pub fn state_with_db(db: Arc<Db>, vars: &[(&str, &str)]) -> AppState {
+    crate::migrations::run(&db).expect("Failed to migrate the test database");
+    let settings = settings(vars);
+    if settings.global_post_numbers {
+        crate::post_numbers::seed_counter(&db).expect("Failed to seed the post number counter");
+    }
+    AppState::new(db, web::Data::new(settings))
+}
+
+static WORKDIR: Once = Once::new();
+static FILES: Mutex<()> = Mutex::const_new(());
+
+// Uploads and thumbnails live under the working directory, so the test binary
+// moves into a scratch directory of its own. Tests that read or write those
+// directories hold the returned guard so they don't see each other's files.
+pub async fn files() -> MutexGuard<'static, ()> {
+    WORKDIR.call_once(|| {
+        let dir: PathBuf = std::env::temp_dir().join(format!("chess_board-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for sub in ["uploads", "thumbs"] {
+            std::fs::create_dir_all(dir.join(sub)).expect("Failed to create the test directories");
+        }
+        let static_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("static");
+        std::os::unix::fs::symlink(static_dir, dir.join("static")).expect("Failed to link the static directory");
+        std::env::set_current_dir(&dir).expect("Failed to enter the test directory");
+    });
+    FILES.lock().await
+}
+
+struct CapturedLog(std::sync::Mutex<Vec<String>>);
+
+impl log::Log for CapturedLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG: CapturedLog = CapturedLog(std::sync::Mutex::new(Vec::new()));
+static LOGGER: Once = Once::new();
+
+// Messages logged at info or above so far, by every test running alongside
+// this one; look for something only the calling test logs
+pub fn logged() -> Vec<String> {
+    LOGGER.call_once(|| {
+        log::set_logger(&LOG).expect("Failed to install the test logger");
+        log::set_max_level(log::LevelFilter::Info);
+    });
+    LOG.0.lock().unwrap().clone()
+}
+
+// A multipart/form-data body. The "image" field is sent as a file named for
+// the format of its contents; "image@name.ext" sends it under a name of its own.
+pub fn multipart(fields: &[(&str, &[u8])]) -> (String, Vec<u8>) {
+    let boundary = "chess-board-test-boundary";
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let filename = match name.split_once('@') {
+            Some((_, filename)) => Some(filename.to_string()),
+            None if *name == "image" => Some(format!("test.{}", sniffed_extension(value))),
+            None => None,
+        };
+        let name = name.split('@').next().unwrap();
+        let disposition = match filename {
+            Some(filename) => format!(
+                "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n",
+                name, filename
+            ),
+            None => format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name),
+        };
+        body.extend_from_slice(disposition.as_bytes());
+        body.extend_from_slice(value);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+// The form as a stream of `chunk`-byte pieces, the way a slow client sends it
+pub fn chunked_form(fields: &[(&str, &[u8])], chunk: usize) -> Multipart {
+    let (content_type, body) = multipart(fields);
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(&content_type).unwrap());
+    let chunks: Vec<Result<web::Bytes, PayloadError>> =
+        body.chunks(chunk).map(|piece| Ok(web::Bytes::copy_from_slice(piece))).collect();
+    Multipart::new(&headers, futures_util::stream::iter(chunks))
+}
+
+fn sniffed_extension(bytes: &[u8]) -> &'static str {
+    match ImageType::sniff(bytes) {
+        Some(ImageType::Png) => "png",
+        Some(ImageType::Gif) => "gif",
+        Some(ImageType::Webp) => "webp",
+        _ => "jpg",
+    }
+}
+
+// A form post from 192.0.2.1, the address every test poster has unless it
+// sets its own peer_addr
+pub fn form_post(uri: &str, fields: &[(&str, &[u8])]) -> TestRequest {
+    let (content_type, body) = multipart(fields);
+    TestRequest::post()
+        .uri(uri)
+        .peer_addr("192.0.2.1:4000".parse().unwrap())
+        .insert_header(("content-type", content_type))
+        .set_payload(body)
+}
+
+// A gradient so the encoders have something to work with
+fn picture(width: u32, height: u32, alpha: u8) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| Rgba([(x * 255 / width) as u8, (y * 255 / height) as u8, 128, alpha]))
+}
+
+pub fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let rgb = image::DynamicImage::ImageRgba8(picture(width, height, 255)).to_rgb8();
+    let mut bytes = Cursor::new(Vec::new());
+    rgb.write_to(&mut bytes, ImageOutputFormat::Jpeg(90)).expect("Failed to encode a test JPEG");
+    bytes.into_inner()
+}
+
+pub fn png(width: u32, height: u32, alpha: u8) -> Vec<u8> {
+    let mut bytes = Cursor::new(Vec::new());
+    picture(width, height, alpha).write_to(&mut bytes, ImageOutputFormat::Png).expect("Failed to encode a test PNG");
+    bytes.into_inner()
+}
+
+pub fn new_thread(title: &str, message: &str) -> NewThread {
+    NewThread {
+        title: title.to_string(),
+        message: message.to_string(),
+        image_url: None,
+        thumbnails: Vec::new(),
+        image_size: None,
+        email: None,
+        poster_hash: None,
+        username: None,
+        tags: Vec::new(),
+        country: None,
+        nsfw: false,
+    }
+}
+
+pub fn new_reply(message: &str) -> NewReply {
+    NewReply {
+        message: message.to_string(),
+        image_url: None,
+        thumbnails: Vec::new(),
+        image_size: None,
+        email: None,
+        poster_hash: None,
+        username: None,
+        country: None,
+        nsfw: false,
+    }
+}
+
+// Move a thread's last bump into the past, as if it had been posted a while ago
+pub fn backdate(db: &Db, thread_id: i32, last_updated: i64) {
+    let mut thread = get_thread(db, thread_id).unwrap();
+    db.remove(bump_key(thread.last_updated, thread_id)).unwrap();
+    thread.last_updated = last_updated;
+    db.insert(bump_key(last_updated, thread_id), &thread_id.to_be_bytes()).unwrap();
+    db.insert(format!("thread_{}", thread_id), serde_json::to_vec(&thread).unwrap()).unwrap();
+}
+
+// Threads and replies kept in memory, for handler tests that don't need sled
+// behind the Repository trait. Replies always bump, with no bump limit.
+#[derive(Default)]
+pub struct MemoryRepository {
+    threads: std::sync::Mutex<Vec<Thread>>,
+    replies: std::sync::Mutex<HashMap<i32, Vec<Reply>>>,
+}
+
+impl MemoryRepository {
+    fn sorted(&self, order: ThreadOrder) -> Vec<Thread> {
+        let mut threads = self.threads.lock().unwrap().clone();
+        match order {
+            ThreadOrder::Bump => threads.sort_by_key(|thread| std::cmp::Reverse((thread.last_updated, thread.id))),
+            ThreadOrder::Created => threads.sort_by_key(|thread| std::cmp::Reverse((thread.created_at, thread.id))),
+        }
+        threads
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn list_threads(&self, order: ThreadOrder, offset: usize, limit: usize) -> Vec<Thread> {
+        self.sorted(order).into_iter().skip(offset).take(limit).collect()
+    }
+
+    fn list_threads_after(&self, after: Option<(i64, i32)>, limit: usize) -> Vec<Thread> {
+        let threads = self.sorted(ThreadOrder::Bump).into_iter();
+        let after = |thread: &Thread| after.is_none_or(|after| (thread.last_updated, thread.id) < after);
+        threads.filter(after).take(limit).collect()
+    }
+
+    fn count_threads(&self) -> usize {
+        self.threads.lock().unwrap().len()
+    }
+
+    fn list_sticky(&self) -> Vec<Thread> {
+        self.sorted(ThreadOrder::Bump).into_iter().filter(Thread::is_sticky).collect()
+    }
+
+    fn list_unpinned(&self, pinned: &[i32], offset: usize, limit: usize) -> Vec<Thread> {
+        let threads = self.sorted(ThreadOrder::Bump).into_iter();
+        threads.filter(|thread| !pinned.contains(&thread.id)).skip(offset).take(limit).collect()
+    }
+
+    fn list_tagged(&self, tag: &str) -> Vec<Thread> {
+        self.sorted(ThreadOrder::Bump).into_iter().filter(|thread| thread.tags.iter().any(|t| t == tag)).collect()
+    }
+
+    fn get_thread(&self, thread_id: i32) -> Option<Thread> {
+        self.threads.lock().unwrap().iter().find(|thread| thread.id == thread_id).cloned()
+    }
+
+    fn create_thread(&self, new_thread: NewThread) -> Result<Thread, RepoError> {
+        let mut threads = self.threads.lock().unwrap();
+        let id = threads.iter().map(|thread| thread.id).max().unwrap_or(0) + 1;
+        let thread = new_thread.to_thread(id, chrono::Utc::now().timestamp());
+        threads.push(thread.clone());
+        Ok(thread)
+    }
+
+    fn list_replies(&self, thread_id: i32) -> Vec<Reply> {
+        self.replies.lock().unwrap().get(&thread_id).cloned().unwrap_or_default()
+    }
+
+    fn get_reply(&self, thread_id: i32, reply_id: i32) -> Option<Reply> {
+        self.list_replies(thread_id).into_iter().find(|reply| reply.id == reply_id)
+    }
+
+    fn reply_exists(&self, thread_id: i32, reply_id: i32) -> bool {
+        self.get_reply(thread_id, reply_id).is_some()
+    }
+
+    fn post_thread(&self, _number: i32) -> Option<i32> {
+        None
+    }
+
+    fn reply_summaries(&self, thread_ids: &[i32]) -> HashMap<i32, ReplySummary> {
+        let replies = self.replies.lock().unwrap();
+        thread_ids
+            .iter()
+            .filter_map(|id| replies.get(id).map(|replies| (*id, replies)))
+            .map(|(id, replies)| {
+                let last_reply_at = replies.iter().map(|reply| reply.created_at).max();
+                (id, ReplySummary { count: replies.len(), last_reply_at })
+            })
+            .collect()
+    }
+
+    fn create_reply(&self, thread_id: i32, new_reply: NewReply, bump: bool) -> Result<Reply, RepoError> {
+        let now = chrono::Utc::now().timestamp();
+        let mut threads = self.threads.lock().unwrap();
+        let thread = threads.iter_mut().find(|thread| thread.id == thread_id);
+        thread_state::can_reply(thread.as_deref(), 0, now).map_err(RepoError::Refused)?;
+        let thread = thread.ok_or(RepoError::Refused(ReplyRefusal::Deleted))?;
+
+        let mut replies = self.replies.lock().unwrap();
+        let replies = replies.entry(thread_id).or_default();
+        let reply = new_reply.to_reply(replies.last().map_or(0, |reply| reply.id) + 1, now);
+        replies.push(reply.clone());
+        if bump {
+            thread.last_updated = now;
+        }
+        Ok(reply)
+    }
+
+    fn count_replies(&self) -> usize {
+        self.replies.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    fn referenced_files(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        for thread in self.threads.lock().unwrap().iter() {
+            repository::add_file_names(&mut names, thread.file_urls());
+        }
+        for reply in self.replies.lock().unwrap().values().flatten() {
+            repository::add_file_names(&mut names, reply.file_urls());
+        }
+        names
+    }
+}
@@ -0,0 +1,138 @@
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use serde_json::json;
+
+use crate::Thread;
+
+// Why a thread takes no new replies
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ReplyRefusal {
+    // There is no such thread, or it was deleted
+    Deleted,
+    // Locked from /admin
+    Locked,
+    // Archived from /admin: kept for reading, closed to replies
+    Archived,
+    // Gone past THREAD_TTL without a bump, and about to be purged
+    Expired,
+}
+
+impl ReplyRefusal {
+    pub fn message(self) -> &'static str {
+        match self {
+            ReplyRefusal::Deleted => "Thread not found",
+            ReplyRefusal::Locked => "This thread is locked and takes no new replies",
+            ReplyRefusal::Archived => "This thread is archived and takes no new replies",
+            ReplyRefusal::Expired => "This thread has expired and takes no new replies",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ReplyRefusal::Deleted => StatusCode::NOT_FOUND,
+            ReplyRefusal::Locked | ReplyRefusal::Archived => StatusCode::FORBIDDEN,
+            ReplyRefusal::Expired => StatusCode::GONE,
+        }
+    }
+
+    pub fn to_response(self) -> HttpResponse {
+        HttpResponse::build(self.status()).body(self.message())
+    }
+
+    pub fn to_json(self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(json!({ "error": self.message() }))
+    }
+}
+
+// Whether a reply may be posted to `thread` (None when it doesn't exist) at
+// `now`. Every reply goes through this inside the reply transaction, whichever
+// handler it came from. A `thread_ttl` of 0 never expires threads.
+pub fn can_reply(thread: Option<&Thread>, thread_ttl: i64, now: i64) -> Result<(), ReplyRefusal> {
+    let thread = thread.ok_or(ReplyRefusal::Deleted)?;
+    if thread.locked {
+        Err(ReplyRefusal::Locked)
+    } else if thread.archived {
+        Err(ReplyRefusal::Archived)
+    } else if thread_ttl > 0 && thread.last_updated < now - thread_ttl {
+        // The same cutoff expiry::purge_expired_threads uses
+        Err(ReplyRefusal::Expired)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, form_post};
+    use crate::{expiry, get_replies, insert_reply, insert_thread, moderation};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    #[test]
+    fn each_state_has_its_reason() {
+        let db = test_support::temp_db();
+        let mut thread = insert_thread(&db, test_support::new_thread("Open", "Hi"), false).unwrap();
+        let now = thread.last_updated;
+        assert_eq!(can_reply(Some(&thread), 0, now), Ok(()));
+        assert_eq!(can_reply(None, 0, now), Err(ReplyRefusal::Deleted));
+        assert_eq!(can_reply(Some(&thread), 60, now + 60), Ok(()));
+        assert_eq!(can_reply(Some(&thread), 60, now + 61), Err(ReplyRefusal::Expired));
+        assert_eq!(can_reply(Some(&thread), 0, now + 1_000_000), Ok(()));
+        thread.archived = true;
+        assert_eq!(can_reply(Some(&thread), 0, now), Err(ReplyRefusal::Archived));
+        // Locking wins over archiving, and either over expiry
+        thread.locked = true;
+        assert_eq!(can_reply(Some(&thread), 60, now + 61), Err(ReplyRefusal::Locked));
+    }
+
+    #[actix_web::test]
+    async fn locked_archived_and_deleted_threads_refuse_replies_from_the_form_and_the_api() {
+        let state = test_support::state(&[("API_KEY", "sesame")]);
+        let open = insert_thread(&state.db, test_support::new_thread("Open", "Hi"), false).unwrap();
+        let locked = insert_thread(&state.db, test_support::new_thread("Locked", "Hi"), false).unwrap();
+        let archived = insert_thread(&state.db, test_support::new_thread("Archived", "Hi"), false).unwrap();
+        let deleted = insert_thread(&state.db, test_support::new_thread("Deleted", "Hi"), false).unwrap();
+        insert_reply(&state.db, deleted.id, test_support::new_reply("Before"), true, 0, 0, false).unwrap();
+        moderation::update_thread(&state.db, locked.id, |thread| thread.locked = true).unwrap();
+        moderation::update_thread(&state.db, archived.id, |thread| thread.archived = true).unwrap();
+        expiry::delete_thread(&state.db, deleted.id, i64::MAX).unwrap();
+        let app = init_service(crate::app(&state)).await;
+
+        let cases = [
+            (open.id, None),
+            (locked.id, Some(ReplyRefusal::Locked)),
+            (archived.id, Some(ReplyRefusal::Archived)),
+            (deleted.id, Some(ReplyRefusal::Deleted)),
+        ];
+        for (thread_id, refusal) in cases {
+            let parent = thread_id.to_string();
+            let form = form_post("/reply", &[("parent_id", parent.as_bytes()), ("message", b"Form")]);
+            let response = call_service(&app, form.to_request()).await;
+            match refusal {
+                None => assert_eq!(response.status(), 303),
+                Some(refusal) => {
+                    assert_eq!(response.status(), refusal.status(), "{:?}", refusal);
+                    let body = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+                    assert!(body.contains(refusal.message()), "{:?}: {}", refusal, body);
+                }
+            }
+
+            let api = TestRequest::post()
+                .uri("/api/reply")
+                .peer_addr("192.0.2.1:4000".parse().unwrap())
+                .insert_header(("Authorization", "Bearer sesame"))
+                .set_json(serde_json::json!({ "parent_id": thread_id, "message": "API" }));
+            let response = call_service(&app, api.to_request()).await;
+            match refusal {
+                None => assert!(response.status().is_success()),
+                Some(refusal) => {
+                    assert_eq!(response.status(), refusal.status(), "{:?}", refusal);
+                    let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+                    assert_eq!(body["error"], refusal.message());
+                }
+            }
+            let expected = if refusal.is_none() { 2 } else { 0 };
+            assert_eq!(get_replies(&state.db, thread_id).len(), expected, "{:?}", refusal);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+
+use crate::settings::Settings;
+
+// Send /thread/5/ to /thread/5 with a 301, keeping the query string, so every
+// page has one URL. The board root keeps its slash (BASE_PATH + "/"), and only
+// GET and HEAD are redirected; a POST would lose its body.
+pub async fn redirect_trailing_slash(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let settings = req.app_data::<web::Data<Settings>>().cloned();
+    let enabled = settings.as_ref().is_some_and(|settings| settings.trailing_slash_redirect);
+    let root = settings.map_or_else(|| "/".to_string(), |settings| settings.url("/"));
+
+    let path = req.path();
+    let trimmed = path.trim_end_matches('/');
+    let redirect = enabled
+        && matches!(*req.method(), Method::GET | Method::HEAD)
+        && path != root
+        && trimmed.len() < path.len()
+        && !trimmed.is_empty()
+        // "//host/" would come out as a protocol-relative link to another site
+        && !trimmed.starts_with("//");
+
+    if redirect {
+        let location = match req.query_string() {
+            "" => trimmed.to_string(),
+            query => format!("{}?{}", trimmed, query),
+        };
+        let response = HttpResponse::MovedPermanently()
+            .append_header((header::LOCATION, location))
+            .finish();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support;
+    use actix_web::test::{call_and_read_body, call_service, init_service, TestRequest};
+
+    #[actix_web::test]
+    async fn trailing_slashes_redirect_except_at_the_root() {
+        let state = test_support::state(&[]);
+        let thread = crate::insert_thread(&state.db, test_support::new_thread("Slash", "Hi"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let get = |uri: String| call_service(&app, TestRequest::get().uri(&uri).to_request());
+
+        let thread_uri = format!("/thread/{}", thread.id);
+        for (uri, location) in [
+            (format!("{}/", thread_uri), thread_uri.clone()),
+            (format!("{}//?order=desc", thread_uri), format!("{}?order=desc", thread_uri)),
+            ("/catalog/".to_string(), "/catalog".to_string()),
+        ] {
+            let response = get(uri.clone()).await;
+            assert_eq!(response.status(), 301, "{}", uri);
+            assert_eq!(response.headers().get("location").unwrap(), location.as_str());
+        }
+        assert_eq!(get("/".to_string()).await.status(), 200);
+        let page = call_and_read_body(&app, TestRequest::get().uri(&thread_uri).to_request()).await;
+        let canonical = format!("<link rel=\"canonical\" href=\"{}\">", state.settings.absolute_url(&thread_uri));
+        assert!(String::from_utf8(page.to_vec()).unwrap().contains(&canonical));
+        assert_eq!(get("//example.com/".to_string()).await.status(), 404);
+        let post = TestRequest::post().uri("/reply/").to_request();
+        assert_ne!(call_service(&app, post).await.status(), 301);
+    }
+
+    #[actix_web::test]
+    async fn the_root_below_a_base_path_keeps_its_slash() {
+        let state = test_support::state(&[("BASE_PATH", "/board")]);
+        let app = init_service(crate::app(&state)).await;
+        assert_eq!(call_service(&app, TestRequest::get().uri("/board/").to_request()).await.status(), 200);
+        let response = call_service(&app, TestRequest::get().uri("/board/catalog/").to_request()).await;
+        assert_eq!(response.headers().get("location").unwrap(), "/board/catalog");
+
+        let state = test_support::state(&[("TRAILING_SLASH_REDIRECT", "false")]);
+        let app = init_service(crate::app(&state)).await;
+        assert_eq!(call_service(&app, TestRequest::get().uri("/catalog/").to_request()).await.status(), 404);
+    }
+}
@@ -0,0 +1,1142 @@
+use actix_multipart::{Field, MultipartError};
+use actix_web::http::header::{self, HeaderMap, HeaderValue};
+use actix_web::{error::BlockingError, web, HttpRequest, HttpResponse};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::stream::StreamExt;
+use log::info;
+use std::io::Write;
+use uuid::Uuid;
+
+use serde::{Deserialize, Serialize};
+use sled::Db;
+
+use crate::image_pipeline::{self, process_image, PipelineConfig, PipelineError, ProcessedImage};
+use crate::repost::{self, RepostMode};
+use crate::settings::Settings;
+use crate::{THUMB_DIR, UPLOAD_DIR};
+
+// What we know about an image once it has been stored in UPLOAD_DIR. Until
+// commit() is called the files only exist under their temporary names.
+pub struct UploadMeta {
+    pub filename: String,
+    // Bytes stored, after normalizing
+    pub size: u64,
+    // Bytes that arrived, before normalizing
+    pub received: u64,
+    pub width: u32,
+    pub height: u32,
+    pub sha256: String,
+    // Thumbnail URLs, smallest first; see image_pipeline::thumbnails
+    pub thumbnails: Vec<String>,
+}
+
+// Width and height of a post's full image, stored with the post so pages can
+// describe the image without opening it
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl UploadMeta {
+    pub fn image_size(&self) -> ImageSize {
+        ImageSize {
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("/uploads/{}", self.filename)
+    }
+
+    // Tell the client how much of its upload arrived (RECEIVED_HEADER)
+    pub fn add_received_header(&self, headers: &mut HeaderMap) {
+        headers.insert(header::HeaderName::from_static(RECEIVED_HEADER), HeaderValue::from(self.received));
+    }
+
+    // Move the image and its thumbnails to their final names. Called once the
+    // post (or upload token) that references them has been stored.
+    pub fn commit(&self) -> std::io::Result<()> {
+        let image = format!("{}{}", UPLOAD_DIR, self.filename);
+        std::fs::rename(temp_path(&image), &image)?;
+        for url in &self.thumbnails {
+            let thumbnail = format!("{}{}", THUMB_DIR, url.rsplit('/').next().unwrap_or_default());
+            std::fs::rename(temp_path(&thumbnail), &thumbnail)?;
+        }
+        Ok(())
+    }
+}
+
+// Where a file is written before it is committed. Nothing serves .tmp files,
+// and the admin garbage collection sweeps up any a crash leaves behind.
+fn temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+// Bounding boxes of the thumbnail variants, smallest first. Posts show the first
+// one and offer the second to high-density screens.
+pub const THUMB_SIZES: [u32; 2] = [125, 250];
+
+// Image formats an upload may be in; ALLOWED_IMAGE_TYPES picks which ones the
+// board takes. The stored file keeps the format, thumbnails are in THUMB_FORMAT.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImageType {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+}
+
+impl ImageType {
+    // Name as used in ALLOWED_IMAGE_TYPES, also matched against file extensions
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(ImageType::Jpeg),
+            "png" => Some(ImageType::Png),
+            "gif" => Some(ImageType::Gif),
+            "webp" => Some(ImageType::Webp),
+            _ => None,
+        }
+    }
+
+    // Recognize the format from the first bytes of the file
+    pub fn sniff(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageType::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageType::Png)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(ImageType::Gif)
+        } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+            Some(ImageType::Webp)
+        } else {
+            None
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "jpg",
+            ImageType::Png => "png",
+            ImageType::Gif => "gif",
+            ImageType::Webp => "webp",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "image/jpeg",
+            ImageType::Png => "image/png",
+            ImageType::Gif => "image/gif",
+            ImageType::Webp => "image/webp",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ImageType::Jpeg => "JPEG",
+            ImageType::Png => "PNG",
+            ImageType::Gif => "GIF",
+            ImageType::Webp => "WebP",
+        }
+    }
+}
+
+// How stored uploads are named (IMAGE_FILENAMES). /uploads/ serves files as
+// immutable, so no scheme may ever give a name to different contents than it
+// had before: the schemes that don't name by contents add a random part.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilenameScheme {
+    Uuid,
+    // Upload time in milliseconds, so the files sort chronologically on disk
+    Timestamp,
+    // sha256 of the stored (metadata stripped) file
+    Hash,
+    // The uploaded file's own name, cut down to a-z, 0-9, - and _
+    SanitizedOriginal,
+}
+
+impl FilenameScheme {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "uuid" => Some(FilenameScheme::Uuid),
+            "timestamp" => Some(FilenameScheme::Timestamp),
+            "hash" => Some(FilenameScheme::Hash),
+            "sanitized-original" => Some(FilenameScheme::SanitizedOriginal),
+            _ => None,
+        }
+    }
+
+    // Stem of the stored name; `attempt` counts names already found taken
+    fn stem(&self, original: Option<&str>, sha256: &str, attempt: u32) -> String {
+        let random = || Uuid::new_v4().simple().to_string()[..8].to_string();
+        let stem = match self {
+            FilenameScheme::Uuid => Uuid::new_v4().to_string(),
+            FilenameScheme::Timestamp => format!("{}-{}", chrono::Utc::now().timestamp_millis(), random()),
+            FilenameScheme::Hash => sha256.to_string(),
+            FilenameScheme::SanitizedOriginal => {
+                format!("{}-{}", sanitize_stem(original.unwrap_or_default()), random())
+            }
+        };
+        match attempt {
+            0 => stem,
+            _ => format!("{}-{}", stem, attempt + 1),
+        }
+    }
+}
+
+// Longest part of an uploaded file's name kept by sanitized-original
+const MAX_ORIGINAL_STEM_CHARS: usize = 48;
+
+// "My Cat (1).JPG" becomes "my-cat-1". Anything but ASCII letters, digits, - and
+// _ turns into a dash, so the result is always a safe name to serve.
+fn sanitize_stem(original: &str) -> String {
+    let stem = original.rsplit_once('.').map_or(original, |(stem, _)| stem);
+    let mut sanitized = String::new();
+    for c in stem.to_lowercase().chars() {
+        let c = if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' };
+        if c != '-' || !(sanitized.is_empty() || sanitized.ends_with('-')) {
+            sanitized.push(c);
+        }
+        if sanitized.len() >= MAX_ORIGINAL_STEM_CHARS {
+            break;
+        }
+    }
+    let sanitized = sanitized.trim_end_matches('-');
+    if sanitized.is_empty() {
+        "image".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+// Tries at giving an upload a free name before giving up
+const MAX_NAME_ATTEMPTS: u32 = 20;
+
+// Write an upload to its name under `scheme`, still as a temporary file, and
+// return the name. A name is claimed by creating its temporary file
+// exclusively, so two uploads can't both get it, and skipped when a committed
+// file already has it (two uploads of one image under Hash).
+fn claim_name(
+    bytes: &[u8],
+    scheme: FilenameScheme,
+    original: Option<&str>,
+    sha256: &str,
+    extension: &str,
+) -> std::io::Result<String> {
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let filename = format!("{}.{}", scheme.stem(original, sha256, attempt), extension);
+        let image = format!("{}{}", UPLOAD_DIR, filename);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(temp_path(&image));
+        let mut file = match file {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        if std::path::Path::new(&image).exists() {
+            let _ = std::fs::remove_file(temp_path(&image));
+            continue;
+        }
+        if let Err(e) = file.write_all(bytes) {
+            let _ = std::fs::remove_file(temp_path(&image));
+            return Err(e);
+        }
+        return Ok(filename);
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "no free upload filename",
+    ))
+}
+
+// Running total of bytes stored in UPLOAD_DIR, kept in step with every save and delete
+const USAGE_KEY: &[u8] = b"upload_bytes_total";
+
+// Refused uploads are counted by reason under upload_rejected_{reason}, for
+// /admin/upload-stats
+const REJECTION_PREFIX: &str = "upload_rejected_";
+pub const REJECTION_REASONS: [&str; 8] = [
+    "wrong_type",
+    "too_large",
+    "too_many_pixels",
+    "decode_failure",
+    "invalid_base64",
+    "quota_exceeded",
+    "repost",
+    "malformed",
+];
+
+// An upload made through /api/upload that no post has claimed yet
+#[derive(Serialize, Deserialize)]
+struct UploadToken {
+    filename: String,
+    size: u64,
+    created_at: i64,
+    #[serde(default)]
+    thumbnails: Vec<String>,
+    #[serde(default)]
+    image_size: Option<ImageSize>,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    // Carries the labels of the types that are allowed, for the message
+    UnsupportedType(String),
+    QuotaExceeded,
+    TooLarge { limit: u64 },
+    // Width times height is over MAX_IMAGE_PIXELS
+    TooManyPixels { limit: u64 },
+    InvalidImage,
+    InvalidEncoding,
+    // Resembles an upload already stored, under REPOST_CHECK=reject
+    Repost,
+    Multipart(MultipartError),
+    Io(std::io::Error),
+}
+
+impl From<MultipartError> for UploadError {
+    fn from(e: MultipartError) -> Self {
+        UploadError::Multipart(e)
+    }
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+impl From<sled::Error> for UploadError {
+    fn from(e: sled::Error) -> Self {
+        UploadError::Io(std::io::Error::other(e))
+    }
+}
+
+impl From<BlockingError> for UploadError {
+    fn from(_: BlockingError) -> Self {
+        UploadError::Io(std::io::Error::other("blocking upload task was cancelled"))
+    }
+}
+
+impl UploadError {
+    // Why the upload was refused, as logged and counted; None for a failure on
+    // the board's side rather than a refusal
+    pub fn reason(&self) -> Option<&'static str> {
+        match self {
+            UploadError::UnsupportedType(_) => Some("wrong_type"),
+            UploadError::TooLarge { .. } => Some("too_large"),
+            UploadError::TooManyPixels { .. } => Some("too_many_pixels"),
+            UploadError::InvalidImage => Some("decode_failure"),
+            UploadError::InvalidEncoding => Some("invalid_base64"),
+            UploadError::QuotaExceeded => Some("quota_exceeded"),
+            UploadError::Repost => Some("repost"),
+            UploadError::Multipart(_) => Some("malformed"),
+            UploadError::Io(_) => None,
+        }
+    }
+
+    // The response a post handler sends back when an upload is refused
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            UploadError::UnsupportedType(allowed) => {
+                HttpResponse::BadRequest().body(format!("Only {} images are allowed", allowed))
+            }
+            UploadError::TooLarge { limit } => HttpResponse::PayloadTooLarge()
+                .body(format!("Images may be at most {} bytes", limit)),
+            UploadError::TooManyPixels { limit } => HttpResponse::BadRequest()
+                .body(format!("Images may be at most {} pixels", limit)),
+            UploadError::QuotaExceeded => HttpResponse::InsufficientStorage()
+                .body("The board is out of image storage; text-only posts still work"),
+            UploadError::InvalidImage => {
+                HttpResponse::BadRequest().body("The uploaded file is not a readable image")
+            }
+            UploadError::Repost => {
+                HttpResponse::BadRequest().body("This image looks like one that was already posted")
+            }
+            UploadError::InvalidEncoding => {
+                HttpResponse::BadRequest().body("The image data is not valid base64")
+            }
+            UploadError::Multipart(e) => HttpResponse::BadRequest().body(format!("Malformed upload: {}", e)),
+            UploadError::Io(e) => {
+                log::error!("Failed to store upload: {}", e);
+                HttpResponse::InternalServerError().body("Failed to store upload")
+            }
+        }
+    }
+}
+
+// Response header stating how many bytes of the image a post carried arrived
+pub const RECEIVED_HEADER: &str = "x-upload-bytes";
+
+// Room next to the image in a post form for the text fields and the multipart
+// framing
+const FORM_ALLOWANCE: u64 = 256 * 1024;
+
+// Refuse a form whose declared length can't fit under MAX_UPLOAD_BYTES before
+// any of it is read, counted like any other refused upload. A body sent
+// without a length is still cut off by read_field once the image passes the limit.
+pub fn check_declared_length(
+    req: &HttpRequest,
+    settings: &Settings,
+    db: &Db,
+    poster: Option<&str>,
+) -> Result<(), UploadError> {
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    let limit = settings.max_upload_bytes;
+    let checked = match declared {
+        Some(length) if length > limit.saturating_add(FORM_ALLOWANCE) => Err(UploadError::TooLarge { limit }),
+        _ => Ok(()),
+    };
+    if let Err(e) = &checked {
+        record_rejection(db, settings, poster, e);
+    }
+    checked
+}
+
+// Stream an image field into UPLOAD_DIR, validating it along the way.
+// Returns Ok(None) when the form's file input was left empty. `poster` is the
+// uploader's poster hash, logged with a refusal.
+pub async fn save_upload(
+    field: &mut Field,
+    settings: &Settings,
+    db: &Db,
+    poster: Option<&str>,
+) -> Result<Option<UploadMeta>, UploadError> {
+    let saved = receive_upload(field, settings, db).await;
+    if let Err(e) = &saved {
+        record_rejection(db, settings, poster, e);
+    }
+    saved
+}
+
+async fn receive_upload(field: &mut Field, settings: &Settings, db: &Db) -> Result<Option<UploadMeta>, UploadError> {
+    let original_name = match field.content_disposition().get_filename() {
+        Some(name) if !name.trim().is_empty() => name.to_lowercase(),
+        _ => return Ok(None),
+    };
+
+    // Refuse before reading anything once the quota is used up
+    let quota = settings.upload_quota_bytes;
+    if quota > 0 && usage(db) >= quota {
+        return Err(UploadError::QuotaExceeded);
+    }
+
+    // Validate file extension; the image pipeline checks the contents against it
+    let image_type = original_name
+        .rsplit_once('.')
+        .and_then(|(_, extension)| ImageType::parse(extension))
+        .filter(|image_type| settings.allowed_image_types.contains(image_type))
+        .ok_or_else(|| UploadError::UnsupportedType(settings.image_type_labels()))?;
+
+    let bytes = read_field(field, settings.max_upload_bytes).await?;
+    store_upload(bytes, Some(image_type), Some(original_name), settings, db).await.map(Some)
+}
+
+// Store an image sent as base64 (optionally as a data: URL) in a JSON body.
+// Goes through the same checks as a multipart upload, and the size limit is
+// applied to the encoded length before anything is decoded.
+pub async fn save_base64(
+    data: &str,
+    settings: &Settings,
+    db: &Db,
+    poster: Option<&str>,
+) -> Result<UploadMeta, UploadError> {
+    let saved = receive_base64(data, settings, db).await;
+    if let Err(e) = &saved {
+        record_rejection(db, settings, poster, e);
+    }
+    saved
+}
+
+async fn receive_base64(data: &str, settings: &Settings, db: &Db) -> Result<UploadMeta, UploadError> {
+    let encoded = match data.trim().split_once(";base64,") {
+        Some((prefix, encoded)) if prefix.starts_with("data:") => encoded,
+        _ => data.trim(),
+    };
+
+    let limit = settings.max_upload_bytes;
+    if encoded.len() as u64 > limit.div_ceil(3) * 4 {
+        return Err(UploadError::TooLarge { limit });
+    }
+
+    let quota = settings.upload_quota_bytes;
+    if quota > 0 && usage(db) >= quota {
+        return Err(UploadError::QuotaExceeded);
+    }
+
+    let bytes = BASE64.decode(encoded).map_err(|_| UploadError::InvalidEncoding)?;
+    if bytes.len() as u64 > limit {
+        return Err(UploadError::TooLarge { limit });
+    }
+    // No filename to go by, so the format comes from the contents alone
+    store_upload(bytes, None, None, settings, db).await
+}
+
+// What thumbnails are encoded as (THUMB_FORMAT), whatever the upload's format.
+// PNG and WebP keep transparency, JPEG puts it on white. WebP is lossless, as no
+// lossy WebP encoder is built in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ThumbFormat {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl ThumbFormat {
+    const ALL: [ThumbFormat; 3] = [ThumbFormat::Jpeg, ThumbFormat::Png, ThumbFormat::Webp];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "jpeg" | "jpg" => Some(ThumbFormat::Jpeg),
+            "png" => Some(ThumbFormat::Png),
+            "webp" => Some(ThumbFormat::Webp),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg => "jpg",
+            ThumbFormat::Png => "png",
+            ThumbFormat::Webp => "webp",
+        }
+    }
+}
+
+// Shared tail of every upload once its bytes have arrived: run them through
+// the image pipeline, give the result its final name, account for it against
+// the quota and write its thumbnails. `claimed` is the format the file's name
+// gives, when it came with one. Nothing is left on disk whenever this fails.
+async fn store_upload(
+    bytes: Vec<u8>,
+    claimed: Option<ImageType>,
+    original: Option<String>,
+    settings: &Settings,
+    db: &Db,
+) -> Result<UploadMeta, UploadError> {
+    // An empty file is a broken image, whatever its name says it is
+    if bytes.is_empty() {
+        return Err(UploadError::InvalidImage);
+    }
+    let received = bytes.len() as u64;
+    let config = PipelineConfig::new(settings);
+    let processed = match web::block(move || process_image(bytes, claimed, &config)).await? {
+        Ok(processed) => processed,
+        Err(PipelineError::UnsupportedType) => return Err(UploadError::UnsupportedType(settings.image_type_labels())),
+        Err(PipelineError::TooManyPixels { limit }) => return Err(UploadError::TooManyPixels { limit }),
+        Err(PipelineError::InvalidImage(e)) => {
+            info!("Rejected an upload that does not decode: {}", e);
+            return Err(UploadError::InvalidImage);
+        }
+    };
+
+    // Compared with the stored uploads before it takes up a name or any space
+    let resemblance = match processed.dhash {
+        Some(hash) => {
+            let db = db.clone();
+            let max_distance = settings.repost_distance;
+            Some((hash, web::block(move || repost::find_similar(&db, hash, max_distance)).await?))
+        }
+        None => None,
+    };
+    if let Some((_, Some(earlier))) = &resemblance {
+        if settings.repost_check == RepostMode::Reject {
+            info!("Rejected upload resembling {}", earlier);
+            return Err(UploadError::Repost);
+        }
+    }
+
+    // Named only now, since Hash names by the contents after normalizing
+    let ProcessedImage {
+        image_type,
+        bytes,
+        width,
+        height,
+        sha256,
+        thumbnails,
+        ..
+    } = processed;
+    let size = bytes.len() as u64;
+    let scheme = settings.image_filenames;
+    let hash = sha256.clone();
+    let extension = image_type.extension();
+    let filename = web::block(move || claim_name(&bytes, scheme, original.as_deref(), &hash, extension)).await??;
+    let filepath = temp_path(&format!("{}{}", UPLOAD_DIR, filename));
+
+    // Reserve the space atomically; another upload may have landed meanwhile
+    let quota = settings.upload_quota_bytes;
+    if !reserve_usage(db, size, quota)? {
+        let _ = std::fs::remove_file(&filepath);
+        return Err(UploadError::QuotaExceeded);
+    }
+
+    let name = filename.clone();
+    let thumb_format = settings.thumb_format;
+    let thumbnails = match web::block(move || write_thumbnails(&name, &thumbnails, thumb_format)).await? {
+        Ok(thumbnails) => thumbnails,
+        Err(e) => {
+            // The post can still show the full image, so this isn't fatal
+            log::warn!("Failed to write thumbnails for {}: {}", filename, e);
+            Vec::new()
+        }
+    };
+
+    if let Some((hash, earlier)) = resemblance {
+        repost::record(db, &filename, hash)?;
+        if let Some(earlier) = earlier {
+            log::warn!("Upload {} looks like a repost of {}", filename, earlier);
+            repost::flag(db, &filename, &earlier)?;
+        }
+    }
+
+    let meta = UploadMeta {
+        filename,
+        size,
+        received,
+        width,
+        height,
+        sha256,
+        thumbnails,
+    };
+    info!(
+        "Received {} bytes for upload {}, stored as {} bytes ({}x{}, sha256 {})",
+        meta.received, meta.filename, meta.size, meta.width, meta.height, meta.sha256
+    );
+    Ok(meta)
+}
+
+// The size a stored image claims in its header, without decoding any pixels
+fn header_dimensions(path: &str) -> image::ImageResult<(u32, u32)> {
+    image::io::Reader::open(path)?.with_guessed_format()?.into_dimensions()
+}
+
+// Size of a committed upload from its header, for posts stored before sizes were
+pub fn stored_image_size(image_url: &str) -> Option<ImageSize> {
+    let filename = image_url.rsplit('/').next().filter(|name| !name.is_empty())?;
+    let (width, height) = header_dimensions(&format!("{}{}", UPLOAD_DIR, filename)).ok()?;
+    Some(ImageSize { width, height })
+}
+
+// Write encoded thumbnails to THUMB_DIR as {name}_{size}.{jpg,png,webp},
+// temporary until the upload is committed, and return their URLs
+fn write_thumbnails(
+    filename: &str,
+    thumbnails: &[(u32, Vec<u8>)],
+    format: ThumbFormat,
+) -> std::io::Result<Vec<String>> {
+    let mut urls = Vec::new();
+    for (size, encoded) in thumbnails {
+        let name = thumbnail_name(filename, *size, format);
+        std::fs::write(temp_path(&format!("{}{}", THUMB_DIR, name)), encoded)?;
+        urls.push(format!("/thumbs/{}", name));
+    }
+    Ok(urls)
+}
+
+// Generate the thumbnails of a stored upload again, replacing the old ones.
+// Thumbnails in a format the board no longer uses are left for /admin/gc, as
+// the post may still point at them.
+pub fn rebuild_thumbnails(filename: &str, format: ThumbFormat) -> Result<Vec<String>, image::ImageError> {
+    let image = image::io::Reader::open(format!("{}{}", UPLOAD_DIR, filename))?.with_guessed_format()?.decode()?;
+    let thumbnails = write_thumbnails(filename, &image_pipeline::thumbnails(&image, format)?, format)?;
+    for url in &thumbnails {
+        let thumbnail = format!("{}{}", THUMB_DIR, url.rsplit('/').next().unwrap_or_default());
+        std::fs::rename(temp_path(&thumbnail), &thumbnail)?;
+    }
+    Ok(thumbnails)
+}
+
+fn thumbnail_name(filename: &str, size: u32, format: ThumbFormat) -> String {
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    format!("{}_{}.{}", stem, size, format.extension())
+}
+
+// The names the thumbnails of `filename` may have, in every format, since
+// THUMB_FORMAT may have changed since they were made
+fn all_thumbnail_names(filename: &str) -> impl Iterator<Item = String> + '_ {
+    THUMB_SIZES
+        .into_iter()
+        .flat_map(move |size| ThumbFormat::ALL.map(|format| thumbnail_name(filename, size, format)))
+}
+
+// srcset for a post image: thumbnails at 1x, 2x, ...; when the image was too
+// small for the larger variant, the original stands in for it
+pub fn srcset(base_path: &str, image_url: &str, thumbnails: &[String]) -> Option<String> {
+    if thumbnails.is_empty() {
+        return None;
+    }
+    let mut candidates: Vec<&str> = thumbnails.iter().map(String::as_str).collect();
+    if candidates.len() < THUMB_SIZES.len() {
+        candidates.push(image_url);
+    }
+    Some(
+        candidates
+            .iter()
+            .enumerate()
+            .map(|(i, url)| format!("{}{} {}x", base_path, url, i + 1))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+// Read the field into memory chunk by chunk, giving up as soon as the size
+// limit is crossed rather than after the whole body has arrived
+async fn read_field<S>(field: &mut S, limit: u64) -> Result<Vec<u8>, UploadError>
+where
+    S: futures_util::Stream<Item = Result<web::Bytes, MultipartError>> + Unpin,
+{
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk?;
+        if (bytes.len() + data.len()) as u64 > limit {
+            return Err(UploadError::TooLarge { limit });
+        }
+        bytes.extend_from_slice(&data);
+    }
+    Ok(bytes)
+}
+
+// Count a refused upload and, with LOG_UPLOAD_REJECTIONS, log it
+fn record_rejection(db: &Db, settings: &Settings, poster: Option<&str>, error: &UploadError) {
+    let Some(reason) = error.reason() else {
+        return;
+    };
+    if settings.log_upload_rejections {
+        info!("Rejected upload from poster {}: {}", poster.unwrap_or("unknown"), reason);
+    }
+    let counted = db.fetch_and_update(format!("{}{}", REJECTION_PREFIX, reason), |old| {
+        let count = old
+            .and_then(|value| <[u8; 8]>::try_from(value).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Some((count + 1).to_be_bytes().to_vec())
+    });
+    if let Err(e) = counted {
+        log::error!("Failed to count a rejected upload: {}", e);
+    }
+}
+
+// Refused uploads so far, by reason
+pub fn rejection_counts(db: &Db) -> Vec<(&'static str, u64)> {
+    REJECTION_REASONS
+        .iter()
+        .map(|reason| {
+            let count = db
+                .get(format!("{}{}", REJECTION_PREFIX, reason))
+                .ok()
+                .flatten()
+                .and_then(|value| <[u8; 8]>::try_from(value.as_ref()).ok())
+                .map_or(0, u64::from_be_bytes);
+            (*reason, count)
+        })
+        .collect()
+}
+
+// Bytes currently used by uploads
+pub fn usage(db: &Db) -> u64 {
+    db.get(USAGE_KEY)
+        .ok()
+        .flatten()
+        .and_then(|value| <[u8; 8]>::try_from(value.as_ref()).ok())
+        .map(u64::from_be_bytes)
+        .unwrap_or(0)
+}
+
+// Add `size` bytes to the usage total unless that would exceed a non-zero quota
+fn reserve_usage(db: &Db, size: u64, quota: u64) -> sled::Result<bool> {
+    let mut reserved = false;
+    db.fetch_and_update(USAGE_KEY, |old| {
+        let used = old
+            .and_then(|value| <[u8; 8]>::try_from(value).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        reserved = quota == 0 || used + size <= quota;
+        let new = if reserved { used + size } else { used };
+        Some(new.to_be_bytes().to_vec())
+    })?;
+    Ok(reserved)
+}
+
+// Overwrite the usage total, e.g. after measuring UPLOAD_DIR from scratch
+pub fn set_usage(db: &Db, bytes: u64) -> sled::Result<()> {
+    db.insert(USAGE_KEY, &bytes.to_be_bytes())?;
+    Ok(())
+}
+
+// Give back the space of a deleted upload
+pub fn release_usage(db: &Db, size: u64) -> sled::Result<()> {
+    db.fetch_and_update(USAGE_KEY, |old| {
+        let used = old
+            .and_then(|value| <[u8; 8]>::try_from(value).ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+        Some(used.saturating_sub(size).to_be_bytes().to_vec())
+    })?;
+    Ok(())
+}
+
+fn token_key(token: &str) -> Vec<u8> {
+    format!("upload_token_{}", token).into_bytes()
+}
+
+// Remember a stored upload under a fresh token that a later JSON post can attach
+pub fn issue_token(db: &Db, meta: &UploadMeta) -> sled::Result<String> {
+    let token = Uuid::new_v4().simple().to_string();
+    let record = UploadToken {
+        filename: meta.filename.clone(),
+        size: meta.size,
+        created_at: chrono::Utc::now().timestamp(),
+        thumbnails: meta.thumbnails.clone(),
+        image_size: Some(meta.image_size()),
+    };
+    db.insert(token_key(&token), serde_json::to_vec(&record).expect("Failed to serialize upload token"))?;
+    Ok(token)
+}
+
+// The image behind a redeemed upload token
+pub struct TokenImage {
+    pub image_url: String,
+    pub thumbnails: Vec<String>,
+    // Unknown for tokens issued before sizes were recorded
+    pub image_size: Option<ImageSize>,
+}
+
+// Claim a token for a post, returning its image. Each token works once, and
+// not at all once it is older than `ttl` seconds.
+pub fn redeem_token(db: &Db, token: &str, ttl: i64) -> Option<TokenImage> {
+    let record: UploadToken = db
+        .remove(token_key(token))
+        .ok()
+        .flatten()
+        .and_then(|value| serde_json::from_slice(&value).ok())?;
+
+    if chrono::Utc::now().timestamp() - record.created_at > ttl {
+        discard_upload(db, &record.filename, record.size);
+        return None;
+    }
+    Some(TokenImage {
+        image_url: format!("/uploads/{}", record.filename),
+        thumbnails: record.thumbnails,
+        image_size: record.image_size,
+    })
+}
+
+// Drop tokens nobody claimed within `ttl` seconds, along with their files
+pub fn purge_expired_tokens(db: &Db, ttl: i64) -> usize {
+    let now = chrono::Utc::now().timestamp();
+    let mut purged = 0;
+
+    for (key, value) in db.scan_prefix(b"upload_token_").flatten() {
+        let record: UploadToken = match serde_json::from_slice(&value) {
+            Ok(record) => record,
+            Err(_) => continue,
+        };
+        if now - record.created_at > ttl && db.remove(&key).ok().flatten().is_some() {
+            discard_upload(db, &record.filename, record.size);
+            purged += 1;
+        }
+    }
+
+    if purged > 0 {
+        info!("Purged {} expired upload tokens", purged);
+    }
+    purged
+}
+
+// Files held by unexpired upload tokens; they aren't orphans yet
+pub fn pending_token_files(db: &Db) -> Vec<String> {
+    db.scan_prefix(b"upload_token_")
+        .values()
+        .flatten()
+        .filter_map(|value| serde_json::from_slice::<UploadToken>(&value).ok())
+        .flat_map(|record| {
+            let thumbnails: Vec<String> = all_thumbnail_names(&record.filename).collect();
+            std::iter::once(record.filename).chain(thumbnails)
+        })
+        .collect()
+}
+
+// Delete an upload that ended up not being used by any post
+pub fn discard(db: &Db, meta: &UploadMeta) {
+    discard_upload(db, &meta.filename, meta.size);
+}
+
+// Delete the image of a removed post, given its /uploads/ URL
+pub fn delete_image(db: &Db, image_url: &str) {
+    let filename = match image_url.rsplit('/').next() {
+        Some(name) if !name.is_empty() => name,
+        _ => return,
+    };
+    let size = std::fs::metadata(format!("{}{}", UPLOAD_DIR, filename)).map_or(0, |metadata| metadata.len());
+    discard_upload(db, filename, size);
+}
+
+// Remove an upload and its thumbnails, committed or not, and give back its space
+fn discard_upload(db: &Db, filename: &str, size: u64) {
+    for thumbnail in all_thumbnail_names(filename) {
+        let path = format!("{}{}", THUMB_DIR, thumbnail);
+        let _ = std::fs::remove_file(temp_path(&path));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    repost::forget(db, filename);
+    let path = format!("{}{}", UPLOAD_DIR, filename);
+    let removed_temp = std::fs::remove_file(temp_path(&path)).is_ok();
+    let removed = std::fs::remove_file(&path).is_ok();
+    if removed_temp || removed {
+        let _ = release_usage(db, size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_multipart::Multipart;
+    use actix_web::test::TestRequest;
+    use futures_util::stream;
+    use sha2::{Digest, Sha256};
+
+    async fn save_first_field(
+        form: &mut Multipart,
+        settings: &Settings,
+        db: &Db,
+    ) -> Result<Option<UploadMeta>, UploadError> {
+        let mut field = form.next().await.expect("the form has no fields").unwrap();
+        save_upload(&mut field, settings, db, None).await
+    }
+
+    #[actix_web::test]
+    async fn save_upload_reports_what_it_stored() {
+        let _files = test_support::files().await;
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[("ALLOWED_IMAGE_TYPES", "png")]);
+        let png = test_support::png(300, 200, 255);
+        let mut form = test_support::chunked_form(&[("image", &png)], 100);
+
+        let meta = save_first_field(&mut form, &settings, &db).await.unwrap().expect("no upload was stored");
+        assert_eq!(meta.received, png.len() as u64);
+        assert_eq!((meta.width, meta.height), (300, 200));
+        assert!(meta.filename.ends_with(".png"));
+        let stored = std::fs::read(temp_path(&format!("{}{}", UPLOAD_DIR, meta.filename))).unwrap();
+        assert_eq!(meta.size, stored.len() as u64);
+        assert_eq!(meta.sha256, format!("{:x}", Sha256::digest(&stored)));
+        assert_eq!(meta.thumbnails.len(), 2);
+        assert_eq!(usage(&db), meta.size);
+        discard(&db, &meta);
+    }
+
+    #[actix_web::test]
+    async fn save_upload_skips_an_empty_file_input() {
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[]);
+        let mut form = test_support::chunked_form(&[("image@", b"")], 16);
+        assert!(save_first_field(&mut form, &settings, &db).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn save_upload_refuses_what_its_name_does_not_allow() {
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[]);
+        let mut form = test_support::chunked_form(&[("image@notes.txt", b"hello")], 16);
+        let refused = save_first_field(&mut form, &settings, &db).await;
+        assert!(matches!(refused, Err(UploadError::UnsupportedType(_))));
+        assert_eq!(rejection_counts(&db)[0], ("wrong_type", 1));
+    }
+
+    #[actix_web::test]
+    async fn read_field_stops_once_the_limit_is_crossed() {
+        let pulled = std::cell::Cell::new(0);
+        let chunks = (0..100).map(|_| {
+            pulled.set(pulled.get() + 1);
+            Ok::<_, MultipartError>(web::Bytes::from_static(&[0; 10]))
+        });
+        let mut field = stream::iter(chunks);
+        assert!(matches!(read_field(&mut field, 55).await, Err(UploadError::TooLarge { limit: 55 })));
+        assert_eq!(pulled.get(), 6);
+
+        let mut field = stream::iter((0..5).map(|_| Ok::<_, MultipartError>(web::Bytes::from_static(&[1; 10]))));
+        assert_eq!(read_field(&mut field, 50).await.unwrap(), vec![1; 50]);
+    }
+
+    #[test]
+    fn declared_lengths_past_the_limit_are_refused_up_front() {
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[("MAX_UPLOAD_BYTES", "1000")]);
+        let declaring =
+            |length: u64| TestRequest::post().insert_header((header::CONTENT_LENGTH, length)).to_http_request();
+        assert!(check_declared_length(&declaring(1000 + FORM_ALLOWANCE), &settings, &db, None).is_ok());
+        let refused = check_declared_length(&declaring(1001 + FORM_ALLOWANCE), &settings, &db, None);
+        assert!(matches!(refused, Err(UploadError::TooLarge { limit: 1000 })));
+        assert!(check_declared_length(&TestRequest::post().to_http_request(), &settings, &db, None).is_ok());
+        assert_eq!(rejection_counts(&db).iter().map(|(_, count)| count).sum::<u64>(), 1);
+    }
+
+    #[actix_web::test]
+    async fn both_thumbnail_sizes_are_made_without_upscaling() {
+        let _files = test_support::files().await;
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[("ALLOWED_IMAGE_TYPES", "png")]);
+        let mut stored = Vec::new();
+        for (width, height) in [(300, 200), (200, 100), (100, 50)] {
+            let png = test_support::png(width, height, 255);
+            let mut form = test_support::chunked_form(&[("image", &png)], 1000);
+            stored.push(save_first_field(&mut form, &settings, &db).await.unwrap().expect("no upload was stored"));
+        }
+
+        let sizes: Vec<Vec<(u32, u32)>> = stored
+            .iter()
+            .map(|meta| {
+                let names = meta.thumbnails.iter().map(|url| url.rsplit('/').next().unwrap());
+                names.map(|name| header_dimensions(&temp_path(&format!("{}{}", THUMB_DIR, name))).unwrap()).collect()
+            })
+            .collect();
+        assert_eq!(sizes, [vec![(125, 83), (250, 167)], vec![(125, 63)], vec![]]);
+
+        let image_url = |meta: &UploadMeta| format!("/uploads/{}", meta.filename);
+        let both = srcset("", &image_url(&stored[0]), &stored[0].thumbnails).unwrap();
+        assert_eq!(both, format!("{} 1x, {} 2x", stored[0].thumbnails[0], stored[0].thumbnails[1]));
+        let one = srcset("/board", &image_url(&stored[1]), &stored[1].thumbnails).unwrap();
+        assert_eq!(one, format!("/board{} 1x, /board{} 2x", stored[1].thumbnails[0], image_url(&stored[1])));
+        assert_eq!(srcset("", &image_url(&stored[2]), &stored[2].thumbnails), None);
+        for meta in &stored {
+            discard(&db, meta);
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_and_truncated_images_are_refused() {
+        let _files = test_support::files().await;
+        let db = test_support::temp_db();
+        let settings = test_support::settings(&[]);
+        let jpeg = test_support::jpeg(300, 200);
+        let before = std::fs::read_dir(UPLOAD_DIR).unwrap().count();
+
+        for (name, bytes) in [("image@empty.jpg", &[][..]), ("image", &jpeg[..jpeg.len() / 2])] {
+            let mut form = test_support::chunked_form(&[(name, bytes)], 1000);
+            let refused = save_first_field(&mut form, &settings, &db).await;
+            assert!(matches!(refused, Err(UploadError::InvalidImage)), "{} of {} bytes", name, bytes.len());
+            assert_eq!(refused.err().unwrap().to_response().status(), 400);
+        }
+        assert_eq!(std::fs::read_dir(UPLOAD_DIR).unwrap().count(), before);
+        assert_eq!(usage(&db), 0);
+    }
+
+    // A small JPEG whose frame header claims `width` x `height`
+    fn jpeg_claiming(width: u16, height: u16) -> Vec<u8> {
+        let mut jpeg = test_support::jpeg(16, 16);
+        let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).expect("no baseline frame header");
+        // Marker, segment length, sample precision, then height and width
+        jpeg[sof + 5..sof + 7].copy_from_slice(&height.to_be_bytes());
+        jpeg[sof + 7..sof + 9].copy_from_slice(&width.to_be_bytes());
+        jpeg
+    }
+
+    #[actix_web::test]
+    async fn images_claiming_too_many_pixels_are_refused() {
+        let _files = test_support::files().await;
+        let db = test_support::temp_db();
+        let before = std::fs::read_dir(UPLOAD_DIR).unwrap().count();
+        // A hundred megapixels, from a file of a few hundred bytes
+        let bomb = jpeg_claiming(10_000, 10_000);
+        assert!(bomb.len() < 2000);
+        let small_cap: &[(&str, &str)] = &[("MAX_IMAGE_PIXELS", "3000")];
+
+        for (image, vars, cap) in [(bomb, &[][..], 50_000_000), (test_support::jpeg(64, 48), small_cap, 3000)] {
+            let settings = test_support::settings(vars);
+            let mut form = test_support::chunked_form(&[("image", &image)], 1000);
+            let refused = save_first_field(&mut form, &settings, &db).await;
+            assert!(matches!(refused, Err(UploadError::TooManyPixels { limit }) if limit == cap), "{:?}", vars);
+            assert_eq!(refused.err().unwrap().to_response().status(), 400);
+        }
+        assert_eq!(std::fs::read_dir(UPLOAD_DIR).unwrap().count(), before);
+    }
+
+    #[actix_web::test]
+    async fn every_filename_scheme_gives_unique_safe_names() {
+        let _files = test_support::files().await;
+        let bytes = b"the same upload every time";
+        let sha256 = format!("{:x}", Sha256::digest(bytes));
+        let original = Some("../../My Cat (1).JPG");
+        let schemes = [
+            FilenameScheme::Uuid,
+            FilenameScheme::Timestamp,
+            FilenameScheme::Hash,
+            FilenameScheme::SanitizedOriginal,
+        ];
+        for scheme in schemes {
+            // Uploads still in flight hold their names as temporary files
+            let names: Vec<String> =
+                (0..5).map(|_| claim_name(bytes, scheme, original, &sha256, "jpg").unwrap()).collect();
+            let mut unique = names.clone();
+            unique.sort();
+            unique.dedup();
+            assert_eq!(unique.len(), names.len(), "{:?} repeated a name: {:?}", scheme, names);
+            for name in &names {
+                let (stem, extension) = name.rsplit_once('.').unwrap();
+                assert_eq!(extension, "jpg");
+                let safe = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_';
+                assert!(!stem.is_empty() && stem.chars().all(safe), "{:?} gave {:?}", scheme, name);
+                assert!(!stem.starts_with('-'), "{:?} gave {:?}", scheme, name);
+                assert_eq!(std::fs::read(temp_path(&format!("{}{}", UPLOAD_DIR, name))).unwrap(), bytes);
+            }
+            match scheme {
+                FilenameScheme::Hash => assert!(names.iter().all(|name| name.starts_with(&sha256))),
+                FilenameScheme::SanitizedOriginal => assert!(names.iter().all(|name| name.starts_with("my-cat-1-"))),
+                _ => {}
+            }
+            for name in names {
+                std::fs::remove_file(temp_path(&format!("{}{}", UPLOAD_DIR, name))).unwrap();
+            }
+        }
+
+        // A committed file keeps its contents; the next upload named the same goes around it
+        let committed = format!("{}{}.png", UPLOAD_DIR, sha256);
+        std::fs::write(&committed, b"already here").unwrap();
+        let name = claim_name(bytes, FilenameScheme::Hash, None, &sha256, "png").unwrap();
+        assert_eq!(name, format!("{}-2.png", sha256));
+        assert_eq!(std::fs::read(&committed).unwrap(), b"already here");
+        std::fs::remove_file(temp_path(&format!("{}{}", UPLOAD_DIR, name))).unwrap();
+        std::fs::remove_file(committed).unwrap();
+    }
+
+    #[test]
+    fn sanitized_names_keep_only_safe_characters() {
+        assert_eq!(sanitize_stem("My Cat (1).JPG"), "my-cat-1");
+        assert_eq!(sanitize_stem("../../etc/passwd.png"), "etc-passwd");
+        assert_eq!(sanitize_stem("снимок.png"), "image");
+        assert_eq!(sanitize_stem(".png"), "image");
+        assert_eq!(sanitize_stem(&"a".repeat(100)).len(), MAX_ORIGINAL_STEM_CHARS);
+    }
+
+    #[test]
+    fn only_refusals_are_counted_and_logging_is_optional() {
+        let db = test_support::temp_db();
+        test_support::logged();
+        let quiet = test_support::settings(&[("LOG_UPLOAD_REJECTIONS", "false")]);
+        let loud = test_support::settings(&[("LOG_UPLOAD_REJECTIONS", "true")]);
+        record_rejection(&db, &quiet, Some("quiet-poster"), &UploadError::TooLarge { limit: 10 });
+        record_rejection(&db, &loud, Some("loud-poster"), &UploadError::TooLarge { limit: 10 });
+        record_rejection(&db, &quiet, None, &UploadError::Repost);
+        record_rejection(&db, &loud, Some("loud-poster"), &UploadError::Io(std::io::Error::other("disk full")));
+
+        let counts: std::collections::HashMap<_, _> = rejection_counts(&db).into_iter().collect();
+        assert_eq!((counts["too_large"], counts["repost"], counts["wrong_type"]), (2, 1, 0));
+        assert_eq!(counts.values().sum::<u64>(), 3);
+        assert!(UploadError::Io(std::io::Error::other("disk full")).reason().is_none());
+
+        let logged = test_support::logged();
+        assert!(!logged.iter().any(|line| line.contains("quiet-poster")));
+        let loud: Vec<&String> = logged.iter().filter(|line| line.contains("loud-poster")).collect();
+        assert_eq!(loud, ["Rejected upload from poster loud-poster: too_large"]);
+    }
+}
@@ -0,0 +1,208 @@
+use actix_web::HttpResponse;
+use askama::Template;
+use log::error;
+use serde_json::json;
+
+use crate::post_rule::{Field, Filled, PostRule};
+use crate::settings::Settings;
+use crate::{word_filter, REPLY_IMAGES_DISABLED};
+
+// The same limits as the maxlength of the post form fields
+pub const MAX_TITLE_CHARS: usize = 75;
+pub const MAX_MESSAGE_CHARS: usize = 8000;
+
+// One problem with a submitted post. Posts are checked completely, so every
+// problem is reported at once instead of one per attempt.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+    InvalidThreadId,
+    EmptyTitle,
+    TitleTooLong,
+    EmptyMessage,
+    MessageTooLong,
+    // More lines than MAX_MESSAGE_LINES, which it carries
+    TooManyLines(usize),
+    // A part of THREAD_RULE or REPLY_RULE the post doesn't meet, in words
+    Required(String),
+    ReplyImagesDisabled,
+    BlockedWord,
+}
+
+impl ValidationError {
+    pub fn message(&self) -> String {
+        match self {
+            ValidationError::InvalidThreadId => "Invalid thread id".to_string(),
+            ValidationError::EmptyTitle => "Title cannot be empty".to_string(),
+            ValidationError::TitleTooLong => "Title may be at most 75 characters".to_string(),
+            ValidationError::EmptyMessage => "Message cannot be empty".to_string(),
+            ValidationError::MessageTooLong => "Message may be at most 8000 characters".to_string(),
+            ValidationError::TooManyLines(limit) => format!("Message may be at most {} lines", limit),
+            ValidationError::Required(message) => message.clone(),
+            ValidationError::ReplyImagesDisabled => REPLY_IMAGES_DISABLED.to_string(),
+            ValidationError::BlockedWord => word_filter::REJECTED.to_string(),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "post_error.html")]
+struct PostErrorTemplate<'a> {
+    errors: &'a [ValidationError],
+    // Where the "Return" link goes
+    back: &'a str,
+    base_path: &'a str,
+    theme_color: &'a str,
+}
+
+// Everything wrong with a new thread's fields
+pub fn thread_errors(settings: &Settings, title: &str, message: &str, has_image: bool) -> Vec<ValidationError> {
+    // A board without titles drops whatever a client sends as one
+    let title = if settings.titles_enabled { title.trim() } else { "" };
+    let filled = Filled {
+        title: !title.is_empty(),
+        message: !message.trim().is_empty(),
+        image: has_image,
+    };
+    let mut errors = rule_errors(&settings.thread_rule, filled, "New threads");
+    if title.chars().count() > MAX_TITLE_CHARS {
+        errors.push(ValidationError::TitleTooLong);
+    }
+    errors.extend(message_errors(settings, message));
+    if settings.word_filter.apply(title).is_err() || settings.word_filter.apply(message.trim()).is_err() {
+        errors.push(ValidationError::BlockedWord);
+    }
+    errors
+}
+
+// Everything wrong with a reply's fields
+pub fn reply_errors(settings: &Settings, message: &str, has_image: bool) -> Vec<ValidationError> {
+    let filled = Filled {
+        title: false,
+        message: !message.trim().is_empty(),
+        image: has_image,
+    };
+    let mut errors = rule_errors(&settings.reply_rule, filled, "Replies");
+    errors.extend(message_errors(settings, message));
+    if settings.word_filter.apply(message.trim()).is_err() {
+        errors.push(ValidationError::BlockedWord);
+    }
+    errors
+}
+
+// One error per part of `rule` the post fails. A missing title or message on
+// its own keeps its short message; anything else says what `posts` need.
+fn rule_errors(rule: &PostRule, filled: Filled, posts: &str) -> Vec<ValidationError> {
+    rule.unmet(filled)
+        .into_iter()
+        .map(|part| match part {
+            PostRule::Field(Field::Title) => ValidationError::EmptyTitle,
+            PostRule::Field(Field::Message) => ValidationError::EmptyMessage,
+            part => ValidationError::Required(format!("{} need {}", posts, part.describe())),
+        })
+        .collect()
+}
+
+fn message_errors(settings: &Settings, message: &str) -> Vec<ValidationError> {
+    let message = message.trim();
+    let mut errors = Vec::new();
+    if message.chars().count() > MAX_MESSAGE_CHARS {
+        errors.push(ValidationError::MessageTooLong);
+    }
+    // lines() treats \r\n as one break, so browsers' line endings count once
+    let limit = settings.max_message_lines;
+    if limit > 0 && message.lines().count() > limit {
+        errors.push(ValidationError::TooManyLines(limit));
+    }
+    errors
+}
+
+// 400 page for the post forms listing every problem, with a link to `back`
+pub fn error_page(settings: &Settings, errors: &[ValidationError], back: &str) -> HttpResponse {
+    let tmpl = PostErrorTemplate {
+        errors,
+        back: &settings.url(back),
+        base_path: &settings.base_path,
+        theme_color: &settings.theme_color,
+    };
+    match tmpl.render() {
+        Ok(rendered) => HttpResponse::BadRequest().content_type("text/html").body(rendered),
+        Err(e) => {
+            error!("Template rendering error: {}", e);
+            HttpResponse::BadRequest().body(errors[0].message())
+        }
+    }
+}
+
+// 400 for the JSON API: "error" holds the first problem as before, "errors" all of them
+pub fn json_errors(errors: &[ValidationError]) -> HttpResponse {
+    let messages: Vec<String> = errors.iter().map(ValidationError::message).collect();
+    HttpResponse::BadRequest().json(json!({ "error": messages[0], "errors": messages }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+
+    #[test]
+    fn every_problem_is_reported_together() {
+        let settings = test_support::settings(&[]);
+        let long = "a".repeat(MAX_MESSAGE_CHARS + 1);
+        let errors = thread_errors(&settings, "  ", &long, false);
+        assert_eq!(errors, [ValidationError::EmptyTitle, ValidationError::MessageTooLong]);
+        assert!(thread_errors(&settings, "Title", "Message", false).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn the_form_and_the_api_list_both_failures() {
+        let state = test_support::state(&[("API_KEY", "sesame")]);
+        let app = init_service(crate::app(&state)).await;
+        let long = "a".repeat(MAX_MESSAGE_CHARS + 1);
+        let expected = [ValidationError::EmptyTitle.message(), ValidationError::MessageTooLong.message()];
+
+        let form = test_support::form_post("/thread", &[("title", b""), ("message", long.as_bytes())]);
+        let response = call_service(&app, form.to_request()).await;
+        assert_eq!(response.status(), 400);
+        let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+        for message in &expected {
+            assert!(page.contains(&format!("<li>{}</li>", message)), "{}", page);
+        }
+
+        let api = TestRequest::post()
+            .uri("/api/thread")
+            .insert_header(("Authorization", "Bearer sesame"))
+            .set_json(json!({ "title": "", "message": long }));
+        let response = call_service(&app, api.to_request()).await;
+        assert_eq!(response.status(), 400);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body, json!({ "error": expected[0], "errors": expected }));
+    }
+
+    #[actix_web::test]
+    async fn messages_past_the_line_limit_are_refused() {
+        let settings = test_support::settings(&[]);
+        let lines = |count: usize, ending: &str| vec!["line"; count].join(ending);
+        assert!(reply_errors(&settings, &lines(50, "\r\n"), false).is_empty());
+        // Blank lines around the message are trimmed before counting
+        assert!(reply_errors(&settings, &format!("\n\n{}\n\n\n", lines(50, "\n")), false).is_empty());
+        let too_many = [ValidationError::TooManyLines(50)];
+        assert_eq!(reply_errors(&settings, &lines(51, "\n"), false), too_many);
+        assert_eq!(reply_errors(&settings, &format!("a{}b", "\n".repeat(60)), false), too_many);
+        let unlimited = test_support::settings(&[("MAX_MESSAGE_LINES", "0")]);
+        assert!(reply_errors(&unlimited, &lines(500, "\n"), false).is_empty());
+
+        let state = test_support::state(&[("MAX_MESSAGE_LINES", "3")]);
+        let thread = crate::insert_thread(&state.db, test_support::new_thread("Thread", "Hi"), false).unwrap();
+        let app = init_service(crate::app(&state)).await;
+        let parent = thread.id.to_string();
+        for (message, status) in [("1\n2\n3", 303), ("1\n2\n3\n4", 400)] {
+            let reply = [("parent_id", parent.as_bytes()), ("message", message.as_bytes())];
+            let response = call_service(&app, test_support::form_post("/reply", &reply).to_request()).await;
+            assert_eq!(response.status(), status, "{:?}", message);
+            let page = String::from_utf8(read_body(response).await.to_vec()).unwrap();
+            assert_eq!(page.contains("<li>Message may be at most 3 lines</li>"), status == 400);
+        }
+        assert_eq!(crate::get_replies(&state.db, thread.id).len(), 1);
+    }
+}
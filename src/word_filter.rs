@@ -0,0 +1,160 @@
+use log::warn;
+
+// Shown when a post is refused in reject mode
+pub const REJECTED: &str = "Your post contains a blocked word";
+
+const CENSORED: &str = "***";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    // Refuse the whole post with 400
+    Reject,
+    // Post it with every blocked term replaced by ***
+    Censor,
+}
+
+// Operator-defined blocklist for thread titles and post messages. Terms match
+// case-insensitively and only as whole words, so blocking "ass" leaves "class"
+// alone.
+pub struct WordFilter {
+    terms: Vec<Vec<char>>,
+    pub mode: FilterMode,
+}
+
+pub struct Blocked;
+
+impl WordFilter {
+    // Terms from the WORD_FILTER list plus one per line of WORD_FILTER_FILE
+    pub fn new(mut terms: Vec<String>, file: Option<&str>, mode: &str) -> Self {
+        if let Some(path) = file {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => terms.extend(contents.lines().map(str::to_string)),
+                Err(e) => warn!("Could not read word filter file {}: {}", path, e),
+            }
+        }
+
+        let mut terms: Vec<Vec<char>> = terms
+            .iter()
+            .map(|term| term.trim())
+            .filter(|term| !term.is_empty() && !term.starts_with('#'))
+            .map(|term| term.chars().map(fold).collect())
+            .collect();
+        // Longest first, so "bad word" wins over "bad" when both are listed
+        terms.sort_by_key(|term| std::cmp::Reverse(term.len()));
+        terms.dedup();
+
+        let mode = match mode.trim().to_lowercase().as_str() {
+            "censor" => FilterMode::Censor,
+            _ => FilterMode::Reject,
+        };
+
+        WordFilter { terms, mode }
+    }
+
+    // The text to store, or Blocked if the post has to be refused
+    pub fn apply(&self, text: &str) -> Result<String, Blocked> {
+        if self.terms.is_empty() {
+            return Ok(text.to_string());
+        }
+
+        let chars: Vec<char> = text.chars().collect();
+        let mut out = String::with_capacity(text.len());
+        let mut i = 0;
+        while i < chars.len() {
+            match self.match_at(&chars, i) {
+                Some(_) if self.mode == FilterMode::Reject => return Err(Blocked),
+                Some(len) => {
+                    out.push_str(CENSORED);
+                    i += len;
+                }
+                None => {
+                    out.push(chars[i]);
+                    i += 1;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    // Length of the blocked term starting at `start`, if one does
+    fn match_at(&self, chars: &[char], start: usize) -> Option<usize> {
+        if start > 0 && is_word_char(chars[start - 1]) {
+            return None;
+        }
+
+        self.terms
+            .iter()
+            .find(|term| {
+                let end = start + term.len();
+                end <= chars.len()
+                    && chars[start..end].iter().zip(term.iter()).all(|(&c, &t)| fold(c) == t)
+                    && (end == chars.len() || !is_word_char(chars[end]))
+            })
+            .map(|term| term.len())
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Lowercase a single character, keeping it as is when lowercasing would change
+// its length (so positions in the original text stay valid)
+fn fold(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+    use actix_web::test::{call_service, init_service};
+
+    fn filter(mode: &str) -> WordFilter {
+        WordFilter::new(vec!["spam".to_string(), "bad word".to_string(), "BAD".to_string()], None, mode)
+    }
+
+    #[test]
+    fn reject_mode_refuses_whole_words_only() {
+        let filter = filter("reject");
+        assert!(filter.apply("Buy SPAM now").is_err());
+        assert!(filter.apply("spam").is_err());
+        assert!(filter.apply("(Spam!)").is_err());
+        assert_eq!(filter.apply("spammer and antispam, badger").ok().as_deref(), Some("spammer and antispam, badger"));
+    }
+
+    #[test]
+    fn censor_mode_stars_out_each_term() {
+        let filter = filter("censor");
+        assert_eq!(filter.apply("Spam, spam and a BAD WORD.").ok().as_deref(), Some("***, *** and a ***."));
+        assert_eq!(filter.apply("bad words are badly spelled").ok().as_deref(), Some("*** words are badly spelled"));
+    }
+
+    #[test]
+    fn the_file_adds_terms_and_skips_comments() {
+        let path = std::env::temp_dir().join(format!("word-filter-{}.txt", std::process::id()));
+        std::fs::write(&path, "# one per line\n\neggs\n").unwrap();
+        let filter = WordFilter::new(Vec::new(), path.to_str(), "censor");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(filter.apply("green eggs # one").ok().as_deref(), Some("green *** # one"));
+    }
+
+    #[actix_web::test]
+    async fn posts_are_refused_or_censored_by_mode() {
+        let post = |message: &[u8]| test_support::form_post("/thread", &[("title", b"Hi"), ("message", message)]);
+
+        let state = test_support::state(&[("WORD_FILTER", "spam")]);
+        let app = init_service(crate::app(&state)).await;
+        assert_eq!(call_service(&app, post(b"Lovely spam").to_request()).await.status(), 400);
+        assert!(state.db.scan_prefix(b"thread_").next().is_none());
+
+        let state = test_support::state(&[("WORD_FILTER", "spam"), ("WORD_FILTER_MODE", "censor")]);
+        let app = init_service(crate::app(&state)).await;
+        assert!(call_service(&app, post(b"Lovely spam").to_request()).await.status().is_redirection());
+        assert_eq!(crate::get_thread(&state.db, 1).unwrap().message, "Lovely ***");
+    }
+}
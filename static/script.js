@@ -1,9 +1,118 @@
 document.addEventListener('DOMContentLoaded', () => {
-    const images = document.querySelectorAll('.expandable-image');
+    const basePath = document.body.dataset.basePath;
+    const messageBox = document.querySelector('.postform textarea[name="message"]');
 
-    images.forEach(img => {
-        img.addEventListener('click', () => {
-            img.classList.toggle('expanded');
+    // Hook up the posts under `root`; also run on replies added by the reply form
+    const enhance = root => {
+        // Without JS the image link opens the full image; with it, it expands in place
+        root.querySelectorAll('.image-link').forEach(link => {
+            const img = link.querySelector('.expandable-image');
+            // Thumbnails swap to the full image while expanded
+            const thumbSrc = img.getAttribute('src');
+            const thumbSrcset = img.getAttribute('srcset');
+            link.addEventListener('click', event => {
+                event.preventDefault();
+                const expanded = img.classList.toggle('expanded');
+                if (expanded) {
+                    img.removeAttribute('srcset');
+                    img.src = link.getAttribute('href');
+                } else {
+                    img.src = thumbSrc;
+                    if (thumbSrcset) {
+                        img.setAttribute('srcset', thumbSrcset);
+                    }
+                }
+            });
         });
-    });
+
+        // Clicking a reply number quotes it into the reply box at the cursor. Without
+        // JS the link opens /thread/{id}/reply?quote=, which fills in the box instead.
+        root.querySelectorAll('.post-number').forEach(link => {
+            link.addEventListener('click', event => {
+                if (!messageBox) {
+                    return;
+                }
+                event.preventDefault();
+                const { threadId, replyId } = link.dataset;
+                fetch(`${basePath}/api/thread/${threadId}/quote/${replyId}`)
+                    .then(response => response.ok ? response.json() : Promise.reject())
+                    .then(body => body.quote)
+                    .catch(() => `>>${replyId}\n`)
+                    .then(quote => {
+                        const at = messageBox.selectionStart;
+                        messageBox.value = messageBox.value.slice(0, at) + quote + messageBox.value.slice(messageBox.selectionEnd);
+                        messageBox.selectionStart = messageBox.selectionEnd = at + quote.length;
+                        messageBox.focus();
+                    });
+            });
+        });
+
+        root.querySelectorAll('.spoiler').forEach(spoiler => {
+            spoiler.addEventListener('click', () => {
+                spoiler.classList.toggle('revealed');
+            });
+        });
+
+        // Video links (MEDIA_EMBEDS) load their player only once clicked, so
+        // nothing is fetched from the video site before that
+        root.querySelectorAll('.media-embed').forEach(link => {
+            link.addEventListener('click', event => {
+                event.preventDefault();
+                const player = document.createElement('iframe');
+                player.className = 'media-player';
+                player.src = link.dataset.embedSrc;
+                player.allow = 'fullscreen; picture-in-picture';
+                player.referrerPolicy = 'strict-origin-when-cross-origin';
+                link.replaceWith(player);
+            });
+        });
+    };
+    enhance(document);
+
+    // On a thread page the reply is posted in the background and added to the
+    // end of the thread. Without JS the form posts to /reply and redirects.
+    const replyForm = document.querySelector('form[action$="/reply"]');
+    // A refused post comes back as an error page listing the problems, or as plain text
+    const refusal = body => {
+        const problems = new DOMParser().parseFromString(body, 'text/html').querySelectorAll('.errors li');
+        return problems.length ? Array.from(problems, problem => problem.textContent).join('\n') : body;
+    };
+    const replies = document.querySelector('.postlists');
+    if (replyForm && replies) {
+        replyForm.addEventListener('submit', event => {
+            event.preventDefault();
+            const submit = replyForm.querySelector('input[type="submit"]');
+            submit.disabled = true;
+            const threadId = replyForm.elements.parent_id.value;
+            fetch(`${basePath}/api/thread/${threadId}/reply`, { method: 'POST', body: new FormData(replyForm) })
+                .then(response => response.text().then(body => response.ok ? body : Promise.reject(refusal(body))))
+                .then(html => {
+                    replies.querySelector(':scope > p')?.remove();
+                    const template = document.createElement('template');
+                    template.innerHTML = html.trim();
+                    const reply = template.content.firstElementChild;
+                    // Newest first (?order=desc) puts it on top instead
+                    if (replies.dataset.order === 'desc') {
+                        replies.prepend(reply);
+                    } else {
+                        replies.appendChild(reply);
+                    }
+                    enhance(reply);
+                    // reset() alone would bring back a ?quote= the page was opened with
+                    replyForm.reset();
+                    messageBox.value = '';
+                    reply.scrollIntoView({ block: 'nearest' });
+                })
+                .catch(reason => alert(typeof reason === 'string' && reason ? reason : 'Failed to post reply'))
+                .finally(() => {
+                    submit.disabled = false;
+                });
+        });
+    }
+
+    // Lets the installed board open offline; see sw.js. Browsers only allow
+    // service workers over HTTPS and on localhost, elsewhere this fails quietly.
+    if ('serviceWorker' in navigator) {
+        navigator.serviceWorker.register(`${basePath}/static/sw.js`, { scope: `${basePath}/` }).catch(() => {});
+    }
 });
@@ -0,0 +1,38 @@
+// Keeps a copy of the board index and its assets, so the installed board still
+// opens without a connection. Online, everything is fetched fresh as usual; the
+// copy only stands in when the network fails.
+const CACHE = 'board-shell-v1';
+// The board root, e.g. https://example.org/board/
+const scope = self.registration.scope;
+const SHELL = [scope, `${scope}static/style.css`, `${scope}static/script.js`, `${scope}favicon.ico`];
+
+self.addEventListener('install', event => {
+    event.waitUntil(caches.open(CACHE).then(cache => cache.addAll(SHELL)).then(() => self.skipWaiting()));
+});
+
+// Drop the caches of older versions of this worker
+self.addEventListener('activate', event => {
+    event.waitUntil(
+        caches.keys()
+            .then(keys => Promise.all(keys.filter(key => key !== CACHE).map(key => caches.delete(key))))
+            .then(() => self.clients.claim())
+    );
+});
+
+self.addEventListener('fetch', event => {
+    const request = event.request;
+    if (request.method !== 'GET' || !SHELL.includes(request.url)) {
+        return;
+    }
+    event.respondWith(
+        fetch(request)
+            .then(response => {
+                if (response.ok) {
+                    const copy = response.clone();
+                    caches.open(CACHE).then(cache => cache.put(request, copy));
+                }
+                return response;
+            })
+            .catch(() => caches.match(request))
+    );
+});